@@ -0,0 +1,59 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use quick_bc::barcode::{AtrandiBarcodes, CellBarcode, Chemistry, Scoring};
+
+
+/// Reads holding random barcode blocks from the whitelist, followed by some insert. Each round gets
+/// mismatches substitutions
+fn simulate_reads(barcodes:&AtrandiBarcodes, num_reads:usize, mismatches:usize, rng:&mut StdRng) -> Vec<Vec<u8>> {
+    let plate = &barcodes.plates[0];
+    let mut block = Vec::new();
+    (0..num_reads).map(|_| {
+        let bc = CellBarcode {plate: 0, wells: std::array::from_fn(|r| rng.gen_range(0..plate.rounds[r].list.len()))};
+        barcodes.write_expected_block(&bc, &mut block);
+        let mut read = block.clone();
+        for round in 0..4 {
+            for _ in 0..mismatches {
+                let pos = 12*round + rng.gen_range(0..8);
+                read[pos] = match read[pos] {b'A' => b'C', b'C' => b'G', b'G' => b'T', _ => b'A'};
+            }
+        }
+        read.extend((0..100).map(|_| b"ACGT"[rng.gen_range(0..4)]));
+        read
+    }).collect()
+}
+
+
+/// Correct each read and write its barcode name into a reused buffer, as to-fastq does
+fn correct_all(barcodes:&AtrandiBarcodes, reads:&[Vec<u8>], name:&mut Vec<u8>) -> usize {
+    let mut count_ok = 0;
+    for read in reads {
+        if let Some(bc) = barcodes.get_correct_bc_from_read(read, None, false) {
+            barcodes.write_bc_name(&bc, name);
+            count_ok += 1;
+        }
+    }
+    count_ok
+}
+
+
+fn bench_correction(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).expect("Failed to read barcode file");
+    let exact = simulate_reads(&barcodes, 10000, 0, &mut rng);
+    let one_mismatch = simulate_reads(&barcodes, 10000, 1, &mut rng);
+    let random = (0..10000).map(|_| (0..150).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect()).collect::<Vec<Vec<u8>>>();
+
+    let mut name = Vec::new();
+    c.bench_function("correct 10k exact", |b| b.iter(|| correct_all(&barcodes, black_box(&exact), &mut name)));
+    c.bench_function("correct 10k 1 mismatch per round", |b| b.iter(|| correct_all(&barcodes, black_box(&one_mismatch), &mut name)));
+    c.bench_function("correct 10k random", |b| b.iter(|| correct_all(&barcodes, black_box(&random), &mut name)));
+
+    barcodes.scorer = Scoring::Quality.scorer(20);
+    c.bench_function("correct 10k 1 mismatch per round, quality scorer", |b| b.iter(|| correct_all(&barcodes, black_box(&one_mismatch), &mut name)));
+}
+
+criterion_group!(benches, bench_correction);
+criterion_main!(benches);
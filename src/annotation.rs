@@ -0,0 +1,306 @@
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+
+use clap::ValueEnum;
+
+
+/// Strand of a feature
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strand {
+    Forward,
+    Reverse,
+    Unknown
+}
+
+impl Strand {
+    pub fn parse(s: &str) -> Strand {
+        match s {
+            "+" => Strand::Forward,
+            "-" => Strand::Reverse,
+            _ => Strand::Unknown
+        }
+    }
+}
+
+
+/// Strandedness of the library: how the read orientation relates to the feature strand
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Strandedness {
+    /// The fragment has the same orientation as the feature
+    Forward,
+    /// The fragment has the opposite orientation of the feature
+    Reverse,
+    /// Orientation is ignored
+    Unstranded
+}
+
+impl Strandedness {
+
+    /// Check if a fragment can come from a feature. fragment_reverse is the orientation of the
+    /// fragment, i.e. of the first read in the pair
+    pub fn accepts(&self, fragment_reverse: bool, feature_strand: Strand) -> bool {
+        match (self, feature_strand) {
+            (Strandedness::Unstranded, _) | (_, Strand::Unknown) => true,
+            (Strandedness::Forward, Strand::Forward) | (Strandedness::Reverse, Strand::Reverse) => !fragment_reverse,
+            (Strandedness::Forward, Strand::Reverse) | (Strandedness::Reverse, Strand::Forward) => fragment_reverse
+        }
+    }
+}
+
+
+/// A genomic region. Coordinates are 0-based, half-open
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub chrom: String,
+    pub start: usize,
+    pub end: usize,
+    pub name: String,
+    pub strand: Strand
+}
+
+
+/// Index of regions for overlap queries
+pub struct RegionIndex {
+    pub regions: Vec<Region>,
+    by_chrom: HashMap<String, Vec<usize>>, //Region IDs sorted by start
+    max_len: HashMap<String, usize>
+}
+
+impl RegionIndex {
+
+    /// Index regions. They must not end before they start
+    pub fn new(regions: Vec<Region>) -> RegionIndex {
+        let mut by_chrom: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut max_len: HashMap<String, usize> = HashMap::new();
+        for (i, r) in regions.iter().enumerate() {
+            by_chrom.entry(r.chrom.clone()).or_default().push(i);
+            let m = max_len.entry(r.chrom.clone()).or_insert(0);
+            *m = (*m).max(r.end.saturating_sub(r.start));
+        }
+        for ids in by_chrom.values_mut() {
+            ids.sort_by_key(|i| regions[*i].start);
+        }
+        RegionIndex {
+            regions,
+            by_chrom,
+            max_len
+        }
+    }
+
+    /// Read regions from a BED file. Name and strand columns are optional
+    pub fn from_bed(path: &PathBuf) -> std::io::Result<RegionIndex> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut regions = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+                continue;
+            }
+            let parts: Vec<&str> = line.split('\t').collect();
+            if parts.len() < 3 {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Malformed BED line: {}", line)));
+            }
+            let start = parts[1].parse::<usize>().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let end = parts[2].parse::<usize>().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if end < start {
+                return Err(Error::new(ErrorKind::InvalidData, format!("BED region ends before it starts: {}", line)));
+            }
+            regions.push(Region {
+                chrom: parts[0].to_string(),
+                start,
+                end,
+                name: parts.get(3).map(|s| s.to_string()).unwrap_or_else(|| format!("{}:{}-{}", parts[0], start, end)),
+                strand: parts.get(5).map(|s| Strand::parse(s)).unwrap_or(Strand::Unknown)
+            });
+        }
+        Ok(RegionIndex::new(regions))
+    }
+
+    /// IDs of all regions overlapping [start,end) on a chromosome
+    pub fn overlapping(&self, chrom: &str, start: usize, end: usize) -> Vec<usize> {
+        let mut hits = Vec::new();
+        let ids = match self.by_chrom.get(chrom) {
+            Some(ids) => ids,
+            None => return hits
+        };
+        let max_len = self.max_len[chrom];
+
+        //Regions starting at or after end cannot overlap; scan backwards until no region can reach start
+        let mut i = ids.partition_point(|id| self.regions[*id].start < end);
+        while i > 0 {
+            i -= 1;
+            let r = &self.regions[ids[i]];
+            if r.start + max_len <= start {
+                break;
+            }
+            if r.end > start {
+                hits.push(ids[i]);
+            }
+        }
+        hits
+    }
+}
+
+
+
+/// A gene from a GTF file, with its exons. Coordinates are 0-based, half-open
+#[derive(Clone, Debug)]
+pub struct Gene {
+    pub id: String,
+    pub name: String,
+    pub biotype: Option<String>,
+    pub chrom: String,
+    pub start: usize,
+    pub end: usize,
+    pub strand: Strand,
+    pub exons: Vec<(usize,usize)>
+}
+
+impl Gene {
+
+    /// Check if an aligned block lies fully within one exon
+    pub fn is_exonic(&self, start: usize, end: usize) -> bool {
+        self.exons.iter().any(|(s,e)| *s <= start && end <= *e)
+    }
+
+    /// Length of the gene counting exons only, with overlapping exons of different transcripts merged
+    pub fn exonic_length(&self) -> usize {
+        let mut exons = self.exons.clone();
+        exons.sort();
+        let mut len = 0;
+        let mut covered_to = 0;
+        for (start, end) in exons {
+            let start = start.max(covered_to);
+            if end > start {
+                len += end - start;
+                covered_to = end;
+            }
+        }
+        len
+    }
+
+    /// Region spanning the whole gene
+    pub fn to_region(&self) -> Region {
+        Region {
+            chrom: self.chrom.clone(),
+            start: self.start,
+            end: self.end,
+            name: self.id.clone(),
+            strand: self.strand
+        }
+    }
+}
+
+
+/// Get the value of an attribute in column 9 of a GTF line, e.g. gene_id "ENSG0001";
+fn gtf_attribute<'a>(attributes: &'a str, key: &str) -> Option<&'a str> {
+    attributes.split(';').find_map(|kv| {
+        let (k, v) = kv.trim().split_once(' ')?;
+        if k==key {
+            Some(v.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+
+/// Read genes from a GTF file. Genes are built from exon lines, in order of first appearance
+pub fn read_gtf(path: &PathBuf) -> std::io::Result<Vec<Gene>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut genes: Vec<Gene> = Vec::new();
+    let mut gene_index: HashMap<String, usize> = HashMap::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() < 9 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Malformed GTF line: {}", line)));
+        }
+        if parts[2] != "exon" {
+            continue;
+        }
+        let start = parts[3].parse::<usize>().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let end = parts[4].parse::<usize>().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if start == 0 || end < start {
+            return Err(Error::new(ErrorKind::InvalidData, format!("GTF exon ends before it starts, or starts at 0: {}", line)));
+        }
+        let start = start - 1;
+        let gene_id = gtf_attribute(parts[8], "gene_id").ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("No gene_id: {}", line)))?;
+
+        let i = *gene_index.entry(gene_id.to_string()).or_insert_with(|| {
+            genes.push(Gene {
+                id: gene_id.to_string(),
+                name: gtf_attribute(parts[8], "gene_name").unwrap_or(gene_id).to_string(),
+                biotype: gtf_attribute(parts[8], "gene_type").or(gtf_attribute(parts[8], "gene_biotype")).map(|b| b.to_string()),
+                chrom: parts[0].to_string(),
+                start,
+                end,
+                strand: Strand::parse(parts[6]),
+                exons: Vec::new()
+            });
+            genes.len() - 1
+        });
+        let gene = &mut genes[i];
+        gene.start = gene.start.min(start);
+        gene.end = gene.end.max(end);
+        gene.exons.push((start, end));
+    }
+    Ok(genes)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(chrom: &str, start: usize, end: usize, strand: Strand) -> Region {
+        Region { chrom: chrom.to_string(), start, end, name: format!("{}", start), strand }
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let index = RegionIndex::new(vec![
+            region("chr1", 100, 200, Strand::Forward),
+            region("chr1", 150, 1000, Strand::Reverse),
+            region("chr2", 100, 200, Strand::Forward)
+        ]);
+        assert_eq!(index.overlapping("chr1", 0, 100).len(), 0);
+        assert_eq!(index.overlapping("chr1", 120, 130), vec![0]);
+        assert_eq!(index.overlapping("chr1", 190, 210).len(), 2);
+        assert_eq!(index.overlapping("chr1", 500, 510), vec![1]);
+        assert_eq!(index.overlapping("chr3", 100, 200).len(), 0);
+    }
+
+    #[test]
+    fn test_strandedness() {
+        assert!(Strandedness::Forward.accepts(false, Strand::Forward));
+        assert!(!Strandedness::Forward.accepts(true, Strand::Forward));
+        assert!(Strandedness::Reverse.accepts(true, Strand::Forward));
+        assert!(Strandedness::Unstranded.accepts(true, Strand::Forward));
+        assert!(Strandedness::Reverse.accepts(false, Strand::Unknown));
+    }
+
+    #[test]
+    fn test_gtf_attribute() {
+        let attr = "gene_id \"ENSG01\"; transcript_id \"ENST01\"; gene_name \"ABC\";";
+        assert_eq!(gtf_attribute(attr, "gene_id"), Some("ENSG01"));
+        assert_eq!(gtf_attribute(attr, "gene_name"), Some("ABC"));
+        assert_eq!(gtf_attribute(attr, "gene_type"), None);
+    }
+
+    #[test]
+    fn test_exonic_length() {
+        let gene = Gene {
+            id: "g".to_string(), name: "g".to_string(), biotype: None, chrom: "chr1".to_string(),
+            start: 100, end: 500, strand: Strand::Forward,
+            exons: vec![(300, 500), (100, 200), (150, 250), (400, 450)]
+        };
+        assert_eq!(gene.exonic_length(), 350);
+    }
+}
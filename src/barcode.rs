@@ -0,0 +1,2242 @@
+use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+
+use csv::ReaderBuilder;
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use bio::pattern_matching::myers::{Myers, MyersBuilder};
+use sha2::{Digest, Sha256};
+
+use crate::pattern::PatternExtractor;
+use crate::histogram::read_histogram;
+
+
+//////////////////////////////////////////
+////////////////////////////////////////// Scoring of barcodes against the whitelist
+//////////////////////////////////////////
+
+/// Scores an observed barcode against a whitelist barcode of the same length. The score is the length
+/// minus a penalty, so a perfect match scores the length and the cutoffs in the correction apply to all scorers
+pub trait BarcodeScorer {
+    /// Penalty for the observed barcode, with base qualities if known (phred+33), against a whitelist barcode
+    fn penalty(&self, observed:&[u8], qual:Option<&[u8]>, expected:&[u8]) -> i32;
+
+    fn score(&self, observed:&[u8], qual:Option<&[u8]>, expected:&[u8]) -> i32 {
+        expected.len() as i32 - self.penalty(observed, qual, expected)
+    }
+
+    /// Best scoring barcode among whitelist barcodes stored back to back, each stride bases long. Ties go to the first
+    fn best_match(&self, observed:&[u8], qual:Option<&[u8]>, seqs:&[u8], stride:usize) -> Option<(usize,i32)> {
+        best_match_by_score(self, observed, qual, seqs, stride)
+    }
+}
+
+
+/// Score each whitelist barcode in turn; see BarcodeScorer::best_match
+fn best_match_by_score<S: BarcodeScorer + ?Sized>(scorer:&S, observed:&[u8], qual:Option<&[u8]>, seqs:&[u8], stride:usize) -> Option<(usize,i32)> {
+    let mut best: Option<(usize,i32)> = None;
+    for (j, expected) in seqs.chunks_exact(stride).enumerate() {
+        let score = scorer.score(observed, qual, expected);
+        if best.is_none_or(|(_, best_score)| score > best_score) {
+            best = Some((j, score));
+        }
+    }
+    best
+}
+
+
+/// Each mismatching base costs 1
+pub struct HammingScorer;
+
+impl BarcodeScorer for HammingScorer {
+    fn penalty(&self, observed:&[u8], _qual:Option<&[u8]>, expected:&[u8]) -> i32 {
+        expected.len() as i32 - num_similar_elements(observed, expected)
+    }
+
+    /// 8-base barcodes, as in the Atrandi chemistry, are compared as one 64-bit word each
+    fn best_match(&self, observed:&[u8], qual:Option<&[u8]>, seqs:&[u8], stride:usize) -> Option<(usize,i32)> {
+        let observed: [u8;8] = match observed.try_into() {
+            Ok(observed) if stride == 8 => observed,
+            _ => return best_match_by_score(self, observed, qual, seqs, stride)
+        };
+        let observed = u64::from_le_bytes(observed);
+        let mut best: Option<(usize,i32)> = None;
+        for (j, expected) in seqs.chunks_exact(8).enumerate() {
+            let expected = u64::from_le_bytes(expected.try_into().expect("stride is 8"));
+            let score = 8 - mismatching_bytes(observed ^ expected) as i32;
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((j, score));
+                if score == 8 {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+
+/// Number of nonzero bytes in a word, i.e. mismatches between two 8-base sequences XORed together
+fn mismatching_bytes(x:u64) -> u32 {
+    //Fold each byte onto its lowest bit
+    let x = x | (x >> 4);
+    let x = x | (x >> 2);
+    let x = x | (x >> 1);
+    (x & 0x0101010101010101).count_ones()
+}
+
+
+/// Mismatches at bases with quality below min_qual, or N, count half (rounded down); others cost 1.
+/// Without qualities this is the same as Hamming distance
+pub struct QualityWeightedScorer {
+    pub min_qual: u8
+}
+
+impl BarcodeScorer for QualityWeightedScorer {
+    fn penalty(&self, observed:&[u8], qual:Option<&[u8]>, expected:&[u8]) -> i32 {
+        let mut confident = 0;
+        let mut uncertain = 0;
+        for i in 0..observed.len() {
+            if observed[i] != expected[i] {
+                let low_qual = qual.is_some_and(|q| q[i] < 33 + self.min_qual);
+                if low_qual || observed[i]==b'N' {
+                    uncertain += 1;
+                } else {
+                    confident += 1;
+                }
+            }
+        }
+        confident + uncertain/2
+    }
+}
+
+
+/// Runs of consecutive mismatches cost open for the first base and extend for each following one.
+/// Isolated sequencing errors are thus cheaper than clustered differences, which suggest a different barcode
+pub struct AffineScorer {
+    pub open: i32,
+    pub extend: i32
+}
+
+impl BarcodeScorer for AffineScorer {
+    fn penalty(&self, observed:&[u8], _qual:Option<&[u8]>, expected:&[u8]) -> i32 {
+        let mut penalty = 0;
+        let mut in_run = false;
+        for i in 0..observed.len() {
+            if observed[i] != expected[i] {
+                penalty += if in_run {self.extend} else {self.open};
+                in_run = true;
+            } else {
+                in_run = false;
+            }
+        }
+        penalty
+    }
+}
+
+
+/// Scoring methods that can be selected on the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Scoring {
+    /// Number of mismatching bases
+    #[default]
+    Hamming,
+    /// Mismatches at low quality bases count half
+    Quality,
+    /// Runs of mismatches are penalized more than isolated ones
+    Affine
+}
+
+impl Scoring {
+    pub fn scorer(&self, min_qual:u8) -> Scorer {
+        match self {
+            Scoring::Hamming => Scorer::Hamming(HammingScorer),
+            Scoring::Quality => Scorer::Quality(QualityWeightedScorer {min_qual}),
+            Scoring::Affine => Scorer::Affine(AffineScorer {open: 1, extend: 2})
+        }
+    }
+}
+
+
+/// The scorer picked on the command line. Dispatching with a match rather than through a trait object lets
+/// best_match run the loop of each scorer compiled for it, e.g. the word-wise Hamming scan
+pub enum Scorer {
+    Hamming(HammingScorer),
+    Quality(QualityWeightedScorer),
+    Affine(AffineScorer)
+}
+
+impl BarcodeScorer for Scorer {
+    fn penalty(&self, observed:&[u8], qual:Option<&[u8]>, expected:&[u8]) -> i32 {
+        match self {
+            Scorer::Hamming(s) => s.penalty(observed, qual, expected),
+            Scorer::Quality(s) => s.penalty(observed, qual, expected),
+            Scorer::Affine(s) => s.penalty(observed, qual, expected)
+        }
+    }
+
+    fn best_match(&self, observed:&[u8], qual:Option<&[u8]>, seqs:&[u8], stride:usize) -> Option<(usize,i32)> {
+        match self {
+            Scorer::Hamming(s) => s.best_match(observed, qual, seqs, stride),
+            Scorer::Quality(s) => s.best_match(observed, qual, seqs, stride),
+            Scorer::Affine(s) => s.best_match(observed, qual, seqs, stride)
+        }
+    }
+}
+
+
+
+//////////////////////////////////////////
+////////////////////////////////////////// Basic whitelist correction
+//////////////////////////////////////////
+
+/// One-mismatch neighbours of the barcodes in a whitelist, giving the index of the barcode in the list.
+/// Sequences that are neighbours of several barcodes are left out
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NeighborIndex {
+    pub neighbors: HashMap<Vec<u8>,usize>
+}
+
+impl NeighborIndex {
+
+    pub fn new(list: &[String]) -> NeighborIndex {
+        let mut neighbors: HashMap<Vec<u8>,Option<usize>> = HashMap::new();
+        for (i, bc) in list.iter().enumerate() {
+            let mut seq = bc.as_bytes().to_vec();
+            for pos in 0..seq.len() {
+                let orig = seq[pos];
+                for &base in b"ACGTN" {
+                    if base != orig {
+                        seq[pos] = base;
+                        neighbors.entry(seq.clone())
+                            .and_modify(|j| if *j != Some(i) {*j = None})
+                            .or_insert(Some(i));
+                    }
+                }
+                seq[pos] = orig;
+            }
+        }
+        //Whitelist barcodes themselves are exact matches, not neighbours
+        for bc in list {
+            neighbors.remove(bc.as_bytes());
+        }
+        NeighborIndex {
+            neighbors: neighbors.into_iter().filter_map(|(seq, i)| i.map(|i| (seq, i))).collect()
+        }
+    }
+}
+
+
+pub struct BarcodeWhitelist {
+    pub list: Vec<String>,    //List for alignment; not sure if worth having separate from set
+    seqs: Vec<u8>,            //The same barcodes back to back, bc_length bases each, for scanning without pointer chasing
+    set: HashMap<Vec<u8>,usize>, //Dictionary for fast lookup of exact matches, giving index in list
+    pub neighbors: NeighborIndex, //Unique one-mismatch neighbours; empty unless built or loaded
+    patterns: Vec<Myers<u64>>, //Myers matchers, same order as list; used when the length is off (indels)
+    bc_length: usize
+}
+
+impl BarcodeWhitelist {
+
+    /// Build whitelist from a list of barcodes
+    pub fn new(list: Vec<String>, bc_length: usize) -> BarcodeWhitelist {
+        assert!(list.iter().all(|bc| bc.len() == bc_length), "Barcodes in a whitelist must have the same length");
+        let set = list.iter().enumerate().map(|(i,bc)| (bc.as_bytes().to_vec(), i)).collect();
+        let seqs = list.iter().flat_map(|bc| bc.bytes()).collect();
+        let patterns = list.iter().map(|bc| Myers::<u64>::new(bc.as_bytes().to_vec())).collect();
+        BarcodeWhitelist {
+            list,
+            seqs,
+            set,
+            neighbors: NeighborIndex::default(),
+            patterns,
+            bc_length
+        }
+    }
+
+
+    /// Index of a barcode in the list, if it is there exactly
+    pub fn index_of(&self, bc: &[u8]) -> Option<usize> {
+        self.set.get(bc).copied()
+    }
+
+
+    /// Compare to each BC allowing one insertion or deletion, using Myers' algorithm, for a barcode one base
+    /// short or long; e.g. taken by a regex pattern such as (?P<bc1>.{7,9}). Ties are treated as failure
+    fn closest_bc_fuzzy(&self, bc_to_match: &[u8]) -> Option<(usize,i32)> {
+        let len_diff = bc_to_match.len().abs_diff(self.bc_length);
+        if len_diff != 1 {
+            return None;
+        }
+        let mut best = UniqueBest::default();
+        for (j, pattern) in self.patterns.iter().enumerate() {
+            //The whitelist barcode may sit anywhere in a longer read barcode, so the length difference is a lower bound
+            let dist = pattern.find_all_end(bc_to_match, 1).map(|(_, d)| d as usize).min();
+            if let Some(dist) = dist {
+                best.offer(j, self.bc_length as i32 - dist.max(len_diff) as i32);
+            }
+        }
+        best.get()
+    }
+
+
+    /// Compare to each BC, see which fits best according to the scorer
+    fn closest_bc_basewise<S: BarcodeScorer + ?Sized>(&self, bc_to_match: &[u8], qual: Option<&[u8]>, scorer: &S) -> Option<(usize,i32)> {
+        scorer.best_match(bc_to_match, qual, &self.seqs, self.bc_length.max(1))
+    }
+
+    /// Correct barcode using whitelist. Returns index of the barcode in the whitelist, and the score.
+    /// Base qualities are optional. A barcode one base short or long is matched allowing an indel
+    pub fn correct_to_whitelist<S: BarcodeScorer + ?Sized>(&self, bc_to_match: &[u8], qual: Option<&[u8]>, scorer: &S) -> Option<(usize,i32)> {
+        if bc_to_match.is_empty() {
+            //Empty barcode
+            None
+        } else if let Some(&i) = self.set.get(bc_to_match) {
+            //See if there is a trivial match
+            //println!("trivial match");
+            Some((i, self.bc_length as i32))
+        } else if let Some(&i) = self.neighbors.neighbors.get(bc_to_match) {
+            //A unique one-mismatch neighbour is the best match
+            Some((i, scorer.score(bc_to_match, qual, self.list[i].as_bytes())))
+        } else if self.bc_length==bc_to_match.len() {
+            //Compare each base if same length. Set a minimum cutoff
+            let m = self.closest_bc_basewise(bc_to_match, qual, scorer)?;
+            if m.1 >= self.bc_length as i32 - MAX_ROUND_PENALTY {
+                Some(m)
+            } else {
+                None
+            }
+
+        } else {
+            //Length differs, likely an indel. Try approximate matching
+            self.closest_bc_fuzzy(bc_to_match)
+        }
+    }
+
+
+    /// All barcodes scoring at least min_score, for joint correction of the rounds. If the length is off,
+    /// only the fuzzy match is returned
+    pub fn candidates<S: BarcodeScorer + ?Sized>(&self, bc_to_match: &[u8], qual: Option<&[u8]>, scorer: &S, min_score: i32) -> Vec<(usize,i32)> {
+        if bc_to_match.len()==self.bc_length {
+            self.seqs.chunks_exact(self.bc_length.max(1)).enumerate()
+                .map(|(i, bc)| (i, scorer.score(bc_to_match, qual, bc)))
+                .filter(|(_, score)| *score >= min_score)
+                .collect()
+        } else {
+            self.closest_bc_fuzzy(bc_to_match).into_iter().filter(|(_, score)| *score >= min_score).collect()
+        }
+    }
+
+}
+
+
+/// Keeps the highest scoring of the items offered, and whether it is tied. Used to pick plates and combinations,
+/// where a tie is treated as failure
+struct UniqueBest<T> {
+    best: Option<(T, i32)>,
+    tie: bool
+}
+
+impl<T> Default for UniqueBest<T> {
+    fn default() -> Self {
+        UniqueBest {best: None, tie: false}
+    }
+}
+
+impl<T> UniqueBest<T> {
+
+    fn offer(&mut self, item: T, score: i32) {
+        match &self.best {
+            Some((_, best_score)) if score < *best_score => {},
+            Some((_, best_score)) if score == *best_score => { self.tie = true; },
+            _ => {
+                self.best = Some((item, score));
+                self.tie = false;
+            }
+        }
+    }
+
+    /// The best item and its score, or None if nothing was offered or the best score is tied
+    fn get(self) -> Option<(T, i32)> {
+        if self.tie {None} else {self.best}
+    }
+}
+
+
+/// Largest penalty for a round to be corrected, on its own or in joint correction; 2 mismatches
+pub const MAX_ROUND_PENALTY: i32 = 2;
+
+/// Largest penalty over all rounds of a barcode, after each round has been corrected
+pub const MAX_TOTAL_PENALTY: i32 = 3;
+
+/// Barcode combinations of one plate seen in a first pass, as a trie over the rounds. Used for joint correction:
+/// rather than correcting each round on its own, the best scoring combination among those seen is picked
+#[derive(Default)]
+pub struct CombinationTrie {
+    children: HashMap<usize, CombinationTrie>
+}
+
+impl CombinationTrie {
+
+    /// Add a combination, given as the index in the whitelist of each round
+    pub fn insert(&mut self, wells: &[usize]) {
+        if let Some((first, rest)) = wells.split_first() {
+            self.children.entry(*first).or_default().insert(rest);
+        }
+    }
+
+    /// The combination with the best total score, given the candidates of each round. Ties are treated as failure
+    pub fn best_path(&self, candidates: &[Vec<(usize,i32)>; 4]) -> Option<([usize;4], i32)> {
+        let mut best = UniqueBest::default();
+        self.search(candidates, 0, &mut [0; 4], 0, &mut best);
+        best.get()
+    }
+
+    fn search(&self, candidates: &[Vec<(usize,i32)>; 4], round: usize, path: &mut [usize;4], score: i32, best: &mut UniqueBest<[usize;4]>) {
+        if round == 4 {
+            best.offer(*path, score);
+            return;
+        }
+        for (i, round_score) in &candidates[round] {
+            if let Some(child) = self.children.get(i) {
+                path[round] = *i;
+                child.search(candidates, round+1, path, score + round_score, best);
+            }
+        }
+    }
+}
+
+
+
+/// Count the number of similar elements in two lists of the same size
+pub fn num_similar_elements(a:&[u8], b:&[u8]) -> i32 {
+    let mut count = 0;
+    for i in 0..a.len() {
+        if a[i] == b[i] {
+            count += 1;
+        }
+    }
+    count
+}
+
+
+
+
+/// A corrected cell barcode: the plate, and the index of the barcode in the whitelist of each round
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CellBarcode {
+    pub plate: usize,
+    pub wells: [usize;4]
+}
+
+
+/// What happened when correcting the barcode of a read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorrectionOutcome {
+    Exact,               //All rounds matched the whitelist exactly
+    Corrected1Mismatch,  //One mismatch in total
+    Corrected2Mismatches, //Two or more mismatches, or an indel
+    FailedRound(usize),  //This round (index in the whitelist) could not be corrected
+    FailedLinker,        //A linker has more than one mismatch; the block is likely shifted or missing
+    Ambiguous,           //Several plates or combinations fit equally well
+    TooShort,            //Read too short to hold the barcode block
+    LowComplexity,       //Barcode block mostly one base or a repeat; not corrected (--max-repeat-fraction)
+    LowBarcodeQuality    //Mean quality of the barcode block too low; not corrected (--min-bc-mean-qual)
+}
+
+impl CorrectionOutcome {
+
+    /// Name of the outcome, as in the report. Rounds are counted from 1
+    pub fn label(&self) -> String {
+        match self {
+            CorrectionOutcome::Exact => "exact".to_string(),
+            CorrectionOutcome::Corrected1Mismatch => "corrected_1_mismatch".to_string(),
+            CorrectionOutcome::Corrected2Mismatches => "corrected_2_mismatches".to_string(),
+            CorrectionOutcome::FailedRound(round) => format!("failed_round_{}", round + 1),
+            CorrectionOutcome::FailedLinker => "failed_linker".to_string(),
+            CorrectionOutcome::Ambiguous => "ambiguous".to_string(),
+            CorrectionOutcome::TooShort => "too_short".to_string(),
+            CorrectionOutcome::LowComplexity => "low_complexity".to_string(),
+            CorrectionOutcome::LowBarcodeQuality => "low_bc_quality".to_string()
+        }
+    }
+}
+
+
+/// Number of reads with each correction outcome
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OutcomeCounts {
+    pub exact: u64,
+    pub corrected_1_mismatch: u64,
+    pub corrected_2_mismatches: u64,
+    pub failed_round: [u64;4],
+    pub failed_linker: u64,
+    pub ambiguous: u64,
+    pub too_short: u64,
+    pub low_complexity: u64,
+    pub low_barcode_quality: u64
+}
+
+impl OutcomeCounts {
+
+    pub fn add(&mut self, outcome: CorrectionOutcome) {
+        match outcome {
+            CorrectionOutcome::Exact => self.exact += 1,
+            CorrectionOutcome::Corrected1Mismatch => self.corrected_1_mismatch += 1,
+            CorrectionOutcome::Corrected2Mismatches => self.corrected_2_mismatches += 1,
+            CorrectionOutcome::FailedRound(round) => self.failed_round[round] += 1,
+            CorrectionOutcome::FailedLinker => self.failed_linker += 1,
+            CorrectionOutcome::Ambiguous => self.ambiguous += 1,
+            CorrectionOutcome::TooShort => self.too_short += 1,
+            CorrectionOutcome::LowComplexity => self.low_complexity += 1,
+            CorrectionOutcome::LowBarcodeQuality => self.low_barcode_quality += 1
+        }
+    }
+}
+
+
+/// A barcode corrected from a read too short to hold all rounds. Missing rounds are None
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PartialCellBarcode {
+    pub plate: usize,
+    pub wells: [Option<usize>;4]
+}
+
+
+/// A full or partial cell barcode packed into one integer, to use as a small key while counting. The plate is in
+/// the top 16 bits; below are 12 bits per round, first round lowest, holding the well plus one, or 0 if missing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PackedBarcode(pub u64);
+
+impl PackedBarcode {
+
+    const ROUND_BITS: usize = 12;
+    const ROUND_MASK: u64 = (1 << Self::ROUND_BITS) - 1;
+    const PLATE_SHIFT: usize = 48;
+
+    pub fn new(plate:usize, wells:[Option<usize>;4]) -> PackedBarcode {
+        let mut packed = (plate as u64) << Self::PLATE_SHIFT;
+        for (round, well) in wells.iter().enumerate() {
+            if let Some(well) = well {
+                packed |= (*well as u64 + 1) << (Self::ROUND_BITS*round);
+            }
+        }
+        PackedBarcode(packed)
+    }
+
+    pub fn plate(&self) -> usize {
+        (self.0 >> Self::PLATE_SHIFT) as usize
+    }
+
+    /// Wells of each round; None for rounds missing from a partial barcode
+    pub fn wells(&self) -> [Option<usize>;4] {
+        std::array::from_fn(|round| match (self.0 >> (Self::ROUND_BITS*round)) & Self::ROUND_MASK {
+            0 => None,
+            well => Some(well as usize - 1)
+        })
+    }
+}
+
+impl CellBarcode {
+    pub fn pack(&self) -> PackedBarcode {
+        PackedBarcode::new(self.plate, self.wells.map(Some))
+    }
+}
+
+impl PartialCellBarcode {
+    pub fn pack(&self) -> PackedBarcode {
+        PackedBarcode::new(self.plate, self.wells)
+    }
+}
+
+
+/// Length of the barcode block at the start of R2
+pub const BC_BLOCK_LEN: usize = 36+8;
+
+/// Length of the linkers between the barcode rounds
+pub const LINKER_LEN: usize = 4;
+
+/// Minimum number of rounds that must fit in a read for a partial barcode to be assigned
+const MIN_PARTIAL_ROUNDS: usize = 2;
+
+
+/// Chemistry of the barcode block: the linkers between the rounds, in the order they appear in the read.
+/// The layout of the block is fixed, but the linker sequences may differ between kit versions
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chemistry {
+    pub linkers: [Vec<u8>;3]
+}
+
+impl Default for Chemistry {
+    fn default() -> Chemistry {
+        Chemistry {
+            linkers: [b"AGGA".to_vec(), b"ACTC".to_vec(), b"AAGG".to_vec()]
+        }
+    }
+}
+
+impl Chemistry {
+
+    /// Parse a comma separated list of the three linkers, e.g. AGGA,ACTC,AAGG
+    pub fn from_linkers(s:&str) -> Result<Chemistry, Box<dyn Error>> {
+        let linkers: Vec<Vec<u8>> = s.split(',').map(|l| l.trim().to_ascii_uppercase().into_bytes()).collect();
+        if linkers.len() != 3 {
+            return Err(format!("Expected 3 linkers, got {}: {}", linkers.len(), s).into());
+        }
+        for linker in &linkers {
+            if linker.len() != LINKER_LEN || !linker.iter().all(|b| b"ACGT".contains(b)) {
+                return Err(format!("Linkers must be {} bases of ACGT: {}", LINKER_LEN, String::from_utf8_lossy(linker)).into());
+            }
+        }
+        Ok(Chemistry {
+            linkers: [linkers[0].clone(), linkers[1].clone(), linkers[2].clone()]
+        })
+    }
+}
+
+
+/// Where to find the barcode plates, the chemistry they are used with, and how to score barcodes
+#[derive(Clone, Debug, Default)]
+pub struct BarcodeSpec {
+    pub plates: Vec<String>,
+    pub chemistry: Chemistry,
+    pub scoring: Scoring,
+    pub scoring_min_qual: u8,
+    pub pattern: Option<String>,
+    pub index: Option<PathBuf>,
+    pub joint: Option<PathBuf>,
+    pub joint_min_count: i64,
+    pub whitelist_sha256: Vec<String>, //Expected checksums of the whitelist files, if pinned
+    pub used_wells: Option<PathBuf>, //Wells used in each round; combinations with other wells are rejected
+    pub min_base_qual: Option<u8>,
+    pub restrict_wells: Option<String> //Wells to correct to in some rounds, e.g. 1:A1-H6; see parse_well_ranges
+}
+
+impl BarcodeSpec {
+
+    /// Read the barcode plates
+    pub fn load(&self) -> Result<AtrandiBarcodes, Box<dyn Error>> {
+        let mut barcodes = AtrandiBarcodes::read_plates(&self.plates, self.chemistry.clone())?;
+        barcodes.verify_checksums(&self.whitelist_sha256)?;
+        if let Some(restrict_wells) = &self.restrict_wells {
+            barcodes.subset_wells(&parse_well_ranges(restrict_wells)?)?;
+        }
+        barcodes.scorer = self.scoring.scorer(self.scoring_min_qual);
+        barcodes.min_base_qual = self.min_base_qual;
+        if let Some(pattern) = &self.pattern {
+            let extractor = PatternExtractor::new(pattern)?;
+            let num_bc = extractor.barcode_segments().len();
+            if num_bc != 4 {
+                return Err(format!("Pattern must have 4 barcode segments, got {}: {}", num_bc, pattern).into());
+            }
+            barcodes.extractor = Some(extractor);
+        }
+        barcodes.load_or_build_index(self.index.as_ref())?;
+        if let Some(joint) = &self.joint {
+            barcodes.load_joint(joint, self.joint_min_count)?;
+        }
+        if let Some(used_wells) = &self.used_wells {
+            barcodes.load_used_wells(used_wells)?;
+        }
+        Ok(barcodes)
+    }
+}
+
+
+/// Layout of a barcode plate. Wells are numbered row by row (A1, A2, ..., A12, B1, ...)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlateFormat {
+    #[default]
+    Wells96,
+    Wells384
+}
+
+impl PlateFormat {
+
+    pub fn rows(&self) -> usize {
+        match self {
+            PlateFormat::Wells96 => 8,
+            PlateFormat::Wells384 => 16
+        }
+    }
+
+    pub fn cols(&self) -> usize {
+        match self {
+            PlateFormat::Wells96 => 12,
+            PlateFormat::Wells384 => 24
+        }
+    }
+
+    pub fn num_wells(&self) -> usize {
+        self.rows()*self.cols()
+    }
+
+    /// Smallest format with at least this many wells
+    pub fn for_num_wells(n: usize) -> Option<PlateFormat> {
+        [PlateFormat::Wells96, PlateFormat::Wells384].into_iter().find(|f| f.num_wells() >= n)
+    }
+
+    /// Name of the n:th well
+    pub fn well_name(&self, i: usize) -> String {
+        format!("{}{}", (b'A' + (i/self.cols()) as u8) as char, i%self.cols() + 1)
+    }
+
+    /// Index of a well given as row and column, both from 0
+    pub fn well_index(&self, row: usize, col: usize) -> Option<usize> {
+        if row < self.rows() && col < self.cols() {
+            Some(row*self.cols() + col)
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Row and column, both from 0, of a well given as e.g. B2 or P24
+pub fn parse_well(well: &str) -> Option<(usize, usize)> {
+    let mut chars = well.trim().chars();
+    let row = chars.next()?.to_ascii_uppercase();
+    if !row.is_ascii_uppercase() {
+        return None;
+    }
+    let col = chars.as_str().parse::<usize>().ok()?;
+    if col == 0 {
+        return None;
+    }
+    Some((row as usize - 'A' as usize, col - 1))
+}
+
+
+/// Wells of one round to correct to: a block of wells from one corner to the opposite one, or a single well
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WellRange {
+    pub round: usize, //From 0
+    pub first: (usize, usize),
+    pub last: (usize, usize)
+}
+
+impl WellRange {
+
+    pub fn contains(&self, row: usize, col: usize) -> bool {
+        (self.first.0.min(self.last.0)..=self.first.0.max(self.last.0)).contains(&row) &&
+            (self.first.1.min(self.last.1)..=self.first.1.max(self.last.1)).contains(&col)
+    }
+}
+
+
+/// Parse wells per round, e.g. "1:A1-H6,2:A1-H12,2:A12": a round (1-4), then a well or a block of wells
+pub fn parse_well_ranges(spec: &str) -> Result<Vec<WellRange>, Box<dyn Error>> {
+    let mut ranges = Vec::new();
+    for part in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let (round, wells) = part.split_once(':').ok_or_else(|| format!("Wells must be given as ROUND:WELLS, got {}", part))?;
+        let round = match round.trim().parse::<usize>() {
+            Ok(round) if (1..=4).contains(&round) => round - 1,
+            _ => return Err(format!("Round must be 1-4, got {}", part).into())
+        };
+        let (first, last) = wells.split_once('-').unwrap_or((wells, wells));
+        let first = parse_well(first).ok_or_else(|| format!("Bad well in {}", part))?;
+        let last = parse_well(last).ok_or_else(|| format!("Bad well in {}", part))?;
+        ranges.push(WellRange {round, first, last});
+    }
+    Ok(ranges)
+}
+
+
+/// Read a whitelist as stored, before any decompression. With the remote feature, it can also be an http(s) URL
+pub fn fetch_whitelist(filename:&str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut raw = Vec::new();
+    if filename.starts_with("http://") || filename.starts_with("https://") {
+        #[cfg(feature = "remote")]
+        {
+            ureq::get(filename).call()?.into_reader().read_to_end(&mut raw)?;
+            return Ok(raw);
+        }
+        #[cfg(not(feature = "remote"))]
+        return Err(format!("Reading whitelists from URLs requires building with --features remote: {}", filename).into());
+    }
+    File::open(filename)?.read_to_end(&mut raw)?;
+    Ok(raw)
+}
+
+
+/// SHA-256 checksum as lowercase hex
+pub fn sha256_hex(data:&[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+
+/// Plate name from the file name of a whitelist, without directory, query and extensions
+fn whitelist_stem(filename:&str) -> String {
+    let filename = filename.split('?').next().unwrap_or(filename);
+    let filename = filename.rsplit('/').next().unwrap_or(filename);
+    let filename = [".gz", ".zst", ".bz2", ".xz"].iter().find_map(|ext| filename.strip_suffix(ext)).unwrap_or(filename);
+    Path::new(filename).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+
+/// Whitelists for each round of one barcode plate
+pub struct AtrandiPlate {
+    pub name: String,
+    pub rounds: Vec<BarcodeWhitelist>,
+    pub format: PlateFormat,
+    pub wells: Vec<Vec<usize>>, //Well of each barcode in each round
+    pub sha256: String //Checksum of the whitelist file
+}
+
+impl AtrandiPlate {
+
+    /// Read dictionary of Atrandi barcodes from file. The plate is taken to be a 384-well plate if any well
+    /// is outside A1-H12
+    pub fn read_atrandi_barcodes(name:&str, filename:&str) -> Result<AtrandiPlate, Box<dyn Error>> {
+        //Whitelists are small; read them whole so the checksum is of the file as stored, compressed or not
+        let raw = fetch_whitelist(filename)?;
+        let sha256 = sha256_hex(&raw);
+        let (reader, _) = niffler::get_reader(Box::new(Cursor::new(raw)))?;
+        let mut rdr = ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_reader(reader);
+        let mut bcs_for_well = vec![vec![] as Vec<String>; 4];
+        let mut coords_for_well = vec![vec![] as Vec<(usize, usize)>; 4];
+        let mut bc_length = None;
+        for result in rdr.records() {
+            let record = result?;
+            let pos=&record[0];
+            let well=&record[1];
+            let bc=&record[2];
+            if *bc_length.get_or_insert(bc.len()) != bc.len() {
+                return Err(format!("Barcodes of different length in {}: {}", filename, bc).into());
+            }
+            let pos_int = match pos.parse::<usize>() {
+                Ok(pos_int) if (1..=4).contains(&pos_int) => pos_int - 1,
+                _ => return Err(format!("Round must be 1-4 in {}, got {}", filename, pos).into())
+            };
+            let coords = parse_well(well).ok_or_else(|| format!("Bad well in {}: {}", filename, well))?;
+            bcs_for_well[pos_int].push(String::from(bc));
+            coords_for_well[pos_int].push(coords);
+        }
+
+        //Pick the plate size from the wells used, then number the wells
+        let format = if coords_for_well.iter().flatten().all(|(row, col)| PlateFormat::Wells96.well_index(*row, *col).is_some()) {
+            PlateFormat::Wells96
+        } else {
+            PlateFormat::Wells384
+        };
+        let mut wells = Vec::new();
+        for (round, coords) in coords_for_well.iter().enumerate() {
+            let mut round_wells = Vec::new();
+            for (row, col) in coords {
+                let well = format.well_index(*row, *col)
+                    .ok_or_else(|| format!("Well outside a 384-well plate in {}: {}{}", filename, (b'A' + *row as u8) as char, col+1))?;
+                if round_wells.contains(&well) {
+                    return Err(format!("Well {} used twice in round {} of {}", format.well_name(well), round+1, filename).into());
+                }
+                round_wells.push(well);
+            }
+            if bcs_for_well[round].iter().collect::<HashSet<_>>().len() != bcs_for_well[round].len() {
+                return Err(format!("Duplicate barcode in round {} of {}", round+1, filename).into());
+            }
+            wells.push(round_wells);
+        }
+
+        let bc_length = bc_length.unwrap_or(0);
+        let whitelists = bcs_for_well.iter().map(|w| BarcodeWhitelist::new(w.to_vec(), bc_length)).collect();
+
+        Ok(AtrandiPlate {name: name.to_string(), rounds: whitelists, format, wells, sha256})
+    }
+
+
+    /// Correct all rounds of a read together, to the best scoring combination seen before
+    fn correct_joint<S: BarcodeScorer + ?Sized>(&self, barcode_tuple:&[&[u8];4], qual_tuple:Option<&[&[u8];4]>, scorer:&S, combinations:&CombinationTrie) -> Option<([usize;4], i32)> {
+        let qual = |i:usize| qual_tuple.map(|q| q[i]);
+        let candidates: [Vec<(usize,i32)>; 4] = std::array::from_fn(|i| self.rounds[i].candidates(barcode_tuple[i], qual(i), scorer, self.rounds[i].bc_length as i32 - MAX_ROUND_PENALTY));
+        combinations.best_path(&candidates)
+    }
+
+
+    /// Correct the barcodes of a read. Returns the index of the barcode in the whitelist of each round, and the total score
+    fn correct<S: BarcodeScorer + ?Sized>(&self, barcode_tuple:&[&[u8];4], qual_tuple:Option<&[&[u8];4]>, scorer:&S, print_debug:bool) -> Option<([usize;4], i32)> {
+
+        //Note swap here of BCs to match logical order in chemistry. Barcode added last is the first one seen in the read
+        let qual = |i:usize| qual_tuple.map(|q| q[i]);
+        let corrected_bc = (
+            self.rounds[0].correct_to_whitelist(barcode_tuple[0], qual(0), scorer)?, //test this first as it is the most likely to fail
+            self.rounds[1].correct_to_whitelist(barcode_tuple[1], qual(1), scorer)?,
+            self.rounds[2].correct_to_whitelist(barcode_tuple[2], qual(2), scorer)?,
+            self.rounds[3].correct_to_whitelist(barcode_tuple[3], qual(3), scorer)?
+        );
+
+        if print_debug {
+            println!("{}.{}.{}.{} in",
+                String::from_utf8_lossy(barcode_tuple[0]), String::from_utf8_lossy(barcode_tuple[1]),
+                String::from_utf8_lossy(barcode_tuple[2]), String::from_utf8_lossy(barcode_tuple[3]));
+            println!("{}.{}.{}.{} out",
+                self.rounds[0].list[corrected_bc.0.0], self.rounds[1].list[corrected_bc.1.0],
+                self.rounds[2].list[corrected_bc.2.0], self.rounds[3].list[corrected_bc.3.0]);
+            println!();
+        }
+
+        //Add a global BC quality constraint
+        let total_m = corrected_bc.0.1 + corrected_bc.1.1 + corrected_bc.2.1 + corrected_bc.3.1;
+        let total_len: i32 = self.rounds.iter().map(|r| r.bc_length as i32).sum();
+        if total_m >= total_len - MAX_TOTAL_PENALTY {
+            Some(([corrected_bc.0.0, corrected_bc.1.0, corrected_bc.2.0, corrected_bc.3.0], total_m))
+        } else {
+            None
+        }
+    }
+
+
+    /// Wells of the barcodes of a read with the fixed Atrandi layout, if every round is in the whitelist as read
+    fn exact_wells(&self, bc_read:&[u8]) -> Option<[usize;4]> {
+        //The first round is last in the read
+        let at = |round:usize| &bc_read[36-12*round..44-12*round];
+        Some([
+            self.rounds[0].index_of(at(0))?,
+            self.rounds[1].index_of(at(1))?,
+            self.rounds[2].index_of(at(2))?,
+            self.rounds[3].index_of(at(3))?
+        ])
+    }
+
+
+    /// Correct the rounds that are present in a short read. Every present round must be corrected,
+    /// and the same per-round quality constraint as for full barcodes applies
+    fn correct_partial<S: BarcodeScorer + ?Sized>(&self, barcode_tuple:&[Option<&[u8]>;4], scorer:&S) -> Option<([Option<usize>;4], i32)> {
+        let mut wells = [None; 4];
+        let mut total_m = 0;
+        let mut total_len = 0;
+        let mut num_present = 0;
+        for i in 0..4 {
+            if let Some(bc) = barcode_tuple[i] {
+                let (j, score) = self.rounds[i].correct_to_whitelist(bc, None, scorer)?;
+                wells[i] = Some(j);
+                total_m += score;
+                total_len += self.rounds[i].bc_length as i32;
+                num_present += 1;
+            }
+        }
+        //As for full barcodes, fewer mismatches in total than there are rounds
+        if num_present >= MIN_PARTIAL_ROUNDS && total_m >= total_len - (num_present as i32 - 1) {
+            Some((wells, total_m))
+        } else {
+            None
+        }
+    }
+}
+
+
+/// Pick the best scoring plate. Ties are treated as failure
+fn pick_best_plate<T>(candidates: impl Iterator<Item=Option<(T, i32)>>) -> Option<(usize, T)> {
+    let mut best = UniqueBest::default();
+    for (plate, cand) in candidates.enumerate() {
+        if let Some((bc, score)) = cand {
+            best.offer((plate, bc), score);
+        }
+    }
+    best.get().map(|(best, _)| best)
+}
+
+
+/// Structure for Atrandi combinatorial barcodes, possibly spanning several plates
+pub struct AtrandiBarcodes {
+    pub plates: Vec<AtrandiPlate>,
+    pub chemistry: Chemistry,
+    pub scorer: Scorer,
+    pub extractor: Option<PatternExtractor>, //Custom layout of the block; default is the fixed Atrandi layout
+    pub joint: Option<Vec<CombinationTrie>>, //Combinations seen per plate, if correcting the rounds jointly
+    pub used_wells: Option<Vec<Vec<Vec<bool>>>>, //Per plate and round, whether each barcode's well was used
+    pub min_base_qual: Option<u8> //Barcode bases read with lower quality are masked to N before correction
+}
+
+impl AtrandiBarcodes {
+
+    /// Read barcode plates. Each is given as PREFIX=FILE, or just FILE in which case the file name is the prefix
+    pub fn read_plates(specs:&[String], chemistry:Chemistry) -> Result<AtrandiBarcodes, Box<dyn Error>> {
+        let mut plates = Vec::new();
+        for spec in specs {
+            //A URL may have = in the query, but a prefix never has a /
+            let (name, filename) = match spec.split_once('=') {
+                Some((name, filename)) if !name.contains('/') => (name.to_string(), filename),
+                _ => (whitelist_stem(spec), spec.as_str())
+            };
+            if name.contains('_') || name.contains('.') {
+                return Err(format!("Plate prefix may not contain _ or . : {}", name).into());
+            }
+            plates.push(AtrandiPlate::read_atrandi_barcodes(&name, filename)?);
+        }
+        if plates.is_empty() {
+            return Err("No barcode files given".into());
+        }
+        Ok(AtrandiBarcodes {plates, chemistry, scorer: Scorer::Hamming(HammingScorer), extractor: None, joint: None, used_wells: None, min_base_qual: None})
+    }
+
+
+    /// Read which wells were used in each round, from a TSV with columns round (1-4) and well (e.g. B2), and
+    /// optionally plate (its name; otherwise the well applies to all plates). Barcodes in other wells are then
+    /// rejected after correction; see is_used
+    pub fn load_used_wells(&mut self, path:&PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut used: Vec<Vec<Vec<bool>>> = self.plates.iter().map(|p| p.rounds.iter().map(|r| vec![false; r.list.len()]).collect()).collect();
+        let mut rdr = ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+        let headers = rdr.headers()?.clone();
+        let column = |name:&str| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name));
+        let (col_round, col_well) = match (column("round"), column("well")) {
+            (Some(col_round), Some(col_well)) => (col_round, col_well),
+            _ => return Err(format!("Used wells file {} must have columns round and well", path.display()).into())
+        };
+        let col_plate = column("plate");
+        for result in rdr.records() {
+            let record = result?;
+            let round = match record[col_round].trim().parse::<usize>() {
+                Ok(round) if (1..=4).contains(&round) => round - 1,
+                _ => return Err(format!("Round must be 1-4 in {}, got {}", path.display(), &record[col_round]).into())
+            };
+            let (row, col) = parse_well(&record[col_well]).ok_or_else(|| format!("Bad well in {}: {}", path.display(), &record[col_well]))?;
+            let plates: Vec<usize> = match col_plate {
+                Some(col_plate) => {
+                    let name = record[col_plate].trim();
+                    vec![self.plates.iter().position(|p| p.name == name).ok_or_else(|| format!("Unknown plate in {}: {}", path.display(), name))?]
+                },
+                None => (0..self.plates.len()).collect()
+            };
+            for plate in plates {
+                let p = &self.plates[plate];
+                let i = p.format.well_index(row, col).and_then(|well| p.wells[round].iter().position(|w| *w == well))
+                    .ok_or_else(|| format!("Well {} in {} has no barcode in round {} of plate {}", &record[col_well], path.display(), round+1, p.name))?;
+                used[plate][round][i] = true;
+            }
+        }
+        for (plate, rounds) in self.plates.iter().zip(&used) {
+            for (round, wells) in rounds.iter().enumerate() {
+                info!("Plate {}, round {}: {} of {} wells used", plate.name, round+1, wells.iter().filter(|u| **u).count(), wells.len());
+            }
+        }
+        self.used_wells = Some(used);
+        Ok(())
+    }
+
+
+    /// Only correct to the barcodes of the given wells, in the rounds listed; other rounds keep all. Unlike with
+    /// used wells, the other barcodes are dropped from the whitelists, so that reads are corrected to the closest
+    /// barcode that was used rather than rejected afterwards
+    pub fn subset_wells(&mut self, ranges:&[WellRange]) -> Result<(), Box<dyn Error>> {
+        for plate in &mut self.plates {
+            for round in 0..plate.rounds.len() {
+                let round_ranges: Vec<&WellRange> = ranges.iter().filter(|r| r.round == round).collect();
+                if round_ranges.is_empty() {
+                    continue;
+                }
+                let cols = plate.format.cols();
+                let keep: Vec<bool> = plate.wells[round].iter().map(|w| round_ranges.iter().any(|r| r.contains(w/cols, w%cols))).collect();
+                let num_kept = keep.iter().filter(|k| **k).count();
+                if num_kept == 0 {
+                    return Err(format!("No barcodes left in round {} of plate {}", round+1, plate.name).into());
+                }
+                info!("Plate {}, round {}: correcting to {} of {} barcodes", plate.name, round+1, num_kept, keep.len());
+
+                let whitelist = &plate.rounds[round];
+                let list = whitelist.list.iter().zip(&keep).filter(|(_, k)| **k).map(|(bc, _)| bc.clone()).collect();
+                plate.rounds[round] = BarcodeWhitelist::new(list, whitelist.bc_length);
+                plate.wells[round] = plate.wells[round].iter().zip(&keep).filter(|(_, k)| **k).map(|(w, _)| *w).collect();
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Whether all rounds of a barcode are in used wells. Missing rounds of partial barcodes are not checked.
+    /// Without a list of used wells, all are
+    pub fn is_used(&self, bc:PackedBarcode) -> bool {
+        match &self.used_wells {
+            Some(used) => bc.wells().iter().enumerate().all(|(round, well)| well.is_none_or(|w| used[bc.plate()][round][w])),
+            None => true
+        }
+    }
+
+
+    /// Check that the whitelist files are the expected ones, given one SHA-256 checksum per plate in order
+    pub fn verify_checksums(&self, expected:&[String]) -> Result<(), Box<dyn Error>> {
+        if expected.is_empty() {
+            return Ok(());
+        }
+        if expected.len() != self.plates.len() {
+            return Err(format!("Got {} whitelist checksums for {} barcode files", expected.len(), self.plates.len()).into());
+        }
+        for (plate, expected) in self.plates.iter().zip(expected) {
+            if !plate.sha256.eq_ignore_ascii_case(expected.trim()) {
+                return Err(format!("Whitelist of plate {} has SHA-256 {}, expected {}. The barcode file is not the pinned version", plate.name, plate.sha256, expected).into());
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Set up the one-mismatch neighbour index of every round. If a sidecar file is given, the index is
+    /// loaded from it if it was made for the same whitelists; otherwise it is built and written there
+    pub fn load_or_build_index(&mut self, path:Option<&PathBuf>) -> Result<(), Box<dyn Error>> {
+        let lists = self.plates.iter().map(|p| p.rounds.iter().map(|r| r.list.clone()).collect()).collect();
+        if let Some(path) = path {
+            if path.exists() {
+                let index: WhitelistIndex = bincode::deserialize_from(BufReader::new(File::open(path)?))?;
+                if index.lists == lists {
+                    info!("Loaded whitelist index from {}", path.display());
+                    self.set_neighbors(index.neighbors);
+                    return Ok(());
+                }
+                warn!("Whitelist index {} was made for other barcodes; rebuilding it", path.display());
+            }
+        }
+
+        let neighbors: Vec<Vec<NeighborIndex>> = self.plates.iter().map(|p| p.rounds.iter().map(|r| NeighborIndex::new(&r.list)).collect()).collect();
+        if let Some(path) = path {
+            let index = WhitelistIndex {lists, neighbors: neighbors.clone()};
+            let mut writer = BufWriter::new(File::create(path)?);
+            bincode::serialize_into(&mut writer, &index)?;
+            writer.flush()?;
+            info!("Stored whitelist index in {}", path.display());
+        }
+        self.set_neighbors(neighbors);
+        Ok(())
+    }
+
+
+    fn set_neighbors(&mut self, neighbors:Vec<Vec<NeighborIndex>>) {
+        for (plate, plate_neighbors) in self.plates.iter_mut().zip(neighbors) {
+            for (round, round_neighbors) in plate.rounds.iter_mut().zip(plate_neighbors) {
+                round.neighbors = round_neighbors;
+            }
+        }
+    }
+
+
+    ///Extract barcode from read, optionally with its base qualities for the scorer.
+    ///If there are several plates, the best scoring one is picked; ties are treated as failure
+    pub fn get_correct_bc_from_read(&self, bc_read:&[u8], bc_qual:Option<&[u8]>, print_debug:bool) -> Option<CellBarcode> {
+        self.correct_with_outcome(bc_read, bc_qual, print_debug).0
+    }
+
+
+    /// Correct the barcode of a read as get_correct_bc_from_read, and also tell how it went
+    pub fn correct_with_outcome(&self, bc_read:&[u8], bc_qual:Option<&[u8]>, print_debug:bool) -> (Option<CellBarcode>, CorrectionOutcome) {
+
+        //Bases from bad cycles are better not trusted. As N they match no barcode, so they cannot outvote the others
+        let bc_read = self.mask_block(bc_read, bc_qual);
+        let bc_read = bc_read.as_ref();
+
+        //Most reads match exactly; no need to extract and score them
+        if self.extractor.is_none() && self.joint.is_none() {
+            if let Some(bc) = self.correct_exact(bc_read) {
+                return (Some(bc), CorrectionOutcome::Exact);
+            }
+        }
+
+        //Extract each BC
+        //let template_bc = br"********AGGA********ACTC********AAGG********T";
+        //let barcode_tuple = extract_bc_by_alignment(template_bc, read_r1.as_bytes(), false);
+
+        //The qualities are cut where the barcodes were found in the sequence; a pattern cannot be matched on them
+        let extracted = match &self.extractor {
+            Some(extractor) => pattern_rounds(extractor, bc_read).map(|ranges| (
+                ranges.map(|(from, to)| &bc_read[from..to]),
+                bc_qual.filter(|q| q.len() >= bc_read.len()).map(|q| ranges.map(|(from, to)| &q[from..to]))
+            )),
+            None => extract_bc_optimistic_atrandi(bc_read).map(|b| (b, bc_qual.and_then(extract_bc_optimistic_atrandi)))
+        };
+        let (barcode_tuple, qual_tuple) = match extracted {
+            Some(extracted) => extracted,
+            None => return (None, CorrectionOutcome::TooShort)
+        };
+
+        let scorer = &self.scorer;
+        let picked = match &self.joint {
+            Some(joint) => pick_best_plate(self.plates.iter().zip(joint).map(|(p, combinations)| p.correct_joint(&barcode_tuple, qual_tuple.as_ref(), scorer, combinations))),
+            None => pick_best_plate(self.plates.iter().map(|p| p.correct(&barcode_tuple, qual_tuple.as_ref(), scorer, print_debug)))
+        };
+        match picked {
+            Some((plate, wells)) => {
+                let bc = CellBarcode {plate, wells};
+                let mismatches: usize = self.mismatches_per_round(&bc, &barcode_tuple).iter().sum();
+                let outcome = match mismatches {
+                    0 => CorrectionOutcome::Exact,
+                    1 => CorrectionOutcome::Corrected1Mismatch,
+                    _ => CorrectionOutcome::Corrected2Mismatches
+                };
+                (Some(bc), outcome)
+            },
+            None => (None, self.failure_reason(bc_read, &barcode_tuple, qual_tuple.as_ref()))
+        }
+    }
+
+
+    /// Mismatches between each round as read and the barcode it was corrected to; an indel counts as two
+    fn mismatches_per_round(&self, bc:&CellBarcode, barcode_tuple:&[&[u8];4]) -> [usize;4] {
+        std::array::from_fn(|i| {
+            let expected = self.plates[bc.plate].rounds[i].list[bc.wells[i]].as_bytes();
+            if expected.len()==barcode_tuple[i].len() {
+                expected.len() - num_similar_elements(expected, barcode_tuple[i]) as usize
+            } else {
+                2
+            }
+        })
+    }
+
+
+    /// Mismatches per round between a read and the barcode it was corrected to, or None if the block cannot be extracted
+    pub fn round_mismatches(&self, bc_read:&[u8], bc:&CellBarcode) -> Option<[usize;4]> {
+        let barcode_tuple = match &self.extractor {
+            Some(extractor) => extract_bc_pattern(extractor, bc_read)?,
+            None => extract_bc_optimistic_atrandi(bc_read)?
+        };
+        Some(self.mismatches_per_round(bc, &barcode_tuple))
+    }
+
+
+    /// Fast path for the fixed Atrandi layout: look up each round as read. Only accepted if exactly one plate has
+    /// all four; otherwise the read goes through full correction, which also sorts out ties between plates
+    fn correct_exact(&self, bc_read:&[u8]) -> Option<CellBarcode> {
+        if bc_read.len() < BC_BLOCK_LEN {
+            return None;
+        }
+        let mut found = None;
+        for (i, plate) in self.plates.iter().enumerate() {
+            if let Some(wells) = plate.exact_wells(bc_read) {
+                if found.is_some() {
+                    return None;
+                }
+                found = Some(CellBarcode {plate: i, wells});
+            }
+        }
+        found
+    }
+
+
+    /// Mask the low quality bases of the barcode block, if asked to. The rest of the read is left as it is, and
+    /// only copied if a base is masked
+    fn mask_block<'a>(&self, bc_read:&'a [u8], bc_qual:Option<&[u8]>) -> Cow<'a, [u8]> {
+        let (min_qual, qual) = match (self.min_base_qual, bc_qual) {
+            (Some(min_qual), Some(qual)) => (min_qual, qual),
+            _ => return Cow::Borrowed(bc_read)
+        };
+        let block_end = match &self.extractor {
+            Some(extractor) => extractor.end(bc_read).unwrap_or(bc_read.len()),
+            None => BC_BLOCK_LEN
+        }.min(bc_read.len()).min(qual.len());
+        match mask_low_quality(&bc_read[..block_end], &qual[..block_end], min_qual) {
+            Cow::Borrowed(_) => Cow::Borrowed(bc_read),
+            Cow::Owned(mut masked) => {
+                masked.extend_from_slice(&bc_read[block_end..]);
+                Cow::Owned(masked)
+            }
+        }
+    }
+
+
+    /// Why the barcode of a read could not be corrected: a bad linker, the first round no plate can correct,
+    /// or else several equally good candidates
+    fn failure_reason(&self, bc_read:&[u8], barcode_tuple:&[&[u8];4], qual_tuple:Option<&[&[u8];4]>) -> CorrectionOutcome {
+        if self.extractor.is_none() {
+            for (i, linker) in self.chemistry.linkers.iter().enumerate() {
+                let at = 8 + 12*i;
+                if linker.len() as i32 - num_similar_elements(linker, &bc_read[at..at+linker.len()]) > 1 {
+                    return CorrectionOutcome::FailedLinker;
+                }
+            }
+        }
+        for round in 0..4 {
+            let qual = qual_tuple.map(|q| q[round]);
+            if self.plates.iter().all(|p| p.rounds[round].correct_to_whitelist(barcode_tuple[round], qual, &self.scorer).is_none()) {
+                return CorrectionOutcome::FailedRound(round);
+            }
+        }
+        CorrectionOutcome::Ambiguous
+    }
+
+
+    /// Correct rounds jointly, using the barcodes with at least min_count reads in a histogram from a first pass
+    pub fn load_joint(&mut self, path:&Path, min_count:i64) -> Result<(), Box<dyn Error>> {
+        let mut joint: Vec<CombinationTrie> = self.plates.iter().map(|_| CombinationTrie::default()).collect();
+        let mut num_combinations = 0;
+        for (name, cnt) in read_histogram(path)? {
+            if cnt < min_count {
+                continue;
+            }
+            if let Some(bc) = self.parse_bc_name(&name) {
+                joint[bc.plate].insert(&bc.wells);
+                num_combinations += 1;
+            }
+        }
+        if num_combinations == 0 {
+            return Err(format!("No barcodes with at least {} reads in {}", min_count, path.display()).into());
+        }
+        info!("Correcting rounds jointly, among {} barcode combinations", num_combinations);
+        self.joint = Some(joint);
+        Ok(())
+    }
+
+
+    /// Position in the read where the barcode block ends
+    pub fn block_end(&self, bc_read:&[u8]) -> usize {
+        match &self.extractor {
+            Some(extractor) => extractor.end(bc_read).unwrap_or(BC_BLOCK_LEN),
+            None => BC_BLOCK_LEN
+        }
+    }
+
+
+    /// Whether a read holds the whole barcode block, as laid out by the pattern if there is one. Shorter reads can
+    /// only be given a partial barcode
+    pub fn has_full_block(&self, bc_read:&[u8]) -> bool {
+        match &self.extractor {
+            Some(extractor) => extractor.end(bc_read).is_some(),
+            None => bc_read.len() >= BC_BLOCK_LEN
+        }
+    }
+
+
+    ///Extract the rounds that fit in a read too short for the full barcode block, and correct them.
+    ///Missing rounds are None
+    pub fn get_partial_bc_from_read(&self, bc_read:&[u8], bc_qual:Option<&[u8]>) -> Option<PartialCellBarcode> {
+        let bc_read = self.mask_block(bc_read, bc_qual);
+        let bc_read = bc_read.as_ref();
+        let barcode_tuple = match &self.extractor {
+            Some(extractor) => extract_bc_pattern_partial(extractor, bc_read),
+            None => extract_bc_partial_atrandi(bc_read)
+        };
+        let scorer = &self.scorer;
+        let (plate, wells) = pick_best_plate(self.plates.iter().map(|p| p.correct_partial(&barcode_tuple, scorer)))?;
+        Some(PartialCellBarcode {plate, wells})
+    }
+
+
+    /// Write the name of a corrected barcode into a reusable buffer: the sequence of each round separated by dots,
+    /// prefixed by the plate name and a colon if there are several plates
+    pub fn write_bc_name(&self, bc:&CellBarcode, out:&mut Vec<u8>) {
+        out.clear();
+        let plate = &self.plates[bc.plate];
+        if self.plates.len() > 1 {
+            out.extend_from_slice(plate.name.as_bytes());
+            out.push(b':');
+        }
+        for (i, round) in plate.rounds.iter().enumerate() {
+            if i > 0 {
+                out.push(b'.');
+            }
+            out.extend_from_slice(round.list[bc.wells[i]].as_bytes());
+        }
+    }
+
+
+    /// Write the name of a packed barcode into a reusable buffer, as write_bc_name or, if rounds are missing,
+    /// write_partial_bc_name
+    pub fn write_packed_name(&self, bc:PackedBarcode, out:&mut Vec<u8>) {
+        let wells = bc.wells();
+        match wells {
+            [Some(w0), Some(w1), Some(w2), Some(w3)] => self.write_bc_name(&CellBarcode {plate: bc.plate(), wells: [w0, w1, w2, w3]}, out),
+            _ => self.write_partial_bc_name(&PartialCellBarcode {plate: bc.plate(), wells}, out)
+        }
+    }
+
+
+    /// Parse a barcode name as written by write_bc_name. Returns None if it is not made of whitelist barcodes
+    pub fn parse_bc_name(&self, name:&str) -> Option<CellBarcode> {
+        let (plate, wells) = self.parse_name_wells(name)?;
+        Some(CellBarcode {plate, wells: [wells[0]?, wells[1]?, wells[2]?, wells[3]?]})
+    }
+
+
+    /// Parse a barcode name as written by write_packed_name, full or partial. Returns None if it is not made of
+    /// whitelist barcodes
+    pub fn parse_packed_name(&self, name:&str) -> Option<PackedBarcode> {
+        let (plate, wells) = self.parse_name_wells(name)?;
+        Some(PackedBarcode::new(plate, wells))
+    }
+
+
+    /// Plate and wells of a barcode name; rounds written as - are missing
+    fn parse_name_wells(&self, name:&str) -> Option<(usize, [Option<usize>;4])> {
+        let (plate, rest) = if self.plates.len() > 1 {
+            let (prefix, rest) = name.split_once(':')?;
+            (self.plates.iter().position(|p| p.name == prefix)?, rest)
+        } else {
+            (0, name)
+        };
+        let parts = rest.split('.').collect::<Vec<_>>();
+        if parts.len() != 4 {
+            return None;
+        }
+        let mut wells = [None; 4];
+        for (i, part) in parts.iter().enumerate() {
+            if *part != "-" {
+                wells[i] = Some(self.plates[plate].rounds[i].index_of(part.as_bytes())?);
+            }
+        }
+        Some((plate, wells))
+    }
+
+
+    /// Sum reads of a barcode histogram per well, for each plate and round. Also returns the number of
+    /// barcodes that are not from these plates
+    pub fn reads_per_well(&self, hist:&[(String,i64)]) -> (Vec<Vec<Vec<i64>>>, usize) {
+        let mut counts = self.plates.iter().map(|p| vec![vec![0i64; p.format.num_wells()]; 4]).collect::<Vec<_>>();
+        let mut count_unknown = 0;
+        for (bc, cnt) in hist {
+            match self.parse_bc_name(bc) {
+                Some(bc) => {
+                    let plate = &self.plates[bc.plate];
+                    for round in 0..4 {
+                        counts[bc.plate][round][plate.wells[round][bc.wells[round]]] += cnt;
+                    }
+                },
+                None => {
+                    count_unknown += 1;
+                }
+            }
+        }
+        (counts, count_unknown)
+    }
+
+
+    /// Write the expected barcode block of a corrected barcode, as it appears in the read, into a reusable buffer
+    pub fn write_expected_block(&self, bc:&CellBarcode, out:&mut Vec<u8>) {
+        out.clear();
+        let plate = &self.plates[bc.plate];
+        for r in (0..4).rev() {
+            out.extend_from_slice(plate.rounds[r].list[bc.wells[r]].as_bytes());
+            if r > 0 {
+                out.extend_from_slice(&self.chemistry.linkers[3-r]);
+            }
+        }
+    }
+
+
+    /// Write the barcode block expected in a read into a reusable buffer. With the fixed Atrandi layout this is
+    /// write_expected_block. With a pattern, the block is taken as read, with each round replaced by the barcode
+    /// it was corrected to; the other bases of the pattern are not known, so they never differ
+    pub fn write_expected_block_in(&self, bc:&CellBarcode, bc_read:&[u8], out:&mut Vec<u8>) {
+        let extractor = match &self.extractor {
+            Some(extractor) => extractor,
+            None => return self.write_expected_block(bc, out)
+        };
+        out.clear();
+        out.extend_from_slice(&bc_read[..self.block_end(bc_read).min(bc_read.len())]);
+        if let Some(rounds) = pattern_rounds(extractor, bc_read) {
+            let plate = &self.plates[bc.plate];
+            for (r, (from, to)) in rounds.iter().enumerate() {
+                let expected = plate.rounds[r].list[bc.wells[r]].as_bytes();
+                if to - from == expected.len() && *to <= out.len() {
+                    out[*from..*to].copy_from_slice(expected);
+                }
+            }
+        }
+    }
+
+
+    /// Which part of the barcode block a cycle is in, as CycleStats::region but following the pattern if there is
+    /// one. A regular expression may place the barcodes differently in each read, so its cycles are not labelled
+    pub fn cycle_region(&self, cycle:usize) -> String {
+        match &self.extractor {
+            None => CycleStats::region(cycle),
+            Some(extractor @ PatternExtractor::Fixed {ranges, ..}) => {
+                //Barcode segments are in read order, and the first one seen is the last round
+                let segments = extractor.barcode_segments();
+                match ranges.iter().position(|(from, to)| (*from..*to).contains(&cycle)) {
+                    Some(i) => match segments.iter().position(|s| *s == i) {
+                        Some(k) => format!("round{}", segments.len() - k),
+                        None => "umi".to_string()
+                    },
+                    None => "linker".to_string()
+                }
+            },
+            Some(PatternExtractor::Regex {..}) => "block".to_string()
+        }
+    }
+
+
+    /// Write the name of a partial barcode into a reusable buffer. Missing rounds are written as -
+    pub fn write_partial_bc_name(&self, bc:&PartialCellBarcode, out:&mut Vec<u8>) {
+        out.clear();
+        let plate = &self.plates[bc.plate];
+        if self.plates.len() > 1 {
+            out.extend_from_slice(plate.name.as_bytes());
+            out.push(b':');
+        }
+        for (i, round) in plate.rounds.iter().enumerate() {
+            if i > 0 {
+                out.push(b'.');
+            }
+            match bc.wells[i] {
+                Some(j) => out.extend_from_slice(round.list[j].as_bytes()),
+                None => out.push(b'-')
+            }
+        }
+    }
+
+}
+
+
+/// Sidecar file with the neighbour index of each plate and round, and the whitelists it was made for
+#[derive(Serialize, Deserialize)]
+struct WhitelistIndex {
+    lists: Vec<Vec<Vec<String>>>,
+    neighbors: Vec<Vec<NeighborIndex>>
+}
+
+
+/// Get the four barcodes from the read. They are returned in the logical order of the chemistry
+pub fn extract_bc_optimistic_atrandi(bc_read:&[u8]) -> Option<[&[u8];4]> {
+
+    if bc_read.len() >= BC_BLOCK_LEN {
+        let barcode_4 = &bc_read[0..8];
+        let barcode_3 = &bc_read[12..20];
+        let barcode_2 = &bc_read[24..32];
+        let barcode_1 = &bc_read[36..44];
+        Some([barcode_1,barcode_2,barcode_3,barcode_4])
+    } else {
+        None
+    }
+}
+
+
+/// Get the four barcodes from the read using a custom pattern. Barcode segments are given in read order,
+/// which like the Atrandi block has the last round first; they are returned in the logical order of the chemistry
+pub fn extract_bc_pattern<'a>(extractor:&PatternExtractor, bc_read:&'a [u8]) -> Option<[&'a [u8];4]> {
+    Some(pattern_rounds(extractor, bc_read)?.map(|(from, to)| &bc_read[from..to]))
+}
+
+
+/// Where the four barcodes are in the read according to a pattern, in the logical order of the chemistry
+fn pattern_rounds(extractor:&PatternExtractor, bc_read:&[u8]) -> Option<[(usize, usize);4]> {
+    let ranges = extractor.extract_ranges(bc_read)?;
+    let bc = extractor.barcode_segments();
+    Some([ranges[bc[3]], ranges[bc[2]], ranges[bc[1]], ranges[bc[0]]])
+}
+
+
+/// Get the barcodes that fit in a short read according to a pattern, in the logical order of the chemistry
+pub fn extract_bc_pattern_partial<'a>(extractor:&PatternExtractor, bc_read:&'a [u8]) -> [Option<&'a [u8]>;4] {
+    let ranges = extractor.partial_ranges(bc_read);
+    let bc = extractor.barcode_segments();
+    std::array::from_fn(|i| ranges[bc[3-i]].map(|(from, to)| &bc_read[from..to]))
+}
+
+
+/// Get as many of the four barcodes as fit in a short read, in the logical order of the chemistry.
+/// The last round is first in the read, so the first rounds are the ones lost
+pub fn extract_bc_partial_atrandi(bc_read:&[u8]) -> [Option<&[u8]>;4] {
+    let mut barcode_tuple = [None; 4];
+    for (i, barcode) in barcode_tuple.iter_mut().enumerate() {
+        let from = 36 - 12*i;
+        if bc_read.len() >= from+8 {
+            *barcode = Some(&bc_read[from..(from+8)]);
+        }
+    }
+    barcode_tuple
+}
+
+
+/// Finds the barcode block anywhere in a long read, by aligning the linker scaffold with the barcodes as wildcards
+pub struct BarcodeBlockFinder {
+    myers: Myers<u64>
+}
+
+impl BarcodeBlockFinder {
+
+    pub fn new(chemistry:&Chemistry) -> BarcodeBlockFinder {
+        let mut scaffold: Vec<u8> = Vec::new();
+        for r in 0..4 {
+            scaffold.extend_from_slice(b"NNNNNNNN");
+            if r < 3 {
+                scaffold.extend_from_slice(&chemistry.linkers[r]);
+            }
+        }
+        let myers = MyersBuilder::new().ambig(b'N', b"ACGT").build_64(scaffold);
+        BarcodeBlockFinder {myers}
+    }
+
+    /// Find the best placement of the block in a sequence. Returns start, end (exclusive) and edit distance
+    pub fn find(&mut self, seq:&[u8], max_dist:u8) -> Option<(usize, usize, u8)> {
+        let mut best: Option<(usize, usize, u8)> = None;
+        for (start, end, dist) in self.myers.find_all(seq, max_dist) {
+            if best.is_none_or(|(_, _, best_dist)| dist < best_dist) {
+                best = Some((start, end, dist));
+            }
+        }
+        best
+    }
+}
+
+
+/// Mismatches per sequencing cycle of the barcode block, compared to the corrected barcode
+#[derive(Default)]
+pub struct CycleStats {
+    pub reads: Vec<u64>,
+    pub mismatches: Vec<u64>,
+    pub n_bases: Vec<u64>
+}
+
+impl CycleStats {
+
+    /// The cycles counted grow with the longest barcode block added, as a pattern may make it longer than BC_BLOCK_LEN
+    pub fn new() -> CycleStats {
+        CycleStats {
+            reads: Vec::new(),
+            mismatches: Vec::new(),
+            n_bases: Vec::new()
+        }
+    }
+
+    /// Add a read, given the barcode block expected from its corrected barcode
+    pub fn add(&mut self, read:&[u8], expected:&[u8]) {
+        let len = read.len().min(expected.len());
+        if self.reads.len() < len {
+            self.reads.resize(len, 0);
+            self.mismatches.resize(len, 0);
+            self.n_bases.resize(len, 0);
+        }
+        for i in 0..len {
+            self.reads[i] += 1;
+            if read[i]==b'N' {
+                self.n_bases[i] += 1;
+            } else if read[i]!=expected[i] {
+                self.mismatches[i] += 1;
+            }
+        }
+    }
+
+    /// Which part of the barcode block a cycle belongs to: round1..round4, or linker
+    pub fn region(cycle: usize) -> String {
+        if cycle%12 < 8 {
+            format!("round{}", 4 - cycle/12)
+        } else {
+            "linker".to_string()
+        }
+    }
+}
+
+
+/// Bases seen at each position of the barcode block over the reads of one cell, as A, C, G, T and other
+#[derive(Clone, Debug, Default)]
+pub struct BlockProfile {
+    pub reads: u32,
+    pub counts: Vec<[u32;5]>
+}
+
+impl BlockProfile {
+
+    fn base_index(base: u8) -> usize {
+        match base {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => 4
+        }
+    }
+
+    /// Add the barcode block of a read
+    pub fn add(&mut self, block:&[u8]) {
+        if self.counts.len() < block.len() {
+            self.counts.resize(block.len(), [0; 5]);
+        }
+        for (i, base) in block.iter().enumerate() {
+            self.counts[i][BlockProfile::base_index(*base)] += 1;
+        }
+        self.reads += 1;
+    }
+
+    /// Most common base at each position; N where no base was called
+    pub fn consensus(&self) -> Vec<u8> {
+        self.counts.iter().map(|c| {
+            let (i, n) = c[..4].iter().enumerate().max_by_key(|(i, n)| (**n, std::cmp::Reverse(*i))).expect("four bases");
+            if *n > 0 {b"ACGT"[i]} else {b'N'}
+        }).collect()
+    }
+
+    /// Fraction of the called bases at each position that differ from the expected block
+    pub fn mismatch_rates(&self, expected:&[u8]) -> Vec<f64> {
+        self.counts.iter().zip(expected).map(|(c, e)| {
+            let called: u32 = c[..4].iter().sum();
+            let matching = c[BlockProfile::base_index(*e)];
+            if called > 0 {(called - matching.min(called)) as f64 / called as f64} else {0.0}
+        }).collect()
+    }
+}
+
+
+/// Phred quality below which a base counts as low quality
+pub const LOW_QUAL: u8 = 20;
+
+/// Base qualities per cycle, over a set of reads. Qualities are phred+33
+#[derive(Clone, Debug, Default)]
+pub struct QualityStats {
+    pub sum: Vec<u64>,
+    pub low: Vec<u64>,
+    pub bases: Vec<u64>
+}
+
+/// Mean quality and fraction of low quality bases, over all cycles and per cycle
+#[derive(Serialize)]
+pub struct QualitySummary {
+    pub mean_quality: f64,
+    pub fraction_below_q20: f64,
+    pub per_cycle: Vec<CycleQuality>
+}
+
+#[derive(Serialize)]
+pub struct CycleQuality {
+    pub cycle: usize,
+    pub mean_quality: f64,
+    pub fraction_below_q20: f64
+}
+
+impl QualityStats {
+
+    /// Add the qualities of a read, or part of a read
+    pub fn add(&mut self, qual:&[u8]) {
+        if qual.len() > self.bases.len() {
+            self.sum.resize(qual.len(), 0);
+            self.low.resize(qual.len(), 0);
+            self.bases.resize(qual.len(), 0);
+        }
+        for (i, q) in qual.iter().enumerate() {
+            let q = q.saturating_sub(33);
+            self.sum[i] += q as u64;
+            self.bases[i] += 1;
+            if q < LOW_QUAL {
+                self.low[i] += 1;
+            }
+        }
+    }
+
+    pub fn summary(&self) -> QualitySummary {
+        let ratio = |a: u64, b: u64| if b>0 {a as f64/b as f64} else {0.0};
+        let total_bases = self.bases.iter().sum();
+        QualitySummary {
+            mean_quality: ratio(self.sum.iter().sum(), total_bases),
+            fraction_below_q20: ratio(self.low.iter().sum(), total_bases),
+            per_cycle: (0..self.bases.len()).map(|i| CycleQuality {
+                cycle: i,
+                mean_quality: ratio(self.sum[i], self.bases[i]),
+                fraction_below_q20: ratio(self.low[i], self.bases[i])
+            }).collect()
+        }
+    }
+}
+
+
+/// How repetitive a sequence is, from 0 to 1: the larger of the fraction of the most common base, and the fraction of
+/// positions repeating the base two positions before (dinucleotide repeats, and homopolymers). Reads from empty
+/// clusters are often like this
+pub fn repeat_fraction(seq:&[u8]) -> f64 {
+    if seq.len() < 3 {
+        return 0.0;
+    }
+    let mut base_counts = [0usize; 256];
+    for b in seq {
+        base_counts[*b as usize] += 1;
+    }
+    let most_common = *base_counts.iter().max().unwrap_or(&0) as f64 / seq.len() as f64;
+    let period_2 = (2..seq.len()).filter(|&i| seq[i]==seq[i-2]).count() as f64 / (seq.len()-2) as f64;
+    most_common.max(period_2)
+}
+
+
+/// Set bases with a phred+33 quality below min_qual to N. Only copies the read if any base is masked
+pub fn mask_low_quality<'a>(seq:&'a [u8], qual:&[u8], min_qual:u8) -> Cow<'a, [u8]> {
+    let is_low = |i: usize| qual.get(i).is_some_and(|q| *q < 33 + min_qual);
+    if !(0..seq.len()).any(is_low) {
+        return Cow::Borrowed(seq);
+    }
+    Cow::Owned(seq.iter().enumerate().map(|(i, b)| if is_low(i) {b'N'} else {*b}).collect())
+}
+
+
+/// Mean phred+33 quality of a read, or part of a read
+pub fn mean_quality(qual:&[u8]) -> f64 {
+    if qual.is_empty() {
+        return 0.0;
+    }
+    qual.iter().map(|q| q.saturating_sub(33) as u64).sum::<u64>() as f64 / qual.len() as f64
+}
+
+
+/// Infer a whitelist for one round from observed barcode counts. Barcodes are taken in order of decreasing
+/// frequency; a barcode within min_distance mismatches of one already accepted is considered a sequencing
+/// error of it and skipped. Stops when max_barcodes are accepted or counts drop below min_count
+pub fn learn_whitelist(counts: &HashMap<Vec<u8>, u64>, max_barcodes: usize, min_distance: usize, min_count: u64) -> Vec<(Vec<u8>, u64)> {
+    let mut sorted: Vec<(&Vec<u8>, &u64)> = counts.iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    let mut accepted: Vec<(Vec<u8>, u64)> = Vec::new();
+    for (bc, &cnt) in sorted {
+        if accepted.len() >= max_barcodes || cnt < min_count {
+            break;
+        }
+        if bc.contains(&b'N') {
+            continue;
+        }
+        let is_error = accepted.iter().any(|(other, _)| {
+            other.len()==bc.len() && (bc.len() as i32 - num_similar_elements(bc, other)) as usize <= min_distance
+        });
+        if !is_error {
+            accepted.push((bc.clone(), cnt));
+        }
+    }
+    accepted
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_correct_to_whitelist() {
+        let whitelist = BarcodeWhitelist::new(vec!["GTAACCGA".to_string(), "TCCTCAAC".to_string()], 8);
+        let scorer = HammingScorer;
+        assert_eq!(whitelist.correct_to_whitelist(b"GTAACCGA", None, &scorer), Some((0,8)));
+        assert_eq!(whitelist.correct_to_whitelist(b"TCCTCAAG", None, &scorer), Some((1,7)));
+        assert_eq!(whitelist.correct_to_whitelist(b"AAAAAAAA", None, &scorer), None);
+        // deletion and insertion
+        assert_eq!(whitelist.correct_to_whitelist(b"TCCTCAC", None, &scorer), Some((1,7)));
+        assert_eq!(whitelist.correct_to_whitelist(b"GTAACCGAT", None, &scorer), Some((0,7)));
+        assert_eq!(whitelist.correct_to_whitelist(b"TCCTC", None, &scorer), None);
+        assert_eq!(whitelist.correct_to_whitelist(b"", None, &scorer), None);
+
+        //Same result through the neighbour index
+        let mut whitelist = whitelist;
+        whitelist.neighbors = NeighborIndex::new(&whitelist.list);
+        assert_eq!(whitelist.neighbors.neighbors.get(&b"TCCTCAAG"[..]), Some(&1));
+        assert_eq!(whitelist.correct_to_whitelist(b"TCCTCAAG", None, &scorer), Some((1,7)));
+    }
+
+    #[test]
+    fn test_correct_long_barcodes() {
+        //Scores and cutoffs follow the barcode length: an exact match scores 10, and up to 2 mismatches are corrected
+        let whitelist = BarcodeWhitelist::new(vec!["GTAACCGATT".to_string(), "TCCTCAACGG".to_string()], 10);
+        let scorer = HammingScorer;
+        assert_eq!(whitelist.correct_to_whitelist(b"GTAACCGATT", None, &scorer), Some((0,10)));
+        assert_eq!(whitelist.correct_to_whitelist(b"GTAACCGATA", None, &scorer), Some((0,9)));
+        assert_eq!(whitelist.correct_to_whitelist(b"GTAACCGAAA", None, &scorer), Some((0,8)));
+        assert_eq!(whitelist.correct_to_whitelist(b"GTAACCGCAA", None, &scorer), None);
+
+        //A plate of 10bp barcodes, read through a pattern
+        let dir = tempfile::tempdir().unwrap();
+        let path_bc = dir.path().join("long.tsv");
+        let seqs = ["AAAAAAAAAA", "CCCCCCCCCC", "GGGGGGGGGG", "TTTTTTTTTT", "ACACACACAC", "GTGTGTGTGT", "AGAGAGAGAG", "CTCTCTCTCT"];
+        let mut table = "pos\twell\tseq\n".to_string();
+        for round in 0..4 {
+            table.push_str(&format!("{}\tA1\t{}\n{}\tA2\t{}\n", round+1, seqs[2*round], round+1, seqs[2*round+1]));
+        }
+        std::fs::write(&path_bc, table).unwrap();
+        let mut barcodes = AtrandiBarcodes::read_plates(&[path_bc.to_string_lossy().to_string()], Chemistry::default()).unwrap();
+        barcodes.extractor = Some(PatternExtractor::new("BBBBBBBBBBxxxxBBBBBBBBBBxxxxBBBBBBBBBBxxxxBBBBBBBBBB").unwrap());
+        let bc = CellBarcode {plate: 0, wells: [1, 0, 1, 0]};
+        let mut read = Vec::new();
+        barcodes.write_expected_block(&bc, &mut read);
+        assert_eq!(read.len(), 52);
+        assert_eq!(barcodes.correct_with_outcome(&read, None, false), (Some(bc), CorrectionOutcome::Exact));
+
+        //Three mismatches in total are corrected, four are not
+        for i in [0, 14, 28] {
+            read[i] = b'N';
+        }
+        assert_eq!(barcodes.get_correct_bc_from_read(&read, None, false), Some(bc));
+        read[42] = b'N';
+        assert_eq!(barcodes.get_correct_bc_from_read(&read, None, false), None);
+    }
+
+    #[test]
+    fn test_whitelist_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        let mut barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        barcodes.load_or_build_index(Some(&path)).unwrap();
+        let built = barcodes.plates[0].rounds[0].neighbors.clone();
+        assert!(!built.neighbors.is_empty());
+
+        let mut barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        barcodes.load_or_build_index(Some(&path)).unwrap();
+        assert_eq!(barcodes.plates[0].rounds[0].neighbors, built);
+    }
+
+    #[test]
+    fn test_scorers() {
+        let expected = b"GTAACCGA";
+        let observed = b"GTAACGCA";
+        assert_eq!(HammingScorer.score(observed, None, expected), 6);
+
+        //Low quality mismatches count half
+        let qual = b"IIIII##I";
+        let scorer = QualityWeightedScorer {min_qual: 20};
+        assert_eq!(scorer.score(observed, Some(qual), expected), 7);
+        assert_eq!(scorer.score(observed, None, expected), 6);
+
+        //Adjacent mismatches are one run
+        let scorer = AffineScorer {open: 1, extend: 2};
+        assert_eq!(scorer.score(observed, None, expected), 5);
+        assert_eq!(scorer.score(b"ATAACCGT", None, expected), 6);
+
+        //The word-wise Hamming scan agrees with scoring base by base, including ties going to the first
+        let seqs = b"GTAACCGATCCTCAACGTAACGCAGTAACGCT";
+        for observed in [&b"GTAACGCA"[..], b"TCCTCAAN", b"NNNNNNNN", b"GTAACGCC"] {
+            assert_eq!(HammingScorer.best_match(observed, None, seqs, 8), best_match_by_score(&HammingScorer, observed, None, seqs, 8));
+        }
+        assert_eq!(HammingScorer.best_match(b"GTAACGCC", None, seqs, 8), Some((2, 7)));
+        assert_eq!(mismatching_bytes(0x0100_0000_0080_0000), 2);
+    }
+
+    #[test]
+    fn test_read_plates() {
+        let barcodes = AtrandiBarcodes::read_plates(&["P1=bc.csv".to_string()], Chemistry::default()).unwrap();
+        assert_eq!(barcodes.plates[0].name, "P1");
+        assert_eq!(barcodes.plates[0].rounds.len(), 4);
+        assert_eq!(barcodes.plates[0].rounds[0].list[0], "GTAACCGA");
+        assert_eq!(whitelist_stem("https://example.org/kits/bc_v2.csv.gz?version=2"), "bc_v2");
+        assert_eq!(whitelist_stem("data/bc.csv.zst"), "bc");
+
+        let sha256 = sha256_hex(&std::fs::read("bc.csv").unwrap());
+        assert_eq!(barcodes.plates[0].sha256, sha256);
+        assert!(barcodes.verify_checksums(&[]).is_ok());
+        assert!(barcodes.verify_checksums(&[sha256.to_uppercase()]).is_ok());
+        assert!(barcodes.verify_checksums(&["0".repeat(64)]).is_err());
+        assert!(barcodes.verify_checksums(&[sha256.clone(), sha256]).is_err());
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_learn_whitelist() {
+        let mut counts = HashMap::new();
+        counts.insert(b"GTAACCGA".to_vec(), 1000);
+        counts.insert(b"GTAACCGT".to_vec(), 50);  //error of the first
+        counts.insert(b"TCCTCAAC".to_vec(), 800);
+        counts.insert(b"ACGTACGT".to_vec(), 2);   //noise
+        let learned = learn_whitelist(&counts, 96, 1, 10);
+        assert_eq!(learned, vec![(b"GTAACCGA".to_vec(), 1000), (b"TCCTCAAC".to_vec(), 800)]);
+    }
+
+    #[test]
+    fn test_plate_format() {
+        assert_eq!(PlateFormat::Wells96.well_name(13), "B2");
+        assert_eq!(PlateFormat::Wells384.well_name(383), "P24");
+        assert_eq!(parse_well("P24"), Some((15, 23)));
+        assert_eq!(parse_well("b2"), Some((1, 1)));
+        assert_eq!(parse_well("A0"), None);
+        let ranges = parse_well_ranges("1:A1-H6, 2:C3").unwrap();
+        assert_eq!(ranges, vec![WellRange {round: 0, first: (0, 0), last: (7, 5)}, WellRange {round: 1, first: (2, 2), last: (2, 2)}]);
+        assert!(ranges[0].contains(3, 5) && !ranges[0].contains(3, 6));
+        assert!(parse_well_ranges("5:A1").is_err());
+        assert!(parse_well_ranges("A1-H6").is_err());
+        assert_eq!(PlateFormat::Wells96.well_index(15, 23), None);
+        assert_eq!(PlateFormat::Wells384.well_index(15, 23), Some(383));
+        assert_eq!(PlateFormat::for_num_wells(200), Some(PlateFormat::Wells384));
+
+        let barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        assert_eq!(barcodes.plates[0].format, PlateFormat::Wells96);
+        assert_eq!(barcodes.plates[0].wells[0][1], 1); //A2
+
+        let bc = CellBarcode {plate: 0, wells: [1, 2, 3, 4]};
+        let mut name = Vec::new();
+        barcodes.write_bc_name(&bc, &mut name);
+        assert_eq!(barcodes.parse_bc_name(&String::from_utf8(name).unwrap()), Some(bc));
+        assert_eq!(barcodes.parse_bc_name("AAAAAAAA.CCCCCCCC"), None);
+
+        let partial = PartialCellBarcode {plate: 0, wells: [None, None, Some(3), Some(4)]};
+        let mut name = Vec::new();
+        barcodes.write_partial_bc_name(&partial, &mut name);
+        let name = String::from_utf8(name).unwrap();
+        assert_eq!(barcodes.parse_packed_name(&name), Some(partial.pack()));
+        assert_eq!(barcodes.parse_bc_name(&name), None);
+    }
+
+    #[test]
+    fn test_quality_stats() {
+        let mut stats = QualityStats::default();
+        stats.add(b"II#");  //40 40 2
+        stats.add(b"5I");   //20 40
+        let summary = stats.summary();
+        assert_eq!(summary.per_cycle.len(), 3);
+        assert_eq!(summary.per_cycle[0].mean_quality, 30.0);
+        assert_eq!(summary.per_cycle[2].fraction_below_q20, 1.0);
+        assert_eq!(summary.mean_quality, 142.0/5.0);
+        assert_eq!(summary.fraction_below_q20, 0.2);
+        assert_eq!(mean_quality(b"5I"), 30.0);
+        assert_eq!(mask_low_quality(b"ACGT", b"I#I5", 20).as_ref(), b"ANGT");
+        assert!(matches!(mask_low_quality(b"ACGT", b"IIII", 20), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_repeat_fraction() {
+        assert_eq!(repeat_fraction(b"GGGGGGGGGG"), 1.0);
+        assert_eq!(repeat_fraction(b"ACACACACAC"), 1.0);
+        assert_eq!(repeat_fraction(b"GGGGGGGGAC"), 0.8);
+        assert!(repeat_fraction(b"GTAACCGAAGGATCCTCAAC") < 0.5);
+    }
+
+    #[test]
+    fn test_cycle_stats() {
+        let barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        let bc = CellBarcode {plate: 0, wells: [0, 0, 0, 0]};
+        let mut expected = Vec::new();
+        barcodes.write_expected_block(&bc, &mut expected);
+        assert_eq!(expected.len(), BC_BLOCK_LEN);
+        assert_eq!(&expected[36..], barcodes.plates[0].rounds[0].list[0].as_bytes());
+        assert_eq!(&expected[8..12], b"AGGA");
+
+        let mut stats = CycleStats::new();
+        let mut read = expected.clone();
+        read[3] = if read[3]==b'A' {b'C'} else {b'A'};
+        read[5] = b'N';
+        stats.add(&read, &expected);
+        assert_eq!(stats.mismatches[3], 1);
+        assert_eq!(stats.n_bases[5], 1);
+        assert_eq!(stats.reads[43], 1);
+        assert_eq!(CycleStats::region(0), "round4");
+        assert_eq!(CycleStats::region(40), "round1");
+        assert_eq!(CycleStats::region(9), "linker");
+    }
+
+    #[test]
+    fn test_barcode_block_finder() {
+        let barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        let bc = CellBarcode {plate: 0, wells: [1, 2, 3, 4]};
+        let mut block = Vec::new();
+        barcodes.write_expected_block(&bc, &mut block);
+
+        let mut read = b"TTTTTTTTTTTTTTTTTTTT".to_vec();
+        read.extend_from_slice(&block);
+        read.extend_from_slice(b"CCCCCCCCCCCCCCCCCCCC");
+        let mut finder = BarcodeBlockFinder::new(&barcodes.chemistry);
+        let (start, end, dist) = finder.find(&read, 4).unwrap();
+        assert_eq!((start, end, dist), (20, 20+BC_BLOCK_LEN, 0));
+        assert_eq!(barcodes.get_correct_bc_from_read(&read[start..], None, false), Some(bc));
+
+        //A read holding just the block is full, not partial
+        assert!(barcodes.has_full_block(&block));
+        assert_eq!(barcodes.get_correct_bc_from_read(&block, None, false), Some(bc));
+        assert!(!barcodes.has_full_block(&block[..BC_BLOCK_LEN-1]));
+
+        //A kit with other linkers does not match the block
+        let other = Chemistry::from_linkers("TTCC,GGAT,CCTT").unwrap();
+        assert_eq!(BarcodeBlockFinder::new(&other).find(&read, 4), None);
+        assert!(Chemistry::from_linkers("AGGA,ACTC").is_err());
+    }
+
+    #[test]
+    fn test_joint_correction() {
+        let mut trie = CombinationTrie::default();
+        trie.insert(&[0, 1, 2, 3]);
+        trie.insert(&[0, 1, 2, 4]);
+        let candidates = [vec![(0, 8)], vec![(1, 8), (5, 8)], vec![(2, 7)], vec![(3, 6), (4, 7)]];
+        assert_eq!(trie.best_path(&candidates), Some(([0, 1, 2, 4], 30)));
+        let tied = [vec![(0, 8)], vec![(1, 8)], vec![(2, 7)], vec![(3, 7), (4, 7)]];
+        assert_eq!(trie.best_path(&tied), None);
+
+        //Only combinations in the histogram are assigned
+        let dir = tempfile::tempdir().unwrap();
+        let path_hist = dir.path().join("joint.tsv");
+        let mut barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        let seen = CellBarcode {plate: 0, wells: [1, 2, 3, 4]};
+        let mut name = Vec::new();
+        barcodes.write_bc_name(&seen, &mut name);
+        std::fs::write(&path_hist, format!("barcode\tcount\n{}\t100\n", String::from_utf8(name).unwrap())).unwrap();
+        barcodes.load_joint(&path_hist, 10).unwrap();
+
+        let mut block = Vec::new();
+        barcodes.write_expected_block(&seen, &mut block);
+        block.push(b'T');
+        assert_eq!(barcodes.get_correct_bc_from_read(&block, None, false), Some(seen));
+        barcodes.write_expected_block(&CellBarcode {plate: 0, wells: [0, 2, 3, 4]}, &mut block);
+        block.push(b'T');
+        assert_eq!(barcodes.get_correct_bc_from_read(&block, None, false), None);
+    }
+
+    #[test]
+    fn test_block_profile() {
+        let mut profile = BlockProfile::default();
+        profile.add(b"ACGT");
+        profile.add(b"ACGA");
+        profile.add(b"ACNA");
+        assert_eq!(profile.reads, 3);
+        assert_eq!(profile.consensus(), b"ACGA");
+        let rates = profile.mismatch_rates(b"ACGT");
+        assert_eq!(rates[..3], [0.0, 0.0, 0.0]);
+        assert!((rates[3] - 2.0/3.0).abs() < 1e-12);
+
+        let mut empty = BlockProfile::default();
+        empty.add(b"N");
+        assert_eq!(empty.consensus(), b"N");
+    }
+
+    #[test]
+    fn test_correction_outcome() {
+        let barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        let bc = CellBarcode {plate: 0, wells: [1, 2, 3, 4]};
+        let mut read = Vec::new();
+        barcodes.write_expected_block(&bc, &mut read);
+        read.push(b'T');
+        assert_eq!(barcodes.correct_with_outcome(&read, None, false), (Some(bc), CorrectionOutcome::Exact));
+        assert_eq!(barcodes.correct_exact(&read), Some(bc));
+
+        let packed = bc.pack();
+        assert_eq!((packed.plate(), packed.wells()), (0, [Some(1), Some(2), Some(3), Some(4)]));
+        let mut name = Vec::new();
+        barcodes.write_packed_name(packed, &mut name);
+        assert_eq!(barcodes.parse_bc_name(&String::from_utf8(name).unwrap()), Some(bc));
+        let partial = PartialCellBarcode {plate: 3, wells: [None, None, Some(0), Some(383)]};
+        assert_eq!(partial.pack().wells(), partial.wells);
+        assert_eq!(partial.pack().plate(), 3);
+
+        let mut one = read.clone();
+        one[37] = if one[37]==b'A' {b'C'} else {b'A'};
+        assert_eq!(barcodes.correct_with_outcome(&one, None, false), (Some(bc), CorrectionOutcome::Corrected1Mismatch));
+        assert_eq!(barcodes.correct_exact(&one), None);
+        assert_eq!(barcodes.round_mismatches(&one, &bc), Some([1, 0, 0, 0]));
+
+        //Rounds in wells not used are rejected
+        let mut used = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        assert!(used.is_used(packed));
+        let dir = tempfile::tempdir().unwrap();
+        let path_used = dir.path().join("used.tsv");
+        let well = |round:usize| PlateFormat::Wells96.well_name(used.plates[0].wells[round][bc.wells[round]]);
+        std::fs::write(&path_used, format!("round\twell\n1\t{}\n2\t{}\n3\t{}\n", well(0), well(1), well(2))).unwrap();
+        used.load_used_wells(&path_used).unwrap();
+        assert!(!used.is_used(packed));
+        assert!(used.is_used(PartialCellBarcode {plate: 0, wells: [Some(1), Some(2), Some(3), None]}.pack()));
+        std::fs::write(&path_used, "round\twell\n5\tA1\n").unwrap();
+        assert!(used.load_used_wells(&path_used).is_err());
+
+        //With only some wells in the whitelist, reads are corrected among those, and named as before
+        let mut subset = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        subset.subset_wells(&parse_well_ranges("1:A1-H2").unwrap()).unwrap();
+        assert_eq!(subset.plates[0].rounds[0].list.len(), 16);
+        assert_eq!(subset.plates[0].rounds[1].list.len(), 24);
+        assert!(subset.plates[0].wells[0].iter().all(|w| w % 12 < 2));
+        assert!(subset.subset_wells(&parse_well_ranges("1:A12").unwrap()).is_err());
+
+        //The same plate twice: an exact match is ambiguous, and left to full correction
+        let twice = AtrandiBarcodes::read_plates(&["A=bc.csv".to_string(), "B=bc.csv".to_string()], Chemistry::default()).unwrap();
+        assert_eq!(twice.correct_exact(&read), None);
+        assert_eq!(twice.correct_with_outcome(&read, None, false), (None, CorrectionOutcome::Ambiguous));
+
+        let mut bad_round = read.clone();
+        bad_round[36..44].copy_from_slice(b"NNNNNNNN");
+        assert_eq!(barcodes.correct_with_outcome(&bad_round, None, false), (None, CorrectionOutcome::FailedRound(0)));
+
+        let mut bad_linker = bad_round.clone();
+        bad_linker[8..12].copy_from_slice(b"NNNN");
+        assert_eq!(barcodes.correct_with_outcome(&bad_linker, None, false), (None, CorrectionOutcome::FailedLinker));
+
+        assert_eq!(barcodes.correct_with_outcome(&read[..20], None, false), (None, CorrectionOutcome::TooShort));
+
+        let mut counts = OutcomeCounts::default();
+        counts.add(CorrectionOutcome::FailedRound(2));
+        assert_eq!(counts.failed_round, [0, 0, 1, 0]);
+        assert_eq!(CorrectionOutcome::FailedRound(2).label(), "failed_round_3");
+        counts.add(CorrectionOutcome::LowBarcodeQuality);
+        assert_eq!(counts.low_barcode_quality, 1);
+        assert_eq!(CorrectionOutcome::LowComplexity.label(), "low_complexity");
+        assert_eq!(CorrectionOutcome::LowBarcodeQuality.label(), "low_bc_quality");
+    }
+
+    #[test]
+    fn test_extract_bc_pattern() {
+        let read = b"AAAAAAAAxxxxCCCCCCCCxxxxGGGGGGGGxxxxTTTTTTTTxxxx";
+        let extractor = PatternExtractor::new("BBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBB").unwrap();
+        assert_eq!(extract_bc_pattern(&extractor, read), extract_bc_optimistic_atrandi(read));
+    }
+
+    #[test]
+    fn test_pattern_block() {
+        //A pattern longer than the Atrandi block: six bases before it
+        let mut barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        barcodes.extractor = Some(PatternExtractor::new("xxxxxxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBB").unwrap());
+        let bc = CellBarcode {plate: 0, wells: [1, 2, 3, 4]};
+        let mut block = Vec::new();
+        barcodes.write_expected_block(&bc, &mut block);
+        let mut read = b"GGGGGG".to_vec();
+        read.extend_from_slice(&block);
+        read.extend_from_slice(b"TTTT");
+        assert_eq!(barcodes.block_end(&read), 50);
+
+        //The expected block follows the pattern, and only differs from the read where a barcode does
+        let mut expected = Vec::new();
+        barcodes.write_expected_block_in(&bc, &read, &mut expected);
+        assert_eq!(expected, &read[..50]);
+        let mut mismatched = read.clone();
+        mismatched[7] = if read[7]==b'A' {b'C'} else {b'A'};
+        barcodes.write_expected_block_in(&bc, &mismatched, &mut expected);
+        assert_eq!(expected, &read[..50]);
+
+        let mut stats = CycleStats::new();
+        stats.add(&mismatched, &expected);
+        assert_eq!(stats.reads.len(), 50);
+        assert_eq!(stats.mismatches[7], 1);
+        assert_eq!(barcodes.cycle_region(0), "linker");
+        assert_eq!(barcodes.cycle_region(6), "round4");
+        assert_eq!(barcodes.cycle_region(49), "round1");
+    }
+
+    #[test]
+    fn test_correct_with_pattern() {
+        let mut barcodes = AtrandiBarcodes::read_plates(&["bc.csv".to_string()], Chemistry::default()).unwrap();
+        barcodes.extractor = Some(PatternExtractor::new("(?P<bc1>.{8})AGGA(?P<bc2>.{8})ACTC(?P<bc3>.{8})AAGG(?P<bc4>.{8})").unwrap());
+        barcodes.scorer = Scoring::Quality.scorer(20);
+        let bc = CellBarcode {plate: 0, wells: [1, 2, 3, 4]};
+        let mut read = Vec::new();
+        barcodes.write_expected_block(&bc, &mut read);
+        assert!(barcodes.has_full_block(&read));
+
+        //Three mismatches in the first round, all at low quality bases: only corrected if the qualities are used
+        let mut qual = vec![b'I'; read.len()];
+        for i in [37, 39, 41] {
+            read[i] = if read[i]==b'A' {b'C'} else {b'A'};
+            qual[i] = b'#';
+        }
+        assert_ne!(barcodes.correct_with_outcome(&read, None, false).0, Some(bc));
+        assert_eq!(barcodes.correct_with_outcome(&read, Some(&qual), false), (Some(bc), CorrectionOutcome::Corrected2Mismatches));
+
+        //A regex may take a barcode one base short, which is corrected allowing the deletion
+        barcodes.extractor = Some(PatternExtractor::new("(?P<bc1>.{7,9})AGGA(?P<bc2>.{8})ACTC(?P<bc3>.{8})AAGG(?P<bc4>.{8})").unwrap());
+        barcodes.write_expected_block(&bc, &mut read);
+        read.remove(3);
+        let barcode_tuple = extract_bc_pattern(barcodes.extractor.as_ref().unwrap(), &read).unwrap();
+        assert_eq!(barcode_tuple[3].len(), 7);
+        assert_eq!(barcodes.correct_with_outcome(&read, None, false), (Some(bc), CorrectionOutcome::Corrected2Mismatches));
+
+        //Partial barcodes are taken where the pattern puts them
+        let mut shifted = b"TT".to_vec();
+        barcodes.write_expected_block(&bc, &mut read);
+        shifted.extend_from_slice(&read);
+        barcodes.extractor = Some(PatternExtractor::new("xxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBB").unwrap());
+        assert!(!barcodes.has_full_block(&shifted[..30]));
+        let partial = barcodes.get_partial_bc_from_read(&shifted[..30], None).unwrap();
+        assert_eq!(partial.wells, [None, None, Some(3), Some(4)]);
+
+        //Only the barcode block is masked, and the read only copied if some of it is
+        barcodes.extractor = None;
+        barcodes.min_base_qual = Some(20);
+        let mut long_read = read.clone();
+        long_read.extend_from_slice(b"ACGT");
+        let mut qual = vec![b'I'; long_read.len()];
+        qual[BC_BLOCK_LEN + 1] = b'#';
+        assert!(matches!(barcodes.mask_block(&long_read, Some(&qual)), Cow::Borrowed(_)));
+        qual[1] = b'#';
+        let masked = barcodes.mask_block(&long_read, Some(&qual));
+        assert_eq!(masked[1], b'N');
+        assert_eq!(&masked[BC_BLOCK_LEN..], b"ACGT");
+    }
+
+    #[test]
+    fn test_extract_bc_partial() {
+        let read = b"AAAAAAAAxxxxCCCCCCCCxxxxGGGGGG";
+        let tuple = extract_bc_partial_atrandi(read);
+        assert_eq!(tuple, [None, None, Some(&b"CCCCCCCC"[..]), Some(&b"AAAAAAAA"[..])]);
+    }
+}
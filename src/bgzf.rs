@@ -0,0 +1,275 @@
+use std::path::PathBuf;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use flate2::read::{GzDecoder, MultiGzDecoder};
+
+
+/// Blocks read ahead of the one being consumed, per decompression thread
+const BLOCKS_AHEAD_PER_THREAD: usize = 4;
+
+
+/// Size of a bgzf block, from the BC subfield of the gzip extra field
+pub fn bgzf_block_size(extra:&[u8]) -> Option<u64> {
+    let mut i = 0;
+    while i + 4 <= extra.len() {
+        let slen = u16::from_le_bytes([extra[i+2], extra[i+3]]) as usize;
+        if extra[i] == b'B' && extra[i+1] == b'C' && slen == 2 && i + 6 <= extra.len() {
+            return Some(u16::from_le_bytes([extra[i+4], extra[i+5]]) as u64 + 1);
+        }
+        i += 4 + slen;
+    }
+    None
+}
+
+
+/// Compressed and uncompressed start of every block of a bgzf file. Only block headers and sizes are read
+pub fn bgzf_blocks(path:&PathBuf) -> std::io::Result<Vec<(u64, u64)>> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut blocks = Vec::new();
+    let mut coffset = 0;
+    let mut uoffset = 0;
+    let mut header = [0u8; 12];
+    let mut isize = [0u8; 4];
+    while coffset < file_len {
+        file.seek(SeekFrom::Start(coffset))?;
+        file.read_exact(&mut header)?;
+        if header[0..4] != [31, 139, 8, 4] {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not bgzf compressed", path.display())));
+        }
+        let mut extra = vec![0u8; u16::from_le_bytes([header[10], header[11]]) as usize];
+        file.read_exact(&mut extra)?;
+        let bsize = bgzf_block_size(&extra)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{} is not bgzf compressed", path.display())))?;
+        file.seek(SeekFrom::Start(coffset + bsize - 4))?;
+        file.read_exact(&mut isize)?;
+        blocks.push((coffset, uoffset));
+        coffset += bsize;
+        uoffset += u32::from_le_bytes(isize) as u64;
+    }
+    Ok(blocks)
+}
+
+
+/// Virtual offset of an uncompressed position: the compressed start of its block in the upper 48 bits, and the
+/// position within the block in the lower 16
+pub fn virtual_offset(blocks:&[(u64, u64)], upos:u64) -> std::io::Result<u64> {
+    let i = blocks.partition_point(|(_, ustart)| *ustart <= upos);
+    if i == 0 {
+        return Err(Error::new(ErrorKind::InvalidInput, "No bgzf block before position"));
+    }
+    let (cstart, ustart) = blocks[i-1];
+    let within = upos - ustart;
+    if within > 0xffff {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Position {} is past the end of the bgzf file", upos)));
+    }
+    Ok((cstart << 16) | within)
+}
+
+
+/// Decompressed stream of a bgzf file from a virtual offset onwards
+pub fn open_bgzf_at(path:&PathBuf, voffset:u64) -> std::io::Result<MultiGzDecoder<BufReader<File>>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(voffset >> 16))?;
+    let mut reader = MultiGzDecoder::new(BufReader::new(file));
+    std::io::copy(&mut (&mut reader).take(voffset & 0xffff), &mut std::io::sink())?;
+    Ok(reader)
+}
+
+
+/// Whether a stream starts with a bgzf block. Only peeks at what is buffered, so nothing is consumed and the
+/// stream can be a pipe
+pub fn is_bgzf<R: BufRead>(reader:&mut R) -> bool {
+    match reader.fill_buf() {
+        Ok(header) if header.len() >= 18 => header[0..4] == [31, 139, 8, 4] && bgzf_block_size(&header[12..18]).is_some(),
+        _ => false
+    }
+}
+
+
+/// Read the next whole block of a bgzf stream, still compressed. None at the end of the stream
+fn read_raw_block<R: Read>(reader:&mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 12];
+    let mut filled = 0;
+    while filled < header.len() {
+        let n = reader.read(&mut header[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    if filled == 0 {
+        return Ok(None);
+    }
+    if filled < header.len() || header[0..4] != [31, 139, 8, 4] {
+        return Err(Error::new(ErrorKind::InvalidData, "Input is not bgzf compressed"));
+    }
+    let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let mut block = header.to_vec();
+    block.resize(header.len() + xlen, 0);
+    reader.read_exact(&mut block[header.len()..])?;
+    let bsize = bgzf_block_size(&block[header.len()..])
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Input is not bgzf compressed"))? as usize;
+    if bsize < block.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "Malformed bgzf block"));
+    }
+    let start = block.len();
+    block.resize(bsize, 0);
+    reader.read_exact(&mut block[start..])?;
+    Ok(Some(block))
+}
+
+
+fn inflate_block(block:&[u8]) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    GzDecoder::new(block).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+
+/// Reader that decompresses a bgzf stream on a pool of threads. One thread splits the stream into blocks, which
+/// is cheap as each block header gives its size; the blocks are decompressed in parallel and read in order
+pub struct ParBgzfReader {
+    blocks: Receiver<Receiver<std::io::Result<Vec<u8>>>>,
+    current: Vec<u8>,
+    pos: usize
+}
+
+impl ParBgzfReader {
+
+    pub fn new<R: Read + Send + 'static>(inner:R, num_threads:usize) -> ParBgzfReader {
+        let num_threads = num_threads.max(1);
+        let queue_len = num_threads * BLOCKS_AHEAD_PER_THREAD;
+
+        //Each block gets a channel of its own for the result. These are queued in order for the reader
+        let (tx_ordered, rx_ordered) = sync_channel(queue_len);
+        let (tx_work, rx_work) = sync_channel::<(Vec<u8>, SyncSender<std::io::Result<Vec<u8>>>)>(queue_len);
+        let rx_work = Arc::new(Mutex::new(rx_work));
+        for _ in 0..num_threads {
+            let rx_work = rx_work.clone();
+            std::thread::spawn(move || loop {
+                let job = rx_work.lock().unwrap().recv();
+                match job {
+                    Ok((block, tx)) => {
+                        let _ = tx.send(inflate_block(&block));
+                    },
+                    Err(_) => break
+                }
+            });
+        }
+
+        std::thread::spawn(move || {
+            let mut inner = inner;
+            loop {
+                let (tx, rx) = sync_channel(1);
+                match read_raw_block(&mut inner) {
+                    Ok(Some(block)) => {
+                        if tx_ordered.send(rx).is_err() || tx_work.send((block, tx)).is_err() {
+                            break;
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        let _ = tx_ordered.send(rx);
+                        break;
+                    }
+                }
+            }
+        });
+
+        ParBgzfReader {
+            blocks: rx_ordered,
+            current: Vec::new(),
+            pos: 0
+        }
+    }
+}
+
+impl Read for ParBgzfReader {
+    fn read(&mut self, buf:&mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.current.len() {
+            match self.blocks.recv() {
+                Ok(rx) => {
+                    self.current = rx.recv().map_err(|_| Error::other("Decompression thread failed"))??;
+                    self.pos = 0;
+                },
+                Err(_) => return Ok(0)
+            }
+        }
+        let n = buf.len().min(self.current.len() - self.pos);
+        buf[..n].copy_from_slice(&self.current[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// One bgzf block holding the data
+    fn bgzf_block(data:&[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut crc = flate2::Crc::new();
+        crc.update(data);
+        let mut block = vec![31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, b'B', b'C', 2, 0];
+        block.extend_from_slice(&((18 + compressed.len() + 8 - 1) as u16).to_le_bytes());
+        block.extend_from_slice(&compressed);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn test_bgzf_offsets() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let path = dir.join("test.gz");
+        let first = bgzf_block(b"0123");
+        let second = bgzf_block(b"456789");
+        std::fs::write(&path, [first.clone(), second.clone(), bgzf_block(b"")].concat()).unwrap();
+
+        let c1 = first.len() as u64;
+        let c2 = c1 + second.len() as u64;
+        let blocks = bgzf_blocks(&path).unwrap();
+        assert_eq!(blocks, vec![(0, 0), (c1, 4), (c2, 10)]);
+        assert_eq!(virtual_offset(&blocks, 2).unwrap(), 2);
+        assert_eq!(virtual_offset(&blocks, 5).unwrap(), (c1 << 16) | 1);
+
+        let mut rest = String::new();
+        open_bgzf_at(&path, (c1 << 16) | 1).unwrap().read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "56789");
+    }
+
+    #[test]
+    fn test_par_bgzf_reader() {
+        let mut expected = Vec::new();
+        let mut stream = Vec::new();
+        for i in 0..100 {
+            let data = format!("block {}\n", i).repeat(i % 7);
+            expected.extend_from_slice(data.as_bytes());
+            stream.extend(bgzf_block(data.as_bytes()));
+        }
+        stream.extend(bgzf_block(b""));
+
+        let mut buffered = BufReader::new(std::io::Cursor::new(stream.clone()));
+        assert!(is_bgzf(&mut buffered));
+        assert!(!is_bgzf(&mut BufReader::new(&b"@read1\nACGT\n+\nIIII\n"[..])));
+
+        let mut data = Vec::new();
+        ParBgzfReader::new(buffered, 3).read_to_end(&mut data).unwrap();
+        assert_eq!(data, expected);
+
+        //A truncated stream is an error, not a short read
+        stream.truncate(stream.len() - 10);
+        assert!(ParBgzfReader::new(std::io::Cursor::new(stream), 2).read_to_end(&mut Vec::new()).is_err());
+    }
+}
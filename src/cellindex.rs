@@ -0,0 +1,196 @@
+use std::path::PathBuf;
+use std::fs::File;
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+use serde::{Deserialize, Serialize};
+
+use crate::bgzf::{bgzf_blocks, virtual_offset};
+
+
+/// Uncompressed bytes of R1 output per index chunk. A cell is indexed by the chunks that hold its reads, so
+/// smaller chunks mean less to decompress when fetching a cell, but a larger index
+pub const CELL_INDEX_CHUNK: u64 = 4*1024*1024;
+
+
+/// Where a chunk of read pairs starts in the R1 and R2 files, and how many pairs it holds. The starts are
+/// uncompressed positions while writing, and bgzf virtual offsets once stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexChunk {
+    pub r1: u64,
+    pub r2: u64,
+    pub pairs: u64
+}
+
+
+/// Random-access index of corrected FASTQ files by cell barcode. Reads are not sorted by cell, so the index
+/// lists the chunks of the files holding reads of each cell; only these are decompressed to fetch the cell
+#[derive(Default, Serialize, Deserialize)]
+pub struct CellIndex {
+    pub chunks: Vec<IndexChunk>,
+    pub cells: HashMap<String, Vec<u32>>
+}
+
+impl CellIndex {
+
+    /// Add a read pair written at these uncompressed positions of the R1 and R2 files. All pairs must be added,
+    /// in the order they are written
+    pub fn add(&mut self, cell:&str, r1_pos:u64, r2_pos:u64) {
+        let new_chunk = match self.chunks.last() {
+            Some(chunk) => r1_pos >= chunk.r1 + CELL_INDEX_CHUNK,
+            None => true
+        };
+        if new_chunk {
+            self.chunks.push(IndexChunk {r1: r1_pos, r2: r2_pos, pairs: 0});
+        }
+        let chunk_id = (self.chunks.len() - 1) as u32;
+        self.chunks[chunk_id as usize].pairs += 1;
+        add_to_cell(&mut self.cells, cell, chunk_id);
+    }
+
+    /// Chunks holding reads of a cell, if it has any
+    pub fn chunks_of(&self, cell:&str) -> Option<Vec<IndexChunk>> {
+        self.cells.get(cell).map(|ids| ids.iter().map(|id| self.chunks[*id as usize]).collect())
+    }
+
+    /// Turn the uncompressed positions into bgzf virtual offsets, once the files are complete
+    pub fn set_virtual_offsets(&mut self, path_r1:&PathBuf, path_r2:Option<&PathBuf>) -> std::io::Result<()> {
+        let blocks_r1 = bgzf_blocks(path_r1)?;
+        let blocks_r2 = path_r2.map(bgzf_blocks).transpose()?;
+        for chunk in &mut self.chunks {
+            chunk.r1 = virtual_offset(&blocks_r1, chunk.r1)?;
+            if let Some(blocks_r2) = &blocks_r2 {
+                chunk.r2 = virtual_offset(blocks_r2, chunk.r2)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn store(&self, path:&PathBuf) -> std::io::Result<()> {
+        store_index(self, path)
+    }
+
+    pub fn read(path:&PathBuf) -> std::io::Result<CellIndex> {
+        read_index(path)
+    }
+}
+
+
+/// Where a chunk of lines starts in a text file, and how many lines it holds. The start is the uncompressed
+/// position while writing, and a bgzf virtual offset once stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogChunk {
+    pub start: u64,
+    pub lines: u64
+}
+
+
+/// Random-access index of the assignment log by cell, chunked like CellIndex. The log is in read order, not
+/// sorted by cell, so this is not a tabix index; use FetchLog to read the lines of a cell
+#[derive(Default, Serialize, Deserialize)]
+pub struct LogIndex {
+    pub chunks: Vec<LogChunk>,
+    pub cells: HashMap<String, Vec<u32>>
+}
+
+impl LogIndex {
+
+    /// Add a line written at this uncompressed position. All lines must be added, in the order they are written
+    pub fn add(&mut self, cell:&str, pos:u64) {
+        let new_chunk = match self.chunks.last() {
+            Some(chunk) => pos >= chunk.start + CELL_INDEX_CHUNK,
+            None => true
+        };
+        if new_chunk {
+            self.chunks.push(LogChunk {start: pos, lines: 0});
+        }
+        let chunk_id = (self.chunks.len() - 1) as u32;
+        self.chunks[chunk_id as usize].lines += 1;
+        add_to_cell(&mut self.cells, cell, chunk_id);
+    }
+
+    /// Chunks holding lines of a cell, if it has any
+    pub fn chunks_of(&self, cell:&str) -> Option<Vec<LogChunk>> {
+        self.cells.get(cell).map(|ids| ids.iter().map(|id| self.chunks[*id as usize]).collect())
+    }
+
+    /// Turn the uncompressed positions into bgzf virtual offsets, once the log is complete
+    pub fn set_virtual_offsets(&mut self, path:&PathBuf) -> std::io::Result<()> {
+        let blocks = bgzf_blocks(path)?;
+        for chunk in &mut self.chunks {
+            chunk.start = virtual_offset(&blocks, chunk.start)?;
+        }
+        Ok(())
+    }
+
+    pub fn store(&self, path:&PathBuf) -> std::io::Result<()> {
+        store_index(self, path)
+    }
+
+    pub fn read(path:&PathBuf) -> std::io::Result<LogIndex> {
+        read_index(path)
+    }
+}
+
+
+/// Note that a cell has entries in a chunk. Chunks are added in order, so only the last one needs checking
+fn add_to_cell(cells:&mut HashMap<String, Vec<u32>>, cell:&str, chunk_id:u32) {
+    match cells.get_mut(cell) {
+        Some(chunk_ids) => {
+            if chunk_ids.last() != Some(&chunk_id) {
+                chunk_ids.push(chunk_id);
+            }
+        },
+        None => {
+            cells.insert(cell.to_string(), vec![chunk_id]);
+        }
+    }
+}
+
+
+fn store_index<T: Serialize>(index:&T, path:&PathBuf) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    bincode::serialize_into(&mut writer, index).map_err(|e| Error::other(e.to_string()))?;
+    writer.flush()
+}
+
+
+fn read_index<T: serde::de::DeserializeOwned>(path:&PathBuf) -> std::io::Result<T> {
+    let reader = BufReader::new(File::open(path)?);
+    bincode::deserialize_from(reader).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cell_index_chunks() {
+        let mut index = CellIndex::default();
+        index.add("A", 0, 0);
+        index.add("B", 100, 120);
+        index.add("A", 200, 220);
+        index.add("A", CELL_INDEX_CHUNK, CELL_INDEX_CHUNK + 50);
+        assert_eq!(index.chunks, vec![
+            IndexChunk {r1: 0, r2: 0, pairs: 3},
+            IndexChunk {r1: CELL_INDEX_CHUNK, r2: CELL_INDEX_CHUNK + 50, pairs: 1}
+        ]);
+        assert_eq!(index.cells["A"], vec![0, 1]);
+        assert_eq!(index.chunks_of("B").unwrap().len(), 1);
+        assert!(index.chunks_of("C").is_none());
+    }
+
+    #[test]
+    fn test_log_index_chunks() {
+        let mut index = LogIndex::default();
+        index.add("A", 0);
+        index.add("*", 40);
+        index.add("A", CELL_INDEX_CHUNK);
+        assert_eq!(index.chunks, vec![
+            LogChunk {start: 0, lines: 2},
+            LogChunk {start: CELL_INDEX_CHUNK, lines: 1}
+        ]);
+        assert_eq!(index.cells["A"], vec![0, 1]);
+        assert_eq!(index.chunks_of("*").unwrap(), vec![LogChunk {start: 0, lines: 2}]);
+    }
+}
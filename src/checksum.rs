@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use sha2::{Digest, Sha256};
+
+
+/// Writer that computes the SHA-256 of everything passing through it, so that large outputs need not be read
+/// again to be checksummed
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Arc<Mutex<Sha256>>
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf:&[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.lock().unwrap().update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// Outputs and the running checksum of each
+type ChecksumList = Arc<Mutex<Vec<(PathBuf, Arc<Mutex<Sha256>>)>>>;
+
+/// SHA-256 checksums of the outputs of a run, stored as a manifest that sha256sum -c can check. Clones share
+/// the same list, so writers on other threads can add to it
+#[derive(Clone, Default)]
+pub struct ChecksumManifest {
+    files: ChecksumList
+}
+
+impl ChecksumManifest {
+
+    /// Checksum an output as it is written. The checksum is complete once the writer is finished
+    pub fn wrap<W: Write>(&self, path:&Path, inner:W) -> HashingWriter<W> {
+        let hasher = Arc::new(Mutex::new(Sha256::new()));
+        self.files.lock().unwrap().push((path.to_path_buf(), hasher.clone()));
+        HashingWriter {inner, hasher}
+    }
+
+    /// Checksum a finished output by reading it; for small files not written through wrap
+    pub fn add_file(&self, path:&PathBuf) -> std::io::Result<()> {
+        if self.files.lock().unwrap().iter().any(|(p, _)| p == path) {
+            return Ok(());
+        }
+        let mut hasher = Sha256::new();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut buf = vec![0u8; 1024*1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        self.files.lock().unwrap().push((path.clone(), Arc::new(Mutex::new(hasher))));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.lock().unwrap().is_empty()
+    }
+
+    /// Directory of the first output, the default place for the manifest
+    pub fn first_dir(&self) -> Option<PathBuf> {
+        self.files.lock().unwrap().first().map(|(p, _)| p.parent().map(|d| d.to_path_buf()).unwrap_or_default())
+    }
+
+    /// Write the manifest. Outputs in the directory of the manifest are listed relative to it
+    pub fn store(&self, path:&PathBuf) -> std::io::Result<()> {
+        let dir = path.parent().map(|d| d.to_path_buf()).unwrap_or_default();
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (file, hasher) in self.files.lock().unwrap().iter() {
+            let digest = hasher.lock().unwrap().clone().finalize();
+            let name = file.strip_prefix(&dir).unwrap_or(file);
+            writeln!(writer, "{:x}  {}", digest, name.display())?;
+        }
+        writer.flush()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let streamed = dir.join("streamed.txt");
+        let finished = dir.join("finished.txt");
+
+        let manifest = ChecksumManifest::default();
+        let mut writer = manifest.wrap(&streamed, File::create(&streamed).unwrap());
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"world").unwrap();
+        drop(writer);
+        std::fs::write(&finished, b"abc").unwrap();
+        manifest.add_file(&finished).unwrap();
+        manifest.add_file(&streamed).unwrap();
+        assert_eq!(manifest.first_dir(), Some(dir.clone()));
+
+        let path_manifest = dir.join("outputs.sha256");
+        manifest.store(&path_manifest).unwrap();
+        assert_eq!(std::fs::read_to_string(&path_manifest).unwrap(), format!("{:x}  streamed.txt\n{:x}  finished.txt\n",
+            Sha256::digest(b"hello world"), Sha256::digest(b"abc")));
+    }
+}
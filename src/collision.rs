@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::error::Error;
+use rand::Rng;
+
+
+/// Frequency of each well in each round, among a set of barcodes in the form A.B.C.D
+pub fn well_frequencies(barcodes:&[&str]) -> Vec<Vec<f64>> {
+    let mut counts: Vec<HashMap<&str,usize>> = Vec::new();
+    for bc in barcodes {
+        for (round, well) in bc.split('.').enumerate() {
+            if counts.len() <= round {
+                counts.push(HashMap::new());
+            }
+            *counts[round].entry(well).or_insert(0) += 1;
+        }
+    }
+    counts.iter().map(|c| {
+        let total: usize = c.values().sum();
+        c.values().map(|n| *n as f64 / total as f64).collect()
+    }).collect()
+}
+
+
+/// Probability that two random cells get the same barcode in all rounds
+pub fn pairwise_collision_probability(well_freqs:&[Vec<f64>]) -> f64 {
+    well_freqs.iter().map(|freqs| freqs.iter().map(|p| p*p).sum::<f64>()).product()
+}
+
+
+/// Expected fraction of cells that share their barcode combination with at least one other cell
+pub fn expected_collision_rate(well_freqs:&[Vec<f64>], num_cells:usize) -> f64 {
+    if num_cells < 2 {
+        return 0.0;
+    }
+    let q = pairwise_collision_probability(well_freqs);
+    1.0 - (1.0 - q).powi(num_cells as i32 - 1)
+}
+
+
+/// Well frequencies when the given number of wells in each round are used equally
+pub fn uniform_well_frequencies(wells:&[usize]) -> Vec<Vec<f64>> {
+    wells.iter().map(|w| vec![1.0 / *w as f64; *w]).collect()
+}
+
+
+/// Fewest wells per round, the same in all rounds, for the expected collision rate to stay at most max_rate.
+/// The rate 1-(1-q)^(n-1) stays at most r while q <= 1-(1-r)^(1/(n-1)), and q = w^-rounds for w wells per round
+pub fn min_wells_per_round(num_rounds:usize, num_cells:usize, max_rate:f64) -> Result<usize, Box<dyn Error>> {
+    if !(max_rate > 0.0 && max_rate < 1.0) {
+        return Err(format!("Collision rate must be between 0 and 1; got {}", max_rate).into());
+    }
+    if num_rounds == 0 {
+        return Err("Need at least one round of barcoding".into());
+    }
+    if num_cells < 2 {
+        return Ok(1);
+    }
+    let max_q = -((-max_rate).ln_1p() / (num_cells - 1) as f64).exp_m1();
+    let mut wells = max_q.powf(-1.0 / num_rounds as f64).ceil().max(1.0) as usize;
+
+    //Rounding may leave the closed form one well off
+    let rate = |w: usize| expected_collision_rate(&uniform_well_frequencies(&vec![w; num_rounds]), num_cells);
+    if wells > 1 && rate(wells - 1) <= max_rate {
+        wells -= 1;
+    } else if rate(wells) > max_rate {
+        wells += 1;
+    }
+    Ok(wells)
+}
+
+
+/// Outcome of giving cells random barcode combinations
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CollisionSimulation {
+    pub cells_in_collisions: f64, //Fraction of cells sharing their combination with another cell
+    pub collided_barcodes: usize  //Combinations given to more than one cell, seen as doublets or worse
+}
+
+/// Give each cell a well in each round, uniformly at random, and see how many cells share a combination
+pub fn simulate_collisions<R: Rng>(wells:&[usize], num_cells:usize, rng:&mut R) -> CollisionSimulation {
+    let mut cells_per_combination: HashMap<u128, usize> = HashMap::new();
+    for _ in 0..num_cells {
+        let combination = wells.iter().fold(0u128, |index, w| index * *w as u128 + rng.gen_range(0..*w) as u128);
+        *cells_per_combination.entry(combination).or_insert(0) += 1;
+    }
+    let collided = cells_per_combination.values().filter(|n| **n > 1);
+    CollisionSimulation {
+        cells_in_collisions: if num_cells > 0 {collided.clone().sum::<usize>() as f64 / num_cells as f64} else {0.0},
+        collided_barcodes: collided.count()
+    }
+}
+
+
+/// Smallest number of mismatches between any two of the barcodes
+pub fn min_pairwise_distance(barcodes:&[&[u8]]) -> Option<usize> {
+    let mut min = None;
+    for (i, a) in barcodes.iter().enumerate() {
+        for b in &barcodes[i+1..] {
+            let d = a.iter().zip(b.iter()).filter(|(x, y)| x != y).count() + a.len().abs_diff(b.len());
+            min = Some(min.map_or(d, |m: usize| m.min(d)));
+        }
+    }
+    min
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expected_collision_rate() {
+        // 2 rounds with 10 equally used wells each: q = 1/100
+        let freqs = vec![vec![0.1; 10]; 2];
+        assert!((pairwise_collision_probability(&freqs) - 0.01).abs() < 1e-12);
+        assert!((expected_collision_rate(&freqs, 2) - 0.01).abs() < 1e-12);
+        assert_eq!(expected_collision_rate(&freqs, 1), 0.0);
+
+        let freqs = well_frequencies(&["A1.B1", "A1.B2", "A2.B1", "A2.B2"]);
+        assert!((pairwise_collision_probability(&freqs) - 0.25).abs() < 1e-12);
+
+        assert_eq!(uniform_well_frequencies(&[10, 10]), vec![vec![0.1; 10]; 2]);
+        assert_eq!(min_wells_per_round(2, 2, 0.02).unwrap(), 8);
+        assert_eq!(min_wells_per_round(4, 1, 0.02).unwrap(), 1);
+        //1-(1-1/96^4)^99999 = 0.118%, and 0.123% for 95 wells
+        assert_eq!(min_wells_per_round(4, 100000, 0.0012).unwrap(), 96);
+        assert!(min_wells_per_round(4, 1000, 0.0).is_err());
+        assert!(min_wells_per_round(4, 1000, 1.0).is_err());
+        assert!(min_wells_per_round(0, 1000, 0.1).is_err());
+    }
+
+    #[test]
+    fn test_simulate_collisions() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        //One combination: all cells collide
+        let sim = simulate_collisions(&[1, 1], 5, &mut rng);
+        assert_eq!(sim, CollisionSimulation {cells_in_collisions: 1.0, collided_barcodes: 1});
+
+        //Close to the expected rate, 1-(1-1/9216)^999 = 10.3%
+        let runs = 20;
+        let mean = (0..runs).map(|_| simulate_collisions(&[96, 96], 1000, &mut rng).cells_in_collisions).sum::<f64>() / runs as f64;
+        assert!((mean - 0.103).abs() < 0.02, "{}", mean);
+
+        assert_eq!(min_pairwise_distance(&[b"AAAA", b"AATT", b"CCCC"]), Some(2));
+        assert_eq!(min_pairwise_distance(&[b"AAAA"]), None);
+    }
+}
@@ -1,67 +1,658 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
 
 use itertools::Itertools;
+use rand::Rng;
 
 
-pub fn store_counttable(
-    path_cnt:&PathBuf,
-    counts:HashMap<String, HashMap<usize,i32>>,
-    name_of_features:Vec<String>
-) -> std::io::Result<()> {
+/// Metadata for one feature (column of features.tsv)
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeatureInfo {
+    pub id: String,
+    pub name: String,
+    pub feature_type: String,
+    pub genome: Option<String>
+}
+
+impl FeatureInfo {
+
+    /// Feature where the name is the same as the ID
+    pub fn new(id: &str, feature_type: &str) -> FeatureInfo {
+        FeatureInfo {
+            id: id.to_string(),
+            name: id.to_string(),
+            feature_type: feature_type.to_string(),
+            genome: None
+        }
+    }
+
+    /// Parse a line of features.tsv. Older single-column files are also accepted
+    fn from_line(line: &str) -> FeatureInfo {
+        let parts = line.split('\t').collect_vec();
+        FeatureInfo {
+            id: parts[0].to_string(),
+            name: parts.get(1).unwrap_or(&parts[0]).to_string(),
+            feature_type: parts.get(2).unwrap_or(&"Unknown").to_string(),
+            genome: parts.get(3).map(|g| g.to_string())
+        }
+    }
+
+    /// Format as a line of features.tsv: id, name, type, and genome if known
+    fn to_line(&self) -> String {
+        match &self.genome {
+            Some(genome) => format!("{}\t{}\t{}\t{}\n", self.id, self.name, self.feature_type, genome),
+            None => format!("{}\t{}\t{}\n", self.id, self.name, self.feature_type)
+        }
+    }
+}
+
+
+/// Count table: counts per cell and feature. Cells are named by barcode, features are indices into the feature list
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CountMatrix {
+    pub counts: HashMap<String, HashMap<usize,i32>>,
+    pub features: Vec<FeatureInfo>
+}
+
+impl CountMatrix {
+
+    /// Empty count table with the given features
+    pub fn new(features: Vec<FeatureInfo>) -> CountMatrix {
+        CountMatrix {
+            counts: HashMap::new(),
+            features
+        }
+    }
+
+    /// Add n counts for a cell and feature. Only allocates a key the first time a cell is seen
+    pub fn add(&mut self, cell: &str, feature: usize, n: i32) {
+        let cellmap = match self.counts.get_mut(cell) {
+            Some(cellmap) => cellmap,
+            None => self.counts.entry(cell.to_string()).or_default()
+        };
+        *cellmap.entry(feature).or_insert(0) += n;
+    }
+
+    pub fn num_cells(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Total counts over all cells and features
+    pub fn total(&self) -> i64 {
+        self.counts.values().flat_map(|c| c.values()).map(|&c| c as i64).sum()
+    }
+
+    /// Keep only cells for which the predicate, given the cell name and its counts, is true
+    pub fn filter_cells<F: Fn(&str, &HashMap<usize,i32>) -> bool>(&mut self, keep: F) {
+        self.counts.retain(|cell, cellmap| keep(cell, cellmap));
+    }
+
+    /// Add the counts of another table. Features are matched by ID; features only present in the other
+    /// table are added. Cells with the same name are summed unless a prefix is given for the other table
+    pub fn merge(&mut self, other: CountMatrix, prefix: Option<&str>) {
+        let mut feature_index: HashMap<String, usize> = self.features.iter().enumerate().map(|(i,f)| (f.id.clone(), i)).collect();
+        let feature_map = other.features.iter().map(|f| {
+            *feature_index.entry(f.id.clone()).or_insert_with(|| {
+                self.features.push(f.clone());
+                self.features.len() - 1
+            })
+        }).collect_vec();
+
+        for (cell, cellmap) in other.counts {
+            let cell = match prefix {
+                Some(prefix) => format!("{}{}", prefix, cell),
+                None => cell
+            };
+            let merged_cellmap = self.counts.entry(cell).or_default();
+            for (featureid, cnt) in cellmap {
+                *merged_cellmap.entry(feature_map[featureid]).or_insert(0) += cnt;
+            }
+        }
+    }
+
+    /// Ambient profile: counts per feature summed over cells with at most max_total counts,
+    /// which are taken to be empty droplets. Also returns the number of such cells
+    pub fn background_profile(&self, max_total: i64) -> (usize, Vec<i64>) {
+        let mut profile = vec![0; self.features.len()];
+        let mut num_empty = 0;
+        for cellmap in self.counts.values() {
+            let total: i64 = cellmap.values().map(|&c| c as i64).sum();
+            if total <= max_total {
+                num_empty += 1;
+                for (featureid, cnt) in cellmap {
+                    profile[*featureid] += *cnt as i64;
+                }
+            }
+        }
+        (num_empty, profile)
+    }
+
+    /// Group features according to a feature map, summing their counts. Features not in the map are kept as they are
+    pub fn group_features(&mut self, map: &HashMap<String,String>) {
+        let (grouped, index_map) = group_features(&self.features, map);
+        self.remap_features(&index_map);
+        self.features = grouped;
+    }
+
+    /// Remove the features that are not to be kept, e.g. genes of unwanted biotypes. Returns the counts
+    /// removed per cell, for cells that had any
+    pub fn remove_features(&mut self, keep: &[bool]) -> HashMap<String,i64> {
+        let mut new_index = vec![None; keep.len()];
+        let mut kept = Vec::new();
+        for (i, f) in self.features.drain(..).enumerate() {
+            if keep[i] {
+                new_index[i] = Some(kept.len());
+                kept.push(f);
+            }
+        }
+        self.features = kept;
+
+        let mut removed: HashMap<String,i64> = HashMap::new();
+        for (cell, cellmap) in self.counts.iter_mut() {
+            let mut cell_removed = 0;
+            *cellmap = cellmap.drain().filter_map(|(featureid, cnt)| match new_index[featureid] {
+                Some(i) => Some((i, cnt)),
+                None => {
+                    cell_removed += cnt as i64;
+                    None
+                }
+            }).collect();
+            if cell_removed > 0 {
+                removed.insert(cell.clone(), cell_removed);
+            }
+        }
+        removed
+    }
+
+    /// Move some features into a table of their own, e.g. spike-ins, leaving the others in this table
+    pub fn split_features(&mut self, take: &[bool]) -> CountMatrix {
+        let mut taken = CountMatrix::new(self.features.iter().zip(take).filter(|(_, t)| **t).map(|(f, _)| f.clone()).collect());
+        let mut new_index = vec![0; take.len()];
+        let mut num_taken = 0;
+        for (i, t) in take.iter().enumerate() {
+            if *t {
+                new_index[i] = num_taken;
+                num_taken += 1;
+            }
+        }
+        for (cell, cellmap) in &self.counts {
+            for (featureid, cnt) in cellmap {
+                if take[*featureid] {
+                    taken.add(cell, new_index[*featureid], *cnt);
+                }
+            }
+        }
+        self.remove_features(&take.iter().map(|t| !t).collect_vec());
+        taken
+    }
+
+    /// Rarefy each cell to the given number of counts, sampled without replacement. Cells with fewer counts are
+    /// removed; returns how many. Cells and features are visited in sorted order, so that a seeded generator
+    /// gives the same result every time
+    pub fn downsample<R: Rng>(&mut self, per_cell: usize, rng: &mut R) -> usize {
+        let num_before = self.counts.len();
+        self.counts.retain(|_, cellmap| cellmap.values().map(|&c| c.max(0) as usize).sum::<usize>() >= per_cell);
+        for cell in self.counts.keys().cloned().sorted().collect_vec() {
+            let cellmap = self.counts.get_mut(&cell).unwrap();
+            let features = cellmap.drain().filter(|(_, cnt)| *cnt > 0).sorted().collect_vec();
+            let total = features.iter().map(|(_, cnt)| *cnt as usize).sum::<usize>();
+
+            //Pick counts by position, then find the feature each position falls in
+            let mut picked = rand::seq::index::sample(rng, total, per_cell).into_vec();
+            picked.sort_unstable();
+            let mut picked = picked.into_iter().peekable();
+            let mut end = 0;
+            for (featureid, cnt) in features {
+                end += cnt as usize;
+                let mut n = 0;
+                while picked.next_if(|&pos| pos < end).is_some() {
+                    n += 1;
+                }
+                if n > 0 {
+                    cellmap.insert(featureid, n);
+                }
+            }
+        }
+        num_before - self.counts.len()
+    }
+
+    /// Move counts to new feature indices, summing features that end up with the same index
+    pub fn remap_features(&mut self, index_map: &[usize]) {
+        for cellmap in self.counts.values_mut() {
+            let mut new_cellmap: HashMap<usize,i32> = HashMap::new();
+            for (featureid, cnt) in cellmap.drain() {
+                *new_cellmap.entry(index_map[featureid]).or_insert(0) += cnt;
+            }
+            *cellmap = new_cellmap;
+        }
+    }
+
+
+    /// Store as a directory with matrix.mtx, barcodes.tsv and features.tsv
+    pub fn store(&self, path_cnt: &Path) -> std::io::Result<()> {
+
+        //Create a folder for the counts
+        if !path_cnt.exists() {
+            fs::create_dir_all(path_cnt)?;
+        }
+
+        //Figure out name of output files
+        let path_count_file =  path_cnt.join("matrix.mtx");
+        let path_features_file =  path_cnt.join("features.tsv");
+        let path_bc_file =  path_cnt.join("barcodes.tsv");
+
+
+        //Figure size of matrix
+        let list_cell = self.counts.keys().collect_vec();
+
+
+        //%%MatrixMarket matrix coordinate integer general
+        //89083 974 6075361
+
+        ////// Write count table
+        let mut writer_h = BufWriter::new(File::create(path_count_file)?);
+        //writer_h.write_all("%%MatrixMarket matrix coordinate real general\n".as_bytes()).expect("Unable to write data");
+        writer_h.write_all("cell\tfeature\tcount\n".as_bytes())?;
+
+        for (cellid, cell) in list_cell.iter().enumerate() {
+
+            let cellmap = self.counts.get(*cell).unwrap();
+            for (bc,cnt) in cellmap.iter() {
+                let line = format!["{}\t{}\t{}\n", cellid+1, bc+1, cnt];
+                writer_h.write_all(line.as_bytes())?;
+            }
+        }
+
+        ////// Write table with BC names
+        let mut writer_cells = BufWriter::new(File::create(path_bc_file)?);
+        for cell in &list_cell {
+            let line = format!["{}\n", cell];
+            writer_cells.write_all(line.as_bytes())?;
+        }
+
+
+        ////// Write table with feature names
+        let mut writer_features = BufWriter::new(File::create(path_features_file)?);
+        for feature in &self.features {
+            let line = feature.to_line();
+            writer_features.write_all(line.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+
+    /// Read a count table written by store
+    pub fn read(path_cnt: &Path) -> std::io::Result<CountMatrix> {
+
+        let features = read_lines(&path_cnt.join("features.tsv"))?.iter().map(|line| FeatureInfo::from_line(line)).collect_vec();
+        let list_cell = read_lines(&path_cnt.join("barcodes.tsv"))?.iter().map(|line| line.split('\t').next().unwrap_or("").to_string()).collect_vec();
 
+        let mut matrix = CountMatrix::new(features);
+        let reader = BufReader::new(File::open(path_cnt.join("matrix.mtx"))?);
+        for line in reader.lines().skip(1) {
+            let line = line?;
+            let parts = line.split('\t').collect_vec();
+            if parts.len() != 3 {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Malformed count line: {}", line)));
+            }
+            let cellid = parse_index(parts[0], list_cell.len())?;
+            let featureid = parse_index(parts[1], matrix.features.len())?;
+            let cnt = parts[2].parse::<i32>().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
 
-    //Create a folder for the counts
-    if !path_cnt.exists() {
-        fs::create_dir(path_cnt)?;
+            matrix.add(&list_cell[cellid], featureid, cnt);
+        }
+
+        Ok(matrix)
+    }
+
+
+    /// Read and merge several count tables; see merge
+    pub fn read_merged(paths_cnt: &[PathBuf], prefixes: &[String]) -> std::io::Result<CountMatrix> {
+        let mut merged = CountMatrix::default();
+        for (i, path_cnt) in paths_cnt.iter().enumerate() {
+            merged.merge(CountMatrix::read(path_cnt)?, prefixes.get(i).map(|p| p.as_str()));
+        }
+        Ok(merged)
+    }
+
+
+    /// Write in long format (cell, feature, count), skipping entries below min_count
+    pub fn store_long_tsv(&self, path_out: &PathBuf, min_count: i32) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path_out)?);
+        writer.write_all("cell\tfeature\tcount\n".as_bytes())?;
+        for (cell, cellmap) in self.counts.iter().sorted_by_key(|(cell,_)| *cell) {
+            for (featureid, cnt) in cellmap.iter().sorted_by_key(|(featureid,_)| **featureid) {
+                if *cnt >= min_count {
+                    let line = format!["{}\t{}\t{}\n", cell, self.features[*featureid].id, cnt];
+                    writer.write_all(line.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
     }
 
-    //Figure out name of output files
-    let path_count_file =  path_cnt.join("matrix.mtx");
-    let path_features_file =  path_cnt.join("features.tsv");
-    let path_bc_file =  path_cnt.join("barcodes.tsv");
-    
 
-    //Figure size of matrix
-    //let num_feature = name_of_features.len();
-    let num_cell = counts.len();
-    let list_cell = counts.keys().map(|x| x).collect_vec();
+    /// Total counts of each cell
+    pub fn cell_totals(&self) -> Vec<(String,i64)> {
+        self.counts.iter().map(|(cell, cellmap)| (cell.clone(), cellmap.values().map(|&c| c as i64).sum())).collect()
+    }
+
+
+    /// The k features with the most counts in a cell; ties are broken by feature order
+    pub fn top_features(&self, cell: &str, k: usize) -> Vec<(usize,i32)> {
+        match self.counts.get(cell) {
+            Some(cellmap) => cellmap.iter().map(|(f, c)| (*f, *c)).sorted_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0))).take(k).collect(),
+            None => Vec::new()
+        }
+    }
+
+
+    /// Write the top k features of the given cells, one line per cell: cell, total count, then name and count of
+    /// each feature, most counted first. Meant for quick checks of targeted panels
+    pub fn store_top_features(&self, path_out: &PathBuf, cells: &[String], k: usize) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path_out)?);
+        writeln!(writer, "cell\ttotal\t{}", (1..=k).map(|i| format!("feature_{}\tcount_{}", i, i)).join("\t"))?;
+        for cell in cells {
+            let total: i64 = self.counts.get(cell).map_or(0, |cellmap| cellmap.values().map(|&c| c as i64).sum());
+            let top = self.top_features(cell, k);
+            write!(writer, "{}\t{}", cell, total)?;
+            for i in 0..k {
+                match top.get(i) {
+                    Some((featureid, cnt)) => write!(writer, "\t{}\t{}", self.features[*featureid].name, cnt)?,
+                    None => write!(writer, "\t\t")?
+                }
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
 
 
-    //%%MatrixMarket matrix coordinate integer general
-    //89083 974 6075361
-    
-    ////// Write count table
-    let output_h = File::create(path_count_file).expect("creation of R1 failed");
-    let mut writer_h = BufWriter::new(output_h);
-    //writer_h.write_all("%%MatrixMarket matrix coordinate real general\n".as_bytes()).expect("Unable to write data");
-    writer_h.write_all("cell\tfeature\tcount\n".as_bytes()).expect("Unable to write data");
+    /// Write the length of each feature (id, name, length); NA where the length is not known
+    pub fn store_feature_lengths(&self, path_out: &PathBuf, lengths: &[Option<usize>]) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path_out)?);
+        writer.write_all("feature\tname\tlength\n".as_bytes())?;
+        for (f, len) in self.features.iter().zip(lengths) {
+            let len = len.map_or("NA".to_string(), |len| len.to_string());
+            writer.write_all(format!("{}\t{}\t{}\n", f.id, f.name, len).as_bytes())?;
+        }
+        Ok(())
+    }
 
-    for cellid in 0..num_cell {
 
-        let cellmap = counts.get(list_cell[cellid]).unwrap();
-        for (bc,cnt) in cellmap.iter() {
-            let line = format!["{}\t{}\t{}\n", cellid+1, bc+1, cnt];
-            writer_h.write_all(line.as_bytes()).expect("Unable to write data");
+    /// Write normalized counts in long format (cell, feature, count, cpm, tpm). Meant for quick QC of small panels
+    pub fn store_normalized(&self, path_out: &PathBuf, lengths: &[Option<usize>]) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path_out)?);
+        writer.write_all("cell\tfeature\tcount\tcpm\ttpm\n".as_bytes())?;
+        for (cell, cellmap) in self.counts.iter().sorted_by_key(|(cell,_)| *cell) {
+            for (featureid, cnt, cpm, tpm) in normalize_cell(cellmap, lengths) {
+                let tpm = tpm.map_or("NA".to_string(), |tpm| format!("{:.3}", tpm));
+                let line = format!["{}\t{}\t{}\t{:.3}\t{}\n", cell, self.features[featureid].id, cnt, cpm, tpm];
+                writer.write_all(line.as_bytes())?;
+            }
         }
+        Ok(())
     }
 
-    ////// Write table with BC names
-    let mut writer_cells = BufWriter::new(File::create(path_bc_file).expect("creation of cell table failed"));
-    for cellid in 0..num_cell {
-        let line = format!["{}\n", list_cell[cellid]];
-        writer_cells.write_all(line.as_bytes()).expect("Unable to write data");
+
+    /// Write in long format (cell, feature, count) as Apache Parquet, skipping entries below min_count
+    #[cfg(feature = "parquet")]
+    pub fn store_long_parquet(&self, path_out: &PathBuf, min_count: i32) -> std::io::Result<()> {
+        use std::sync::Arc;
+        use parquet::data_type::{ByteArray, ByteArrayType, Int32Type};
+        use parquet::file::properties::WriterProperties;
+        use parquet::file::writer::SerializedFileWriter;
+        use parquet::schema::parser::parse_message_type;
+
+        fn to_io_error(e: parquet::errors::ParquetError) -> Error {
+            Error::other(e)
+        }
+
+        //Collect the columns
+        let mut col_cell: Vec<ByteArray> = Vec::new();
+        let mut col_feature: Vec<ByteArray> = Vec::new();
+        let mut col_count: Vec<i32> = Vec::new();
+        for (cell, cellmap) in self.counts.iter().sorted_by_key(|(cell,_)| *cell) {
+            for (featureid, cnt) in cellmap.iter().sorted_by_key(|(featureid,_)| **featureid) {
+                if *cnt >= min_count {
+                    col_cell.push(ByteArray::from(cell.as_str()));
+                    col_feature.push(ByteArray::from(self.features[*featureid].id.as_str()));
+                    col_count.push(*cnt);
+                }
+            }
+        }
+
+        let schema = Arc::new(parse_message_type("
+            message counts {
+                REQUIRED BYTE_ARRAY cell (UTF8);
+                REQUIRED BYTE_ARRAY feature (UTF8);
+                REQUIRED INT32 count;
+            }
+        ").map_err(to_io_error)?);
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut writer = SerializedFileWriter::new(File::create(path_out)?, schema, props).map_err(to_io_error)?;
+
+        let mut row_group_writer = writer.next_row_group().map_err(to_io_error)?;
+        for col in 0..3 {
+            let mut col_writer = row_group_writer.next_column().map_err(to_io_error)?.expect("Missing column");
+            match col {
+                0 => { col_writer.typed::<ByteArrayType>().write_batch(&col_cell, None, None).map_err(to_io_error)?; },
+                1 => { col_writer.typed::<ByteArrayType>().write_batch(&col_feature, None, None).map_err(to_io_error)?; },
+                _ => { col_writer.typed::<Int32Type>().write_batch(&col_count, None, None).map_err(to_io_error)?; }
+            }
+            col_writer.close().map_err(to_io_error)?;
+        }
+        row_group_writer.close().map_err(to_io_error)?;
+        writer.close().map_err(to_io_error)?;
+
+        Ok(())
     }
+}
+
 
 
-    ////// Write table with feature names
-    let mut writer_cells = BufWriter::new(File::create(path_features_file).expect("creation of feature table failed"));
-    for feature in name_of_features {
-        let line = format!["{}\n", feature];
-        writer_cells.write_all(line.as_bytes()).expect("Unable to write data");
+/// Read a feature map: a TSV with a feature ID and the group it belongs to on each line
+pub fn read_feature_map(path:&PathBuf) -> std::io::Result<HashMap<String,String>> {
+    let mut map = HashMap::new();
+    for line in read_lines(path)? {
+        if line.is_empty() {
+            continue;
+        }
+        let (id, group) = line.split_once('\t').ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Malformed feature map line: {}", line)))?;
+        map.insert(id.to_string(), group.to_string());
     }
+    Ok(map)
+}
 
-    Ok(())
+
+/// Read the number of molecules of each spike-in added per cell: spike-in ID and molecules, tab separated.
+/// The first line may be a header
+pub fn read_spike_in_molecules(path:&PathBuf) -> std::io::Result<HashMap<String,f64>> {
+    let mut molecules = HashMap::new();
+    for (i, line) in read_lines(path)?.into_iter().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let malformed = || Error::new(ErrorKind::InvalidData, format!("Malformed spike-in line: {}", line));
+        let (id, n) = line.split_once('\t').ok_or_else(malformed)?;
+        match n.trim().parse::<f64>() {
+            Ok(n) => {
+                molecules.insert(id.to_string(), n);
+            },
+            Err(_) if i == 0 => {},
+            Err(_) => return Err(malformed())
+        }
+    }
+    Ok(molecules)
+}
+
+
+/// Group features according to a feature map. Features not in the map are kept as they are.
+/// Returns the new list of features, and the new index of each old feature
+pub fn group_features(
+    features:&[FeatureInfo],
+    map:&HashMap<String,String>
+) -> (Vec<FeatureInfo>, Vec<usize>) {
+    let mut grouped: Vec<FeatureInfo> = Vec::new();
+    let mut group_index: HashMap<String, usize> = HashMap::new();
+    let index_map = features.iter().map(|f| {
+        let group = map.get(&f.id).unwrap_or(&f.id);
+        *group_index.entry(group.clone()).or_insert_with(|| {
+            let mut g = f.clone();
+            if group != &f.id {
+                g.id = group.clone();
+                g.name = group.clone();
+            }
+            grouped.push(g);
+            grouped.len() - 1
+        })
+    }).collect_vec();
+    (grouped, index_map)
+}
+
+
+/// Lengths of features after grouping: the sum over the features in each group, unknown if any of them is
+pub fn group_lengths(
+    features:&[FeatureInfo],
+    lengths:&[Option<usize>],
+    map:&HashMap<String,String>
+) -> Vec<Option<usize>> {
+    let (grouped, index_map) = group_features(features, map);
+    let mut grouped_lengths = vec![Some(0); grouped.len()];
+    for (i, len) in index_map.iter().zip(lengths) {
+        grouped_lengths[*i] = grouped_lengths[*i].zip(*len).map(|(a, b)| a + b);
+    }
+    grouped_lengths
+}
+
+
+/// CPM and TPM of each feature of a cell, sorted by feature. CPM is relative to all counts of the cell. For TPM, counts
+/// are first divided by feature length, and only features of known length take part
+fn normalize_cell(cellmap:&HashMap<usize,i32>, lengths:&[Option<usize>]) -> Vec<(usize, i32, f64, Option<f64>)> {
+    let total: i64 = cellmap.values().map(|&c| c as i64).sum();
+    let rate = |featureid: usize, cnt: i32| lengths[featureid].filter(|&len| len > 0).map(|len| cnt as f64 / len as f64);
+    let total_rate: f64 = cellmap.iter().filter_map(|(featureid, cnt)| rate(*featureid, *cnt)).sum();
+    cellmap.iter().sorted_by_key(|(featureid,_)| **featureid).map(|(featureid, cnt)| {
+        let cpm = *cnt as f64 * 1e6 / total as f64;
+        let tpm = rate(*featureid, *cnt).map(|r| r * 1e6 / total_rate);
+        (*featureid, *cnt, cpm, tpm)
+    }).collect()
+}
+
+
+/// Read all lines of a file
+fn read_lines(path:&PathBuf) -> std::io::Result<Vec<String>> {
+    let reader = BufReader::new(File::open(path)?);
+    reader.lines().collect()
+}
+
+
+/// Parse a 1-based index into a 0-based one, checking the range
+fn parse_index(s:&str, len:usize) -> std::io::Result<usize> {
+    let i = s.parse::<usize>().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    if i==0 || i>len {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Index out of range: {}", i)));
+    }
+    Ok(i-1)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_matrix() {
+        let mut a = CountMatrix::new(vec![FeatureInfo::new("g1", "Gene Expression"), FeatureInfo::new("g2", "Gene Expression")]);
+        a.add("c1", 0, 2);
+        a.add("c1", 0, 1);
+        a.add("c2", 1, 5);
+        let mut b = CountMatrix::new(vec![FeatureInfo::new("g2", "Gene Expression"), FeatureInfo::new("g3", "Gene Expression")]);
+        b.add("c1", 0, 1);
+        b.add("c1", 1, 4);
+        a.merge(b, None);
+        assert_eq!(a.features.len(), 3);
+        assert_eq!(a.counts["c1"], HashMap::from([(0, 3), (1, 1), (2, 4)]));
+        assert_eq!(a.total(), 13);
+
+        let map = HashMap::from([("g2".to_string(), "G".to_string()), ("g3".to_string(), "G".to_string())]);
+        a.group_features(&map);
+        assert_eq!(a.features.iter().map(|f| f.id.as_str()).collect_vec(), vec!["g1", "G"]);
+        assert_eq!(a.counts["c1"], HashMap::from([(0, 3), (1, 5)]));
+
+        let (num_empty, profile) = a.background_profile(5);
+        assert_eq!(num_empty, 1);
+        assert_eq!(profile, vec![0, 5]);
+
+        a.filter_cells(|_, cellmap| cellmap.values().sum::<i32>() > 5);
+        assert_eq!(a.num_cells(), 1);
+
+        let removed = a.remove_features(&[false, true]);
+        assert_eq!(a.features.iter().map(|f| f.id.as_str()).collect_vec(), vec!["G"]);
+        assert_eq!(a.counts["c1"], HashMap::from([(0, 5)]));
+        assert_eq!(removed, HashMap::from([("c1".to_string(), 3)]));
+    }
+
+    #[test]
+    fn test_split_features() {
+        let mut m = CountMatrix::new(vec![FeatureInfo::new("g1", "Gene Expression"), FeatureInfo::new("ERCC-1", "Gene Expression"), FeatureInfo::new("g2", "Gene Expression")]);
+        m.add("c1", 0, 2);
+        m.add("c1", 1, 3);
+        m.add("c2", 2, 4);
+        let spike_in = m.split_features(&[false, true, false]);
+        assert_eq!(spike_in.features.iter().map(|f| f.id.as_str()).collect_vec(), vec!["ERCC-1"]);
+        assert_eq!(spike_in.counts["c1"], HashMap::from([(0, 3)]));
+        assert!(!spike_in.counts.contains_key("c2"));
+        assert_eq!(m.features.iter().map(|f| f.id.as_str()).collect_vec(), vec!["g1", "g2"]);
+        assert_eq!(m.counts["c2"], HashMap::from([(1, 4)]));
+    }
+
+    #[test]
+    fn test_downsample() {
+        use rand::SeedableRng;
+        let mut m = CountMatrix::new(vec![FeatureInfo::new("g1", "Gene Expression"), FeatureInfo::new("g2", "Gene Expression")]);
+        m.add("c1", 0, 30);
+        m.add("c1", 1, 70);
+        m.add("c2", 0, 5);
+        m.add("c3", 1, 10);
+        let mut a = m.clone();
+        assert_eq!(a.downsample(10, &mut rand::rngs::StdRng::seed_from_u64(1)), 1);
+        assert_eq!(a.num_cells(), 2);
+        assert_eq!(a.counts["c1"].values().sum::<i32>(), 10);
+        assert_eq!(a.counts["c3"], HashMap::from([(1, 10)]));
+
+        let mut b = m.clone();
+        b.downsample(10, &mut rand::rngs::StdRng::seed_from_u64(1));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn test_top_features() {
+        let mut m = CountMatrix::new(vec![FeatureInfo::new("g1", "Gene Expression"), FeatureInfo::new("g2", "Gene Expression"), FeatureInfo::new("g3", "Gene Expression")]);
+        m.add("c1", 0, 2);
+        m.add("c1", 1, 5);
+        m.add("c1", 2, 2);
+        assert_eq!(m.top_features("c1", 2), vec![(1, 5), (0, 2)]);
+        assert!(m.top_features("c2", 2).is_empty());
+        assert_eq!(m.cell_totals(), vec![("c1".to_string(), 9)]);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let cellmap = HashMap::from([(0, 10), (1, 30), (2, 60)]);
+        let lengths = vec![Some(1000), Some(3000), None];
+        let normalized = normalize_cell(&cellmap, &lengths);
+        assert_eq!(normalized.iter().map(|n| n.2).collect_vec(), vec![1e5, 3e5, 6e5]);
+        assert!((normalized[0].3.unwrap() - 5e5).abs() < 1e-6);
+        assert!((normalized[1].3.unwrap() - 5e5).abs() < 1e-6);
+        assert_eq!(normalized[2].3, None);
+
+        let features = vec![FeatureInfo::new("a1", "Gene Expression"), FeatureInfo::new("a2", "Gene Expression"), FeatureInfo::new("*", "Reference")];
+        let map = HashMap::from([("a1".to_string(), "A".to_string()), ("a2".to_string(), "A".to_string())]);
+        assert_eq!(group_lengths(&features, &lengths, &map), vec![Some(4000), None]);
+    }
 }
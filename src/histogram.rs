@@ -0,0 +1,387 @@
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use gzp::{deflate::Gzip, par::compress::{ParCompress, ParCompressBuilder}, ZWriter};
+
+
+/// Writer for auxiliary tables such as histograms and per-read logs.
+/// Output is gzip compressed if the file name ends in .gz
+pub enum TableWriter {
+    Plain(BufWriter<Box<dyn Write + Send>>),
+    Gzip(ParCompress<Gzip>),
+}
+impl TableWriter {
+    pub fn create(path:&Path) -> std::io::Result<TableWriter> {
+        Ok(TableWriter::from_writer(path, Box::new(File::create(path)?)))
+    }
+
+    /// Whether a table written to this path is gzip compressed
+    pub fn is_gzip(path:&Path) -> bool {
+        path.extension().is_some_and(|ext| ext == "gz")
+    }
+
+    /// Write a table to an output already opened at this path, compressed with the default settings if gzip
+    pub fn from_writer(path:&Path, output:Box<dyn Write + Send>) -> TableWriter {
+        if TableWriter::is_gzip(path) {
+            TableWriter::Gzip(ParCompressBuilder::new().from_writer(output))
+        } else {
+            TableWriter::Plain(BufWriter::new(output))
+        }
+    }
+
+    /// Flush the output. Must be called for gzip output to be complete
+    pub fn finish(self) -> std::io::Result<()> {
+        match self {
+            TableWriter::Plain(mut w) => w.flush(),
+            TableWriter::Gzip(mut w) => w.finish().map_err(|e| Error::other(e.to_string())),
+        }
+    }
+}
+impl Write for TableWriter {
+    fn write(&mut self, buf:&[u8]) -> std::io::Result<usize> {
+        match self {
+            TableWriter::Plain(w) => w.write(buf),
+            TableWriter::Gzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TableWriter::Plain(w) => w.flush(),
+            TableWriter::Gzip(w) => w.flush(),
+        }
+    }
+}
+
+
+/// Count-min sketch: approximate counts in fixed memory. Estimates are never too low, but may be too high
+/// when barcodes collide in all rows
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    table: Vec<u32>
+}
+
+impl CountMinSketch {
+
+    pub fn new(width: usize, depth: usize) -> CountMinSketch {
+        CountMinSketch {
+            width,
+            depth,
+            table: vec![0; width*depth]
+        }
+    }
+
+    fn cell(&self, key: &[u8], row: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        row.hash(&mut hasher);
+        key.hash(&mut hasher);
+        row*self.width + (hasher.finish() as usize % self.width)
+    }
+
+    /// Add one count for a key, and return the new estimate
+    pub fn add(&mut self, key: &[u8]) -> u32 {
+        let mut estimate = u32::MAX;
+        for row in 0..self.depth {
+            let cell = self.cell(key, row);
+            self.table[cell] = self.table[cell].saturating_add(1);
+            estimate = estimate.min(self.table[cell]);
+        }
+        estimate
+    }
+
+    pub fn estimate(&self, key: &[u8]) -> u32 {
+        (0..self.depth).map(|row| self.table[self.cell(key, row)]).min().unwrap_or(0)
+    }
+
+    /// Memory used by the counters
+    pub fn num_bytes(&self) -> usize {
+        self.table.len() * std::mem::size_of::<u32>()
+    }
+}
+
+
+/// Bloom filter: remembers which keys were seen in fixed memory. May wrongly claim a key was seen, at a rate
+/// that grows as the filter fills up, but never misses one
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_hashes: usize,
+    num_keys: u64 //Keys added that were not already there
+}
+
+impl BloomFilter {
+
+    pub fn new(num_bits: usize, num_hashes: usize) -> BloomFilter {
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64).max(1)],
+            num_hashes,
+            num_keys: 0
+        }
+    }
+
+    /// Number of hashes giving the fewest false positives for a filter of num_bits holding num_keys keys
+    pub fn optimal_num_hashes(num_bits: usize, num_keys: usize) -> usize {
+        (num_bits as f64 / num_keys.max(1) as f64 * std::f64::consts::LN_2).round().clamp(1.0, 16.0) as usize
+    }
+
+    /// Expected rate of keys wrongly claimed to be there, once the filter holds num_keys keys
+    pub fn false_positive_rate(&self, num_keys: u64) -> f64 {
+        let num_bits = (self.bits.len() * 64) as f64;
+        (1.0 - (-(self.num_hashes as f64) * num_keys as f64 / num_bits).exp()).powi(self.num_hashes as i32)
+    }
+
+    /// Add a key. Returns true if it (probably) was already there
+    pub fn insert(&mut self, key: &[u8]) -> bool {
+        //Two hashes combined give the k positions (Kirsch-Mitzenmacher)
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let h1 = hasher.finish();
+        0u8.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+
+        let num_bits = self.bits.len() as u64 * 64;
+        let mut seen = true;
+        for i in 0..self.num_hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+            let (word, mask) = ((bit / 64) as usize, 1u64 << (bit % 64));
+            seen &= self.bits[word] & mask != 0;
+            self.bits[word] |= mask;
+        }
+        if !seen {
+            self.num_keys += 1;
+        }
+        seen
+    }
+
+    /// Number of keys added so far
+    pub fn num_keys(&self) -> u64 {
+        self.num_keys
+    }
+
+    /// Memory used by the bits
+    pub fn num_bytes(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u64>()
+    }
+}
+
+
+/// Bits per key of a Bloom filter sized for the keys expected, giving about 0.05% false positives
+const SEEN_KEYS_BITS_PER_KEY: usize = 16;
+
+/// Keys seen so far, e.g. to find duplicate reads: exactly in a set, or once memory runs low, approximately in a
+/// Bloom filter. The filter may then wrongly report new keys as seen
+pub enum SeenKeys {
+    Exact(HashSet<u64>),
+    Approximate(BloomFilter)
+}
+
+impl Default for SeenKeys {
+    fn default() -> SeenKeys {
+        SeenKeys::Exact(HashSet::new())
+    }
+}
+
+impl SeenKeys {
+
+    /// Add a key. Returns true if it (probably) was already there
+    pub fn insert(&mut self, key: u64) -> bool {
+        match self {
+            SeenKeys::Exact(set) => !set.insert(key),
+            SeenKeys::Approximate(filter) => filter.insert(&key.to_le_bytes())
+        }
+    }
+
+    /// Memory used, counting a byte of overhead per slot of the set
+    pub fn num_bytes(&self) -> usize {
+        match self {
+            SeenKeys::Exact(set) => set.capacity() * (std::mem::size_of::<u64>() + 1),
+            SeenKeys::Approximate(filter) => filter.num_bytes()
+        }
+    }
+
+    /// Expected rate of new keys wrongly reported as seen, at the keys held now
+    pub fn false_positive_rate(&self) -> f64 {
+        match self {
+            SeenKeys::Exact(_) => 0.0,
+            SeenKeys::Approximate(filter) => filter.false_positive_rate(filter.num_keys())
+        }
+    }
+
+    /// Move the keys into a Bloom filter of at most max_bytes, sized for expected_keys keys in all if known.
+    /// Returns the false positive rate expected once that many keys are in; without an expectation, at the keys
+    /// held now
+    pub fn make_approximate(&mut self, expected_keys: Option<usize>, max_bytes: usize) -> f64 {
+        if let SeenKeys::Exact(set) = self {
+            let num_keys = expected_keys.unwrap_or(0).max(set.len());
+            let max_bits = max_bytes.saturating_mul(8).max(64);
+            let num_bits = match expected_keys {
+                Some(_) => max_bits.min(num_keys.saturating_mul(SEEN_KEYS_BITS_PER_KEY)),
+                None => max_bits
+            };
+            let mut filter = BloomFilter::new(num_bits, BloomFilter::optimal_num_hashes(num_bits, num_keys));
+            for key in set.drain() {
+                filter.insert(&key.to_le_bytes());
+            }
+            *self = SeenKeys::Approximate(filter);
+        }
+        match (self, expected_keys) {
+            (SeenKeys::Approximate(filter), Some(expected_keys)) => filter.false_positive_rate(expected_keys.max(filter.num_keys() as usize) as u64),
+            (seen, _) => seen.false_positive_rate()
+        }
+    }
+}
+
+
+/// Read a barcode histogram (barcode, count) as written by ToFastq. Gzip compressed files are also accepted
+pub fn read_histogram(path:&Path) -> std::io::Result<Vec<(String,i64)>> {
+    let (reader, _) = niffler::get_reader(Box::new(File::open(path)?))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    let reader = BufReader::new(reader);
+    let mut hist = Vec::new();
+    for line in reader.lines().skip(1) {
+        let line = line?;
+        let (bc, cnt) = line.split_once('\t').ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Malformed histogram line: {}", line)))?;
+        let cnt = cnt.parse::<i64>().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        hist.push((bc.to_string(), cnt));
+    }
+    Ok(hist)
+}
+
+
+/// Sum several barcode histograms, e.g. from different lanes. The result is sorted by decreasing count
+pub fn merge_histograms(paths:&[PathBuf]) -> std::io::Result<Vec<(String,i64)>> {
+    let mut merged: HashMap<String,i64> = HashMap::new();
+    for path in paths {
+        for (bc, cnt) in read_histogram(path)? {
+            *merged.entry(bc).or_insert(0) += cnt;
+        }
+    }
+    let mut merged: Vec<(String,i64)> = merged.into_iter().collect();
+    merged.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(merged)
+}
+
+
+/// The n barcodes with the most reads; ties are broken by name, so the choice does not depend on input order
+pub fn top_barcodes(hist:&[(String,i64)], n:usize) -> Vec<String> {
+    let mut sorted: Vec<&(String,i64)> = hist.iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    sorted.into_iter().take(n).map(|(bc, _)| bc.clone()).collect()
+}
+
+
+/// Cells expected in a run, for estimate_cells, unless given
+pub const DEFAULT_EXPECTED_CELLS: usize = 3000;
+
+/// Estimate the number of cells from the reads per barcode, by the order-of-magnitude rule of CellRanger 2:
+/// barcodes with at least a tenth of the reads of the barcode at the 99th percentile of the expected cells
+pub fn estimate_cells(counts:&[i64], expected_cells:usize) -> usize {
+    if counts.is_empty() || expected_cells == 0 {
+        return 0;
+    }
+    let mut sorted = counts.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+    let rank = ((expected_cells as f64 * 0.01) as usize).min(sorted.len() - 1);
+    let threshold = (sorted[rank] as f64 / 10.0).max(1.0);
+    sorted.iter().take_while(|&&cnt| cnt as f64 >= threshold).count()
+}
+
+
+/// Write a barcode histogram in the same format as ToFastq
+pub fn store_histogram(path:&Path, hist:&[(String,i64)]) -> std::io::Result<()> {
+    let mut writer = TableWriter::create(path)?;
+    writer.write_all("barcode\tcount\n".as_bytes())?;
+    for (bc, cnt) in hist {
+        writeln!(writer, "{}\t{}", bc, cnt)?;
+    }
+    writer.finish()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_histograms() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let h1 = dir.join("h1.tsv");
+        let h2 = dir.join("h2.tsv");
+        store_histogram(&h1, &[("A.A.A.A".to_string(), 5), ("C.C.C.C".to_string(), 1)]).unwrap();
+        store_histogram(&h2, &[("C.C.C.C".to_string(), 7)]).unwrap();
+        let merged = merge_histograms(&[h1, h2]).unwrap();
+        assert_eq!(merged, vec![("C.C.C.C".to_string(), 8), ("A.A.A.A".to_string(), 5)]);
+
+        let hgz = dir.join("merged.tsv.gz");
+        store_histogram(&hgz, &merged).unwrap();
+        assert_eq!(read_histogram(&hgz).unwrap(), merged);
+    }
+
+    #[test]
+    fn test_estimate_cells() {
+        let mut counts = vec![1000; 100];
+        counts.extend(vec![5; 1000]);
+        counts.push(150);
+        assert_eq!(estimate_cells(&counts, 100), 101);
+        assert_eq!(estimate_cells(&counts, 3000), 101);
+        assert_eq!(estimate_cells(&[], 100), 0);
+    }
+
+    #[test]
+    fn test_top_barcodes() {
+        let hist = vec![("G.G.G.G".to_string(), 3), ("A.A.A.A".to_string(), 10), ("C.C.C.C".to_string(), 3)];
+        assert_eq!(top_barcodes(&hist, 2), vec!["A.A.A.A".to_string(), "C.C.C.C".to_string()]);
+        assert_eq!(top_barcodes(&hist, 5).len(), 3);
+    }
+
+    #[test]
+    fn test_count_min_sketch() {
+        let mut sketch = CountMinSketch::new(1024, 4);
+        for _ in 0..5 {
+            sketch.add(b"A.A.A.A");
+        }
+        assert_eq!(sketch.add(b"C.C.C.C"), 1);
+        assert!(sketch.estimate(b"A.A.A.A") >= 5);
+        assert_eq!(sketch.estimate(b"G.G.G.G"), 0);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(1 << 16, 4);
+        assert!(!filter.insert(b"read1"));
+        assert!(!filter.insert(b"read2"));
+        assert!(filter.insert(b"read1"));
+        let false_positives = (0..1000).filter(|i| filter.insert(format!("other{}", i).as_bytes())).count();
+        assert!(false_positives < 10);
+        assert_eq!(filter.num_keys(), 1002 - false_positives as u64);
+        assert_eq!(BloomFilter::optimal_num_hashes(10_000, 1000), 7);
+    }
+
+    #[test]
+    fn test_seen_keys() {
+        let mut seen = SeenKeys::default();
+        for key in 0..5000 {
+            assert!(!seen.insert(key));
+        }
+        assert!(seen.insert(42));
+
+        //Sized for the keys still to come, the filter keeps working well past the keys it started with
+        let expected_fp = seen.make_approximate(Some(200_000), 1 << 20);
+        assert!(expected_fp < 0.01);
+        assert!(seen.num_bytes() <= 1 << 20);
+        assert!((0..5000).all(|key| seen.insert(key)));
+        let false_positives = (5000..200_000).filter(|key| seen.insert(*key)).count();
+        assert!(false_positives < 2000, "{} false positives", false_positives);
+        assert!(seen.false_positive_rate() < 0.01);
+
+        //A filter too small for the keys is reported as such
+        let mut small = SeenKeys::default();
+        small.insert(1);
+        assert!(small.make_approximate(Some(1_000_000), 1024) > 0.5);
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+
+/// Marker for k-mers present in more than one transcript
+const AMBIGUOUS: u32 = u32::MAX;
+
+
+/// Index from canonical k-mers to the transcript they occur in. Experimental; meant for small
+/// targeted panels. K-mers shared between transcripts are not used for voting
+pub struct KmerIndex {
+    k: usize,
+    map: HashMap<u64, u32>
+}
+
+impl KmerIndex {
+
+    /// Create an empty index. k must be at most 31
+    pub fn new(k: usize) -> KmerIndex {
+        assert!(k>0 && k<=31, "k must be between 1 and 31");
+        KmerIndex {
+            k,
+            map: HashMap::new()
+        }
+    }
+
+    /// Add all k-mers of a transcript
+    pub fn add_transcript(&mut self, transcript_id: u32, seq: &[u8]) {
+        let k = self.k;
+        let map = &mut self.map;
+        for_each_canonical_kmer(seq, k, |kmer| {
+            map.entry(kmer)
+                .and_modify(|t| if *t!=transcript_id { *t = AMBIGUOUS })
+                .or_insert(transcript_id);
+        });
+    }
+
+    /// Assign a read to the transcript with most k-mer votes. Reads with fewer than min_votes
+    /// votes, or with a tie between transcripts, are not assigned
+    pub fn assign(&self, seq: &[u8], min_votes: usize) -> Option<u32> {
+        let mut votes: HashMap<u32, usize> = HashMap::new();
+        for_each_canonical_kmer(seq, self.k, |kmer| {
+            if let Some(&t) = self.map.get(&kmer) {
+                if t!=AMBIGUOUS {
+                    *votes.entry(t).or_insert(0) += 1;
+                }
+            }
+        });
+
+        let mut best: Option<(u32, usize)> = None;
+        let mut tie = false;
+        for (t, n) in votes {
+            match best {
+                Some((_, best_n)) if n < best_n => {},
+                Some((_, best_n)) if n == best_n => { tie = true; },
+                _ => {
+                    best = Some((t, n));
+                    tie = false;
+                }
+            }
+        }
+        let (t, n) = best?;
+        if tie || n < min_votes {
+            return None;
+        }
+        Some(t)
+    }
+
+    /// Number of distinct k-mers in the index
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+
+/// 2-bit encoding of a base; None for N and other characters
+fn encode_base(b: u8) -> Option<u64> {
+    match b {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None
+    }
+}
+
+
+/// Call f for each canonical k-mer (the smaller of the forward and reverse complement encoding).
+/// K-mers containing other bases than ACGT are skipped
+fn for_each_canonical_kmer<F: FnMut(u64)>(seq: &[u8], k: usize, mut f: F) {
+    let mask: u64 = (1 << (2*k)) - 1;
+    let shift = 2*(k-1);
+    let mut fwd: u64 = 0;
+    let mut rev: u64 = 0;
+    let mut valid = 0;
+    for &b in seq {
+        match encode_base(b) {
+            Some(x) => {
+                fwd = ((fwd << 2) | x) & mask;
+                rev = (rev >> 2) | ((3-x) << shift);
+                valid += 1;
+                if valid >= k {
+                    f(fwd.min(rev));
+                }
+            },
+            None => {
+                valid = 0;
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kmer_assign() {
+        let mut index = KmerIndex::new(5);
+        index.add_transcript(0, b"ACGTTGCATGCAAAC");
+        index.add_transcript(1, b"TTTTGGGGCCCCAAAATTT");
+
+        assert_eq!(index.assign(b"CGTTGCATG", 2), Some(0));
+        // reverse complement of a part of transcript 1
+        assert_eq!(index.assign(b"AAATTTTGGG", 2), Some(1));
+        assert_eq!(index.assign(b"GGGGGGGGGG", 1), None);
+    }
+}
@@ -1,2 +1,13 @@
 pub mod io;
 pub mod countfile;
+pub mod trim;
+pub mod kmer;
+pub mod histogram;
+pub mod collision;
+pub mod annotation;
+pub mod barcode;
+pub mod pattern;
+pub mod umi;
+pub mod bgzf;
+pub mod cellindex;
+pub mod checksum;
@@ -1,216 +1,595 @@
 
 use itertools::Itertools;
-use log::{error, debug}; //, info, trace, warn
-use std::collections::HashMap;
-use std::collections::HashSet;
+use log::{error, debug, warn}; //, info, trace
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::error::Error;
 use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
 
 use seq_io::fastq::Record as FastqRecord;
 use seq_io::fastq::Reader as FastqReader;
+use seq_io::fastq::OwnedRecord;
 use niffler::get_reader;
 use csv::ReaderBuilder;
-use clap::{Parser, Subcommand};
-use gzp::{deflate::Gzip, par::compress::{ParCompress, ParCompressBuilder}, ZWriter};
+use clap::{Parser, Subcommand, ValueEnum};
+use gzp::{deflate::{Bgzf, Gzip}, par::compress::{ParCompress, ParCompressBuilder}, FormatSpec, ZWriter, BGZF_BLOCK_SIZE};
 use env_logger::{Builder, Env};
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+
+/// Set on SIGINT/SIGTERM. The read loop stops at the next read, and the outputs so far are finished
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit code after finishing the outputs of an interrupted run, as for a process stopped by SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
 
 
 //////////////////////////////////////////
-////////////////////////////////////////// Basic whitelist correction
+////////////////////////////////////////// /// Copied from babbles ; fastq reading
 //////////////////////////////////////////
 
-pub struct BarcodeWhitelist {
-    list: Vec<String>,    //List for alignment; not sure if worth having separate from set
-    set: HashSet<String>, //Dictionary for fast lookup of exact matches
-    bc_length: usize
-}
 
-impl BarcodeWhitelist {
+pub fn open_fastq(file_handle: &PathBuf) -> FastqReader<Box<dyn std::io::Read>> {
+    open_fastq_counted(file_handle, Arc::new(AtomicU64::new(0)), 0)
+}
 
 
-    /// Compare to each BC, see which fits best --- each base that matches give 1p, other 0p
-    fn closest_bc_basewise(&self, bc_to_match: &String) -> Option<(String,i32)> {
-        let mut best_bc = &self.list[0];
-        let mut best_bc_score = num_similar_elements(bc_to_match.as_bytes(), best_bc.as_bytes());
-        for j in 1..self.list.len() {
-            let score = num_similar_elements(bc_to_match.as_bytes(), self.list[j].as_bytes());
-            if score>best_bc_score {
-                best_bc_score = score;
-                best_bc = &self.list[j];
-            }
-        }
-        //println!("best bc basewise {}",best_bc.to_string());
+/// Passes reads through, keeping count of the bytes
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>
+}
 
-        return Some((best_bc.to_string(),best_bc_score));
+impl<R: std::io::Read> std::io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
     }
+}
 
-    /// Correct barcode using whitelist
-    fn correct_to_whitelist(&self, bc_to_match: &String) -> Option<(String,i32)> { 
-        if bc_to_match.len()==0 {
-            //Empty barcode
-            return None;
-        } else if self.set.contains(bc_to_match) {
-            //See if there is a trivial match
-            //println!("trivial match");
-            return Some((bc_to_match.to_string(),8));
-        } else if self.bc_length==bc_to_match.len() {
-            //Compare each base if same length. Set a minimum cutoff
-            let m = self.closest_bc_basewise(bc_to_match)?;
-            if m.1 >=6 {
-                return Some(m);
-            } else {
-                return None;
-            }
 
-        } else {
-            //Fail
-            return None;
+/// Open a FASTQ file, counting the bytes read from the file (before decompression) to tell progress. bgzf files
+/// are decompressed on this many threads, unless 0
+pub fn open_fastq_counted(file_handle: &PathBuf, bytes_read: Arc<AtomicU64>, decompress_threads: usize) -> FastqReader<Box<dyn std::io::Read>> {
+    let opened_handle = match File::open(file_handle) {
+        Ok(file) => file,
+        Err(_) => {
+            error!("Could not open file {}", &file_handle.display());
+            process::exit(1)
         }
+    };
+    let mut buffered = std::io::BufReader::new(CountingReader {inner: opened_handle, count: bytes_read});
+    if decompress_threads > 0 && is_bgzf(&mut buffered) {
+        debug!("Opened file {} as bgzf, decompressing with {} threads", &file_handle.display(), decompress_threads);
+        let reader = ParBgzfReader::new(buffered, decompress_threads);
+        return FastqReader::new(Box::new(reader));
     }
+    let (reader, _) = match get_reader(Box::new(buffered)) {
+        Ok((reader, compression)) => {
+            debug!("Opened file {} with compression {:?}", &file_handle.display(), &compression);
+            (reader, compression)
+        },
+        Err(_) => {
+            error!("Could read reverse file {}", &file_handle.display());
+            process::exit(1)
+        }
+    };
+    let fastq = FastqReader::new(reader);
+    fastq
+}
 
+
+/// Records per batch sent from a FASTQ reader thread, and how many batches may wait in the channel
+const READ_BATCH_SIZE: usize = 4096;
+const READ_QUEUE_BATCHES: usize = 2;
+
+/// Records read by AsyncFastqReader in one batch, all in one buffer instead of three allocations per record.
+/// Each record is where its header, sequence and qualities start, and where it ends; or why it is malformed
+struct RecordBuffer {
+    data: Vec<u8>,
+    records: Vec<Result<[usize;4], String>>
 }
 
+impl RecordBuffer {
 
+    fn with_capacity(num_records: usize) -> RecordBuffer {
+        RecordBuffer {data: Vec::new(), records: Vec::with_capacity(num_records)}
+    }
 
-/// Count the number of similar elements in two lists of the same size
-fn num_similar_elements(a:&[u8], b:&[u8]) -> i32 {
-    let mut count = 0;
-    for i in 0..a.len() {
-        if a[i] == b[i] {
-            count = count + 1;
+    /// Copy a record into the buffer, converting Phred+64 qualities if asked to
+    fn push<R: FastqRecord>(&mut self, record: &R, convert_phred64: bool) {
+        let start = self.data.len();
+        self.data.extend_from_slice(record.head());
+        let seq_start = self.data.len();
+        self.data.extend_from_slice(record.seq());
+        let qual_start = self.data.len();
+        self.data.extend_from_slice(record.qual());
+        if convert_phred64 {
+            phred64_to_phred33(&mut self.data[qual_start..]);
         }
+        self.records.push(Ok([start, seq_start, qual_start, self.data.len()]));
     }
-    return count;
-}
 
+    /// Qualities of the well-formed records
+    fn quals(&self) -> impl Iterator<Item = &[u8]> {
+        self.records.iter().flatten().map(|offsets| &self.data[offsets[2]..offsets[3]])
+    }
 
+    /// Convert the qualities of all records from Phred+64
+    fn convert_phred64(&mut self) {
+        for offsets in self.records.iter().flatten() {
+            phred64_to_phred33(&mut self.data[offsets[2]..offsets[3]]);
+        }
+    }
+}
 
 
-/// Structure for Atrandi combinatorial barcodes
-pub struct AtrandiBarcodes {
-    rounds: Vec<BarcodeWhitelist>
+/// FASTQ record from AsyncFastqReader. It shares the buffer of its batch, which is freed once all its
+/// records are dropped
+#[derive(Clone)]
+pub struct BatchRecord {
+    buffer: Arc<RecordBuffer>,
+    offsets: [usize;4]
 }
 
-impl AtrandiBarcodes {
+impl BatchRecord {
 
-    /// Read dictionary of Atrandi barcodes from file
-    fn read_atrandi_barcodes(filename:&str) -> Result<AtrandiBarcodes, Box<dyn Error>> {
-        let mut rdr = ReaderBuilder::new()
-            .delimiter(b'\t')
-            .from_path(filename)?;
-        let mut bcs_for_well = vec![vec![] as Vec<String>; 4];
-        let mut bc_length = 666;
-        for result in rdr.records() {
-            let record = result?;
-            let pos=&record[0];
-            //let well=&record[1];
-            let bc=&record[2];
-            bc_length = bc.len();
-            let pos_int = pos.parse::<usize>().unwrap() - 1;
-            bcs_for_well[pos_int].push(String::from(bc));        
-        }
+    pub fn head(&self) -> &[u8] {
+        &self.buffer.data[self.offsets[0]..self.offsets[1]]
+    }
 
-        let whitelists = bcs_for_well.iter().map(|w| BarcodeWhitelist {
-            list: w.to_vec(),
-            set: HashSet::from_iter(w.to_vec()),
-            bc_length: bc_length
-        }  ).collect();
-        
-        Ok(AtrandiBarcodes {rounds: whitelists})
+    pub fn seq(&self) -> &[u8] {
+        &self.buffer.data[self.offsets[1]..self.offsets[2]]
     }
 
+    pub fn qual(&self) -> &[u8] {
+        &self.buffer.data[self.offsets[2]..self.offsets[3]]
+    }
+}
 
-    ///Extract barcode from read
-    fn get_correct_bc_from_read(&self, bc_read:&str, print_debug:bool) -> Option<(String,String,String,String)> {
 
-        //Extract each BC
-        //let template_bc = br"********AGGA********ACTC********AAGG********T"; 
-        //let barcode_tuple = extract_bc_by_alignment(template_bc, read_r1.as_bytes(), false);  
+/// FASTQ reader that decompresses and parses on its own thread. Records are passed in batches over a bounded
+/// channel, so the next batch is read while the current one is processed. Malformed records are passed on as
+/// an error message, so the caller can decide whether to skip them
+pub struct AsyncFastqReader {
+    rx: Receiver<RecordBuffer>,
+    batch: Option<Arc<RecordBuffer>>,
+    next_record: usize, //In the current batch
+    bytes_read: Arc<AtomicU64>, //Of the file, as read by the thread
+    file_size: u64
+}
 
-        let barcode_tuple = extract_bc_optimistic_atrandi(bc_read)?;
+impl AsyncFastqReader {
+
+    /// Open a FASTQ file and start reading it. The quality encoding is checked on the first batch, and Phred+64
+    /// converted if asked to. The file is only read once, so it can be a pipe
+    pub fn open(file_handle: &PathBuf, decompress_threads: usize, fix_phred64: bool) -> AsyncFastqReader {
+        let (tx, rx) = sync_channel(READ_QUEUE_BATCHES);
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let file_size = std::fs::metadata(file_handle).map(|m| m.len()).unwrap_or(0);
+        let file_handle = file_handle.clone();
+        let thread_bytes_read = bytes_read.clone();
+        std::thread::spawn(move || {
+            let mut reader = open_fastq_counted(&file_handle, thread_bytes_read, decompress_threads);
+            let mut batch = RecordBuffer::with_capacity(READ_BATCH_SIZE);
+            let mut convert_phred64 = None; //Known once the first batch is read
+            while let Some(record) = reader.next() {
+                match record {
+                    Ok(record) => {
+                        match check_fastq_record(&record) {
+                            Ok(()) => batch.push(&record, convert_phred64 == Some(true)),
+                            Err(problem) => batch.records.push(Err(format!("Malformed record {} in {}: {}", 
+                                String::from_utf8_lossy(record.head()), file_handle.display(), problem)))
+                        };
+                    },
+                    Err(e) => {
+                        error!("Error reading record from {}: {}", file_handle.display(), e);
+                        process::exit(1)
+                    }
+                }
+                //Tell where in the file a malformed record is
+                if let Some(Err(message)) = batch.records.last_mut() {
+                    let position = reader.position();
+                    message.push_str(&format!(" (line {}, byte {})", position.line(), position.byte()));
+                }
+                if batch.records.len() == READ_BATCH_SIZE {
+                    if convert_phred64.is_none() {
+                        convert_phred64 = Some(AsyncFastqReader::check_encoding(&mut batch, &file_handle, fix_phred64));
+                    }
+                    //Sending fails if the reader was dropped, e.g. stopping early; then there is no point continuing
+                    if tx.send(std::mem::replace(&mut batch, RecordBuffer::with_capacity(READ_BATCH_SIZE))).is_err() {
+                        return;
+                    }
+                }
+            }
+            if !batch.records.is_empty() {
+                if convert_phred64.is_none() {
+                    AsyncFastqReader::check_encoding(&mut batch, &file_handle, fix_phred64);
+                }
+                let _ = tx.send(batch);
+            }
+        });
+        AsyncFastqReader {rx, batch: None, next_record: 0, bytes_read, file_size}
+    }
 
-        //Note swap here of BCs to match logical order in chemistry. Barcode added last is the first one seen in the read
-        let corrected_bc = (
-            self.rounds[0].correct_to_whitelist(&barcode_tuple.0)?, //test this first as it is the most likely to fail
-            self.rounds[1].correct_to_whitelist(&barcode_tuple.1)?,
-            self.rounds[2].correct_to_whitelist(&barcode_tuple.2)?,
-            self.rounds[3].correct_to_whitelist(&barcode_tuple.3)?
-        );
-    
-        if print_debug {
-            println!("{}.{}.{}.{} in", barcode_tuple.0, barcode_tuple.1, barcode_tuple.2, barcode_tuple.3);
-            println!("{}.{}.{}.{} out", corrected_bc.0.0,corrected_bc.1.0,corrected_bc.2.0,corrected_bc.3.0);
-            println!("");  
+    /// Check the quality encoding of the first batch, read as is, and convert it if needed. Returns whether the
+    /// qualities need converting
+    fn check_encoding(batch: &mut RecordBuffer, file_handle: &Path, fix_phred64: bool) -> bool {
+        let convert = check_quality_encoding(file_handle, batch.quals(), fix_phred64);
+        if convert {
+            batch.convert_phred64();
         }
+        convert
+    }
 
-        //Add a global BC quality constraint
-        let total_m = corrected_bc.0.1 + corrected_bc.1.1 + corrected_bc.2.1 + corrected_bc.3.1;
-        if total_m > 7*4 {
-            return Some((corrected_bc.0.0, corrected_bc.1.0, corrected_bc.2.0, corrected_bc.3.0));
+    /// Fraction of the file read so far, if its size is known (not for pipes)
+    pub fn fraction_read(&self) -> Option<f64> {
+        if self.file_size == 0 {
+            None
         } else {
-            return None;
+            Some((self.bytes_read.load(Ordering::Relaxed) as f64 / self.file_size as f64).min(1.0))
+        }
+    }
+}
+
+impl Iterator for AsyncFastqReader {
+    type Item = Result<BatchRecord, String>;
+
+    /// Get the next record, or None at the end of the file
+    fn next(&mut self) -> Option<Result<BatchRecord, String>> {
+        loop {
+            if let Some(batch) = &self.batch {
+                if let Some(record) = batch.records.get(self.next_record) {
+                    self.next_record += 1;
+                    return Some(match record {
+                        Ok(offsets) => Ok(BatchRecord {buffer: batch.clone(), offsets: *offsets}),
+                        Err(message) => Err(message.clone())
+                    });
+                }
+            }
+            self.batch = Some(Arc::new(self.rx.recv().ok()?));
+            self.next_record = 0;
         }
     }
+}
 
+
+/// Check that a FASTQ record has as many qualities as bases, bases are letters, and qualities are printable
+fn check_fastq_record<R: FastqRecord>(record: &R) -> Result<(), String> {
+    if record.seq().len() != record.qual().len() {
+        return Err(format!("{} bases but {} qualities", record.seq().len(), record.qual().len()));
+    }
+    if let Some(c) = record.seq().iter().find(|c| !c.is_ascii_alphabetic()) {
+        return Err(format!("invalid base {:?}", *c as char));
+    }
+    if let Some(c) = record.qual().iter().find(|c| !(b'!'..=b'~').contains(*c)) {
+        return Err(format!("invalid quality character {:?}", *c as char));
+    }
+    Ok(())
 }
 
 
-fn extract_bc_optimistic_atrandi(bc_read:&str) -> Option<(String,String,String,String)> {
+/// How to deal with problems in the input FASTQ files
+#[derive(Clone, Copy, Default)]
+struct InputOptions {
+    lenient: bool,          //Only warn if R1 and R2 have different numbers of reads
+    skip_malformed: bool,   //Skip malformed read pairs instead of failing
+    fix_phred64: bool,      //Convert Phred+64 qualities to Phred+33
+    decompress_threads: usize //Threads per bgzf input; 0 to read it as a single stream
+}
 
-    if bc_read.len() > 36+8 {
-        let barcode_4 = &bc_read[(0 +0)..(0+8)];
-        let barcode_3 = &bc_read[(12+0)..(12+8)];
-        let barcode_2 = &bc_read[(24+0)..(24+8)];
-        let barcode_1 = &bc_read[(36+0)..(36+8)];
-        return Some((barcode_1.to_string(),barcode_2.to_string(),barcode_3.to_string(),barcode_4.to_string()))
-    } else {
-        return None;
+
+/// Check how the qualities of a FASTQ file are encoded, from those of its first reads. Phred+64 is an error unless
+/// it is to be converted; binned qualities get a warning. Returns whether qualities need converting
+fn check_quality_encoding<'a>(path: &Path, quals: impl Iterator<Item = &'a [u8]>, fix_phred64: bool) -> bool {
+    let profile = match QualityProfile::new(quals) {
+        Some(profile) => profile,
+        None => return false
+    };
+    debug!("Qualities of {}: {:?}", path.display(), profile);
+    if profile.is_binned() {
+        warn!("Qualities of {} are binned into {} levels; quality-aware barcode scoring has little to go on", 
+            path.display(), profile.distinct);
+    }
+    match profile.offset() {
+        PhredOffset::Phred64 if fix_phred64 => {
+            warn!("Qualities of {} look like Phred+64; converting to Phred+33", path.display());
+            true
+        },
+        PhredOffset::Phred64 => {
+            error!("Qualities of {} look like Phred+64 (range {:?} to {:?}). Use --fix-phred64 to convert them", 
+                path.display(), profile.min as char, profile.max as char);
+            process::exit(1)
+        },
+        PhredOffset::Ambiguous => {
+            warn!("Cannot tell whether the qualities of {} are Phred+33 or Phred+64 (range {:?} to {:?}); assuming Phred+33", 
+                path.display(), profile.min as char, profile.max as char);
+            false
+        },
+        PhredOffset::Phred33 => false
     }
 }
 
 
+/// R1 and R2 read in step. If one file ends before the other, the reads left over are counted and reported;
+/// this is an error unless lenient. Malformed pairs are an error, or counted and skipped
+pub struct PairedFastqReader {
+    r1: AsyncFastqReader,
+    r2: AsyncFastqReader,
+    lenient: bool,
+    skip_malformed: bool,
+    num_malformed: u64
+}
 
+impl PairedFastqReader {
 
+    fn open(path_r1: &PathBuf, path_r2: &PathBuf, input: &InputOptions) -> PairedFastqReader {
+        PairedFastqReader {
+            r1: AsyncFastqReader::open(path_r1, input.decompress_threads, input.fix_phred64),
+            r2: AsyncFastqReader::open(path_r2, input.decompress_threads, input.fix_phred64),
+            lenient: input.lenient,
+            skip_malformed: input.skip_malformed,
+            num_malformed: 0
+        }
+    }
 
+    /// Fraction of the input read so far, if known. The thread reading ahead makes this a slight overestimate
+    pub fn fraction_read(&self) -> Option<f64> {
+        Some((self.r1.fraction_read()? + self.r2.fraction_read()?)/2.0)
+    }
 
+    fn report_malformed(&self) {
+        if self.num_malformed > 0 {
+            warn!("Skipped {} malformed read pairs", self.num_malformed);
+        }
+    }
 
+    fn report_leftover(&self, longer: &str, shorter: &str, leftover: usize) {
+        if self.lenient {
+            warn!("{} ended before {}; ignoring {} reads left in {}", shorter, longer, leftover, longer);
+        } else {
+            error!("{} ended before {}, with {} reads left in {}. The input may be truncated; use --lenient to continue anyway", 
+                shorter, longer, leftover, longer);
+            process::exit(1)
+        }
+    }
+}
 
+impl Iterator for PairedFastqReader {
+    type Item = (BatchRecord, BatchRecord);
+
+    /// Get the next pair of reads, or None once either file has ended
+    fn next(&mut self) -> Option<(BatchRecord, BatchRecord)> {
+        loop {
+            match (self.r1.next(), self.r2.next()) {
+                (Some(Ok(record_r1)), Some(Ok(record_r2))) => {
+                    return Some((record_r1, record_r2));
+                },
+                (Some(Err(message)), Some(_)) | (Some(_), Some(Err(message))) => {
+                    if !self.skip_malformed {
+                        error!("{}. Use --skip-malformed to skip such reads", message);
+                        process::exit(1)
+                    }
+                    if self.num_malformed == 0 {
+                        warn!("{}; skipping this and further malformed read pairs", message);
+                    }
+                    self.num_malformed += 1;
+                },
+                (None, None) => {
+                    self.report_malformed();
+                    return None;
+                },
+                (Some(_), None) => {
+                    let leftover = 1 + self.r1.by_ref().count();
+                    self.report_malformed();
+                    self.report_leftover("R1", "R2", leftover);
+                    return None;
+                },
+                (None, Some(_)) => {
+                    let leftover = 1 + self.r2.by_ref().count();
+                    self.report_malformed();
+                    self.report_leftover("R2", "R1", leftover);
+                    return None;
+                }
+            }
+        }
+    }
+}
 
 
 
+/// Read pairs per batch corrected on a worker thread, and batches in flight per thread
+const CORRECT_BATCH_PAIRS: usize = 1024;
+const CORRECT_BATCHES_PER_THREAD: usize = 4;
 
+/// Barcode correction of a read pair, if R2 is long enough for the full barcode block
+type PairCorrection = Option<(Option<CellBarcode>, CorrectionOutcome)>;
+type CorrectedPair = (BatchRecord, BatchRecord, PairCorrection);
 
+/// Order of the output reads when barcodes are corrected on several threads
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputOrder {
+    /// Same order as the input; batches that finish early wait in a reorder buffer
+    Input,
+    /// Batches are written as they finish. Faster when some batches are slow, but the read order (and which of
+    /// duplicate reads is kept) differs between runs
+    Unordered
+}
 
+/// Read pairs, with the barcodes optionally corrected ahead on worker threads. Without workers, pairs are
+/// passed on as read and the caller corrects them. For array jobs, only the pairs of one shard are passed on
+struct CorrectingReader {
+    reader: PairedFastqReader,
+    shard: Option<(u64, u64)>,
+    input_pairs: u64, //Read from the input, including the pairs of other shards
+    workers: Option<CorrectionWorkers>,
+    current: std::vec::IntoIter<CorrectedPair>
+}
 
+impl CorrectingReader {
 
-//////////////////////////////////////////
-////////////////////////////////////////// /// Copied from babbles ; fastq reading
-//////////////////////////////////////////
+    fn new(reader: PairedFastqReader, shard: Option<(u64, u64)>, threads: usize, order: OutputOrder, barcodes: &Arc<AtrandiBarcodes>) -> CorrectingReader {
+        CorrectingReader {
+            reader,
+            shard,
+            input_pairs: 0,
+            workers: if threads > 0 {Some(CorrectionWorkers::spawn(barcodes, threads, order))} else {None},
+            current: Vec::new().into_iter()
+        }
+    }
 
+    /// Order of the pairs passed on: always the input order, unless corrected on worker threads
+    fn order(&self) -> OutputOrder {
+        self.workers.as_ref().map_or(OutputOrder::Input, |w| w.order)
+    }
 
-pub fn open_fastq(file_handle: &PathBuf) -> FastqReader<Box<dyn std::io::Read>> {
-    let opened_handle = match File::open(file_handle) {
-        Ok(file) => file,
-        Err(_) => {
-            error!("Could not open file {}", &file_handle.display());
-            process::exit(1)
+    /// Next pair of this shard, straight from the input
+    fn next_in_shard(reader: &mut PairedFastqReader, shard: Option<(u64, u64)>, input_pairs: &mut u64) -> Option<(BatchRecord, BatchRecord)> {
+        loop {
+            let pair = reader.next()?;
+            *input_pairs += 1;
+            match shard {
+                Some((index, count)) if (*input_pairs - 1) % count != index => {},
+                _ => return Some(pair)
+            }
         }
-    };
-    let (reader, _) = match get_reader(Box::new(opened_handle)) {
-        Ok((reader, compression)) => {
-            debug!("Opened file {} with compression {:?}", &file_handle.display(), &compression);
-            (reader, compression)
-        },
-        Err(_) => {
-            error!("Could read reverse file {}", &file_handle.display());
-            process::exit(1)
+    }
+
+    /// Get the next pair, with its barcode correction if done by a worker
+    pub fn next(&mut self) -> Option<CorrectedPair> {
+        loop {
+            if let Some(pair) = self.current.next() {
+                return Some(pair);
+            }
+            let workers = match &mut self.workers {
+                Some(workers) => workers,
+                None => {
+                    let (record_r1, record_r2) = CorrectingReader::next_in_shard(&mut self.reader, self.shard, &mut self.input_pairs)?;
+                    return Some((record_r1, record_r2, None));
+                }
+            };
+
+            //Keep the workers busy, then take the next batch they have finished
+            while !workers.input_done && workers.in_flight() < workers.max_in_flight {
+                let batch = std::iter::from_fn(|| CorrectingReader::next_in_shard(&mut self.reader, self.shard, &mut self.input_pairs))
+                    .take(CORRECT_BATCH_PAIRS).collect_vec();
+                if batch.len() < CORRECT_BATCH_PAIRS {
+                    workers.input_done = true;
+                }
+                if !batch.is_empty() {
+                    workers.send(batch);
+                }
+            }
+            self.current = workers.next_batch()?.into_iter();
         }
-    };
-    let fastq = FastqReader::new(reader);
-    fastq
+    }
+
+    pub fn fraction_read(&self) -> Option<f64> {
+        self.reader.fraction_read()
+    }
+}
+
+/// Threads correcting numbered batches of read pairs. In input order, batches finishing early wait in a reorder
+/// buffer until the ones before them have been passed on; unordered, they are passed on as they finish. Batches
+/// in the buffer count as in flight, so it holds at most max_in_flight of them
+struct CorrectionWorkers {
+    order: OutputOrder,
+    jobs: SyncSender<(u64, Vec<(BatchRecord, BatchRecord)>)>,
+    done: Receiver<(u64, Vec<CorrectedPair>)>,
+    max_in_flight: usize,
+    num_sent: u64,
+    num_passed: u64,
+    next_out: u64, //Batch to pass on next, in input order
+    reorder: BTreeMap<u64, Vec<CorrectedPair>>,
+    input_done: bool
+}
+
+impl CorrectionWorkers {
+
+    fn spawn(barcodes: &Arc<AtrandiBarcodes>, threads: usize, order: OutputOrder) -> CorrectionWorkers {
+        let max_in_flight = threads * CORRECT_BATCHES_PER_THREAD;
+        let (tx_jobs, rx_jobs) = sync_channel::<(u64, Vec<(BatchRecord, BatchRecord)>)>(max_in_flight);
+        let rx_jobs = Arc::new(Mutex::new(rx_jobs));
+        let (tx_done, rx_done) = channel();
+        for _ in 0..threads {
+            let (rx_jobs, tx_done, barcodes) = (rx_jobs.clone(), tx_done.clone(), barcodes.clone());
+            std::thread::spawn(move || loop {
+                let job = rx_jobs.lock().unwrap().recv();
+                match job {
+                    Ok((id, pairs)) => {
+                        let corrected = pairs.into_iter().map(|(record_r1, record_r2)| {
+                            let correction = if barcodes.has_full_block(record_r2.seq()) {
+                                Some(barcodes.correct_with_outcome(record_r2.seq(), Some(record_r2.qual()), false))
+                            } else {
+                                None
+                            };
+                            (record_r1, record_r2, correction)
+                        }).collect_vec();
+                        if tx_done.send((id, corrected)).is_err() {
+                            break;
+                        }
+                    },
+                    Err(_) => break
+                }
+            });
+        }
+        CorrectionWorkers {
+            order,
+            jobs: tx_jobs,
+            done: rx_done,
+            max_in_flight,
+            num_sent: 0,
+            num_passed: 0,
+            next_out: 0,
+            reorder: BTreeMap::new(),
+            input_done: false
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        (self.num_sent - self.num_passed) as usize
+    }
+
+    fn send(&mut self, batch: Vec<(BatchRecord, BatchRecord)>) {
+        self.jobs.send((self.num_sent, batch)).expect("Barcode correction thread failed");
+        self.num_sent += 1;
+    }
+
+    /// Next finished batch, or None once all sent have been passed on
+    fn next_batch(&mut self) -> Option<Vec<CorrectedPair>> {
+        loop {
+            if let Some(batch) = self.reorder.remove(&self.next_out) {
+                self.next_out += 1;
+                self.num_passed += 1;
+                return Some(batch);
+            }
+            if self.num_passed == self.num_sent {
+                //Every batch must have been passed on exactly once
+                assert!(self.reorder.is_empty(), "Corrected batches {:?} were never written", self.reorder.keys().collect_vec());
+                return None;
+            }
+            let (id, batch) = self.done.recv().expect("Barcode correction thread failed");
+            match self.order {
+                OutputOrder::Input => {
+                    assert!(id >= self.next_out && !self.reorder.contains_key(&id), "Corrected batch {} received twice", id);
+                    self.reorder.insert(id, batch);
+                },
+                OutputOrder::Unordered => {
+                    self.num_passed += 1;
+                    return Some(batch);
+                }
+            }
+        }
+    }
 }
 
 
@@ -220,291 +599,5543 @@ pub fn open_fastq(file_handle: &PathBuf) -> FastqReader<Box<dyn std::io::Read>>
 //////////////////////////////////////////
 
 /* 
-fn write_fastq_str(parz: &mut ParCompress<Gzip>, readname:&str, seq:&str, qual:&str) {
-    write_fastq(parz, readname.as_bytes(), seq.as_bytes(), qual.as_bytes());
+fn write_fastq_str(batch: &mut Vec<u8>, readname:&str, seq:&str, qual:&str) {
+    write_fastq(batch, readname.as_bytes(), seq.as_bytes(), qual.as_bytes());
 }
 */
 
-fn write_fastq(parz: &mut ParCompress<Gzip>, readname:&[u8], seq:&[u8], qual:&[u8]) {
-    parz.write_all(b"@").unwrap();
-    parz.write_all(readname).unwrap();
-    parz.write_all(b"\n").unwrap();
+/// Reads between checks of whether a progress event is due
+const PROGRESS_CHECK_READS: u64 = 10000;
 
-    parz.write_all(seq).unwrap();
-    parz.write_all(b"\n").unwrap();
+/// Single-line JSON progress events on stderr, for workflow managers and dashboards
+struct ProgressJson {
+    interval: Duration,
+    start: Instant,
+    last: Instant
+}
 
-    parz.write_all(b"+\n").unwrap();
+impl ProgressJson {
 
-    parz.write_all(qual).unwrap();
-    parz.write_all(b"\n").unwrap();
-}
+    fn new(interval_secs: u64) -> ProgressJson {
+        let now = Instant::now();
+        ProgressJson {interval: Duration::from_secs(interval_secs), start: now, last: now}
+    }
 
+    /// Emit an event if the interval has passed since the last one
+    fn tick(&mut self, reads: u64, assigned: u64, fraction_read: Option<f64>) {
+        if self.last.elapsed() >= self.interval {
+            self.last = Instant::now();
+            self.emit("progress", reads, assigned, fraction_read);
+        }
+    }
 
+    /// Emit an event. The ETA is extrapolated from how much of the input has been read
+    fn emit(&self, event: &str, reads: u64, assigned: u64, fraction_read: Option<f64>) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let eta = fraction_read.filter(|f| *f > 0.0).map(|f| elapsed*(1.0-f)/f);
+        let line = serde_json::json!({
+            "event": event,
+            "reads": reads,
+            "assigned": assigned,
+            "assign_rate": if reads>0 {assigned as f64/reads as f64} else {0.0},
+            "elapsed_s": elapsed,
+            "fraction_read": fraction_read,
+            "eta_s": eta
+        });
+        eprintln!("{}", line);
+    }
+}
 
 
+/// Number of bytes to collect in an output batch before handing it over to the compressor
+const OUTPUT_BATCH_SIZE: usize = 4*1024*1024;
 
-fn parse_to_fastq(
-    path_in_r1:&PathBuf,
-    path_in_r2:&PathBuf,
-    path_out_r1:&PathBuf,
-    path_out_r2:&PathBuf,
-    histogram_file:&PathBuf
-) {
+/// Buffer size gzp uses unless told otherwise
+const DEFAULT_COMPRESS_BUFFER: usize = 128*1024;
 
-    let print_debug = false;
+/// Smallest buffer gzp accepts, the size of the deflate dictionary
+const MIN_COMPRESS_BUFFER: usize = 32*1024;
 
-    println!("reading whitelist ");
-    let atrandi_barcodes = AtrandiBarcodes::read_atrandi_barcodes("bc.csv").expect("Failed to read barcode file");
+/// Blocks a parallel compressor keeps in flight per thread. gzp queues up to 2 blocks per thread for the
+/// compressors, and as many compressed blocks for the writer. Once full, writes block until the output catches up
+const COMPRESS_BLOCKS_PER_THREAD: usize = 4;
 
-    /////////// Set up input
-    let mut f_r1 = open_fastq(&path_in_r1);
-    let mut f_r2 = open_fastq(&path_in_r2);
+/// Thread count and buffer size for the parallel compression of output files.
+///
+/// Each writer holds at most about buffer * (4 * threads + 1) bytes of blocks, plus the batch being filled
+/// (OUTPUT_BATCH_SIZE). With the default 128 KiB buffer this is about 8 MiB at 8 threads, and 12 MiB at 16 threads,
+/// per output file. A memory cap shrinks the buffer, and then the thread count, until the writer fits
+#[derive(Clone)]
+struct CompressOptions {
+    threads: Option<usize>,
+    buffer: Option<usize>,
+    max_memory: Option<usize>,
+    checksums: Option<ChecksumManifest> //Checksum outputs as they are written
+}
 
-    /////////// Set up output
-    let output_r1 = File::create(path_out_r1).expect("creation of R1 failed");
-    let output_r2 = File::create(path_out_r2).expect("creation of R2 failed");
+impl CompressOptions {
 
-    let mut parz_r1: ParCompress<Gzip> = ParCompressBuilder::new().from_writer(output_r1);
-    let mut parz_r2: ParCompress<Gzip> = ParCompressBuilder::new().from_writer(output_r2);
+    /// Expected peak memory of one writer, in bytes
+    fn memory_envelope(threads: usize, buffer: usize) -> usize {
+        OUTPUT_BATCH_SIZE + buffer * (COMPRESS_BLOCKS_PER_THREAD * threads + 1)
+    }
 
+    /// Thread count and buffer size to use for one writer, fitted to the memory cap if there is one. The buffer
+    /// is at most max_buffer bytes
+    fn fit(&self, num_writers: usize, max_buffer: usize) -> (usize, usize) {
+        let mut threads = match self.threads {
+            Some(threads) => threads,
+            None => {
+                let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+                (cpus.saturating_sub(1) / num_writers.max(1)).max(1)
+            }
+        };
+        let mut buffer = self.buffer.unwrap_or(DEFAULT_COMPRESS_BUFFER).min(max_buffer);
+        if let Some(max_memory) = self.max_memory {
+            let budget = max_memory.saturating_sub(OUTPUT_BATCH_SIZE);
+            let fitted = budget / (COMPRESS_BLOCKS_PER_THREAD * threads + 1);
+            if fitted < buffer {
+                buffer = fitted.max(MIN_COMPRESS_BUFFER);
+            }
+            while threads > 1 && CompressOptions::memory_envelope(threads, buffer) > max_memory {
+                threads -= 1;
+            }
+            if CompressOptions::memory_envelope(threads, buffer) > max_memory {
+                warn!("Compression memory cap of {} bytes is too low; using at least {} bytes per output file",
+                    max_memory, CompressOptions::memory_envelope(threads, buffer));
+            }
+        }
+        (threads, buffer)
+    }
 
-    let mut barcode_per_cell_count = HashMap::new();
+    /// Set up a parallel compressor. Unless a thread count is given, the available CPUs are shared among
+    /// the writers open at the same time, keeping one for the main thread. The available CPUs respect cgroup limits
+    fn writer<F: FormatSpec>(&self, output: Box<dyn Write + Send>, num_writers: usize) -> ParCompress<F> {
+        self.writer_capped(output, num_writers, usize::MAX)
+    }
 
+    /// Set up a parallel bgzf compressor. Each buffer becomes one bgzf block, and blocks can hold at most
+    /// 64 KiB, so the buffer is capped at the largest block gzp writes
+    fn writer_bgzf(&self, output: Box<dyn Write + Send>, num_writers: usize) -> ParCompress<Bgzf> {
+        self.writer_capped(output, num_writers, BGZF_BLOCK_SIZE)
+    }
 
-    /////////// Handle all reads
-    let mut read_count = 0;
-    let mut count_ok_reads = 0;
-    while let Some(record_r1) = f_r1.next() {
+    /// Set up a parallel compressor with a buffer of at most max_buffer bytes
+    fn writer_capped<F: FormatSpec>(&self, output: Box<dyn Write + Send>, num_writers: usize, max_buffer: usize) -> ParCompress<F> {
+        let (threads, buffer) = self.fit(num_writers, max_buffer);
+        debug!("Compressing with {} threads, {} byte buffer; expected peak memory {} bytes",
+            threads, buffer, CompressOptions::memory_envelope(threads, buffer));
+        ParCompressBuilder::new()
+            .num_threads(threads).expect("Invalid number of compression threads")
+            .buffer_size(buffer).expect("Invalid compression buffer size")
+            .from_writer(output)
+    }
 
-        read_count = read_count + 1;
-        if read_count%100000 == 0 {
-            println!("Processed reads: {}   Ok reads: {}   fraction: {}", read_count, count_ok_reads, count_ok_reads as f64/read_count as f64);
+    /// Create an output file, checksummed as it is written if asked for
+    fn create(&self, path: &PathBuf) -> Box<dyn Write + Send> {
+        let output = match File::create(path) {
+            Ok(output) => output,
+            Err(e) => {
+                error!("Could not create output file {}: {}", path.display(), e);
+                process::exit(1)
+            }
+        };
+        match &self.checksums {
+            Some(checksums) => Box::new(checksums.wrap(path, output)),
+            None => Box::new(output)
         }
+    }
 
-        if read_count == 50000000  {
-            println!("done early");
-            break;
+    /// Open a compressed output file. The format follows the extension: .bz2, .xz and .zst are written with a
+    /// single thread, anything else is gzip with the parallel compressor
+    fn output(&self, path: &PathBuf, num_writers: usize) -> OutputWriter {
+        let output = self.create(path);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("bz2") => OutputWriter::Bzip(bzip2::write::BzEncoder::new(BufWriter::new(output), bzip2::Compression::new(6))),
+            Some("xz") => OutputWriter::Xz(xz2::write::XzEncoder::new(BufWriter::new(output), 6)),
+            Some("zst") => OutputWriter::Zstd(zstd::stream::write::Encoder::new(BufWriter::new(output), 6)
+                .expect("Could not set up output compression")),
+            _ => OutputWriter::Gzip(self.writer(output, num_writers))
         }
+    }
 
+    /// Create a table output, checksummed as it is written if asked for. Tables are written one at a time,
+    /// so a gzip compressed table gets the threads of a single writer
+    fn table(&self, path: &PathBuf) -> TableWriter {
+        let output = self.create(path);
+        if TableWriter::is_gzip(path) {
+            TableWriter::Gzip(self.writer(output, 1))
+        } else {
+            TableWriter::Plain(BufWriter::new(output))
+        }
+    }
 
-        let record_r2 = f_r2.next().expect("No r2");
-
-        let record_r1: seq_io::fastq::RefRecord = record_r1.expect("Error reading record");
-        let record_r2: seq_io::fastq::RefRecord = record_r2.expect("Error reading record");
-    
-        let seq_r2=String::from_utf8_lossy(record_r2.seq());
-        let bc = atrandi_barcodes.get_correct_bc_from_read(&seq_r2, print_debug);
+    /// Open a bgzf compressed output file, for random access through an index. bgzf is also valid gzip
+    fn output_bgzf(&self, path: &PathBuf, num_writers: usize) -> OutputWriter {
+        OutputWriter::Bgzf(self.writer_bgzf(self.create(path), num_writers))
+    }
+}
 
-        match bc {
-            Some(bc) => {
-                count_ok_reads = count_ok_reads + 1;
+/// Compressed output file; see CompressOptions::output
+enum OutputWriter {
+    Gzip(ParCompress<Gzip>),
+    Bgzf(ParCompress<Bgzf>),
+    Bzip(bzip2::write::BzEncoder<BufWriter<Box<dyn Write + Send>>>),
+    Xz(xz2::write::XzEncoder<BufWriter<Box<dyn Write + Send>>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<Box<dyn Write + Send>>>)
+}
 
-                let concat_bc = format!("{}.{}.{}.{}",bc.0,bc.1,bc.2,bc.3);
+impl OutputWriter {
+
+    /// Finish compression, writing the trailer of the format. Must be called for the file to be complete
+    fn finish(self) -> std::io::Result<()> {
+        let to_io_error = |e: gzp::GzpError| std::io::Error::other(e.to_string());
+        match self {
+            OutputWriter::Gzip(mut parz) => parz.finish().map_err(to_io_error),
+            OutputWriter::Bgzf(mut parz) => parz.finish().map_err(to_io_error),
+            OutputWriter::Bzip(encoder) => encoder.finish()?.flush(),
+            OutputWriter::Xz(encoder) => encoder.finish()?.flush(),
+            OutputWriter::Zstd(encoder) => encoder.finish()?.flush()
+        }
+    }
+}
 
-                //Count barcodes
-                match barcode_per_cell_count.get(&concat_bc) {
-                    Some(cnt) => {
-                        barcode_per_cell_count.insert(concat_bc.clone(), cnt+1);
-                    },
-                    None => {
-                        barcode_per_cell_count.insert(concat_bc.clone(), 1);
-                    }
-                }
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputWriter::Gzip(parz) => parz.write(buf),
+            OutputWriter::Bgzf(parz) => parz.write(buf),
+            OutputWriter::Bzip(encoder) => encoder.write(buf),
+            OutputWriter::Xz(encoder) => encoder.write(buf),
+            OutputWriter::Zstd(encoder) => encoder.write(buf)
+        }
+    }
 
-                //Typical FASTQ record
-                //@M03699:228:000000000-LCH6K:1:1102:12164:1000 1:N:0:CAGGTT
-                //NCAGTTACTTGCAGGAATCTCCACCTGCTCTCCATCGACTACGTCTTTCGACCTCGCCTTAGGTCCCGACTTACC
-                //+
-                //#8B<CFDGGGFGGFGGFGGGGGGGGGFGCGFFGGGGGDGFDEGGGGGGGGGGGCGCEGGGGGGGGGGGEFGGFGG
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Gzip(parz) => parz.flush(),
+            OutputWriter::Bgzf(parz) => parz.flush(),
+            OutputWriter::Bzip(encoder) => encoder.flush(),
+            OutputWriter::Xz(encoder) => encoder.flush(),
+            OutputWriter::Zstd(encoder) => encoder.flush()
+        }
+    }
+}
 
+/// Format a FASTQ record into the batch buffer
+fn write_fastq(batch: &mut Vec<u8>, readname:&[u8], seq:&[u8], qual:&[u8]) {
+    batch.push(b'@');
+    batch.extend_from_slice(readname);
+    batch.push(b'\n');
 
-                //Read 1 is the same. Update name to include BC
-                let new_r1_name = format!("{}_{}",&concat_bc, record_r1.id().unwrap());
-                write_fastq(&mut parz_r1, 
-                    new_r1_name.as_bytes(),
-                    record_r1.seq(),
-                    record_r1.qual()
-                );
+    batch.extend_from_slice(seq);
+    batch.push(b'\n');
 
-                //For Read 2, we will chop off the BC part. Update name to include BC
-                let new_r2_name = format!("{}_{}",&concat_bc, record_r2.id().unwrap());
+    batch.extend_from_slice(b"+\n");
 
-                let from: usize = 36+8;
-                let to = record_r2.seq().len();
-                let from = if from<to {from} else {to}; //to be on the safe side
-                let new_r2_seq = &record_r2.seq()[from..to];
-                let new_r2_qual = &record_r2.qual()[from..to];
+    batch.extend_from_slice(qual);
+    batch.push(b'\n');
+}
 
-                write_fastq(&mut parz_r2, 
-                    new_r2_name.as_bytes(),
-                    new_r2_seq,
-                    new_r2_qual
-                );
+/// Run metadata for read groups: given on the command line, or as a TSV of SAM read group tags and values (SM, PL, LB, PU)
+#[derive(Clone, Debug, Default)]
+struct RunMetadata {
+    id: Option<String>,
+    sample: Option<String>,
+    platform: Option<String>,
+    library: Option<String>,
+    platform_unit: Option<String>
+}
 
+impl RunMetadata {
 
-            },
-            None => {
-                //println!("Cannot tell BC");
+    /// Read a metadata file, one tag and value per line. Values given later, e.g. on the command line, take precedence
+    fn read(path:&PathBuf) -> std::io::Result<RunMetadata> {
+        let mut metadata = RunMetadata::default();
+        for line in std::fs::read_to_string(path)?.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
             }
-        };
+            let (tag, value) = line.split_once('\t').ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Malformed metadata line: {}", line)))?;
+            let value = Some(value.trim().to_string());
+            match tag.trim() {
+                "ID" => metadata.id = value,
+                "SM" => metadata.sample = value,
+                "PL" => metadata.platform = value,
+                "LB" => metadata.library = value,
+                "PU" => metadata.platform_unit = value,
+                other => warn!("Ignoring unknown metadata tag {}", other)
+            }
+        }
+        Ok(metadata)
     }
 
-    parz_r1.finish().unwrap();
-    parz_r2.finish().unwrap();
-
-
-    ////// Write barcode histogram
-    let output_h = File::create(histogram_file).expect("creation of R1 failed");
-    let mut writer_h = BufWriter::new(output_h);
-    writer_h.write_all("barcode\tcount\n".as_bytes()).expect("Unable to write data");
-    for (bc, cnt) in &barcode_per_cell_count {
-        let toprint = format!("{}\t{}\n", bc, cnt);
-        writer_h.write_all(toprint.as_bytes()).expect("Unable to write data");
+    fn is_empty(&self) -> bool {
+        self.id.is_none() && self.sample.is_none() && self.platform.is_none() && self.library.is_none() && self.platform_unit.is_none()
     }
 
+    /// Read group ID: as given, else the sample name
+    fn read_group_id(&self) -> Option<&str> {
+        self.id.as_deref().or(self.sample.as_deref())
+    }
 
+    /// Tags other than ID, as (tag, value)
+    fn tags(&self) -> Vec<(&str, &str)> {
+        [("SM", &self.sample), ("PL", &self.platform), ("LB", &self.library), ("PU", &self.platform_unit)].into_iter()
+            .filter_map(|(tag, value)| value.as_deref().map(|v| (tag, v)))
+            .collect()
+    }
 
-    println!("done");
+    /// Header line for the read group, e.g. for bwa mem -R
+    fn read_group_line(&self) -> String {
+        let mut line = format!("@RG\\tID:{}", self.read_group_id().unwrap_or("quick_bc"));
+        for (tag, value) in self.tags() {
+            line.push_str(&format!("\\t{}:{}", tag, value));
+        }
+        line
+    }
 
+    /// FASTQ comment assigning the read group, in SAM tag format so aligners can copy it (e.g. bwa mem -C). The
+    /// header then needs the read group line too (bwa mem -R {rg})
+    fn fastq_comment(&self) -> Option<String> {
+        self.read_group_id().map(|id| format!("RG:Z:{}", id))
+    }
 }
 
 
+/// Quote a string as one word for the shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
 
 
-/////////////////////////////////////////////////////////////////////////////////////////
-///////////////////////////////// Generate count table //////////////////////////////////
-/////////////////////////////////////////////////////////////////////////////////////////
+/// Build the name of an output read, BC_readid, into a reusable buffer
+fn make_read_name(out: &mut Vec<u8>, concat_bc: &[u8], head: &[u8]) {
+    out.clear();
+    out.extend_from_slice(concat_bc);
+    out.push(b'_');
+    //Only keep the ID; the description after the first space is dropped
+    let id_len = head.iter().position(|&c| c==b' ').unwrap_or(head.len());
+    out.extend_from_slice(&head[..id_len]);
+}
 
+/// Make a duplicate read name unique by appending .dupN, N counting the duplicates found so far
+fn add_duplicate_suffix(out: &mut Vec<u8>, serial: Option<u64>) {
+    if let Some(serial) = serial {
+        out.extend_from_slice(format!(".dup{}", serial).as_bytes());
+    }
+}
 
+/// Append a comment to a read name, separated by a space
+fn add_comment(out: &mut Vec<u8>, comment: &Option<String>) {
+    if let Some(comment) = comment {
+        out.push(b' ');
+        out.extend_from_slice(comment.as_bytes());
+    }
+}
 
-fn count_seq_per_bc(ibam:&PathBuf, path_csv:&PathBuf) {
+/// Hand over the batch to the compressor if it is large enough, or if forced
+fn flush_fastq_batch(parz: &mut OutputWriter, batch: &mut Vec<u8>, force: bool) {
+    if force || batch.len() >= OUTPUT_BATCH_SIZE {
+        parz.write_all(batch).unwrap();
+        batch.clear();
+    }
+}
 
-    let mut barcode_per_cell_count: HashMap<String, HashMap<usize,i32>> = HashMap::new();
 
 
-    use noodles::bam;
-    use bstr::ByteSlice;
 
 
-    let mut reader = bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
-    let header = reader.read_header().expect("Could not read BAM header");
+/// Unaligned BAM output. Read pairs are written as unmapped mates, with the barcode and UMI in tags
+struct UbamWriter {
+    writer: Box<dyn noodles::sam::alignment::io::Write>,
+    header: noodles::sam::Header,
+    read_group: Option<String>
+}
 
+impl UbamWriter {
 
-    //Set up a list of features
-    let allind: Vec<usize> = (0..header.reference_sequences().len()).collect();
-    let mut name_of_features = allind.iter().map(|i| header.reference_sequences().get_index(*i).expect("!").0.to_string()).collect_vec();
-    let id_noname = name_of_features.len();
-    name_of_features.push("*".to_string());
-    println!("Names of features:");
-    println!("{:?}", name_of_features);
+    /// Create the BAM file. The header has no references, only the read group if one is given
+    fn create(path:&PathBuf, metadata:&RunMetadata, compress:&CompressOptions) -> UbamWriter {
+        use noodles::bam;
+        use noodles::sam::header::record::value::{Map, map::ReadGroup};
+        use noodles::sam::header::record::value::map::read_group::tag as rg_tag;
+
+        let mut header = noodles::sam::Header::default();
+        let read_group = metadata.read_group_id().map(|id| id.to_string());
+        if let Some(id) = &read_group {
+            let mut rg = Map::<ReadGroup>::default();
+            if let Some(sample) = &metadata.sample {
+                rg.other_fields_mut().insert(rg_tag::SAMPLE, sample.as_str().into());
+            }
+            if let Some(platform) = &metadata.platform {
+                rg.other_fields_mut().insert(rg_tag::PLATFORM, platform.as_str().into());
+            }
+            if let Some(library) = &metadata.library {
+                rg.other_fields_mut().insert(rg_tag::LIBRARY, library.as_str().into());
+            }
+            if let Some(platform_unit) = &metadata.platform_unit {
+                rg.other_fields_mut().insert(rg_tag::PLATFORM_UNIT, platform_unit.as_str().into());
+            }
+            header.read_groups_mut().insert(id.as_str().into(), rg);
+        }
+
+        let mut writer = bam::io::Writer::new(compress.create(path));
+        writer.write_header(&header).expect("Could not write BAM header");
+        UbamWriter {
+            writer: Box::new(writer),
+            header,
+            read_group
+        }
+    }
+
+    /// Tags shared by both reads of a pair. UMIs are not corrected, so UB is the same as UR
+    fn tags(&self, cell_bc:&[u8], raw_bc:&[u8], raw_bc_qual:&[u8], umi:&[u8], umi_qual:&[u8]) -> noodles::sam::alignment::record_buf::Data {
+        use noodles::sam::alignment::record::data::field::Tag;
+        use noodles::sam::alignment::record_buf::data::field::Value;
+
+        let as_value = |s:&[u8]| Value::from(String::from_utf8_lossy(s).into_owned());
+        let mut data = noodles::sam::alignment::record_buf::Data::default();
+        data.insert(Tag::CELL_BARCODE_ID, as_value(cell_bc));
+        data.insert(Tag::CELL_BARCODE_SEQUENCE, as_value(raw_bc));
+        data.insert(Tag::CELL_BARCODE_QUALITY_SCORES, as_value(raw_bc_qual));
+        if !umi.is_empty() {
+            data.insert(Tag::UMI_SEQUENCE, as_value(umi));
+            data.insert(Tag::UMI_ID, as_value(umi));
+            data.insert(Tag::UMI_QUALITY_SCORES, as_value(umi_qual));
+        }
+        if let Some(read_group) = &self.read_group {
+            data.insert(Tag::READ_GROUP, Value::from(read_group.as_str()));
+        }
+        data
+    }
+
+    /// Write one read of a pair. Anything after the first space in the name, such as a FASTQ comment, is dropped
+    fn write_read(&mut self, name:&[u8], seq:&[u8], qual:&[u8], first_segment:bool, data:&noodles::sam::alignment::record_buf::Data) {
+        use noodles::sam::alignment::RecordBuf;
+        use noodles::sam::alignment::record::Flags;
+        use noodles::sam::alignment::record_buf::{QualityScores, Sequence};
+
+        let name_len = name.iter().position(|&c| c==b' ').unwrap_or(name.len());
+        let segment = if first_segment {Flags::FIRST_SEGMENT} else {Flags::LAST_SEGMENT};
+        let record = RecordBuf::builder()
+            .set_name(&name[..name_len])
+            .set_flags(Flags::SEGMENTED | Flags::UNMAPPED | Flags::MATE_UNMAPPED | segment)
+            .set_sequence(Sequence::from(seq.to_vec()))
+            .set_quality_scores(QualityScores::from(qual.iter().map(|q| q.saturating_sub(33)).collect::<Vec<u8>>()))
+            .set_data(data.clone())
+            .build();
+        self.writer.write_alignment_record(&self.header, &record).expect("Could not write BAM record");
+    }
+
+    fn finish(mut self) {
+        self.writer.finish(&self.header).expect("Could not finish BAM file");
+    }
+}
+
+
+/// Where corrected reads go: two gzipped FASTQ files, interleaved into the stdin of an aligner, or an unaligned BAM
+enum ReadSink {
+    Files(OutputWriter, OutputWriter),
+    Aligner(process::Child, process::ChildStdin),
+    Bam(UbamWriter)
+}
+
+impl ReadSink {
+
+    /// Open output files, or spawn the aligner command in a shell. {out} in the command is replaced by the aligner output path.
+    /// Output files are bgzf compressed if they are to be indexed
+    #[allow(clippy::too_many_arguments)]
+    fn open(path_out_r1:&Option<PathBuf>, path_out_r2:&Option<PathBuf>, align_cmd:&Option<String>, align_out:&Option<PathBuf>, path_out_bam:&Option<PathBuf>, bgzf:bool, metadata:&RunMetadata, compress:&CompressOptions) -> ReadSink {
+        if let Some(path_out_bam) = path_out_bam {
+            return ReadSink::Bam(UbamWriter::create(path_out_bam, metadata, compress));
+        }
+        match align_cmd {
+            Some(align_cmd) => {
+                let cmd = match align_out {
+                    Some(align_out) => align_cmd.replace("{out}", &align_out.to_string_lossy()),
+                    None => align_cmd.clone()
+                };
+                println!("Starting aligner: {}", cmd);
+                let mut command = process::Command::new("sh");
+                command.arg("-c").arg(&cmd).stdin(process::Stdio::piped());
+                //In a process group of its own, an interrupt from the terminal does not reach the aligner. It
+                //aligns the reads written so far, and ends once its input is closed
+                #[cfg(unix)]
+                std::os::unix::process::CommandExt::process_group(&mut command, 0);
+                let mut child = command.spawn().expect("Failed to start aligner");
+                let stdin = child.stdin.take().expect("Failed to open aligner stdin");
+                ReadSink::Aligner(child, stdin)
+            },
+            None => {
+                let path_out_r1 = path_out_r1.as_ref().expect("No R1 output");
+                let path_out_r2 = path_out_r2.as_ref().expect("No R2 output");
+                if bgzf {
+                    ReadSink::Files(compress.output_bgzf(path_out_r1, 2), compress.output_bgzf(path_out_r2, 2))
+                } else {
+                    ReadSink::Files(compress.output(path_out_r1, 2), compress.output(path_out_r2, 2))
+                }
+            }
+        }
+    }
+
+    /// An aligner gets read pairs interleaved in one stream
+    fn is_interleaved(&self) -> bool {
+        matches!(self, ReadSink::Aligner(_, _))
+    }
+
+    /// Add a read to the batch as FASTQ, or write it to the BAM file with the tags of the pair
+    fn add_read(&mut self, batch:&mut Vec<u8>, name:&[u8], seq:&[u8], qual:&[u8], first_segment:bool, bam_tags:&Option<noodles::sam::alignment::record_buf::Data>) {
+        match (self, bam_tags) {
+            (ReadSink::Bam(ubam), Some(bam_tags)) => ubam.write_read(name, seq, qual, first_segment, bam_tags),
+            _ => write_fastq(batch, name, seq, qual)
+        }
+    }
+
+    /// Hand over the batches if they are large enough, or if forced
+    fn flush(&mut self, batch_r1: &mut Vec<u8>, batch_r2: &mut Vec<u8>, force: bool) {
+        match self {
+            ReadSink::Files(parz_r1, parz_r2) => {
+                flush_fastq_batch(parz_r1, batch_r1, force);
+                flush_fastq_batch(parz_r2, batch_r2, force);
+            },
+            ReadSink::Aligner(_, stdin) => {
+                if force || batch_r1.len() >= OUTPUT_BATCH_SIZE {
+                    if let Err(e) = stdin.write_all(batch_r1) {
+                        error!("Failed to write to aligner: {}", e);
+                        process::exit(1)
+                    }
+                    batch_r1.clear();
+                }
+            },
+            ReadSink::Bam(_) => {}
+        }
+    }
+
+    /// Finish compression, or close the aligner stdin and wait for it to finish
+    fn finish(self) {
+        match self {
+            ReadSink::Files(parz_r1, parz_r2) => {
+                parz_r1.finish().expect("Unable to write data");
+                parz_r2.finish().expect("Unable to write data");
+            },
+            ReadSink::Aligner(mut child, stdin) => {
+                drop(stdin);
+                let status = child.wait().expect("Failed to wait for aligner");
+                if !status.success() {
+                    error!("Aligner failed: {}", status);
+                    process::exit(1)
+                }
+            },
+            ReadSink::Bam(ubam) => ubam.finish()
+        }
+    }
+}
+
+
+/// Hash of what identifies a PCR/optical duplicate: barcode, UMI and the start of R1
+fn dedup_key(bc:&[u8], umi:&[u8], r1_prefix:&[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bc.hash(&mut hasher);
+    umi.hash(&mut hasher);
+    r1_prefix.hash(&mut hasher);
+    hasher.finish()
+}
+
+
+/// Short numeric IDs of barcodes, in order of first appearance. Used to keep read names small. The IDs are given
+/// as reads are written, so they are not stable: they depend on the order of the input, and with unordered output
+/// on the threads too. Only the translation table of the same run tells which barcode an ID is
+#[derive(Default)]
+struct BarcodeIds {
+    ids: HashMap<PackedBarcode, usize>,
+    barcodes: Vec<PackedBarcode>
+}
+
+impl BarcodeIds {
+
+    /// Get the ID of a barcode, giving it a new one if not seen before
+    fn id_of(&mut self, bc: PackedBarcode) -> usize {
+        *self.ids.entry(bc).or_insert_with(|| {
+            self.barcodes.push(bc);
+            self.barcodes.len() - 1
+        })
+    }
+
+    /// Write the translation table, barcode to ID
+    fn store(&self, mut writer: TableWriter, atrandi_barcodes: &AtrandiBarcodes) -> std::io::Result<()> {
+        writer.write_all("barcode\tid\n".as_bytes())?;
+        let mut name = Vec::new();
+        for (id, bc) in self.barcodes.iter().enumerate() {
+            atrandi_barcodes.write_packed_name(*bc, &mut name);
+            writer.write_all(&name)?;
+            writeln!(writer, "\t{}", id)?;
+        }
+        writer.finish()
+    }
+}
+
+
+/// Counts per cell and feature while reading, with cells as packed barcodes. They are only named for the count table
+#[derive(Default)]
+struct PackedCounts {
+    counts: HashMap<PackedBarcode, HashMap<usize,i32>>
+}
+
+impl PackedCounts {
+
+    fn add(&mut self, bc: PackedBarcode, feature: usize, n: i32) {
+        *self.counts.entry(bc).or_default().entry(feature).or_insert(0) += n;
+    }
+
+    fn into_matrix(self, features: Vec<FeatureInfo>, atrandi_barcodes: &AtrandiBarcodes) -> CountMatrix {
+        let mut matrix = CountMatrix::new(features);
+        let mut name = Vec::new();
+        for (bc, cellmap) in self.counts {
+            atrandi_barcodes.write_packed_name(bc, &mut name);
+            matrix.counts.insert(String::from_utf8_lossy(&name).into_owned(), cellmap);
+        }
+        matrix
+    }
+}
+
+
+/// Size of the sketch counting rare barcodes beyond --max-distinct-barcodes, and the count at which
+/// such a barcode is moved into the exact histogram
+const TAIL_SKETCH_WIDTH: usize = 1 << 22;
+const TAIL_SKETCH_DEPTH: usize = 4;
+const TAIL_PROMOTE_COUNT: u32 = 10;
+
+/// Fraction of --max-memory at which ToFastq switches to its low-memory strategies, and how often memory use
+/// is checked. The sketch for barcodes counted approximately from then on takes at most a tenth of the limit
+const MEMORY_SOFT_FRACTION: f64 = 0.8;
+const MEMORY_CHECK_READS: u64 = 100000;
+
+/// Rate of unique reads wrongly dropped by the low-memory duplicate filter above which to warn
+const DEDUP_MAX_FALSE_POSITIVES: f64 = 0.001;
+
+/// Approximate heap bytes of a hash table with this many slots: the entries, and a control byte each
+fn table_bytes<T>(capacity: usize) -> usize {
+    capacity * (std::mem::size_of::<T>() + 1)
+}
+
+/// Hash functions of the Bloom filter for duplicate read names. With the default 256 MiB filter, about 1% of
+/// unique names are wrongly flagged once 200M read pairs have been seen
+const NAME_FILTER_HASHES: usize = 7;
+
+/// Minimum overlap with the barcode block at the end of R1 to call read-through
+const READ_THROUGH_MIN_OVERLAP: usize = 10;
+
+/// Edits allowed when searching R1 for the linker scaffold. Only the 12 linker bases are fixed, so with 2 edits
+/// on the order of 1% of random 150bp reads would match somewhere; with 1, under 0.1%
+const R1_BLOCK_MAX_DIST: u8 = 1;
+
+/// What to do with R1 reads that contain the barcode construct, a library artifact
+#[derive(Clone, Copy, ValueEnum)]
+enum R1BlockPolicy {
+    /// Do not look for it
+    Ignore,
+    /// Count such reads
+    Count,
+    /// Count such reads, and cut R1 where the construct starts
+    Trim
+}
+
+/// Summary of a ToFastq run, written as JSON
+#[derive(Serialize)]
+struct FastqReport {
+    reads: u64,
+    reads_with_barcode: u64,
+    reads_low_barcode_quality: u64,
+    reads_low_complexity: u64,
+    reads_unused_well: u64,
+    outcomes: OutcomeCounts,
+    quality: QualityReport,
+    lengths: LengthReport,
+    lengths_trimmed: LengthReport, //Of the reads written, after trimming
+    partial: bool, //Interrupted; only the reads before that are included
+    output_order: OutputOrder,
+    whitelists: Vec<WhitelistReport>,
+    duplicate_names: Option<u64>, //Read pairs whose name was (probably) seen before, if checked
+    r1_with_block: Option<u64> //Assigned read pairs with the barcode construct in R1, if checked
+}
+
+/// Sample sheet row for downstream pipelines. The first columns are those of nf-core, e.g. scrnaseq; expected_cells
+/// is estimated from the reads per barcode
+#[derive(Serialize)]
+struct SampleSheetRow {
+    sample: String,
+    fastq_1: String,
+    fastq_2: String,
+    expected_cells: usize,
+    reads: u64,
+    reads_with_barcode: u64,
+    chemistry: String,
+    linkers: String,
+    barcode_files: String
+}
+
+/// Write the sample sheet as CSV, or as JSON if the name ends in .json
+fn store_sample_sheet(path:&PathBuf, row:&SampleSheetRow, compress:&CompressOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let output = BufWriter::new(compress.create(path));
+    if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::to_writer_pretty(output, &[row])?;
+    } else {
+        let mut writer = csv::Writer::from_writer(output);
+        writer.serialize(row)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Which barcode definitions were used
+#[derive(Serialize)]
+struct WhitelistReport {
+    plate: String,
+    sha256: String
+}
+
+/// Number of input reads of each length, indexed by length
+#[derive(Serialize, Default)]
+struct LengthReport {
+    r1: Vec<u64>,
+    r2: Vec<u64>
+}
+
+impl LengthReport {
+    fn add(hist:&mut Vec<u64>, len:usize) {
+        if hist.len() <= len {
+            hist.resize(len+1, 0);
+        }
+        hist[len] += 1;
+    }
+}
+
+/// Base qualities of the barcode block, compared to the rest of the reads
+#[derive(Serialize)]
+struct QualityReport {
+    barcode: QualitySummary,
+    r1: QualitySummary,
+    r2_insert: QualitySummary
+}
+
+/// Names of the outputs in an output directory, as templates where {sample} is the sample name. All subcommands
+/// name their reads, histogram, reports and logs from these, so that a new output only needs a template here
+const OUT_R1: &str = "{sample}_R1.fastq.gz";
+const OUT_R2: &str = "{sample}_R2.fastq.gz";
+const OUT_HISTOGRAM: &str = "barcode_histogram.tsv";
+const OUT_REPORT_JSON: &str = "report.json";
+const OUT_ALIGNED_BAM: &str = "aligned.bam";
+const OUT_COUNTS: &str = "counts";
+const OUT_SUMMARY: &str = "summary.tsv";
+const OUT_UMI_STATS: &str = "umi_stats.tsv";
+const OUT_CELL_QC: &str = "cell_qc.tsv";
+const OUT_MAPPING_STATS: &str = "mapping_stats.tsv";
+const OUT_BACKGROUND: &str = "background.tsv";
+const OUT_SPLICED: &str = "spliced";
+const OUT_UNSPLICED: &str = "unspliced";
+const OUT_FEATURE_LENGTHS: &str = "feature_lengths.tsv";
+const OUT_NORMALIZED: &str = "normalized.tsv";
+const OUT_GUIDE_SUMMARY: &str = "guide_summary.tsv";
+const OUT_SPIKE_IN: &str = "spike_in";
+const OUT_INSERT_SIZES: &str = "insert_sizes.tsv";
+const OUT_CHECKSUMS: &str = "outputs.sha256";
+const OUT_SAMPLE_SHEET: &str = "samplesheet.csv";
+
+/// Outputs of one run. Those in an output directory are named from templates; others are added one by one. Before
+/// the run, the list is checked so that no earlier results are overwritten by mistake, and after it, the outputs
+/// on the list are checksummed if asked to
+#[derive(Default)]
+struct RunOutputs {
+    dir: Option<PathBuf>,
+    sample: Option<String>,
+    files: Vec<PathBuf>
+}
+
+impl RunOutputs {
+
+    /// Outputs of a sample, in a directory
+    fn new(dir:&Path, sample:&str) -> RunOutputs {
+        RunOutputs {
+            dir: Some(dir.to_path_buf()),
+            sample: Some(sample.to_string()),
+            files: Vec::new()
+        }
+    }
+
+    /// Outputs that are not named after a sample, e.g. the files next to a count table
+    fn in_dir(dir:&Path) -> RunOutputs {
+        RunOutputs {
+            dir: Some(dir.to_path_buf()),
+            sample: None,
+            files: Vec::new()
+        }
+    }
+
+    /// Create the output directory if needed
+    fn create_dir(&self) {
+        if let Some(dir) = &self.dir {
+            std::fs::create_dir_all(dir).expect("Failed to create output directory");
+        }
+    }
+
+    /// Path of an output in the output directory, given its name template
+    fn path(&self, template:&str) -> PathBuf {
+        let dir = self.dir.as_ref().expect("No output directory");
+        match &self.sample {
+            Some(sample) => dir.join(template.replace("{sample}", sample)),
+            None => {
+                assert!(!template.contains("{sample}"), "Output {} needs a sample name", template);
+                dir.join(template)
+            }
+        }
+    }
+
+    /// Add outputs to the list, leaving out those not written
+    fn add<'a>(&mut self, paths:impl IntoIterator<Item = &'a Option<PathBuf>>) {
+        self.files.extend(paths.into_iter().flatten().cloned());
+    }
+
+    /// Where the checksum manifest goes: in the output directory, or else next to the first output
+    fn manifest_path(&self) -> Option<PathBuf> {
+        match &self.dir {
+            Some(_) => Some(self.path(OUT_CHECKSUMS)),
+            None => self.files.first().map(|p| p.parent().map(|d| d.to_path_buf()).unwrap_or_default().join(OUT_CHECKSUMS))
+        }
+    }
+
+    /// Checksum the outputs that were not checksummed as they were written. Outputs that are directories, such as
+    /// count tables, are checksummed file by file
+    fn checksum(&self, checksums:&ChecksumManifest) {
+        fn add(path:&PathBuf, checksums:&ChecksumManifest) {
+            if path.is_dir() {
+                let mut entries = std::fs::read_dir(path).expect("Could not list output directory")
+                    .map(|entry| entry.expect("Could not list output directory").path()).collect_vec();
+                entries.sort();
+                for entry in &entries {
+                    add(entry, checksums);
+                }
+            } else if path.is_file() {
+                checksums.add_file(path).expect("Could not checksum output");
+            }
+        }
+        for path in &self.files {
+            add(path, checksums);
+        }
+    }
+
+    /// Stop if any of the outputs already exist, listing them, so that the results of an earlier run are not
+    /// clobbered by mistake. With force, they are overwritten. The checksum manifest is checked too if written
+    fn check_overwrite(&self, force:bool, checksums:bool) {
+        if force {
+            return;
+        }
+        let manifest = if checksums {self.manifest_path()} else {None};
+        let existing = self.files.iter().chain(manifest.iter()).filter(|p| p.exists()).collect_vec();
+        if !existing.is_empty() {
+            error!("Refusing to overwrite existing outputs (use --force to overwrite):");
+            for path in existing {
+                error!("  {}", path.display());
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Options of ToFastq, apart from the inputs, histogram and the options shared with other subcommands. The
+/// default writes nothing but the reads and histogram, with no trimming beyond the barcode block and no filters
+struct ToFastqOptions {
+    path_out_r1: Option<PathBuf>,
+    path_out_r2: Option<PathBuf>,
+    align_cmd: Option<String>,
+    align_out: Option<PathBuf>,
+    path_out_bam: Option<PathBuf>,
+    no_trim: bool,
+    trim_extra: usize,
+    min_qual: Option<u8>,
+    qual_window: usize,
+    min_assign_rate: f64,
+    allow_empty: bool,
+    allow_partial: bool,
+    cycle_stats_file: Option<PathBuf>,
+    bc_consensus: Option<PathBuf>,
+    bc_consensus_errors: Option<PathBuf>,
+    bc_consensus_min_reads: u32,
+    cell_index_file: Option<PathBuf>,
+    assignment_log_file: Option<PathBuf>,
+    trim_read_through: bool,
+    r1_block: R1BlockPolicy,
+    umi_len: usize,
+    dedup_prefix: Option<usize>,
+    dedup_report: Option<PathBuf>,
+    max_reads_per_cell: Option<u64>,
+    max_distinct_barcodes: Option<usize>,
+    max_memory: Option<usize>,
+    translation_table: Option<PathBuf>,
+    short_names: bool,
+    raw_barcode_tag: bool,
+    name_filter_mb: Option<usize>,
+    uniquify_names: bool,
+    min_bc_mean_qual: Option<u8>,
+    max_repeat_fraction: Option<f64>,
+    report_json: Option<PathBuf>,
+    sample_sheet: Option<PathBuf>,
+    sample: String,
+    expected_cells: usize,
+    shard: Option<(u64, u64)>,
+    correct_threads: usize,
+    output_order: OutputOrder,
+    progress_json: Option<u64>
+}
+
+impl Default for ToFastqOptions {
+    fn default() -> ToFastqOptions {
+        ToFastqOptions {
+            path_out_r1: None,
+            path_out_r2: None,
+            align_cmd: None,
+            align_out: None,
+            path_out_bam: None,
+            no_trim: false,
+            trim_extra: 0,
+            min_qual: None,
+            qual_window: 4,
+            min_assign_rate: 0.0,
+            allow_empty: false,
+            allow_partial: false,
+            cycle_stats_file: None,
+            bc_consensus: None,
+            bc_consensus_errors: None,
+            bc_consensus_min_reads: 10,
+            cell_index_file: None,
+            assignment_log_file: None,
+            trim_read_through: false,
+            r1_block: R1BlockPolicy::Ignore,
+            umi_len: 0,
+            dedup_prefix: None,
+            dedup_report: None,
+            max_reads_per_cell: None,
+            max_distinct_barcodes: None,
+            max_memory: None,
+            translation_table: None,
+            short_names: false,
+            raw_barcode_tag: false,
+            name_filter_mb: None,
+            uniquify_names: false,
+            min_bc_mean_qual: None,
+            max_repeat_fraction: None,
+            report_json: None,
+            sample_sheet: None,
+            sample: "sample".to_string(),
+            expected_cells: DEFAULT_EXPECTED_CELLS,
+            shard: None,
+            correct_threads: 0,
+            output_order: OutputOrder::Input,
+            progress_json: None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn parse_to_fastq(
+    path_in_r1:&PathBuf,
+    path_in_r2:&PathBuf,
+    histogram_file:&PathBuf,
+    options:&ToFastqOptions,
+    metadata:&RunMetadata,
+    input:&InputOptions,
+    compress:&CompressOptions,
+    barcode_spec:&BarcodeSpec
+) {
+
+    let ToFastqOptions {
+        ref path_out_r1, ref path_out_r2, ref align_cmd, ref align_out, ref path_out_bam,
+        no_trim, trim_extra, min_qual, qual_window, min_assign_rate, allow_empty, allow_partial,
+        ref cycle_stats_file, ref bc_consensus, ref bc_consensus_errors, bc_consensus_min_reads, ref cell_index_file, ref assignment_log_file,
+        trim_read_through, r1_block, umi_len, dedup_prefix, ref dedup_report, max_reads_per_cell, max_distinct_barcodes, max_memory,
+        ref translation_table, short_names, raw_barcode_tag, name_filter_mb, uniquify_names, min_bc_mean_qual, max_repeat_fraction,
+        ref report_json, ref sample_sheet, ref sample, expected_cells, shard, correct_threads, output_order, progress_json
+    } = *options;
+
+    let print_debug = false;
+
+    println!("reading whitelist ");
+    let atrandi_barcodes = Arc::new(barcode_spec.load().expect("Failed to read barcode file"));
+    for plate in &atrandi_barcodes.plates {
+        debug!("Whitelist of plate {}: SHA-256 {}", plate.name, plate.sha256);
+    }
+
+    /////////// Set up input. Barcodes are optionally corrected ahead on worker threads
+    let mut reader = CorrectingReader::new(PairedFastqReader::open(path_in_r1, path_in_r2, input), shard, correct_threads, output_order, &atrandi_barcodes);
+    if correct_threads > 0 {
+        println!("Correcting barcodes on {} threads; output in {} order", correct_threads, 
+            match reader.order() {OutputOrder::Input => "input", OutputOrder::Unordered => "no particular"});
+    }
+
+    /////////// Set up output. The aligner command may ask for the read group line with {rg}
+    let align_cmd = align_cmd.as_ref().map(|cmd| cmd.replace("{rg}", &shell_quote(&metadata.read_group_line())));
+    let mut sink = ReadSink::open(path_out_r1, path_out_r2, &align_cmd, align_out, path_out_bam, cell_index_file.is_some(), metadata, compress);
+    let fastq_comment = metadata.fastq_comment();
+    let mut read_comment = fastq_comment.clone();
+    let interleaved = sink.is_interleaved();
+
+    let mut batch_r1: Vec<u8> = Vec::with_capacity(OUTPUT_BATCH_SIZE + 1024);
+    let mut batch_r2: Vec<u8> = Vec::with_capacity(OUTPUT_BATCH_SIZE + 1024);
+
+    //Reads of each cell by position in the uncompressed output, for random access by cell
+    let mut cell_index = cell_index_file.as_ref().map(|_| CellIndex::default());
+    let mut written_r1: u64 = 0;
+    let mut written_r2: u64 = 0;
+
+    //Assignment of each read pair, indexed by cell like the reads
+    let mut assignment_log = assignment_log_file.as_ref().map(|path| compress.output_bgzf(path, 3));
+    let mut log_index = assignment_log_file.as_ref().map(|_| LogIndex::default());
+    let mut batch_log: Vec<u8> = Vec::new();
+    let mut written_log: u64 = 0;
+
+
+    //Cells are kept as packed barcodes while counting, and named when written
+    let mut barcode_per_cell_count: HashMap<PackedBarcode, i32> = HashMap::new();
+
+    //Once there are too many distinct barcodes, new ones are counted approximately until they are frequent enough
+    let mut max_distinct_barcodes = max_distinct_barcodes;
+    let mut tail_sketch = max_distinct_barcodes.map(|_| CountMinSketch::new(TAIL_SKETCH_WIDTH, TAIL_SKETCH_DEPTH));
+    let mut count_tail_reads: u64 = 0;
+    let mut warned_distinct = false;
+
+    //IDs of barcodes, for the translation table and short read names
+    let mut barcode_ids = if translation_table.is_some() || short_names {Some(BarcodeIds::default())} else {None};
+
+    //Scratch buffers, reused for each read
+    let mut concat_bc: Vec<u8> = Vec::new();
+    let mut new_name: Vec<u8> = Vec::new();
+    let mut expected_block: Vec<u8> = Vec::new();
+    let mut rc_block: Vec<u8> = Vec::new();
+    let mut rc_r1: Vec<u8> = Vec::new();
+    let mut short_bc: Vec<u8> = Vec::new();
+
+    let mut cycle_stats = CycleStats::new();
+    let mut bc_profiles: Option<HashMap<PackedBarcode, BlockProfile>> = 
+        if bc_consensus.is_some() || bc_consensus_errors.is_some() {Some(HashMap::new())} else {None};
+    let mut max_profile_len = 0; //Longest barcode block in a profile, for the memory estimate
+    let mut qual_barcode = QualityStats::default();
+    let mut qual_r1 = QualityStats::default();
+    let mut qual_r2_insert = QualityStats::default();
+    let mut read_lengths = LengthReport::default();
+    let mut trimmed_lengths = LengthReport::default();
+
+
+    /////////// Handle all reads
+    let mut read_count = 0;
+    let mut count_ok_reads = 0;
+    let mut count_short_reads = 0;
+    let mut count_partial_reads = 0;
+    let mut count_read_through = 0;
+    let mut count_r1_block = 0;
+    let mut count_duplicates = 0;
+    let mut count_capped = 0;
+    let mut count_unused_well = 0;
+    let mut outcome_counts = OutcomeCounts::default();
+
+    //Read pairs written per cell, when capped
+    let mut written_per_cell: HashMap<PackedBarcode, u64> = HashMap::new();
+
+    //Hashes of barcode, UMI and R1 start seen so far; and per cell, reads and duplicates
+    //When memory runs low, the set of hashes becomes a Bloom filter
+    let mut dedup_seen = SeenKeys::default();
+    let mut dedup_per_cell: HashMap<PackedBarcode, (u64, u64)> = HashMap::new();
+    let mut warned_dedup_filter = false;
+    let mut low_memory = false;
+    //Read names seen so far, if checking for duplicates
+    let mut name_filter = name_filter_mb.map(|mb| BloomFilter::new(mb*1024*1024*8, NAME_FILTER_HASHES));
+    let mut count_duplicate_names: u64 = 0;
+    //Linker scaffold, to find the barcode construct in R1 if asked to
+    let mut r1_block_finder = match r1_block {
+        R1BlockPolicy::Ignore => None,
+        _ => Some(BarcodeBlockFinder::new(&atrandi_barcodes.chemistry))
+    };
+
+    let mut progress = progress_json.map(ProgressJson::new);
+
+    let mut interrupted = false;
+    while let Some((record_r1, record_r2, correction)) = reader.next() {
+
+        read_count = read_count + 1;
+        if read_count%100000 == 0 {
+            println!("Processed reads: {}   Ok reads: {}   fraction: {}", read_count, count_ok_reads, count_ok_reads as f64/read_count as f64);
+        }
+        if let (Some(progress), 0) = (&mut progress, read_count%PROGRESS_CHECK_READS) {
+            progress.tick(read_count, count_ok_reads, reader.fraction_read());
+        }
+
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            warn!("Interrupted after {} reads; finishing outputs", read_count);
+            interrupted = true;
+            break;
+        }
+
+        //Keep the counting structures within --max-memory: switch to low-memory strategies as the limit
+        //approaches, and stop early if even that is not enough
+        if let (Some(max_memory), 0) = (max_memory, read_count%MEMORY_CHECK_READS) {
+            let used = table_bytes::<(PackedBarcode, i32)>(barcode_per_cell_count.capacity())
+                + table_bytes::<(PackedBarcode, u64)>(written_per_cell.capacity())
+                + dedup_seen.num_bytes()
+                + table_bytes::<(PackedBarcode, (u64, u64))>(dedup_per_cell.capacity())
+                + bc_profiles.as_ref().map_or(0, |p| table_bytes::<(PackedBarcode, BlockProfile)>(p.capacity()) + p.len()*max_profile_len*std::mem::size_of::<[u32;5]>())
+                + tail_sketch.as_ref().map_or(0, |s| s.num_bytes())
+                + name_filter.as_ref().map_or(0, |f| f.num_bytes());
+            if !low_memory && used as f64 > MEMORY_SOFT_FRACTION*max_memory as f64 {
+                warn!("Counting uses about {} MiB, near --max-memory; new barcodes are counted approximately from now on{}", 
+                    used/1024/1024, if dedup_prefix.is_some() {", and duplicates found with a Bloom filter"} else {""});
+                low_memory = true;
+                max_distinct_barcodes = Some(max_distinct_barcodes.map_or(barcode_per_cell_count.len(), |m| m.min(barcode_per_cell_count.len())));
+                if tail_sketch.is_none() {
+                    let width = TAIL_SKETCH_WIDTH.min(max_memory/10/(TAIL_SKETCH_DEPTH*std::mem::size_of::<u32>())).max(1);
+                    tail_sketch = Some(CountMinSketch::new(width, TAIL_SKETCH_DEPTH));
+                }
+                //The filter gets half of the memory left once the set is freed, and is sized for the reads still
+                //to come if the input size is known. Too small, it would drop more and more unique reads
+                if dedup_prefix.is_some() {
+                    let budget = max_memory.saturating_sub(used - dedup_seen.num_bytes())/2;
+                    let expected_keys = reader.fraction_read().filter(|f| *f > 0.0).map(|f| (count_ok_reads as f64/f) as usize);
+                    let false_positive_rate = dedup_seen.make_approximate(expected_keys, budget);
+                    debug!("Duplicate filter of {} MiB; expected false positive rate {:.4}", dedup_seen.num_bytes()/1024/1024, false_positive_rate);
+                    if false_positive_rate > DEDUP_MAX_FALSE_POSITIVES {
+                        warn!("The duplicate filter is expected to wrongly drop {:.2}% of unique reads by the end of the input; raise --max-memory to avoid this", 
+                            100.0*false_positive_rate);
+                        warned_dedup_filter = true;
+                    }
+                }
+            } else if low_memory && used > max_memory {
+                warn!("Counting uses about {} MiB, over --max-memory even with low-memory strategies; stopping after {} reads", 
+                    used/1024/1024, read_count);
+                interrupted = true;
+                break;
+            }
+            if !warned_dedup_filter && dedup_seen.false_positive_rate() > DEDUP_MAX_FALSE_POSITIVES {
+                warn!("The duplicate filter is filling up; {:.2}% of unique reads are now wrongly dropped as duplicates. Raise --max-memory to avoid this", 
+                    100.0*dedup_seen.false_positive_rate());
+                warned_dedup_filter = true;
+            }
+        }
+
+        //Duplicate names break tag assignment and dedup downstream. Optionally number them to make them unique
+        let mut duplicate_serial = None;
+        if let Some(name_filter) = &mut name_filter {
+            let head = record_r1.head();
+            let id_len = head.iter().position(|&c| c==b' ').unwrap_or(head.len());
+            if name_filter.insert(&head[..id_len]) {
+                count_duplicate_names += 1;
+                if uniquify_names {
+                    duplicate_serial = Some(count_duplicate_names);
+                }
+            }
+        }
+
+        //Base qualities of the barcode block, the rest of R2, and R1. The block is as long as the pattern says
+        let block_len = atrandi_barcodes.block_end(record_r2.seq()).min(record_r2.qual().len());
+        if report_json.is_some() {
+            qual_barcode.add(&record_r2.qual()[..block_len]);
+            qual_r2_insert.add(&record_r2.qual()[block_len..]);
+            qual_r1.add(record_r1.qual());
+            LengthReport::add(&mut read_lengths.r1, record_r1.seq().len());
+            LengthReport::add(&mut read_lengths.r2, record_r2.seq().len());
+        }
+
+        //Skip reads that are mostly one base or a dinucleotide repeat before trying to correct them. Barcodes read
+        //in bad cycles are likely to be corrected to the wrong cell, so these are left out too. Both are still logged
+        let filtered = if max_repeat_fraction.is_some_and(|max| repeat_fraction(&record_r2.seq()[..block_len]) > max) {
+            Some(CorrectionOutcome::LowComplexity)
+        } else if min_bc_mean_qual.is_some_and(|min| mean_quality(&record_r2.qual()[..block_len]) < min as f64) {
+            Some(CorrectionOutcome::LowBarcodeQuality)
+        } else {
+            None
+        };
+    
+        //Reads too short for the full barcode block are rejected, unless partial barcodes are allowed
+        let mut read_outcome = CorrectionOutcome::TooShort;
+        let mut is_partial = false;
+        let assigned: Option<PackedBarcode> = if let Some(outcome) = filtered {
+            outcome_counts.add(outcome);
+            read_outcome = outcome;
+            None
+        } else if atrandi_barcodes.has_full_block(record_r2.seq()) {
+            let (bc, outcome) = match correction {
+                Some(correction) => correction,
+                None => atrandi_barcodes.correct_with_outcome(record_r2.seq(), Some(record_r2.qual()), print_debug)
+            };
+            outcome_counts.add(outcome);
+            read_outcome = outcome;
+            match bc {
+                Some(bc) => {
+                    atrandi_barcodes.write_bc_name(&bc, &mut concat_bc);
+                    atrandi_barcodes.write_expected_block_in(&bc, record_r2.seq(), &mut expected_block);
+                    if cycle_stats_file.is_some() {
+                        cycle_stats.add(record_r2.seq(), &expected_block);
+                    }
+                    Some(bc.pack())
+                },
+                None => None
+            }
+        } else {
+            count_short_reads += 1;
+            outcome_counts.add(CorrectionOutcome::TooShort);
+            if allow_partial {
+                match atrandi_barcodes.get_partial_bc_from_read(record_r2.seq(), Some(record_r2.qual())) {
+                    Some(bc) => {
+                        count_partial_reads += 1;
+                        is_partial = true;
+                        expected_block.clear();
+                        atrandi_barcodes.write_partial_bc_name(&bc, &mut concat_bc);
+                        Some(bc.pack())
+                    },
+                    None => None
+                }
+            } else {
+                None
+            }
+        };
+
+        //A well that was not used in the experiment means the barcode was corrected to the wrong one
+        let mut in_unused_well = false;
+        let assigned = match assigned {
+            Some(packed_bc) if !atrandi_barcodes.is_used(packed_bc) => {
+                count_unused_well += 1;
+                in_unused_well = true;
+                None
+            },
+            assigned => assigned
+        };
+
+        //Log the read name, cell (* if none), outcome and barcode block as read
+        if let (Some(log), Some(log_index)) = (&mut assignment_log, &mut log_index) {
+            let head = record_r1.head();
+            let id_len = head.iter().position(|&c| c==b' ').unwrap_or(head.len());
+            let cell = if assigned.is_some() {String::from_utf8_lossy(&concat_bc)} else {"*".into()};
+            let label = if in_unused_well {
+                "unused_well".to_string()
+            } else if is_partial {
+                "partial".to_string()
+            } else {
+                read_outcome.label()
+            };
+            log_index.add(&cell, written_log + batch_log.len() as u64);
+            batch_log.extend_from_slice(&head[..id_len]);
+            writeln!(batch_log, "\t{}\t{}\t{}", cell, label, String::from_utf8_lossy(&record_r2.seq()[..block_len])).expect("Unable to write data");
+            if batch_log.len() >= OUTPUT_BATCH_SIZE {
+                log.write_all(&batch_log).expect("Unable to write data");
+                written_log += batch_log.len() as u64;
+                batch_log.clear();
+            }
+        }
+
+        if let Some(packed_bc) = assigned {
+            count_ok_reads += 1;
+
+            //Count barcodes
+            match barcode_per_cell_count.get_mut(&packed_bc) {
+                Some(cnt) => {
+                    *cnt += 1;
+                },
+                None => {
+                    match (max_distinct_barcodes, &mut tail_sketch) {
+                        (Some(max_distinct), Some(sketch)) if barcode_per_cell_count.len() >= max_distinct => {
+                            if !warned_distinct {
+                                warn!("More than {} distinct barcodes; counting further ones approximately", max_distinct);
+                                warned_distinct = true;
+                            }
+                            let estimate = sketch.add(&packed_bc.0.to_le_bytes());
+                            if estimate >= TAIL_PROMOTE_COUNT {
+                                //The earlier reads of the barcode are now in the histogram too
+                                barcode_per_cell_count.insert(packed_bc, estimate as i32);
+                                count_tail_reads = count_tail_reads.saturating_sub(estimate as u64 - 1);
+                            } else {
+                                count_tail_reads += 1;
+                            }
+                        },
+                        _ => {
+                            barcode_per_cell_count.insert(packed_bc, 1);
+                        }
+                    }
+                }
+            }
+
+            //Per-cell tables only hold barcodes of the histogram, so that --max-distinct-barcodes bounds them too
+            let in_histogram = barcode_per_cell_count.contains_key(&packed_bc);
+
+            //Raw barcode blocks per cell. A profile starts at the second read of a cell, so the many barcodes
+            //seen only once take no memory
+            if let Some(bc_profiles) = &mut bc_profiles {
+                let has_room = !low_memory || bc_profiles.contains_key(&packed_bc);
+                if has_room && !expected_block.is_empty() && barcode_per_cell_count.get(&packed_bc).is_some_and(|n| *n >= 2) {
+                    bc_profiles.entry(packed_bc).or_default().add(&record_r2.seq()[..block_len]);
+                    max_profile_len = max_profile_len.max(block_len);
+                }
+            }
+
+            //Drop reads with the same barcode, UMI and start of R1 as an earlier read
+            if let Some(dedup_prefix) = dedup_prefix {
+                let umi_to = (block_len+umi_len).min(record_r2.seq().len());
+                let r1_to = dedup_prefix.min(record_r1.seq().len());
+                let key = dedup_key(&packed_bc.0.to_le_bytes(), &record_r2.seq()[block_len..umi_to], &record_r1.seq()[..r1_to]);
+                let is_dup = dedup_seen.insert(key);
+                if in_histogram {
+                    let cell_stats = dedup_per_cell.entry(packed_bc).or_insert((0, 0));
+                    cell_stats.0 += 1;
+                    if is_dup {
+                        cell_stats.1 += 1;
+                    }
+                }
+                if is_dup {
+                    count_duplicates += 1;
+                    continue;
+                }
+            }
+
+            //Drop reads beyond the cap for this cell; the histogram still counts all of them
+            if let (Some(max_reads_per_cell), true) = (max_reads_per_cell, in_histogram) {
+                let written = written_per_cell.entry(packed_bc).or_insert(0);
+                if *written >= max_reads_per_cell {
+                    count_capped += 1;
+                    continue;
+                }
+                *written += 1;
+            }
+
+            //Typical FASTQ record
+            //@M03699:228:000000000-LCH6K:1:1102:12164:1000 1:N:0:CAGGTT
+            //NCAGTTACTTGCAGGAATCTCCACCTGCTCTCCATCGACTACGTCTTTCGACCTCGCCTTAGGTCCCGACTTACC
+            //+
+            //#8B<CFDGGGFGGFGGFGGGGGGGGGFGCGFFGGGGGDGFDEGGGGGGGGGGGCGCEGGGGGGGGGGGEFGGFGG
+
+
+            //Optionally name reads by the ID of the barcode rather than the barcode itself
+            if let Some(barcode_ids) = &mut barcode_ids {
+                let id = barcode_ids.id_of(packed_bc);
+                if short_names {
+                    short_bc.clear();
+                    write!(short_bc, "{}", id).expect("Unable to write data");
+                }
+            }
+            let name_bc = if short_names {&short_bc} else {&concat_bc};
+
+            //For uBAM output, the barcode and UMI go in tags: the full block as read, and what follows it
+            let bam_tags = match &sink {
+                ReadSink::Bam(ubam) => {
+                    let umi_to = (block_len+umi_len).min(record_r2.seq().len());
+                    Some(ubam.tags(&concat_bc,
+                        &record_r2.seq()[..block_len], &record_r2.qual()[..block_len],
+                        &record_r2.seq()[block_len..umi_to], &record_r2.qual()[block_len..umi_to]
+                    ))
+                },
+                _ => None
+            };
+
+            //Optionally keep the barcode as read in the comment, next to the corrected one
+            if raw_barcode_tag {
+                let comment = read_comment.get_or_insert_with(String::new);
+                comment.clear();
+                if let Some(fastq_comment) = &fastq_comment {
+                    comment.push_str(fastq_comment);
+                    comment.push('\t');
+                }
+                comment.push_str("CB:Z:");
+                comment.push_str(&String::from_utf8_lossy(&concat_bc));
+                comment.push_str("\tCR:Z:");
+                comment.push_str(&String::from_utf8_lossy(&record_r2.seq()[..block_len]));
+                comment.push_str("\tCY:Z:");
+                comment.push_str(&String::from_utf8_lossy(&record_r2.qual()[..block_len]));
+                let umi_to = (block_len+umi_len).min(record_r2.seq().len());
+                if umi_to > block_len {
+                    comment.push_str("\tUR:Z:");
+                    comment.push_str(&String::from_utf8_lossy(&record_r2.seq()[block_len..umi_to]));
+                }
+            }
+
+            //Read 1 is the same. Update name to include BC
+            make_read_name(&mut new_name, name_bc, record_r1.head());
+            add_duplicate_suffix(&mut new_name, duplicate_serial);
+            add_comment(&mut new_name, &read_comment);
+            let mut r1_len = match min_qual {
+                Some(min_qual) => quality_trim_len(record_r1.qual(), min_qual, qual_window),
+                None => record_r1.seq().len()
+            };
+
+            //Short inserts make R1 run into the barcode block. Detect, and optionally cut it off
+            let mut is_read_through = false;
+            if !expected_block.is_empty() {
+                rc_block.clear();
+                rc_block.extend(expected_block.iter().rev().map(|b| complement(*b)));
+                if let Some(insert_len) = find_read_through(record_r1.seq(), &rc_block, READ_THROUGH_MIN_OVERLAP, 2) {
+                    count_read_through += 1;
+                    is_read_through = true;
+                    if trim_read_through {
+                        r1_len = r1_len.min(insert_len);
+                    }
+                }
+            }
+
+            //Artifacts can also put the whole barcode construct in R1, on either strand. Chimeric reads like
+            //these align poorly, so optionally keep only what comes before the construct
+            if let (Some(finder), false) = (&mut r1_block_finder, is_read_through) {
+                let seq = record_r1.seq();
+                let block_start = match finder.find(seq, R1_BLOCK_MAX_DIST) {
+                    Some((start, _, _)) => Some(start),
+                    None => {
+                        rc_r1.clear();
+                        rc_r1.extend(seq.iter().rev().map(|b| complement(*b)));
+                        finder.find(&rc_r1, R1_BLOCK_MAX_DIST).map(|(_, end, _)| seq.len() - end)
+                    }
+                };
+                if let Some(block_start) = block_start {
+                    count_r1_block += 1;
+                    if let R1BlockPolicy::Trim = r1_block {
+                        r1_len = r1_len.min(block_start);
+                    }
+                }
+            }
+            let batch_len_r1 = batch_r1.len();
+            sink.add_read(&mut batch_r1,
+                &new_name,
+                &record_r1.seq()[..r1_len],
+                &record_r1.qual()[..r1_len],
+                true, &bam_tags
+            );
+
+            //For Read 2, we will chop off the BC part unless asked not to. Update name to include BC
+            make_read_name(&mut new_name, name_bc, record_r2.head());
+            add_duplicate_suffix(&mut new_name, duplicate_serial);
+            add_comment(&mut new_name, &read_comment);
+
+            let from: usize = if no_trim {0} else {atrandi_barcodes.block_end(record_r2.seq())+trim_extra};
+            let to = record_r2.seq().len();
+            let from = if from<to {from} else {to}; //to be on the safe side
+
+            //Optionally trim low quality bases in the 3' end
+            let to = match min_qual {
+                Some(min_qual) => from + quality_trim_len(&record_r2.qual()[from..to], min_qual, qual_window),
+                None => to
+            };
+            let new_r2_seq = &record_r2.seq()[from..to];
+            let new_r2_qual = &record_r2.qual()[from..to];
+            if report_json.is_some() {
+                LengthReport::add(&mut trimmed_lengths.r1, r1_len);
+                LengthReport::add(&mut trimmed_lengths.r2, new_r2_seq.len());
+            }
+
+            let batch_len_r2 = batch_r2.len();
+            sink.add_read(if interleaved {&mut batch_r1} else {&mut batch_r2},
+                &new_name,
+                new_r2_seq,
+                new_r2_qual,
+                false, &bam_tags
+            );
+
+            if let Some(cell_index) = &mut cell_index {
+                cell_index.add(std::str::from_utf8(name_bc).expect("Barcode name is not UTF-8"), written_r1, written_r2);
+                written_r1 += (batch_r1.len() - batch_len_r1) as u64;
+                written_r2 += (batch_r2.len() - batch_len_r2) as u64;
+            }
+
+            sink.flush(&mut batch_r1, &mut batch_r2, false);
+
+
+        }
+    }
+    if let Some(progress) = &progress {
+        progress.emit(if interrupted {"interrupted"} else {"done"}, read_count, count_ok_reads, reader.fraction_read());
+    }
+
+    ////// Check that enough reads were assigned; an empty output is most likely a mistake. This is done before
+    ////// the outputs are finished, so a failed run does not leave outputs, a report or a sample sheet that look complete
+    let assign_rate = if read_count>0 {count_ok_reads as f64/read_count as f64} else {0.0};
+    if !interrupted && (count_ok_reads==0 || assign_rate < min_assign_rate) {
+        error!("Only {} of {} reads ({:.2}%) could be assigned a barcode; minimum rate is {:.2}%", 
+            count_ok_reads, read_count, 100.0*assign_rate, 100.0*min_assign_rate);
+        if allow_empty {
+            warn!("Continuing anyway as --allow-empty was given");
+        } else {
+            error!("Barcode failures: round 1-4 {:?}   linker {}   ambiguous {}   too short {}", 
+                outcome_counts.failed_round, outcome_counts.failed_linker, outcome_counts.ambiguous, outcome_counts.too_short);
+            error!("Check that the inputs and barcode file are correct, or use --allow-empty");
+            process::exit(1)
+        }
+    }
+
+    sink.flush(&mut batch_r1, &mut batch_r2, true);
+    sink.finish();
+    if let (Some(cell_index), Some(path), Some(path_out_r1), Some(path_out_r2)) = (&mut cell_index, cell_index_file, path_out_r1, path_out_r2) {
+        cell_index.set_virtual_offsets(path_out_r1, Some(path_out_r2)).expect("Failed to index output reads");
+        cell_index.store(path).expect("Failed to store cell index");
+        println!("Indexed {} cells in {} chunks: {}", cell_index.cells.len(), cell_index.chunks.len(), path.display());
+    }
+    if let (Some(mut log), Some(mut log_index), Some(path)) = (assignment_log, log_index, assignment_log_file) {
+        log.write_all(&batch_log).expect("Unable to write data");
+        log.finish().expect("Unable to write data");
+        log_index.set_virtual_offsets(path).expect("Failed to index assignment log");
+        log_index.store(&assignment_log_index(path)).expect("Failed to store assignment log index");
+        println!("Assignment log: {}", path.display());
+    }
+    if let Some(align_out) = align_out {
+        println!("Aligned reads: {}", align_out.display());
+    }
+    if let Some(path_out_bam) = path_out_bam {
+        println!("Unaligned BAM: {}", path_out_bam.display());
+    }
+
+
+    ////// Write barcode histogram
+    let mut writer_h = compress.table(histogram_file);
+    writer_h.write_all("barcode\tcount\n".as_bytes()).expect("Unable to write data");
+    for (bc, cnt) in &barcode_per_cell_count {
+        atrandi_barcodes.write_packed_name(*bc, &mut concat_bc);
+        writer_h.write_all(&concat_bc).expect("Unable to write data");
+        writeln!(writer_h, "\t{}", cnt).expect("Unable to write data");
+    }
+    writer_h.finish().expect("Unable to write data");
+
+
+    ////// Write the barcode to ID translation table
+    if let (Some(translation_table), Some(barcode_ids)) = (translation_table, &barcode_ids) {
+        barcode_ids.store(compress.table(translation_table), &atrandi_barcodes).expect("creation of translation table failed");
+    }
+
+
+    ////// Write mismatch rate per cycle of the barcode block
+    if let Some(cycle_stats_file) = cycle_stats_file {
+        let mut writer = compress.table(cycle_stats_file);
+        writer.write_all("cycle\tregion\treads\tmismatches\tn_bases\tmismatch_rate\n".as_bytes()).expect("Unable to write data");
+        for i in 0..cycle_stats.reads.len() {
+            let rate = if cycle_stats.reads[i]>0 {cycle_stats.mismatches[i] as f64/cycle_stats.reads[i] as f64} else {0.0};
+            let line = format!("{}\t{}\t{}\t{}\t{}\t{}\n", 
+                i, atrandi_barcodes.cycle_region(i), cycle_stats.reads[i], cycle_stats.mismatches[i], cycle_stats.n_bases[i], rate);
+            writer.write_all(line.as_bytes()).expect("Unable to write data");
+        }
+        writer.finish().expect("Unable to write data");
+    }
+
+
+    ////// Write the consensus of the raw barcode block per cell, and how often each position differs from the
+    ////// expected block. Systematic synthesis errors in a well show up as a consensus differing from it
+    if let Some(bc_profiles) = &bc_profiles {
+        let cells = bc_profiles.iter().filter(|(_, p)| p.reads >= bc_consensus_min_reads).sorted_by_key(|(bc, _)| **bc).collect_vec();
+        let mut writer_fa = bc_consensus.as_ref().map(|path| compress.table(path));
+        let mut writer_err = bc_consensus_errors.as_ref().map(|path| compress.table(path));
+        if let Some(writer_err) = &mut writer_err {
+            let block_len = cells.iter().map(|(_, p)| p.counts.len()).max().unwrap_or(0);
+            writeln!(writer_err, "cell\treads\t{}", (0..block_len).join("\t")).expect("Unable to write data");
+        }
+        let mut num_differing = 0;
+        for (packed_bc, profile) in &cells {
+            atrandi_barcodes.write_packed_name(**packed_bc, &mut concat_bc);
+            let bc = CellBarcode {plate: packed_bc.plate(), wells: packed_bc.wells().map(|w| w.expect("Profiles are only kept for full barcodes"))};
+            let consensus = profile.consensus();
+            atrandi_barcodes.write_expected_block_in(&bc, &consensus, &mut expected_block);
+            let differences = consensus.iter().zip(&expected_block).enumerate().filter(|(_, (c, e))| c != e)
+                .map(|(i, (c, e))| format!("{}:{}>{}", i, *e as char, *c as char)).collect_vec();
+            if !differences.is_empty() {
+                num_differing += 1;
+            }
+            if let Some(writer_fa) = &mut writer_fa {
+                writeln!(writer_fa, ">{} reads={} differences={}\n{}", String::from_utf8_lossy(&concat_bc), profile.reads,
+                    if differences.is_empty() {"none".to_string()} else {differences.join(",")}, String::from_utf8_lossy(&consensus)).expect("Unable to write data");
+            }
+            if let Some(writer_err) = &mut writer_err {
+                writeln!(writer_err, "{}\t{}\t{}", String::from_utf8_lossy(&concat_bc), profile.reads,
+                    profile.mismatch_rates(&expected_block).iter().map(|r| format!("{:.4}", r)).join("\t")).expect("Unable to write data");
+            }
+        }
+        for writer in [writer_fa, writer_err].into_iter().flatten() {
+            writer.finish().expect("Unable to write data");
+        }
+        println!("Barcode consensus of {} cells with at least {} reads; {} differ from the expected barcode", 
+            cells.len(), bc_consensus_min_reads, num_differing);
+    }
+
+
+    ////// Write duplication rate per cell
+    if let Some(dedup_report) = dedup_report {
+        let mut writer = compress.table(dedup_report);
+        writer.write_all("barcode\treads\tduplicates\tduplication_rate\n".as_bytes()).expect("Unable to write data");
+        for (bc, (reads, dups)) in &dedup_per_cell {
+            atrandi_barcodes.write_packed_name(*bc, &mut concat_bc);
+            writer.write_all(&concat_bc).expect("Unable to write data");
+            writeln!(writer, "\t{}\t{}\t{}", reads, dups, *dups as f64 / *reads as f64).expect("Unable to write data");
+        }
+        writer.finish().expect("Unable to write data");
+    }
+
+
+    ////// Report outcome per category
+    if let Some((index, count)) = shard {
+        println!("Split {} of {}: handled {} of {} read pairs", index, count, read_count, reader.input_pairs);
+    }
+    println!("Reads with full barcode: {}", count_ok_reads - count_partial_reads);
+    println!("Reads too short for the barcode block: {}", count_short_reads);
+    if allow_partial {
+        println!("Short reads assigned a partial barcode: {}", count_partial_reads);
+    }
+    println!("Reads not assigned: {}", read_count - count_ok_reads);
+    println!("Barcode correction: exact {}   1 mismatch {}   2+ mismatches {}", 
+        outcome_counts.exact, outcome_counts.corrected_1_mismatch, outcome_counts.corrected_2_mismatches);
+    println!("Barcode failures: round 1-4 {:?}   linker {}   ambiguous {}   too short {}", 
+        outcome_counts.failed_round, outcome_counts.failed_linker, outcome_counts.ambiguous, outcome_counts.too_short);
+    if max_repeat_fraction.is_some() {
+        println!("Reads not assigned due to a low complexity barcode read: {}", outcome_counts.low_complexity);
+    }
+    if let Some(min_bc_mean_qual) = min_bc_mean_qual {
+        println!("Reads not assigned due to mean barcode quality below {}: {}", min_bc_mean_qual, outcome_counts.low_barcode_quality);
+    }
+    if atrandi_barcodes.used_wells.is_some() {
+        println!("Reads not assigned due to a barcode in a well not used: {}", count_unused_well);
+    }
+    println!("R1 reads running into the barcode block (short inserts): {}{}", count_read_through, 
+        if trim_read_through {", trimmed"} else {""});
+    if r1_block_finder.is_some() {
+        println!("R1 reads containing the barcode construct: {}{}", count_r1_block, 
+            if let R1BlockPolicy::Trim = r1_block {", trimmed"} else {""});
+    }
+    if name_filter.is_some() {
+        println!("Read pairs with a duplicate name: {}{}", count_duplicate_names, 
+            if uniquify_names {", renamed"} else {""});
+    }
+    if dedup_prefix.is_some() {
+        println!("Duplicate reads dropped: {} ({:.2}% of assigned)", count_duplicates, 
+            100.0*count_duplicates as f64/count_ok_reads.max(1) as f64);
+    }
+    if let Some(max_distinct_barcodes) = max_distinct_barcodes {
+        println!("Distinct barcodes counted exactly: {} (cap {}); reads of rare barcodes left out of the histogram: {}", 
+            barcode_per_cell_count.len(), max_distinct_barcodes, count_tail_reads);
+    }
+    if let Some(max_reads_per_cell) = max_reads_per_cell {
+        let num_capped_cells = written_per_cell.values().filter(|&&written| written >= max_reads_per_cell).count();
+        println!("Reads dropped by --max-reads-per-cell: {} (from {} cells)", count_capped, num_capped_cells);
+    }
+
+
+    ////// Write the JSON report
+    if let Some(report_json) = report_json {
+        let report = FastqReport {
+            reads: read_count,
+            reads_with_barcode: count_ok_reads,
+            reads_low_barcode_quality: outcome_counts.low_barcode_quality,
+            reads_low_complexity: outcome_counts.low_complexity,
+            reads_unused_well: count_unused_well,
+            outcomes: outcome_counts.clone(),
+            quality: QualityReport {
+                barcode: qual_barcode.summary(),
+                r1: qual_r1.summary(),
+                r2_insert: qual_r2_insert.summary()
+            },
+            lengths: read_lengths,
+            lengths_trimmed: trimmed_lengths,
+            partial: interrupted,
+            output_order: reader.order(),
+            whitelists: atrandi_barcodes.plates.iter().map(|p| WhitelistReport {plate: p.name.clone(), sha256: p.sha256.clone()}).collect(),
+            duplicate_names: name_filter.as_ref().map(|_| count_duplicate_names),
+            r1_with_block: r1_block_finder.as_ref().map(|_| count_r1_block)
+        };
+        let writer = BufWriter::new(compress.create(report_json));
+        serde_json::to_writer_pretty(writer, &report).expect("Unable to write data");
+    }
+
+
+    ////// Leave a marker next to the histogram, so the outputs of an interrupted run are not taken as complete
+    if interrupted {
+        let mut path_marker = histogram_file.clone().into_os_string();
+        path_marker.push(".partial");
+        std::fs::write(&path_marker, format!("Interrupted after {} reads\n", read_count)).expect("Unable to write partial marker");
+        warn!("Outputs are partial; see {}", PathBuf::from(path_marker).display());
+        process::exit(EXIT_INTERRUPTED);
+    }
+
+
+    ////// Describe the outputs for downstream pipelines
+    if let (Some(sample_sheet), Some(path_out_r1), Some(path_out_r2)) = (sample_sheet, path_out_r1, path_out_r2) {
+        let counts = barcode_per_cell_count.values().map(|&cnt| cnt as i64).collect_vec();
+        let row = SampleSheetRow {
+            sample: sample.to_string(),
+            fastq_1: std::fs::canonicalize(path_out_r1).unwrap_or(path_out_r1.clone()).display().to_string(),
+            fastq_2: std::fs::canonicalize(path_out_r2).unwrap_or(path_out_r2.clone()).display().to_string(),
+            expected_cells: estimate_cells(&counts, expected_cells),
+            reads: read_count,
+            reads_with_barcode: count_ok_reads,
+            chemistry: "atrandi".to_string(),
+            linkers: atrandi_barcodes.chemistry.linkers.iter().map(|l| String::from_utf8_lossy(l)).join(","),
+            barcode_files: atrandi_barcodes.plates.iter().map(|p| p.name.as_str()).join(";")
+        };
+        println!("Estimated cells: {}", row.expected_cells);
+        store_sample_sheet(sample_sheet, &row, compress).expect("creation of sample sheet failed");
+    }
+
+    println!("done");
+
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Split reads by cell ///////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Bytes to collect for one cell before writing them to its file
+const SPLIT_BATCH_SIZE: usize = 16*1024;
+
+/// Gzip output files, one per cell and read. Only max_open files are kept open; when another one is needed, the
+/// least recently used is closed and later reopened for appending, as a new gzip member
+struct CellFilePool {
+    outdir: PathBuf,
+    max_open: usize,
+    open: HashMap<String, (GzEncoder<File>, u64)>, //File, and when it was last written
+    created: HashSet<String>,
+    tick: u64
+}
+
+impl CellFilePool {
+
+    fn new(outdir: &Path, max_open: usize) -> CellFilePool {
+        CellFilePool {
+            outdir: outdir.to_path_buf(),
+            max_open: max_open.max(1),
+            open: HashMap::new(),
+            created: HashSet::new(),
+            tick: 0
+        }
+    }
+
+    /// Append data to a file. Files are truncated the first time they are written in this run
+    fn write(&mut self, name: &str, data: &[u8]) -> std::io::Result<()> {
+        self.tick += 1;
+        if !self.open.contains_key(name) {
+            if self.open.len() >= self.max_open {
+                let oldest = self.open.iter().min_by_key(|(_, (_, last))| *last).map(|(k, _)| k.clone()).expect("No open file");
+                let (encoder, _) = self.open.remove(&oldest).expect("No open file");
+                encoder.finish()?;
+            }
+            let first = self.created.insert(name.to_string());
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(first)
+                .append(!first)
+                .open(self.outdir.join(name))?;
+            self.open.insert(name.to_string(), (GzEncoder::new(file, Compression::default()), 0));
+        }
+        let (encoder, last) = self.open.get_mut(name).expect("File not open");
+        *last = self.tick;
+        encoder.write_all(data)
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        for (_, (encoder, _)) in self.open {
+            encoder.finish()?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Assignment log of a ToFastq run, read along with the reads it wrote. Both are in input order, so the line of
+/// each read is found by skipping those of the reads that were not written
+struct AssignmentLogReader {
+    lines: std::io::Lines<std::io::BufReader<flate2::read::MultiGzDecoder<File>>>,
+    path: PathBuf
+}
+
+impl AssignmentLogReader {
+
+    fn open(path: &PathBuf) -> AssignmentLogReader {
+        use std::io::BufRead;
+
+        let file = File::open(path).expect("Could not open assignment log");
+        AssignmentLogReader {
+            lines: std::io::BufReader::new(flate2::read::MultiGzDecoder::new(file)).lines(),
+            path: path.clone()
+        }
+    }
+
+    /// Cell and outcome of a written read, from its name without the barcode prefix. Duplicate suffixes and
+    /// comments added to the name are ignored
+    fn find(&mut self, name: &[u8]) -> (String, String) {
+        let id_len = name.iter().position(|&c| c==b' ').unwrap_or(name.len());
+        let id = &name[..id_len];
+        loop {
+            let line = match self.lines.next() {
+                Some(line) => line.expect("Could not read assignment log"),
+                None => {
+                    error!("Read {} is not in the assignment log {}; the log must be from the same run, with --output-order input",
+                        String::from_utf8_lossy(id), self.path.display());
+                    process::exit(1)
+                }
+            };
+            let mut fields = line.split('\t');
+            let log_id = fields.next().unwrap_or("").as_bytes();
+            if id == log_id || (id.starts_with(log_id) && id[log_id.len()..].starts_with(b".dup")) {
+                let cell = fields.next().unwrap_or("*").to_string();
+                let outcome = fields.next().unwrap_or("").to_string();
+                return (cell, outcome);
+            }
+        }
+    }
+}
+
+
+/// Go through the read pairs of barcoded FASTQ files, passing those of the given cells on with their cell. Cells
+/// are taken from the read names, or from the assignment log of the run if given, which also works for short
+/// names; then only reads with one of the given outcomes are kept, if any are. Returns the number of read pairs,
+/// and how many of them were left out
+fn for_each_cell_read(
+    path_r1:&PathBuf,
+    path_r2:&PathBuf,
+    cells:&HashSet<String>,
+    assignment_log:Option<&PathBuf>,
+    outcomes:&[String],
+    input:&InputOptions,
+    mut handle:impl FnMut(&str, &BatchRecord, &BatchRecord)
+) -> (u64, u64) {
+    let mut log = assignment_log.map(AssignmentLogReader::open);
+    let reader = PairedFastqReader::open(path_r1, path_r2, input);
+    let mut read_count = 0;
+    let mut count_not_kept = 0;
+    for (record_r1, record_r2) in reader {
+        read_count += 1;
+        let head = record_r1.head();
+        let bc_len = head.iter().position(|&c| c==b'_').unwrap_or(0);
+        let cell = match &mut log {
+            Some(log) => {
+                let (cell, outcome) = log.find(&head[(bc_len + 1).min(head.len())..]);
+                if !outcomes.is_empty() && !outcomes.contains(&outcome) {
+                    count_not_kept += 1;
+                    continue;
+                }
+                std::borrow::Cow::Owned(cell)
+            },
+            None => String::from_utf8_lossy(&head[..bc_len])
+        };
+        if !cells.contains(cell.as_ref()) {
+            count_not_kept += 1;
+            continue;
+        }
+        handle(&cell, &record_r1, &record_r2);
+    }
+    (read_count, count_not_kept)
+}
+
+
+/// Split barcoded FASTQ files into one pair of files per called cell, i.e. barcodes with at least min_reads
+/// reads in the histogram. Cells are taken from the assignment log if given
+#[allow(clippy::too_many_arguments)]
+fn split_fastq_by_cell(
+    path_r1:&PathBuf,
+    path_r2:&PathBuf,
+    histogram_file:&Path,
+    assignment_log:Option<&PathBuf>,
+    outdir:&PathBuf,
+    min_reads:i64,
+    max_open:usize,
+    input:&InputOptions
+) {
+    let hist = read_histogram(histogram_file).expect("Failed to read histogram");
+    let cells: HashSet<String> = hist.into_iter().filter(|(_, cnt)| *cnt >= min_reads).map(|(bc, _)| bc).collect();
+    println!("Splitting reads of {} cells with at least {} reads into {}", cells.len(), min_reads, outdir.display());
+    std::fs::create_dir_all(outdir).expect("Failed to create output directory");
+
+    let mut pool = CellFilePool::new(outdir, max_open);
+    let file_name = |bc: &str, read: &str| format!("{}_{}.fastq.gz", bc.replace(':', "_"), read);
+
+    //Reads of each cell are collected and written in batches
+    let mut batches: HashMap<String, (Vec<u8>, Vec<u8>)> = HashMap::new();
+    for_each_cell_read(path_r1, path_r2, &cells, assignment_log, &[], input, |bc, record_r1, record_r2| {
+        let (batch_r1, batch_r2) = match batches.get_mut(bc) {
+            Some(batch) => batch,
+            None => batches.entry(bc.to_string()).or_default()
+        };
+        write_fastq(batch_r1, record_r1.head(), record_r1.seq(), record_r1.qual());
+        write_fastq(batch_r2, record_r2.head(), record_r2.seq(), record_r2.qual());
+        if batch_r1.len() >= SPLIT_BATCH_SIZE {
+            pool.write(&file_name(bc, "R1"), batch_r1).expect("Unable to write data");
+            pool.write(&file_name(bc, "R2"), batch_r2).expect("Unable to write data");
+            batch_r1.clear();
+            batch_r2.clear();
+        }
+    });
+    for (bc, (batch_r1, batch_r2)) in &batches {
+        if !batch_r1.is_empty() {
+            pool.write(&file_name(bc, "R1"), batch_r1).expect("Unable to write data");
+            pool.write(&file_name(bc, "R2"), batch_r2).expect("Unable to write data");
+        }
+    }
+    pool.finish().expect("Unable to write data");
+    println!("Wrote reads of {} cells", batches.len());
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Refilter corrected reads //////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Apply new cell filters to the FASTQ files of an earlier ToFastq run, using its histogram to decide which
+/// cells to keep. Barcodes are taken from the read names, or from the assignment log of the run if given, so
+/// nothing is extracted or corrected again. With the log, reads can also be kept by correction outcome
+#[allow(clippy::too_many_arguments)]
+fn refilter_fastq(
+    path_r1:&PathBuf,
+    path_r2:&PathBuf,
+    path_out_r1:&Path,
+    path_out_r2:&Path,
+    histogram_file:&Path,
+    assignment_log:&Option<PathBuf>,
+    outcomes:&[String],
+    path_out_hist:&Option<PathBuf>,
+    min_reads:i64,
+    top_cells:Option<usize>,
+    cell_list:&Option<PathBuf>,
+    max_reads_per_cell:Option<u64>,
+    input:&InputOptions,
+    compress:&CompressOptions,
+    barcode_spec:&BarcodeSpec
+) {
+    ////// Decide which cells to keep from the histogram
+    let hist = read_histogram(histogram_file).expect("Failed to read histogram");
+    let mut cells: HashSet<String> = match top_cells {
+        Some(n) => top_barcodes(&hist, n).into_iter().collect(),
+        None => hist.iter().map(|(bc, _)| bc.clone()).collect()
+    };
+    let hist: HashMap<String,i64> = hist.into_iter().collect();
+    cells.retain(|bc| hist[bc] >= min_reads);
+    if let Some(cell_list) = cell_list {
+        let listed: HashSet<String> = std::fs::read_to_string(cell_list).expect("Could not read cell list")
+            .lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect();
+        cells.retain(|bc| listed.contains(bc));
+    }
+    if barcode_spec.used_wells.is_some() {
+        let atrandi_barcodes = barcode_spec.load().expect("Failed to read barcode file");
+        let num_cells = cells.len();
+        cells.retain(|bc| atrandi_barcodes.parse_packed_name(bc).is_some_and(|bc| atrandi_barcodes.is_used(bc)));
+        println!("Cells left out for a barcode in a well not used: {}", num_cells - cells.len());
+    }
+    println!("Keeping reads of {} of {} cells", cells.len(), hist.len());
+    if cells.is_empty() {
+        warn!("No cells pass the filters");
+    }
+
+    ////// Copy the reads of these cells
+    let mut sink = ReadSink::open(&Some(path_out_r1.to_path_buf()), &Some(path_out_r2.to_path_buf()), &None, &None, &None, false, &RunMetadata::default(), compress);
+    let mut batch_r1: Vec<u8> = Vec::with_capacity(OUTPUT_BATCH_SIZE + 1024);
+    let mut batch_r2: Vec<u8> = Vec::with_capacity(OUTPUT_BATCH_SIZE + 1024);
+    let mut written_per_cell: HashMap<String, i64> = HashMap::new();
+    let mut count_capped = 0;
+    let (read_count, count_not_kept) = for_each_cell_read(path_r1, path_r2, &cells, assignment_log.as_ref(), outcomes, input, |bc, record_r1, record_r2| {
+        let written = match written_per_cell.get_mut(bc) {
+            Some(written) => written,
+            None => written_per_cell.entry(bc.to_string()).or_insert(0)
+        };
+        if let Some(max_reads_per_cell) = max_reads_per_cell {
+            if *written as u64 >= max_reads_per_cell {
+                count_capped += 1;
+                return;
+            }
+        }
+        *written += 1;
+        sink.add_read(&mut batch_r1, record_r1.head(), record_r1.seq(), record_r1.qual(), true, &None);
+        sink.add_read(&mut batch_r2, record_r2.head(), record_r2.seq(), record_r2.qual(), false, &None);
+        sink.flush(&mut batch_r1, &mut batch_r2, false);
+    });
+    sink.flush(&mut batch_r1, &mut batch_r2, true);
+    sink.finish();
+
+    println!("Processed reads: {}   Kept: {}   not in kept cells: {}", read_count, read_count - count_not_kept - count_capped, count_not_kept);
+    if let Some(max_reads_per_cell) = max_reads_per_cell {
+        println!("Reads left out beyond {} per cell: {}", max_reads_per_cell, count_capped);
+    }
+    if read_count > 0 && count_not_kept == read_count {
+        warn!("No reads matched a kept cell. Read names must start with the full barcode; for --short-names, give the --assignment-log of the run");
+    }
+
+    ////// Reads written per cell, in the same format as the histogram
+    if let Some(path_out_hist) = path_out_hist {
+        let mut out_hist: Vec<(String,i64)> = written_per_cell.into_iter().collect();
+        out_hist.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        store_histogram(path_out_hist, &out_hist).expect("Failed to store histogram");
+    }
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Fetch reads of a cell /////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Extract the reads of one cell from corrected FASTQ files, using the index from ToFastq --cell-index. Only the
+/// chunks of the files holding reads of the cell are decompressed
+fn fetch_cell(
+    path_r1:&PathBuf,
+    path_r2:&PathBuf,
+    path_index:&PathBuf,
+    cell:&str,
+    path_out_r1:&Path,
+    path_out_r2:&Path,
+    compress:&CompressOptions
+) {
+    let index = CellIndex::read(path_index).expect("Failed to read cell index");
+    let chunks = match index.chunks_of(cell) {
+        Some(chunks) => chunks,
+        None => {
+            error!("Cell {} is not in the index; give the barcode as in the read names", cell);
+            process::exit(1)
+        }
+    };
+
+    let mut sink = ReadSink::open(&Some(path_out_r1.to_path_buf()), &Some(path_out_r2.to_path_buf()), &None, &None, &None, false, &RunMetadata::default(), compress);
+    let mut batch_r1: Vec<u8> = Vec::with_capacity(OUTPUT_BATCH_SIZE + 1024);
+    let mut batch_r2: Vec<u8> = Vec::with_capacity(OUTPUT_BATCH_SIZE + 1024);
+    let mut prefix = cell.as_bytes().to_vec();
+    prefix.push(b'_');
+    let mut count_fetched = 0;
+
+    ////// Read the pairs of each chunk, keeping those of the cell
+    for chunk in &chunks {
+        let mut reader_r1 = FastqReader::new(open_bgzf_at(path_r1, chunk.r1).expect("Could not read R1"));
+        let mut reader_r2 = FastqReader::new(open_bgzf_at(path_r2, chunk.r2).expect("Could not read R2"));
+        for _ in 0..chunk.pairs {
+            let (record_r1, record_r2) = match (reader_r1.next(), reader_r2.next()) {
+                (Some(Ok(record_r1)), Some(Ok(record_r2))) => (record_r1, record_r2),
+                _ => {
+                    error!("The reads do not match the index; was it made for other files?");
+                    process::exit(1)
+                }
+            };
+            if record_r1.head().starts_with(&prefix) {
+                count_fetched += 1;
+                sink.add_read(&mut batch_r1, record_r1.head(), record_r1.seq(), record_r1.qual(), true, &None);
+                sink.add_read(&mut batch_r2, record_r2.head(), record_r2.seq(), record_r2.qual(), false, &None);
+                sink.flush(&mut batch_r1, &mut batch_r2, false);
+            }
+        }
+    }
+    sink.flush(&mut batch_r1, &mut batch_r2, true);
+    sink.finish();
+    println!("Fetched {} read pairs of cell {} from {} of {} chunks", count_fetched, cell, chunks.len(), index.chunks.len());
+}
+
+
+
+
+/// Index of an assignment log, next to it
+fn assignment_log_index(path_log:&Path) -> PathBuf {
+    let mut path = path_log.to_path_buf().into_os_string();
+    path.push(".cidx");
+    PathBuf::from(path)
+}
+
+
+/// Print the assignment log lines of one cell, or of the unassigned reads (*), using the index next to the log
+fn fetch_log(path_log:&PathBuf, cell:&str, path_out:&Option<PathBuf>) {
+    use std::io::BufRead;
+
+    let index = LogIndex::read(&assignment_log_index(path_log)).expect("Failed to read assignment log index");
+    let chunks = match index.chunks_of(cell) {
+        Some(chunks) => chunks,
+        None => {
+            error!("Cell {} is not in the assignment log", cell);
+            process::exit(1)
+        }
+    };
+    let mut writer: Box<dyn Write> = match path_out {
+        Some(path_out) => Box::new(BufWriter::new(File::create(path_out).expect("Could not create output file"))),
+        None => Box::new(BufWriter::new(std::io::stdout()))
+    };
+
+    ////// Read the lines of each chunk, keeping those of the cell
+    let mut count_fetched = 0;
+    for chunk in &chunks {
+        let reader = std::io::BufReader::new(open_bgzf_at(path_log, chunk.start).expect("Could not read assignment log"));
+        for line in reader.lines().take(chunk.lines as usize) {
+            let line = line.expect("Could not read assignment log");
+            if line.split('\t').nth(1) == Some(cell) {
+                count_fetched += 1;
+                writeln!(writer, "{}", line).expect("Unable to write data");
+            }
+        }
+    }
+    writer.flush().expect("Unable to write data");
+    eprintln!("Fetched {} read pairs of cell {} from {} of {} chunks", count_fetched, cell, chunks.len(), index.chunks.len());
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Generate count table //////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+
+/// Feature type when counting reads per reference sequence
+const FEATURE_TYPE_REFERENCE: &str = "Reference sequence";
+
+/// Feature type when counting reads per region from a BED file
+const FEATURE_TYPE_REGION: &str = "Region";
+
+/// Feature type when counting reads per gene from a GTF file
+const FEATURE_TYPE_GENE: &str = "Gene Expression";
+
+/// Options of CountSeq, apart from the BAM and the count table. The default counts reads per reference sequence
+/// over all cells, on one thread
+struct CountSeqOptions {
+    mito_prefix: Option<String>,
+    ribo_list: Option<PathBuf>,
+    path_regions: Option<PathBuf>,
+    path_gtf: Option<PathBuf>,
+    strandedness: Strandedness,
+    velocity: bool,
+    feature_map: Option<PathBuf>,
+    exclude_unmapped: bool,
+    on_bad_name: BadNamePolicy,
+    region: Option<String>,
+    background_max_count: Option<i64>,
+    normalized: bool,
+    umi: bool,
+    top_cells: Option<usize>,
+    top_cells_histogram: Option<PathBuf>,
+    include_biotypes: Vec<String>,
+    exclude_biotypes: Vec<String>,
+    spike_in_prefix: Option<String>,
+    spike_in_molecules: Option<PathBuf>,
+    expected_cells: usize,
+    threads: usize
+}
+
+impl Default for CountSeqOptions {
+    fn default() -> CountSeqOptions {
+        CountSeqOptions {
+            mito_prefix: None,
+            ribo_list: None,
+            path_regions: None,
+            path_gtf: None,
+            strandedness: Strandedness::Unstranded,
+            velocity: false,
+            feature_map: None,
+            exclude_unmapped: false,
+            on_bad_name: BadNamePolicy::Error,
+            region: None,
+            background_max_count: None,
+            normalized: false,
+            umi: false,
+            top_cells: None,
+            top_cells_histogram: None,
+            include_biotypes: Vec::new(),
+            exclude_biotypes: Vec::new(),
+            spike_in_prefix: None,
+            spike_in_molecules: None,
+            expected_cells: DEFAULT_EXPECTED_CELLS,
+            threads: 1
+        }
+    }
+}
+
+fn count_seq_per_bc(
+    ibam:&PathBuf, 
+    path_csv:&Path,
+    options:&CountSeqOptions
+) {
+
+    let CountSeqOptions {
+        ref mito_prefix, ref ribo_list, ref path_regions, ref path_gtf, strandedness, velocity, ref feature_map, exclude_unmapped,
+        on_bad_name, ref region, background_max_count, normalized, umi, top_cells, ref top_cells_histogram,
+        ref include_biotypes, ref exclude_biotypes, ref spike_in_prefix, ref spike_in_molecules, expected_cells, threads
+    } = *options;
+
+    use noodles::bam;
+    use noodles::core::region::Interval;
+    use noodles::csi::BinningIndex;
+
+    let outputs = RunOutputs::in_dir(path_csv);
+
+    let mut reader = bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
+    let header = reader.read_header().expect("Could not read BAM header");
+
+    //Only count the cells with the most reads; the BAM may hold millions of background barcodes
+    let keep_cells: Option<HashSet<String>> = top_cells.map(|n| {
+        let hist = match top_cells_histogram {
+            Some(path) => read_histogram(path).expect("Failed to read histogram"),
+            None => {
+                println!("Counting reads per barcode to find the top {} cells...", n);
+                reads_per_barcode(ibam, on_bad_name)
+            }
+        };
+        let cells: HashSet<String> = top_barcodes(&hist, n).into_iter().collect();
+        println!("Counting the top {} of {} barcodes", cells.len(), hist.len());
+        cells
+    });
+
+
+    //Set up a list of features; either reference sequences, regions, or genes
+    let allind: Vec<usize> = (0..header.reference_sequences().len()).collect();
+    let name_of_refseq = allind.iter().map(|i| header.reference_sequences().get_index(*i).expect("!").0.to_string()).collect_vec();
+    let genes = path_gtf.as_ref().map(|p| read_gtf(p).expect("Could not read GTF"));
+    let regions = match &genes {
+        Some(genes) => Some(RegionIndex::new(genes.iter().map(|g| g.to_region()).collect())),
+        None => path_regions.as_ref().map(|p| RegionIndex::from_bed(p).expect("Could not read regions"))
+    };
+    let mut features = match (&genes, &regions) {
+        (Some(genes), _) => genes.iter().map(|g| FeatureInfo {
+            id: g.id.clone(),
+            name: g.name.clone(),
+            feature_type: FEATURE_TYPE_GENE.to_string(),
+            genome: None
+        }).collect_vec(),
+        (None, Some(regions)) => regions.regions.iter().map(|r| FeatureInfo::new(&r.name, FEATURE_TYPE_REGION)).collect_vec(),
+        (None, None) => name_of_refseq.iter().map(|name| FeatureInfo::new(name, FEATURE_TYPE_REFERENCE)).collect_vec()
+    };
+    let id_noname = features.len();
+    features.push(FeatureInfo::new("*", FEATURE_TYPE_REFERENCE));
+
+    //Lengths of features, for normalization. Unknown for unmapped reads
+    let mut feature_lengths = match (&genes, &regions) {
+        (Some(genes), _) => genes.iter().map(|g| Some(g.exonic_length())).collect_vec(),
+        (None, Some(regions)) => regions.regions.iter().map(|r| Some(r.end.saturating_sub(r.start))).collect_vec(),
+        (None, None) => header.reference_sequences().iter().map(|(_, rs)| Some(rs.length().get())).collect_vec()
+    };
+    feature_lengths.push(None);
+    println!("Names of features:");
+    println!("{:?}", features.iter().map(|f| &f.id).collect_vec());
+
+    let counter = SeqCounter {
+        name_of_refseq: &name_of_refseq,
+        regions: regions.as_ref(),
+        genes: genes.as_ref(),
+        id_noname,
+        strandedness,
+        velocity,
+        exclude_unmapped,
+        on_bad_name,
+        umi,
+        keep_cells: keep_cells.as_ref()
+    };
 
     //Perform all the counting
     println!("Counting...");
+    let mut counts = SeqCounts::new(features);
+    if threads > 1 && region.is_none() {
+        //Each thread takes the next reference sequence and queries it using the index; the last task is the unplaced reads
+        let next_task = AtomicUsize::new(0);
+        let parts = std::thread::scope(|scope| {
+            let handles = (0..threads).map(|_| scope.spawn(|| {
+                let mut part = SeqCounts::new(counts.matrix.features.clone());
+                let mut indexed_reader = bam::io::indexed_reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file; --threads requires a .bai index");
+                indexed_reader.read_header().expect("Could not read BAM header");
+                loop {
+                    let task = next_task.fetch_add(1, Ordering::Relaxed);
+                    if task < name_of_refseq.len() {
+                        //Read the chunks of the reference sequence rather than query it as a region; a region query
+                        //leaves out unmapped reads placed next to their mate, as they overlap nothing
+                        let chunks = indexed_reader.index().query(task, Interval::from(..)).expect("Could not query BAM index");
+                        let mut chunk_reader = bam::io::Reader::from(noodles::csi::io::Query::new(indexed_reader.get_mut(), chunks));
+                        for result in chunk_reader.records() {
+                            let record = result.expect("Could not read BAM record");
+                            if record.reference_sequence_id().transpose().expect("Bad reference sequence ID") == Some(task) {
+                                counter.count(&mut part, &record);
+                            }
+                        }
+                    } else if task == name_of_refseq.len() {
+                        for result in indexed_reader.query_unmapped().expect("Could not query unplaced reads") {
+                            counter.count(&mut part, &result.expect("Could not read BAM record"));
+                        }
+                    } else {
+                        break;
+                    }
+                }
+                part
+            })).collect_vec();
+            handles.into_iter().map(|h| h.join().expect("Counting thread failed")).collect_vec()
+        });
+        for part in parts {
+            counts.merge(part);
+        }
+    } else {
+        //Either stream the whole BAM, or only query reads overlapping a region using the index
+        let mut indexed_reader;
+        let records: Box<dyn Iterator<Item = std::io::Result<bam::Record>> + '_> = match region {
+            Some(region) => {
+                let region: noodles::core::Region = region.parse().expect("Could not parse region, expected chr:start-end");
+                indexed_reader = bam::io::indexed_reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file; --region requires a .bai index");
+                indexed_reader.read_header().expect("Could not read BAM header");
+                Box::new(indexed_reader.query(&header, &region).expect("Could not query region"))
+            },
+            None => Box::new(reader.records())
+        };
+        for result in records {
+            counter.count(&mut counts, &result.expect("Could not read BAM record"));
+        }
+    }
+    let SeqCounts {mut matrix, mut spliced, mut unspliced, umis, umis_spliced, umis_unspliced, mapping_per_cell, insert_sizes, count_bad_name, count_no_umi, count_not_top} = counts;
+    if keep_cells.is_some() {
+        println!("Skipped {} records of barcodes outside the top cells", count_not_top);
+    }
+
+    ////// Collapse UMIs into molecules
+    if umi {
+        let stats = umis.collapse_into(&mut matrix);
+        umis_spliced.collapse_into(&mut spliced);
+        umis_unspliced.collapse_into(&mut unspliced);
+        println!("UMIs: {} reads, {} distinct UMIs, {} molecules; {:.2}% of reads had a UMI error", 
+            stats.reads, stats.umis, stats.molecules, 100.0*stats.error_rate());
+        if count_no_umi > 0 {
+            warn!("Skipped {} records without a UMI", count_no_umi);
+        }
+        store_umi_stats(&outputs.path(OUT_UMI_STATS), &stats).expect("Failed to store UMI stats");
+    }
+
+
+    ////// Leave out genes of unwanted biotypes, e.g. rRNA in total-RNA protocols. Reads are still assigned
+    ////// using all genes, so that they are not moved to overlapping genes of other biotypes
+    let mut excluded_per_cell = None;
+    if let (Some(genes), true) = (&genes, !include_biotypes.is_empty() || !exclude_biotypes.is_empty()) {
+        let keep = matrix.features.iter().enumerate().map(|(i, _)| match genes.get(i) {
+            Some(gene) => {
+                let biotype = gene.biotype.as_deref().unwrap_or("");
+                (include_biotypes.is_empty() || include_biotypes.iter().any(|b| b == biotype)) && 
+                    !exclude_biotypes.iter().any(|b| b == biotype)
+            },
+            None => true //Unassigned reads
+        }).collect_vec();
+        let excluded = matrix.remove_features(&keep);
+        spliced.remove_features(&keep);
+        unspliced.remove_features(&keep);
+        feature_lengths = feature_lengths.into_iter().zip(&keep).filter(|(_, k)| **k).map(|(len, _)| len).collect_vec();
+        println!("Left out {} genes by biotype, with {} counts", 
+            keep.iter().filter(|k| !**k).count(), excluded.values().sum::<i64>());
+        excluded_per_cell = Some(excluded);
+    }
+
+    ////// Count spike-ins such as ERCC in a table of their own. Given the molecules added per cell, the fraction
+    ////// recovered estimates the capture efficiency of each cell
+    let mut spike_in_per_cell = None;
+    let mut spike_in_total_molecules = None;
+    if let Some(spike_in_prefix) = spike_in_prefix {
+        let is_spike_in = matrix.features.iter().map(|f| f.id.starts_with(spike_in_prefix.as_str())).collect_vec();
+        let spike_in = matrix.split_features(&is_spike_in);
+        spliced.remove_features(&is_spike_in.iter().map(|s| !s).collect_vec());
+        unspliced.remove_features(&is_spike_in.iter().map(|s| !s).collect_vec());
+        feature_lengths = feature_lengths.into_iter().zip(&is_spike_in).filter(|(_, s)| !**s).map(|(len, _)| len).collect_vec();
+        if spike_in.features.is_empty() {
+            warn!("No features start with the spike-in prefix {}", spike_in_prefix);
+        }
+        println!("Spike-ins: {} features, {} counts", spike_in.features.len(), spike_in.total());
+
+        if let Some(spike_in_molecules) = spike_in_molecules {
+            let molecules = read_spike_in_molecules(spike_in_molecules).expect("Could not read spike-in molecules");
+            let num_missing = molecules.keys().filter(|id| !spike_in.features.iter().any(|f| &f.id == *id)).count();
+            if num_missing > 0 {
+                warn!("{} of {} spike-ins with a number of molecules are not among the features", num_missing, molecules.len());
+            }
+            spike_in_total_molecules = Some(molecules.values().sum::<f64>());
+        }
+        spike_in.store(&outputs.path(OUT_SPIKE_IN)).expect("Failed to store spike-in count table");
+        spike_in_per_cell = Some(spike_in.counts.iter()
+            .map(|(cell, cellmap)| (cell.clone(), cellmap.values().map(|&c| c as i64).sum::<i64>())).collect::<HashMap<String,i64>>());
+    }
+
+    ////// Per-cell QC on mitochondrial and ribosomal content, counts left out by biotype, and spike-ins
+    if mito_prefix.is_some() || ribo_list.is_some() || excluded_per_cell.is_some() || spike_in_per_cell.is_some() {
+        let ribo_names: HashSet<String> = match ribo_list {
+            Some(ribo_list) => std::fs::read_to_string(ribo_list).expect("Could not read ribosomal list")
+                .lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect(),
+            None => HashSet::new()
+        };
+        let is_mito = matrix.features.iter().map(|f| mito_prefix.as_ref().is_some_and(|p| f.id.starts_with(p.as_str()))).collect_vec();
+        let is_ribo = matrix.features.iter().map(|f| ribo_names.contains(&f.id)).collect_vec();
+
+        store_cell_qc(&outputs.path(OUT_CELL_QC), &matrix.counts, &is_mito, &is_ribo, excluded_per_cell.as_ref(),
+            spike_in_per_cell.as_ref(), spike_in_total_molecules).expect("Failed to store cell QC");
+    }
+
+    if count_bad_name > 0 {
+        warn!("Skipped {} records without a barcode", count_bad_name);
+    }
+
+    store_mapping_stats(&outputs.path(OUT_MAPPING_STATS), &mapping_per_cell).expect("Failed to store mapping stats");
+
+    ////// Insert sizes of paired reads, per quartile of cells by reads. Without --top-cells, cells are called as
+    ////// for the ToFastq sample sheet
+    if !insert_sizes.is_empty() {
+        let reads = mapping_per_cell.iter().map(|(cell, (mapped, unmapped))| (cell.clone(), (mapped + unmapped) as i64)).collect_vec();
+        let num_cells = match &keep_cells {
+            Some(_) => reads.len(),
+            None => estimate_cells(&reads.iter().map(|(_, n)| *n).collect_vec(), expected_cells)
+        };
+        let mut cells = top_barcodes(&reads, num_cells);
+        cells.reverse();
+        let medians = store_insert_sizes(&outputs.path(OUT_INSERT_SIZES), &insert_sizes, &cells).expect("Failed to store insert sizes");
+        println!("Median insert size of {} cells by quartile of reads, fewest first: {}", cells.len(),
+            medians.iter().map(|m| m.map_or("NA".to_string(), |m| m.to_string())).join(", "));
+    }
+
+    ////// Aggregate features into groups, e.g. amplicons into genes
+    if let Some(feature_map) = feature_map {
+        let map = read_feature_map(feature_map).expect("Could not read feature map");
+        let num_features = matrix.features.len();
+        feature_lengths = group_lengths(&matrix.features, &feature_lengths, &map);
+        matrix.group_features(&map);
+        spliced.group_features(&map);
+        unspliced.group_features(&map);
+        println!("Grouped {} features into {}", num_features, matrix.features.len());
+    }
+
+    ////// Estimate the ambient profile from barcodes with few counts, i.e. empty droplets
+    if let Some(background_max_count) = background_max_count {
+        let (num_empty, profile) = matrix.background_profile(background_max_count);
+        println!("Background estimated from {} barcodes with at most {} counts", num_empty, background_max_count);
+        if num_empty == 0 {
+            warn!("No barcodes with at most {} counts; background profile is empty", background_max_count);
+        }
+        store_background(&outputs.path(OUT_BACKGROUND), &matrix.features, &profile).expect("Failed to store background profile");
+    }
+
+    if velocity {
+        spliced.store(&outputs.path(OUT_SPLICED)).expect("Failed to store spliced count table");
+        unspliced.store(&outputs.path(OUT_UNSPLICED)).expect("Failed to store unspliced count table");
+    }
+
+    matrix.store(path_csv).expect("Failed to store count table");
+
+    if normalized {
+        matrix.store_feature_lengths(&outputs.path(OUT_FEATURE_LENGTHS), &feature_lengths).expect("Failed to store feature lengths");
+        matrix.store_normalized(&outputs.path(OUT_NORMALIZED), &feature_lengths).expect("Failed to store normalized counts");
+    }
+
+}
+
+
+/// Counts collected by CountSeq; one per thread when counting in parallel. With UMIs, reads go into the UMI sets
+/// instead, to be collapsed into the tables once all are counted
+struct SeqCounts {
+    matrix: CountMatrix,
+    spliced: CountMatrix,     //Reads fully within exons, for velocity
+    unspliced: CountMatrix,   //Reads touching introns, for velocity
+    umis: UmiCounts,
+    umis_spliced: UmiCounts,
+    umis_unspliced: UmiCounts,
+    mapping_per_cell: HashMap<String, (u64,u64)>, //Mapped and unmapped reads per cell
+    insert_sizes: HashMap<String, HashMap<u32,u64>>, //Proper pairs per insert size bin, per cell
+    count_bad_name: u64,
+    count_no_umi: u64,
+    count_not_top: u64 //Records of cells left out by --top-cells
+}
+
+impl SeqCounts {
+
+    fn new(features: Vec<FeatureInfo>) -> SeqCounts {
+        SeqCounts {
+            matrix: CountMatrix::new(features.clone()),
+            spliced: CountMatrix::new(features.clone()),
+            unspliced: CountMatrix::new(features),
+            umis: UmiCounts::default(),
+            umis_spliced: UmiCounts::default(),
+            umis_unspliced: UmiCounts::default(),
+            mapping_per_cell: HashMap::new(),
+            insert_sizes: HashMap::new(),
+            count_bad_name: 0,
+            count_no_umi: 0,
+            count_not_top: 0
+        }
+    }
+
+    /// Add the counts of another thread. Features are the same, so the tables are summed
+    fn merge(&mut self, other: SeqCounts) {
+        self.matrix.merge(other.matrix, None);
+        self.spliced.merge(other.spliced, None);
+        self.unspliced.merge(other.unspliced, None);
+        self.umis.merge(other.umis);
+        self.umis_spliced.merge(other.umis_spliced);
+        self.umis_unspliced.merge(other.umis_unspliced);
+        for (cell, (mapped, unmapped)) in other.mapping_per_cell {
+            let cell_stats = self.mapping_per_cell.entry(cell).or_insert((0, 0));
+            cell_stats.0 += mapped;
+            cell_stats.1 += unmapped;
+        }
+        for (cell, sizes) in other.insert_sizes {
+            let cell_sizes = self.insert_sizes.entry(cell).or_default();
+            for (bin, n) in sizes {
+                *cell_sizes.entry(bin).or_insert(0) += n;
+            }
+        }
+        self.count_bad_name += other.count_bad_name;
+        self.count_no_umi += other.count_no_umi;
+        self.count_not_top += other.count_not_top;
+    }
+}
+
+
+/// How CountSeq assigns a BAM record to a cell and feature
+struct SeqCounter<'a> {
+    name_of_refseq: &'a [String],
+    regions: Option<&'a RegionIndex>,
+    genes: Option<&'a Vec<Gene>>,
+    id_noname: usize,
+    strandedness: Strandedness,
+    velocity: bool,
+    exclude_unmapped: bool,
+    on_bad_name: BadNamePolicy,
+    umi: bool,
+    keep_cells: Option<&'a HashSet<String>> //Only count these cells, if given
+}
+
+impl SeqCounter<'_> {
+
+    /// Count one record
+    fn count(&self, counts: &mut SeqCounts, record: &noodles::bam::Record) {
+        use noodles::sam::alignment::record::Cigar;
+        use noodles::sam::alignment::record::data::field::{Tag, Value};
+
+        //Get the barcode
+        let bc = match barcode_of_record(record, self.on_bad_name) {
+            Some(bc) => bc,
+            None => {
+                counts.count_bad_name += 1;
+                return;
+            }
+        };
+        let bc = bc.as_str();
+        if let Some(keep_cells) = self.keep_cells {
+            if !keep_cells.contains(bc) {
+                counts.count_not_top += 1;
+                return;
+            }
+        }
+
+        //Get the UMI, if counting molecules
+        let umi = if self.umi {
+            match record.data().get(&Tag::UMI_SEQUENCE) {
+                Some(Ok(Value::String(umi))) => Some(umi.to_vec()),
+                _ => {
+                    counts.count_no_umi += 1;
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        //Keep track of mapped and unmapped reads per cell; unmapped reads optionally not counted
+        let seqid = record.reference_sequence_id();
+        let is_unmapped = seqid.is_none() || record.flags().is_unmapped();
+        let cell_stats = match counts.mapping_per_cell.get_mut(bc) {
+            Some(cell_stats) => cell_stats,
+            None => counts.mapping_per_cell.entry(bc.to_string()).or_insert((0, 0))
+        };
+        if is_unmapped {
+            cell_stats.1 += 1;
+            if self.exclude_unmapped {
+                return;
+            }
+        } else {
+            cell_stats.0 += 1;
+        }
+
+        //Insert size of proper pairs, counted once per pair
+        let flags = record.flags();
+        if !is_unmapped && flags.is_properly_segmented() && flags.is_first_segment() && !flags.is_secondary() && !flags.is_supplementary() {
+            let bin = (record.template_length().unsigned_abs() / INSERT_SIZE_BIN).min(INSERT_SIZE_MAX / INSERT_SIZE_BIN);
+            let cell_sizes = match counts.insert_sizes.get_mut(bc) {
+                Some(cell_sizes) => cell_sizes,
+                None => counts.insert_sizes.entry(bc.to_string()).or_default()
+            };
+            *cell_sizes.entry(bin).or_insert(0) += 1;
+        }
+
+        //Figure out which feature. Need to map <no chromosome>
+        let feature_name = match seqid {
+            Some(seqid) => {
+                let seqid = seqid.expect("huh");
+                match self.regions {
+                    Some(regions) => {
+                        //Assign to a region if the read overlaps exactly one, on the right strand
+                        let start = record.alignment_start().expect("Mapped read without position").expect("Bad alignment start").get() - 1;
+                        let span = record.cigar().alignment_span().expect("Bad CIGAR").max(1);
+                        let flags = record.flags();
+                        let fragment_reverse = flags.is_reverse_complemented() ^ (flags.is_segmented() && flags.is_last_segment());
+                        let hits = regions.overlapping(&self.name_of_refseq[seqid], start, start+span).into_iter()
+                            .filter(|r| self.strandedness.accepts(fragment_reverse, regions.regions[*r].strand))
+                            .collect_vec();
+                        if hits.len()==1 { 
+                            //For velocity, see if all aligned blocks are within exons of the gene
+                            if let (true, Some(genes)) = (self.velocity, self.genes) {
+                                let gene = &genes[hits[0]];
+                                let blocks = aligned_blocks(start, &record.cigar());
+                                let is_spliced = blocks.iter().all(|(s,e)| gene.is_exonic(*s,*e));
+                                match (&umi, is_spliced) {
+                                    (Some(umi), true) => counts.umis_spliced.add(bc, hits[0], umi),
+                                    (Some(umi), false) => counts.umis_unspliced.add(bc, hits[0], umi),
+                                    (None, true) => counts.spliced.add(bc, hits[0], 1),
+                                    (None, false) => counts.unspliced.add(bc, hits[0], 1)
+                                }
+                            }
+                            hits[0]
+                        } else { 
+                            self.id_noname 
+                        }
+                    },
+                    None => seqid
+                }
+            },
+            None => {
+                self.id_noname
+            }
+        };
+
+        //Update count in table
+        match &umi {
+            Some(umi) => counts.umis.add(bc, feature_name, umi),
+            None => counts.matrix.add(bc, feature_name, 1)
+        }
+    }
+}
+
+
+/// What to do with BAM records whose name does not follow the BC_readid convention
+#[derive(Clone, Copy, ValueEnum)]
+enum BadNamePolicy {
+    /// Skip the record
+    Skip,
+    /// Stop with an error
+    Error,
+    /// Take the barcode from the CB tag; skip the record if there is none
+    UseTag
+}
+
+
+/// Get the cell barcode of a BAM record from its name, BC_readid. If the name is missing or does not
+/// follow the convention, apply the policy; None means the record should be skipped
+fn barcode_of_record(record:&noodles::bam::Record, policy:BadNamePolicy) -> Option<String> {
+    use noodles::sam::alignment::record::data::field::{Tag, Value};
+    use bstr::ByteSlice;
+
+    if let Some(name) = record.name() {
+        if let Some((bc,_)) = name.to_str_lossy().split_once('_') {
+            return Some(bc.to_string());
+        }
+    }
+    match policy {
+        BadNamePolicy::Skip => None,
+        BadNamePolicy::Error => {
+            error!("BAM record name does not follow convention BC_readid: {:?}", record.name().map(|n| n.to_str_lossy().to_string()));
+            process::exit(1)
+        },
+        BadNamePolicy::UseTag => match record.data().get(&Tag::CELL_BARCODE_ID) {
+            Some(Ok(Value::String(bc))) => Some(bc.to_str_lossy().to_string()),
+            _ => None
+        }
+    }
+}
+
+
+/// Number of records per barcode in a BAM file, as a histogram
+fn reads_per_barcode(ibam:&PathBuf, on_bad_name:BadNamePolicy) -> Vec<(String,i64)> {
+    let mut reader = noodles::bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
+    reader.read_header().expect("Could not read BAM header");
+    let mut counts: HashMap<String,i64> = HashMap::new();
+    for result in reader.records() {
+        if let Some(bc) = barcode_of_record(&result.expect("Could not read BAM record"), on_bad_name) {
+            *counts.entry(bc).or_insert(0) += 1;
+        }
+    }
+    counts.into_iter().collect()
+}
+
+
+/// Get the blocks of the reference covered by aligned bases; split at skips (N) of spliced reads
+fn aligned_blocks(start:usize, cigar:&dyn noodles::sam::alignment::record::Cigar) -> Vec<(usize,usize)> {
+    use noodles::sam::alignment::record::cigar::op::Kind;
+    let mut blocks = Vec::new();
+    let mut block_start = start;
+    let mut pos = start;
+    for op in cigar.iter() {
+        let op = op.expect("Bad CIGAR");
+        match op.kind() {
+            Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch | Kind::Deletion => {
+                pos += op.len();
+            },
+            Kind::Skip => {
+                if pos > block_start {
+                    blocks.push((block_start, pos));
+                }
+                pos += op.len();
+                block_start = pos;
+            },
+            _ => {}
+        }
+    }
+    if pos > block_start {
+        blocks.push((block_start, pos));
+    }
+    blocks
+}
+
+
+/// Insert sizes are counted in bins of this many bases. Longer inserts all go in the last bin
+const INSERT_SIZE_BIN: u32 = 10;
+const INSERT_SIZE_MAX: u32 = 1000;
+
+/// Write the insert size distribution of each quartile of cells, given in order of increasing reads: quartile
+/// (1 has the fewest reads), cells in it, start of the insert size bin, and pairs. Fragmentation problems tend
+/// to show first in the weaker cells. Returns the median insert size of each quartile, if it has pairs
+fn store_insert_sizes(
+    path:&PathBuf,
+    insert_sizes:&HashMap<String, HashMap<u32,u64>>,
+    cells:&[String]
+) -> std::io::Result<Vec<Option<u32>>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all("quartile\tcells\tinsert_size\tpairs\n".as_bytes())?;
+    let mut medians = Vec::new();
+    for quartile in 0..4 {
+        let quartile_cells = &cells[quartile*cells.len()/4..(quartile+1)*cells.len()/4];
+        let mut hist: BTreeMap<u32,u64> = BTreeMap::new();
+        for sizes in quartile_cells.iter().filter_map(|cell| insert_sizes.get(cell)) {
+            for (bin, n) in sizes {
+                *hist.entry(*bin).or_insert(0) += n;
+            }
+        }
+        for (bin, n) in &hist {
+            writeln!(writer, "{}\t{}\t{}\t{}", quartile+1, quartile_cells.len(), bin*INSERT_SIZE_BIN, n)?;
+        }
+        let total: u64 = hist.values().sum();
+        let mut cumulative = 0;
+        medians.push(hist.iter().find(|(_, n)| {
+            cumulative += *n;
+            2*cumulative >= total
+        }).map(|(bin, _)| bin*INSERT_SIZE_BIN));
+    }
+    writer.flush()?;
+    Ok(medians)
+}
+
+
+/// Insert sizes of one quartile of cells: the quartile, the cells in it, and (insert size, pairs)
+type InsertSizeQuartile = (usize, usize, Vec<(u32, u64)>);
+
+/// Read the insert sizes written by store_insert_sizes: for each quartile, the cells in it and (insert size, pairs)
+fn read_insert_sizes(path:&PathBuf) -> std::io::Result<Vec<InsertSizeQuartile>> {
+    let mut quartiles: Vec<InsertSizeQuartile> = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines().skip(1) {
+        let parse_error = || std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Malformed insert size line: {}", line));
+        let parts = line.split('\t').collect_vec();
+        if parts.len() != 4 {
+            return Err(parse_error());
+        }
+        let quartile = parts[0].parse::<usize>().map_err(|_| parse_error())?;
+        let cells = parts[1].parse::<usize>().map_err(|_| parse_error())?;
+        let size = parts[2].parse::<u32>().map_err(|_| parse_error())?;
+        let pairs = parts[3].parse::<u64>().map_err(|_| parse_error())?;
+        match quartiles.last_mut() {
+            Some((q, _, points)) if *q == quartile => points.push((size, pairs)),
+            _ => quartiles.push((quartile, cells, vec![(size, pairs)]))
+        }
+    }
+    Ok(quartiles)
+}
+
+
+/// Write per-cell mapped and unmapped reads, and the mapped fraction
+fn store_mapping_stats(
+    path:&PathBuf,
+    mapping_per_cell:&HashMap<String, (u64,u64)>
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all("cell\ttotal\tmapped\tunmapped\tmapped_fraction\n".as_bytes())?;
+    for (cell, (mapped, unmapped)) in mapping_per_cell.iter().sorted_by_key(|(cell,_)| *cell) {
+        let total = mapped + unmapped;
+        let line = format!("{}\t{}\t{}\t{}\t{:.4}\n", cell, total, mapped, unmapped, *mapped as f64/total as f64);
+        writer.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+
+/// Write the UMI summary: reads, distinct UMIs, molecules after collapsing, and reads with a corrected UMI
+fn store_umi_stats(
+    path:&PathBuf,
+    stats:&UmiStats
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all("reads\tumis\tmolecules\tcorrected_reads\terror_rate\n".as_bytes())?;
+    let line = format!("{}\t{}\t{}\t{}\t{:.6}\n", stats.reads, stats.umis, stats.molecules, stats.corrected, stats.error_rate());
+    writer.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+
+/// Write the ambient profile: counts per feature, and the fraction of all background counts
+fn store_background(
+    path:&PathBuf,
+    features:&[FeatureInfo],
+    profile:&[i64]
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let total: i64 = profile.iter().sum();
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all("feature\tcount\tfraction\n".as_bytes())?;
+    for (feature, cnt) in features.iter().zip(profile) {
+        let fraction = if total>0 {*cnt as f64/total as f64} else {0.0};
+        let line = format!("{}\t{}\t{:.6e}\n", feature.id, cnt, fraction);
+        writer.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+
+/// Write per-cell total reads, and percentage of mitochondrial and ribosomal reads
+fn store_cell_qc(
+    path:&PathBuf,
+    counts:&HashMap<String, HashMap<usize,i32>>,
+    is_mito:&[bool],
+    is_ribo:&[bool],
+    excluded:Option<&HashMap<String,i64>>,
+    spike_in:Option<&HashMap<String,i64>>,
+    spike_in_molecules:Option<f64>
+) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all("cell\ttotal\tmito\tpct_mito\tribo\tpct_ribo".as_bytes())?;
+    if excluded.is_some() {
+        writer.write_all("\texcluded_biotype\tpct_excluded_biotype".as_bytes())?;
+    }
+    if spike_in.is_some() {
+        writer.write_all("\tspike_in\tpct_spike_in".as_bytes())?;
+        if spike_in_molecules.is_some() {
+            writer.write_all("\tcapture_efficiency".as_bytes())?;
+        }
+    }
+    writer.write_all("\n".as_bytes())?;
+    for (cell, cellmap) in counts.iter().sorted_by_key(|(cell,_)| *cell) {
+        let mut total = 0;
+        let mut mito = 0;
+        let mut ribo = 0;
+        for (featureid, cnt) in cellmap {
+            total += cnt;
+            if is_mito[*featureid] {
+                mito += cnt;
+            }
+            if is_ribo[*featureid] {
+                ribo += cnt;
+            }
+        }
+        let pct = |n: i32| if total>0 {100.0*n as f64/total as f64} else {0.0};
+        let line = format!("{}\t{}\t{}\t{:.2}\t{}\t{:.2}", cell, total, mito, pct(mito), ribo, pct(ribo));
+        writer.write_all(line.as_bytes())?;
+        //Percentage of all counts of the cell, before leaving any out
+        if let Some(excluded) = excluded {
+            let n = excluded.get(cell).copied().unwrap_or(0);
+            let all = total as i64 + n;
+            write!(writer, "\t{}\t{:.2}", n, if all>0 {100.0*n as f64/all as f64} else {0.0})?;
+        }
+        //Spike-ins are kept out of the total; the percentage is of the counts including them
+        if let Some(spike_in) = spike_in {
+            let n = spike_in.get(cell).copied().unwrap_or(0);
+            let all = total as i64 + n;
+            write!(writer, "\t{}\t{:.2}", n, if all>0 {100.0*n as f64/all as f64} else {0.0})?;
+            if let Some(spike_in_molecules) = spike_in_molecules {
+                write!(writer, "\t{:.4}", if spike_in_molecules>0.0 {n as f64/spike_in_molecules} else {0.0})?;
+            }
+        }
+        writer.write_all("\n".as_bytes())?;
+    }
+    Ok(())
+}
+
+
+
+fn merge_counts(inputs:&[PathBuf], prefixes:&[String], path_out:&Path) {
+
+    if !prefixes.is_empty() && prefixes.len() != inputs.len() {
+        error!("Number of prefixes ({}) does not match number of inputs ({})", prefixes.len(), inputs.len());
+        process::exit(1)
+    }
+
+    println!("Merging {} count tables...", inputs.len());
+    let merged = CountMatrix::read_merged(inputs, prefixes).expect("Failed to read count tables");
+    println!("Merged table has {} cells and {} features", merged.num_cells(), merged.features.len());
+
+    merged.store(path_out).expect("Failed to store count table");
+}
+
+
+/// Rarefy a count table to the same number of counts per cell, so that samples sequenced to different depths
+/// can be compared
+fn downsample_counts(path_in:&Path, per_cell:usize, seed:u64, path_out:&Path) {
+    use rand::SeedableRng;
+
+    let mut counts = CountMatrix::read(path_in).expect("Failed to read count table");
+    let num_cells = counts.num_cells();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let num_removed = counts.downsample(per_cell, &mut rng);
+    println!("Downsampled {} cells to {} counts each; left out {} cells with fewer counts", 
+        num_cells - num_removed, per_cell, num_removed);
+    if counts.num_cells() == 0 {
+        warn!("No cells have {} counts; the output is empty", per_cell);
+    }
+
+    counts.store(path_out).expect("Failed to store count table");
+}
+
+
+fn merge_histograms_cmd(inputs:&[PathBuf], path_out:&Path) {
+    println!("Merging {} histograms...", inputs.len());
+    let merged = merge_histograms(inputs).expect("Failed to read histograms");
+    println!("Merged histogram has {} barcodes and {} reads", merged.len(), merged.iter().map(|(_,cnt)| cnt).sum::<i64>());
+    store_histogram(path_out, &merged).expect("Failed to store histogram");
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Feature barcode counting /////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Feature type for antibody/hashing tags
+const FEATURE_TYPE_ANTIBODY: &str = "Antibody Capture";
+
+
+/// Find which feature barcode best matches a region of a read using Myers' algorithm.
+/// Ties between several features are treated as no match
+fn match_feature_barcode(features:&[Barcode], region:&[u8], max_dist:u8) -> Option<usize> {
+    let mut best: Option<(usize, u8)> = None;
+    let mut num_best = 0;
+    for (j, feature) in features.iter().enumerate() {
+        let dist = feature.pattern.find_all_end(region, max_dist).map(|(_, d)| d).min();
+        if let Some(dist) = dist {
+            match best {
+                Some((_, best_dist)) if best_dist < dist => {},
+                Some((_, best_dist)) if best_dist == dist => { num_best += 1; },
+                _ => {
+                    best = Some((j, dist));
+                    num_best = 1;
+                }
+            }
+        }
+    }
+    let (j, _) = best?;
+    if num_best > 1 {
+        return None;
+    }
+    Some(j)
+}
+
+
+#[allow(clippy::too_many_arguments)]
+fn count_features(
+    path_in_r1:&PathBuf,
+    path_in_r2:&PathBuf,
+    path_features:&Vec<PathBuf>,
+    path_out:&Path,
+    feature_start:usize,
+    max_dist:u8,
+    input:&InputOptions,
+    barcode_spec:&BarcodeSpec
+) {
+
+    println!("reading whitelist ");
+    let atrandi_barcodes = barcode_spec.load().expect("Failed to read barcode file");
+    let feature_barcodes = read_barcodes(path_features);
+    if feature_barcodes.is_empty() {
+        error!("No feature barcodes found");
+        process::exit(1)
+    }
+
+    //Allow for a few extra bases in the search window, in case of indels
+    let window_len = feature_barcodes.iter().map(|f| f.sequence.len()).max().unwrap() + max_dist as usize;
+
+    let reader = PairedFastqReader::open(path_in_r1, path_in_r2, input);
+
+    let features = feature_barcodes.iter().map(|f| FeatureInfo::new(&f.name, FEATURE_TYPE_ANTIBODY)).collect_vec();
+    let mut cell_counts = PackedCounts::default();
+
+    let mut read_count = 0;
+    let mut count_ok_bc = 0;
+    let mut count_ok_feature = 0;
+    for (record_r1, record_r2) in reader {
+        read_count += 1;
+        if read_count%100000 == 0 {
+            println!("Processed reads: {}   Ok barcode: {}   Ok feature: {}", read_count, count_ok_bc, count_ok_feature);
+        }
+
+        let bc = match atrandi_barcodes.get_correct_bc_from_read(record_r2.seq(), Some(record_r2.qual()), false) {
+            Some(bc) => bc,
+            None => continue
+        };
+        count_ok_bc += 1;
+
+        let seq_r1 = record_r1.seq();
+        let from = feature_start.min(seq_r1.len());
+        let to = (feature_start + window_len).min(seq_r1.len());
+        let featureid = match match_feature_barcode(&feature_barcodes, &seq_r1[from..to], max_dist) {
+            Some(featureid) => featureid,
+            None => continue
+        };
+        count_ok_feature += 1;
+
+        cell_counts.add(bc.pack(), featureid, 1);
+    }
+
+    println!("Processed reads: {}   Ok barcode: {}   Ok feature: {}", read_count, count_ok_bc, count_ok_feature);
+    let matrix = cell_counts.into_matrix(features, &atrandi_barcodes);
+
+    matrix.store(path_out).expect("Failed to store count table");
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// CRISPR guide counting ////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Feature type for CRISPR guides
+const FEATURE_TYPE_GUIDE: &str = "CRISPR Guide Capture";
+
+
+/// Find which guide matches a sequence with at most one mismatch. Ties are treated as no match
+fn match_guide(guides:&[Barcode], guide_index:&HashMap<Vec<u8>,usize>, seq:&[u8]) -> Option<usize> {
+    //Trivial exact match
+    if let Some(&j) = guide_index.get(seq) {
+        return Some(j);
+    }
+    let mut found = None;
+    for (j, guide) in guides.iter().enumerate() {
+        if guide.sequence.len()==seq.len() && num_similar_elements(&guide.sequence, seq) as usize + 1 >= seq.len() {
+            if found.is_some() {
+                return None;
+            }
+            found = Some(j);
+        }
+    }
+    found
+}
+
+
+fn count_guides(
+    path_in_r1:&PathBuf,
+    path_in_r2:&PathBuf,
+    path_guides:&Vec<PathBuf>,
+    path_out:&Path,
+    guide_start:usize,
+    input:&InputOptions,
+    barcode_spec:&BarcodeSpec
+) {
+
+    println!("reading whitelist ");
+    let atrandi_barcodes = barcode_spec.load().expect("Failed to read barcode file");
+    let guides = read_barcodes(path_guides);
+    if guides.is_empty() {
+        error!("No guides found");
+        process::exit(1)
+    }
+    let guide_len = guides[0].sequence.len();
+    if guides.iter().any(|g| g.sequence.len()!=guide_len) {
+        error!("All guides must have the same length");
+        process::exit(1)
+    }
+    let guide_index: HashMap<Vec<u8>,usize> = guides.iter().enumerate().map(|(j,g)| (g.sequence.clone(), j)).collect();
+
+    let reader = PairedFastqReader::open(path_in_r1, path_in_r2, input);
+
+    let features = guides.iter().map(|g| FeatureInfo::new(&g.name, FEATURE_TYPE_GUIDE)).collect_vec();
+    let mut cell_counts = PackedCounts::default();
+
+    let mut read_count = 0;
+    let mut count_ok_bc = 0;
+    let mut count_ok_guide = 0;
+    for (record_r1, record_r2) in reader {
+        read_count += 1;
+        if read_count%100000 == 0 {
+            println!("Processed reads: {}   Ok barcode: {}   Ok guide: {}", read_count, count_ok_bc, count_ok_guide);
+        }
+
+        let bc = match atrandi_barcodes.get_correct_bc_from_read(record_r2.seq(), Some(record_r2.qual()), false) {
+            Some(bc) => bc,
+            None => continue
+        };
+        count_ok_bc += 1;
+
+        let seq_r1 = record_r1.seq();
+        if seq_r1.len() < guide_start + guide_len {
+            continue;
+        }
+        let guideid = match match_guide(&guides, &guide_index, &seq_r1[guide_start..(guide_start+guide_len)]) {
+            Some(guideid) => guideid,
+            None => continue
+        };
+        count_ok_guide += 1;
+
+        cell_counts.add(bc.pack(), guideid, 1);
+    }
+
+    println!("Processed reads: {}   Ok barcode: {}   Ok guide: {}", read_count, count_ok_bc, count_ok_guide);
+    let matrix = cell_counts.into_matrix(features, &atrandi_barcodes);
+
+    ////// Summarize each guide: total reads, number of cells, mean reads per cell with the guide
+    let mut total_reads = vec![0_i64; guides.len()];
+    let mut num_cells = vec![0_i64; guides.len()];
+    for cellmap in matrix.counts.values() {
+        for (guideid, cnt) in cellmap {
+            total_reads[*guideid] += *cnt as i64;
+            num_cells[*guideid] += 1;
+        }
+    }
+
+    matrix.store(path_out).expect("Failed to store count table");
+
+    let output_s = File::create(RunOutputs::in_dir(path_out).path(OUT_GUIDE_SUMMARY)).expect("creation of guide summary failed");
+    let mut writer_s = BufWriter::new(output_s);
+    writer_s.write_all("guide\treads\tcells\tmean_reads_per_cell\n".as_bytes()).expect("Unable to write data");
+    for (j, guide) in guides.iter().enumerate() {
+        let mean = if num_cells[j]>0 {total_reads[j] as f64/num_cells[j] as f64} else {0.0};
+        let line = format!("{}\t{}\t{}\t{}\n", guide.name, total_reads[j], num_cells[j], mean);
+        writer_s.write_all(line.as_bytes()).expect("Unable to write data");
+    }
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// K-mer based counting /////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Feature type for transcripts counted by k-mer voting
+const FEATURE_TYPE_TRANSCRIPT: &str = "Transcript";
+
+
+#[allow(clippy::too_many_arguments)]
+fn count_kmers(
+    path_in_r1:&PathBuf,
+    path_in_r2:&PathBuf,
+    path_transcripts:&PathBuf,
+    path_out:&Path,
+    k:usize,
+    min_votes:usize,
+    input:&InputOptions,
+    barcode_spec:&BarcodeSpec
+) {
+
+    println!("reading whitelist ");
+    let atrandi_barcodes = barcode_spec.load().expect("Failed to read barcode file");
+
+    ////// Build k-mer index of all transcripts
+    println!("Building k-mer index");
+    let mut index = KmerIndex::new(k);
+    let mut features: Vec<FeatureInfo> = Vec::new();
+    let mut reader = open_fasta(path_transcripts);
+    while let Some(record) = reader.next() {
+        let record = record.expect("Error reading record");
+        index.add_transcript(features.len() as u32, &record.full_seq());
+        features.push(FeatureInfo::new(record.id().expect("Bad transcript name"), FEATURE_TYPE_TRANSCRIPT));
+    }
+    println!("Indexed {} transcripts with {} k-mers", features.len(), index.len());
+
+    let reader = PairedFastqReader::open(path_in_r1, path_in_r2, input);
+
+    let mut cell_counts = PackedCounts::default();
+
+    let mut read_count = 0;
+    let mut count_ok_bc = 0;
+    let mut count_ok_transcript = 0;
+    for (record_r1, record_r2) in reader {
+        read_count += 1;
+        if read_count%100000 == 0 {
+            println!("Processed reads: {}   Ok barcode: {}   Ok transcript: {}", read_count, count_ok_bc, count_ok_transcript);
+        }
+
+        let bc = match atrandi_barcodes.get_correct_bc_from_read(record_r2.seq(), Some(record_r2.qual()), false) {
+            Some(bc) => bc,
+            None => continue
+        };
+        count_ok_bc += 1;
+
+        let transcriptid = match index.assign(record_r1.seq(), min_votes) {
+            Some(transcriptid) => transcriptid as usize,
+            None => continue
+        };
+        count_ok_transcript += 1;
+
+        cell_counts.add(bc.pack(), transcriptid, 1);
+    }
+
+    println!("Processed reads: {}   Ok barcode: {}   Ok transcript: {}", read_count, count_ok_bc, count_ok_transcript);
+    let matrix = cell_counts.into_matrix(features, &atrandi_barcodes);
+
+    matrix.store(path_out).expect("Failed to store count table");
+}
+
+
+
+
+/// Output formats for ConvertCounts
+#[derive(Clone, Copy, ValueEnum)]
+enum LongFormat {
+    Tsv,
+    Parquet
+}
+
+fn convert_counts(path_in:&Path, path_out:&PathBuf, format:LongFormat, min_count:i32) {
+
+    let matrix = CountMatrix::read(path_in).expect("Failed to read count table");
+
+    match format {
+        LongFormat::Tsv => {
+            matrix.store_long_tsv(path_out, min_count).expect("Failed to write TSV");
+        },
+        LongFormat::Parquet => {
+            #[cfg(feature = "parquet")]
+            matrix.store_long_parquet(path_out, min_count).expect("Failed to write parquet");
+
+            #[cfg(not(feature = "parquet"))]
+            {
+                error!("Parquet support not compiled in; rebuild with --features parquet");
+                process::exit(1)
+            }
+        }
+    }
+}
+
+
+/// List the top features of each called cell. Cells are called from their total counts, by the same rule as
+/// for the ToFastq sample sheet, unless the number of cells is given
+fn top_features_cmd(path_in:&Path, path_out:&PathBuf, k:usize, num_cells:Option<usize>, expected_cells:usize) {
+
+    let matrix = CountMatrix::read(path_in).expect("Failed to read count table");
+
+    let totals = matrix.cell_totals();
+    let num_cells = num_cells.unwrap_or_else(|| estimate_cells(&totals.iter().map(|(_, total)| *total).collect_vec(), expected_cells));
+    let cells = top_barcodes(&totals, num_cells);
+    println!("Listing the top {} features of {} of {} cells", k, cells.len(), totals.len());
+
+    matrix.store_top_features(path_out, &cells, k).expect("Failed to write top features");
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// ATAC fragment file ////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Write all fragments starting at the same position, sorted by end and barcode, with their duplicate count
+fn flush_fragments(
+    writer:&mut ParCompress<Bgzf>,
+    chrom:&str,
+    start:usize,
+    fragments:&mut HashMap<(usize,String),i32>
+) {
+    for ((end, bc), cnt) in fragments.drain().sorted() {
+        let line = format!("{}\t{}\t{}\t{}\t{}\n", chrom, start, end, bc, cnt);
+        writer.write_all(line.as_bytes()).expect("Unable to write data");
+    }
+}
+
+
+fn bam_to_fragments(ibam:&PathBuf, path_out:&PathBuf, min_mapq:u8, on_bad_name:BadNamePolicy, compress:&CompressOptions) {
+
+    use noodles::bam;
+
+    //Tn5 inserts with a 9bp duplication; shift to the center of the insertion
+    const TN5_SHIFT_PLUS: usize = 4;
+    const TN5_SHIFT_MINUS: usize = 5;
+
+    let mut reader = bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
+    let header = reader.read_header().expect("Could not read BAM header");
+
+    let mut writer: ParCompress<Bgzf> = compress.writer_bgzf(compress.create(path_out), 1);
+
+    //Fragments starting at the current position. BAM is coordinate sorted, so when the position changes they can be written
+    let mut fragments: HashMap<(usize,String),i32> = HashMap::new();
+    let mut cur_chrom: Option<usize> = None;
+    let mut cur_start = 0;
+
+    let mut num_fragments = 0;
+    println!("Extracting fragments...");
+    for result in reader.records() {
+        let record = result.expect("Could not read BAM record");
+
+        //Only use the leftmost mate of good proper pairs
+        let flags = record.flags();
+        if flags.is_unmapped() || flags.is_secondary() || flags.is_supplementary() || 
+            flags.is_qc_fail() || !flags.is_properly_segmented() {
+            continue;
+        }
+        if record.mapping_quality().map(|q| q.get()).unwrap_or(255) < min_mapq {
+            continue;
+        }
+        let tlen = record.template_length();
+        if tlen <= 0 {
+            continue;
+        }
+
+        let chrom = match record.reference_sequence_id() {
+            Some(seqid) => seqid.expect("Bad reference sequence ID"),
+            None => continue
+        };
+        let pos = match record.alignment_start() {
+            Some(pos) => pos.expect("Bad alignment start").get() - 1,
+            None => continue
+        };
+        let start = pos + TN5_SHIFT_PLUS;
+        let end = match (pos + tlen as usize).checked_sub(TN5_SHIFT_MINUS) {
+            Some(end) if end > start => end,
+            _ => continue
+        };
+
+        let bc = match barcode_of_record(&record, on_bad_name) {
+            Some(bc) => bc,
+            None => continue
+        };
+
+        if cur_chrom != Some(chrom) || cur_start != start {
+            if let Some(c) = cur_chrom {
+                let chrom_name = header.reference_sequences().get_index(c).expect("!").0.to_string();
+                flush_fragments(&mut writer, &chrom_name, cur_start, &mut fragments);
+            }
+            cur_chrom = Some(chrom);
+            cur_start = start;
+        }
+
+        let cnt = fragments.entry((end, bc)).or_insert(0);
+        if *cnt == 0 {
+            num_fragments += 1;
+        }
+        *cnt += 1;
+    }
+    if let Some(c) = cur_chrom {
+        let chrom_name = header.reference_sequences().get_index(c).expect("!").0.to_string();
+        flush_fragments(&mut writer, &chrom_name, cur_start, &mut fragments);
+    }
+    writer.finish().unwrap();
+
+    println!("Wrote {} unique fragments", num_fragments);
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Coverage per group ////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Read a list of cells, or a TSV with cell and group. Cells without a group are put in the group "selected"
+fn read_cell_groups(path:&PathBuf) -> HashMap<String,String> {
+    let mut rdr = ReaderBuilder::new()
+        .delimiter(b'\t')
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .expect("Could not open cell list");
+    let mut groups = HashMap::new();
+    for result in rdr.records() {
+        let record = result.expect("Could not read cell list");
+        let cell = record[0].to_string();
+        let group = record.get(1).unwrap_or("selected").to_string();
+        groups.insert(cell, group);
+    }
+    groups
+}
+
+
+fn coverage_per_group(ibam:&PathBuf, path_cells:&PathBuf, path_out:&PathBuf, bin_size:usize, on_bad_name:BadNamePolicy) {
+
+    use noodles::bam;
+    use noodles::sam::alignment::record::Cigar;
+
+    let cell_groups = read_cell_groups(path_cells);
+    let list_groups = cell_groups.values().cloned().sorted().dedup().collect_vec();
+    let group_index: HashMap<&String,usize> = list_groups.iter().enumerate().map(|(i,g)| (g,i)).collect();
+    println!("Computing coverage for {} cells in {} groups", cell_groups.len(), list_groups.len());
+
+    let mut reader = bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
+    let header = reader.read_header().expect("Could not read BAM header");
+    let num_chrom = header.reference_sequences().len();
+
+    //Coverage per group, chromosome and bin. Bins are allocated as needed
+    let mut coverage: Vec<Vec<Vec<u32>>> = vec![vec![Vec::new(); num_chrom]; list_groups.len()];
+
+    for result in reader.records() {
+        let record = result.expect("Could not read BAM record");
+
+        let flags = record.flags();
+        if flags.is_unmapped() || flags.is_secondary() || flags.is_supplementary() || flags.is_duplicate() {
+            continue;
+        }
+
+        let bc = match barcode_of_record(&record, on_bad_name) {
+            Some(bc) => bc,
+            None => continue
+        };
+        let group = match cell_groups.get(&bc) {
+            Some(group) => group_index[group],
+            None => continue
+        };
+
+        let (chrom, start) = match (record.reference_sequence_id(), record.alignment_start()) {
+            (Some(chrom), Some(start)) => (chrom.expect("Bad reference sequence ID"), start.expect("Bad alignment start").get() - 1),
+            _ => continue
+        };
+        let span = record.cigar().alignment_span().expect("Bad CIGAR");
+        if span == 0 {
+            continue;
+        }
+
+        let bins = &mut coverage[group][chrom];
+        let last_bin = (start + span - 1) / bin_size;
+        if bins.len() <= last_bin {
+            bins.resize(last_bin + 1, 0);
+        }
+        for bin in &mut bins[(start / bin_size)..=last_bin] {
+            *bin += 1;
+        }
+    }
+
+    ////// Write one bedGraph per group, merging consecutive bins with the same value
+    if !path_out.exists() {
+        std::fs::create_dir(path_out).expect("Could not create output directory");
+    }
+    for (group, group_cov) in list_groups.iter().zip(coverage.iter()) {
+        let output = File::create(path_out.join(format!("{}.bedgraph", group))).expect("creation of bedGraph failed");
+        let mut writer = BufWriter::new(output);
+        writer.write_all(format!("track type=bedGraph name=\"{}\"\n", group).as_bytes()).expect("Unable to write data");
+        for (chrom, bins) in group_cov.iter().enumerate() {
+            let chrom_name = header.reference_sequences().get_index(chrom).expect("!").0.to_string();
+            let mut i = 0;
+            while i < bins.len() {
+                let mut j = i + 1;
+                while j < bins.len() && bins[j] == bins[i] {
+                    j += 1;
+                }
+                if bins[i] > 0 {
+                    let line = format!("{}\t{}\t{}\t{}\n", chrom_name, i*bin_size, j*bin_size, bins[i]);
+                    writer.write_all(line.as_bytes()).expect("Unable to write data");
+                }
+                i = j;
+            }
+        }
+    }
+    println!("Wrote bedGraph files; convert with bedGraphToBigWig if bigWig is needed");
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Species mixing ////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+fn barnyard(path_in:&Path, genomes:&[String], path_out:&Path, min_reads:i32, min_fraction:f64) {
+
+    let matrix = CountMatrix::read(path_in).expect("Failed to read count table");
+
+    //Figure out which genome each feature belongs to, by prefix of the reference name
+    let feature_genome = matrix.features.iter().map(|f| genomes.iter().position(|g| f.id.starts_with(g.as_str()))).collect_vec();
+
+    let output = File::create(path_out).expect("creation of barnyard file failed");
+    let mut writer = BufWriter::new(output);
+    let header = format!("cell\t{}\tassignment\n", genomes.join("\t"));
+    writer.write_all(header.as_bytes()).expect("Unable to write data");
+
+    let mut num_per_genome = vec![0; genomes.len()];
+    let mut num_mixed = 0;
+    for (cell, cellmap) in matrix.counts.iter().sorted_by_key(|(cell,_)| *cell) {
+        let mut reads_per_genome = vec![0; genomes.len()];
+        for (featureid, cnt) in cellmap {
+            if let Some(g) = feature_genome[*featureid] {
+                reads_per_genome[g] += cnt;
+            }
+        }
+        let total: i32 = reads_per_genome.iter().sum();
+        if total < min_reads {
+            continue;
+        }
+
+        let (best, best_cnt) = reads_per_genome.iter().enumerate().max_by_key(|(_,c)| **c).unwrap();
+        let assignment = if *best_cnt as f64 >= min_fraction*total as f64 {
+            num_per_genome[best] += 1;
+            genomes[best].as_str()
+        } else {
+            num_mixed += 1;
+            "mixed"
+        };
+
+        let line = format!("{}\t{}\t{}\n", cell, reads_per_genome.iter().join("\t"), assignment);
+        writer.write_all(line.as_bytes()).expect("Unable to write data");
+    }
+
+    ////// Estimate collision rate. Only collisions between species are visible; 
+    ////// if genome i makes up fraction p_i of cells, then a fraction 1-sum(p_i^2) of collisions are visible
+    let num_cells = num_per_genome.iter().sum::<i32>() + num_mixed;
+    if num_cells > 0 {
+        let frac_visible = 1.0 - num_per_genome.iter().map(|n| {
+            let p = *n as f64 / (num_cells - num_mixed).max(1) as f64;
+            p*p
+        }).sum::<f64>();
+        let frac_mixed = num_mixed as f64 / num_cells as f64;
+        println!("Cells: {}   per genome: {:?}   mixed: {} ({:.2}%)", num_cells, num_per_genome, num_mixed, 100.0*frac_mixed);
+        if frac_visible > 0.0 {
+            println!("Estimated collision rate (including same-species): {:.2}%", 100.0*(frac_mixed/frac_visible).min(1.0));
+        }
+    } else {
+        warn!("No cells with at least {} reads", min_reads);
+    }
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Barcode collisions ////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+fn estimate_collisions(
+    histogram_file:&Path, 
+    path_out:&PathBuf, 
+    num_cells:Option<usize>, 
+    min_reads:i64,
+    max_fold:f64
+) {
+
+    //Call cells: either the top N barcodes, or all above a read cutoff
+    let hist = read_histogram(histogram_file).expect("Failed to read histogram");
+    let mut cells = hist.iter().sorted_by_key(|(bc,cnt)| (-cnt, bc.clone())).collect_vec();
+    match num_cells {
+        Some(n) => cells.truncate(n),
+        None => cells.retain(|(_,cnt)| *cnt >= min_reads)
+    }
+    if cells.is_empty() {
+        error!("No cells called from histogram");
+        process::exit(1)
+    }
+
+    ////// Expected collisions from the observed well usage
+    let well_freqs = well_frequencies(&cells.iter().map(|(bc,_)| bc.as_str()).collect_vec());
+    let q = pairwise_collision_probability(&well_freqs);
+    let rate = expected_collision_rate(&well_freqs, cells.len());
+    println!("Cells: {}   wells used per round: {:?}", cells.len(), well_freqs.iter().map(|f| f.len()).collect_vec());
+    println!("Pairwise collision probability: {:e}   expected fraction of cells in collisions: {:.3}%", q, 100.0*rate);
+
+    ////// Flag barcodes with far more reads than typical as likely multiplets
+    let median = cells[cells.len()/2].1 as f64;
+    let output = File::create(path_out).expect("creation of collision file failed");
+    let mut writer = BufWriter::new(output);
+    writer.write_all("barcode\tcount\tstatus\n".as_bytes()).expect("Unable to write data");
+    let mut num_flagged = 0;
+    for (bc, cnt) in &cells {
+        let status = if *cnt as f64 > max_fold*median {
+            num_flagged += 1;
+            "likely_multiplet"
+        } else {
+            "singlet"
+        };
+        let line = format!("{}\t{}\t{}\n", bc, cnt, status);
+        writer.write_all(line.as_bytes()).expect("Unable to write data");
+    }
+    println!("Flagged {} of {} cells as likely multiplets (more than {}x median reads)", num_flagged, cells.len(), max_fold);
+}
+
+
+/// Plan an experiment: expected and simulated barcode collisions for numbers of cells and wells used per round,
+/// and how well the barcodes in the first wells of each round can be told apart
+#[allow(clippy::too_many_arguments)]
+fn design_experiment(
+    cell_counts:&Vec<usize>,
+    num_rounds:usize,
+    wells:&[usize],
+    num_simulations:usize,
+    seed:u64,
+    max_rate:f64,
+    path_out:&Option<PathBuf>,
+    barcode_spec:&BarcodeSpec
+) {
+    use rand::SeedableRng;
+
+    let wells = match wells.len() {
+        1 => vec![wells[0]; num_rounds],
+        n if n == num_rounds => wells.to_vec(),
+        n => {
+            error!("Give either one number of wells for all rounds, or one per round ({}); got {}", num_rounds, n);
+            process::exit(1)
+        }
+    };
+    if wells.contains(&0) {
+        error!("Each round needs at least one well");
+        process::exit(1)
+    }
+    let freqs = uniform_well_frequencies(&wells);
+    println!("Wells per round: {}   combinations: {}", wells.iter().join("x"), wells.iter().map(|w| *w as f64).product::<f64>());
+
+    ////// Collisions per number of cells; simulated spread as the 2.5 and 97.5 percentiles
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let mut lines = vec!["cells\texpected_pct_in_collisions\tsimulated_pct_in_collisions\tsimulated_low\tsimulated_high\tcollided_barcodes\tmin_wells_per_round".to_string()];
+    for num_cells in cell_counts {
+        let min_wells = min_wells_per_round(num_rounds, *num_cells, max_rate).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1)
+        });
+        let expected = expected_collision_rate(&freqs, *num_cells);
+        let sims = (0..num_simulations).map(|_| simulate_collisions(&wells, *num_cells, &mut rng)).collect_vec();
+        let rates = sims.iter().map(|s| s.cells_in_collisions).sorted_by(|a, b| a.total_cmp(b)).collect_vec();
+        let percentile = |p: f64| rates.get(((rates.len() as f64 - 1.0)*p).round() as usize).copied().unwrap_or(0.0);
+        let mean = rates.iter().sum::<f64>() / rates.len().max(1) as f64;
+        let collided = sims.iter().map(|s| s.collided_barcodes as f64).sum::<f64>() / sims.len().max(1) as f64;
+        lines.push(format!("{}\t{:.3}\t{:.3}\t{:.3}\t{:.3}\t{:.1}\t{}", 
+            num_cells, 100.0*expected, 100.0*mean, 100.0*percentile(0.025), 100.0*percentile(0.975), collided,
+            min_wells));
+    }
+    println!("Fewest wells per round for at most {:.1}% of cells in collisions, in the last column", 100.0*max_rate);
+    for line in &lines {
+        println!("{}", line);
+    }
+    if let Some(path_out) = path_out {
+        let mut writer = TableWriter::create(path_out).expect("creation of design file failed");
+        for line in &lines {
+            writeln!(writer, "{}", line).expect("Unable to write data");
+        }
+        writer.finish().expect("Unable to write data");
+    }
+
+    ////// Barcodes in the wells used should differ enough for mismatches to be corrected to the right one
+    match barcode_spec.load() {
+        Ok(atrandi_barcodes) => {
+            let plate = &atrandi_barcodes.plates[0];
+            for (round, (whitelist, w)) in plate.rounds.iter().zip(&wells).enumerate() {
+                if *w > whitelist.list.len() {
+                    warn!("Round {} has {} barcodes, fewer than {} wells", round+1, whitelist.list.len(), w);
+                }
+                let used = whitelist.list.iter().take(*w).map(|bc| bc.as_bytes()).collect_vec();
+                match min_pairwise_distance(&used) {
+                    Some(d) => println!("Round {}: barcodes of the first {} wells differ in at least {} bases; up to {} mismatches can be corrected", 
+                        round+1, used.len(), d, d.saturating_sub(1)/2),
+                    None => println!("Round {}: a single barcode", round+1)
+                }
+            }
+        },
+        Err(e) => warn!("Could not read the barcodes, so their distances are not shown: {}", e)
+    }
+}
+
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Plate heatmap /////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Sum reads per well of each plate and round, written as a grid with one line per plate row
+fn plate_heatmap(histogram_file:&Path, path_out:&Path, barcode_spec:&BarcodeSpec) {
+    let atrandi_barcodes = barcode_spec.load().expect("Failed to read barcode file");
+    let hist = read_histogram(histogram_file).expect("Failed to read histogram");
+
+    let (counts, count_unknown) = atrandi_barcodes.reads_per_well(&hist);
+    if count_unknown > 0 {
+        warn!("{} barcodes in the histogram are not from the given plates", count_unknown);
+    }
+
+    let max_cols = atrandi_barcodes.plates.iter().map(|p| p.format.cols()).max().unwrap_or(0);
+    let mut writer = TableWriter::create(path_out).expect("creation of heatmap failed");
+    let header = format!("plate\tround\trow\t{}\n", (1..=max_cols).join("\t"));
+    writer.write_all(header.as_bytes()).expect("Unable to write data");
+    for (plate, plate_counts) in atrandi_barcodes.plates.iter().zip(&counts) {
+        let cols = plate.format.cols();
+        for (round, round_counts) in plate_counts.iter().enumerate() {
+            for row in 0..plate.format.rows() {
+                let line = format!("{}\t{}\t{}\t{}{}\n", 
+                    plate.name, round+1, (b'A' + row as u8) as char,
+                    round_counts[row*cols..(row+1)*cols].iter().join("\t"),
+                    "\t".repeat(max_cols - cols));
+                writer.write_all(line.as_bytes()).expect("Unable to write data");
+            }
+        }
+    }
+    writer.finish().expect("Unable to write data");
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// HTML report ///////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+/// Standalone report page; plots are drawn by embedded JS from the data put in place of the placeholder
+const REPORT_TEMPLATE: &str = include_str!("report.html");
+
+/// Points on the knee plot at most; ranks are picked evenly on a log scale
+const KNEE_MAX_POINTS: usize = 1000;
+
+
+/// Write a self-contained HTML report from the barcode histogram and, if given, the JSON report of ToFastq
+fn html_report(histogram_file:&Path, report_json:&Option<PathBuf>, insert_sizes:&Option<PathBuf>, path_out:&PathBuf, barcode_spec:&BarcodeSpec) {
+    let atrandi_barcodes = barcode_spec.load().expect("Failed to read barcode file");
+    let hist = read_histogram(histogram_file).expect("Failed to read histogram");
+
+    //Knee plot, as (rank, reads) from the most common barcode
+    let sorted = hist.iter().map(|(_, cnt)| *cnt).sorted_by(|a, b| b.cmp(a)).collect_vec();
+    let step = (sorted.len() as f64).powf(1.0/KNEE_MAX_POINTS as f64);
+    let mut knee = Vec::new();
+    let mut next_rank = 1.0;
+    for (i, cnt) in sorted.iter().enumerate() {
+        let rank = i+1;
+        if rank as f64 >= next_rank || rank == sorted.len() {
+            knee.push((rank, *cnt));
+            next_rank = rank as f64 * step;
+        }
+    }
+
+    let (counts, count_unknown) = atrandi_barcodes.reads_per_well(&hist);
+    if count_unknown > 0 {
+        warn!("{} barcodes in the histogram are not from the given plates", count_unknown);
+    }
+    let plates = atrandi_barcodes.plates.iter().zip(&counts).map(|(plate, plate_counts)| serde_json::json!({
+        "name": plate.name,
+        "rows": plate.format.rows(),
+        "cols": plate.format.cols(),
+        "rounds": plate_counts
+    })).collect_vec();
+
+    let run: serde_json::Value = match report_json {
+        Some(report_json) => {
+            let file = File::open(report_json).expect("Could not open JSON report");
+            serde_json::from_reader(std::io::BufReader::new(file)).expect("Could not parse JSON report")
+        },
+        None => serde_json::Value::Null
+    };
+
+    //Insert size distribution of each quartile of cells
+    let insert_sizes: serde_json::Value = match insert_sizes {
+        Some(insert_sizes) => read_insert_sizes(insert_sizes).expect("Could not read insert sizes").into_iter()
+            .map(|(quartile, cells, points)| serde_json::json!({
+                "quartile": quartile,
+                "cells": cells,
+                "points": points
+            })).collect(),
+        None => serde_json::Value::Null
+    };
+
+    let data = serde_json::json!({
+        "num_barcodes": hist.len(),
+        "total_reads": sorted.iter().sum::<i64>(),
+        "knee": knee,
+        "plates": plates,
+        "run": run,
+        "insert_sizes": insert_sizes
+    });
+
+    //Keep the data from closing the script block
+    let data = serde_json::to_string(&data).expect("Unable to serialize report").replace("</", "<\\/");
+    let html = REPORT_TEMPLATE.replace("\"__REPORT_DATA__\"", &data);
+    std::fs::write(path_out, html).expect("Unable to write report");
+    println!("Report: {}", path_out.display());
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Read groups per cell //////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+fn assign_read_groups(ibam:&PathBuf, obam:&PathBuf, path_map:&Option<PathBuf>, max_groups:usize, on_bad_name:BadNamePolicy, metadata:&RunMetadata) {
+
+    use noodles::bam;
+    use noodles::sam::alignment::RecordBuf;
+    use noodles::sam::alignment::io::Write as AlignmentWrite;
+    use noodles::sam::alignment::record::data::field::Tag;
+    use noodles::sam::alignment::record_buf::data::field::Value;
+    use noodles::sam::header::record::value::{Map, map::ReadGroup};
+    use noodles::sam::header::record::value::map::read_group::tag as rg_tag;
+
+    ////// First pass: find all cells, so the header can list the read groups
+    println!("Collecting cell barcodes...");
+    let mut reader = bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
+    let mut header = reader.read_header().expect("Could not read BAM header");
+    let mut cells: HashSet<String> = HashSet::new();
+    for result in reader.records() {
+        let record = result.expect("Could not read BAM record");
+        if let Some(bc) = barcode_of_record(&record, on_bad_name) {
+            cells.insert(bc);
+        }
+    }
+    let cells = cells.into_iter().sorted().collect_vec();
+
+    //One read group per cell, or pool cells if there are too many
+    let pooled = cells.len() > max_groups;
+    let cell_group: HashMap<String,String> = cells.iter().enumerate().map(|(i,bc)| {
+        let group = if pooled { format!("pool{}", i % max_groups) } else { bc.clone() };
+        (bc.clone(), group)
+    }).collect();
+    let groups = cell_group.values().cloned().sorted().dedup().collect_vec();
+    println!("Cells: {}   read groups: {}{}", cells.len(), groups.len(), if pooled {" (pooled)"} else {""});
+
+    for group in &groups {
+        let mut rg = Map::<ReadGroup>::default();
+        rg.other_fields_mut().insert(rg_tag::SAMPLE, group.as_str().into());
+        if let Some(platform) = &metadata.platform {
+            rg.other_fields_mut().insert(rg_tag::PLATFORM, platform.as_str().into());
+        }
+        if let Some(library) = metadata.library.as_ref().or(metadata.sample.as_ref()) {
+            rg.other_fields_mut().insert(rg_tag::LIBRARY, library.as_str().into());
+        }
+        if let Some(platform_unit) = &metadata.platform_unit {
+            rg.other_fields_mut().insert(rg_tag::PLATFORM_UNIT, platform_unit.as_str().into());
+        }
+        header.read_groups_mut().insert(group.as_str().into(), rg);
+    }
+
+    ////// Second pass: tag each record
+    let mut reader = bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
+    reader.read_header().expect("Could not read BAM header");
+    let mut writer = bam::io::Writer::new(File::create(obam).expect("Could not create BAM file"));
+    writer.write_header(&header).expect("Could not write BAM header");
+    for result in reader.records() {
+        let record = result.expect("Could not read BAM record");
+        //Records without a barcode are passed on without a read group
+        let mut record_buf = RecordBuf::try_from_alignment_record(&header, &record).expect("Could not convert BAM record");
+        if let Some(bc) = barcode_of_record(&record, on_bad_name) {
+            record_buf.data_mut().insert(Tag::READ_GROUP, Value::from(cell_group[&bc].as_str()));
+        }
+        writer.write_alignment_record(&header, &record_buf).expect("Could not write BAM record");
+    }
+    writer.try_finish().expect("Could not finish BAM file");
+
+    ////// Optionally write which cell went into which group
+    if let Some(path_map) = path_map {
+        let mut writer_m = BufWriter::new(File::create(path_map).expect("creation of read group map failed"));
+        writer_m.write_all("cell\tread_group\n".as_bytes()).expect("Unable to write data");
+        for bc in &cells {
+            let line = format!("{}\t{}\n", bc, cell_group[bc]);
+            writer_m.write_all(line.as_bytes()).expect("Unable to write data");
+        }
+    }
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Normalize barcode naming //////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Where a BAM file keeps the cell barcode of each read
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BarcodeLocation {
+    /// Read name prefix, BC_readid, as written by this tool
+    Name,
+    /// CB tag, as used by CellRanger and STARsolo
+    Tag,
+    /// Both the read name and the CB tag
+    Both
+}
+
+
+/// Rewrite a barcode string: drop a CellRanger-style suffix such as -1, drop the separators between rounds,
+/// and add a suffix
+fn normalize_barcode(bc:&str, strip_suffix:bool, plain:bool, add_suffix:&Option<String>) -> String {
+    let mut bc = bc;
+    if strip_suffix {
+        if let Some((prefix, suffix)) = bc.rsplit_once('-') {
+            if !suffix.is_empty() && suffix.bytes().all(|c| c.is_ascii_digit()) {
+                bc = prefix;
+            }
+        }
+    }
+    let mut bc = if plain {bc.replace('.', "")} else {bc.to_string()};
+    if let Some(add_suffix) = add_suffix {
+        bc.push_str(add_suffix);
+    }
+    bc
+}
+
+
+/// Move the cell barcodes of a BAM between the read name and the CB tag, and rewrite them, so that the barcodes
+/// of this tool and of e.g. CellRanger or STARsolo can be compared as identical strings
+fn normalize_bam_barcodes(
+    ibam:&PathBuf,
+    obam:&PathBuf,
+    from:BarcodeLocation,
+    to:BarcodeLocation,
+    strip_suffix:bool,
+    plain:bool,
+    add_suffix:&Option<String>
+) {
+    use noodles::bam;
+    use noodles::sam::alignment::RecordBuf;
+    use noodles::sam::alignment::io::Write as AlignmentWrite;
+    use noodles::sam::alignment::record::data::field::{Tag, Value as RecordValue};
+    use noodles::sam::alignment::record_buf::data::field::Value;
+    use bstr::ByteSlice;
+
+    let mut reader = bam::io::reader::Builder::default().build_from_path(ibam).expect("Could not read BAM file");
+    let header = reader.read_header().expect("Could not read BAM header");
+    let mut writer = bam::io::Writer::new(File::create(obam).expect("Could not create BAM file"));
+    writer.write_header(&header).expect("Could not write BAM header");
+
+    let mut count_records = 0;
+    let mut count_no_barcode = 0;
     for result in reader.records() {
         let record = result.expect("Could not read BAM record");
+        count_records += 1;
+        let name = record.name().map(|n| n.to_str_lossy().to_string()).unwrap_or_default();
+
+        ////// Find the barcode, and the read name without it
+        let from_name = name.split_once('_').map(|(bc, readid)| (bc.to_string(), readid.to_string()));
+        let from_tag = match record.data().get(&Tag::CELL_BARCODE_ID) {
+            Some(Ok(RecordValue::String(bc))) => Some(bc.to_str_lossy().to_string()),
+            _ => None
+        };
+        let (bc, readid) = match (from, from_name, from_tag) {
+            (BarcodeLocation::Tag, _, Some(bc)) => (bc, name.clone()),
+            (BarcodeLocation::Name, Some((bc, readid)), _) | (BarcodeLocation::Both, Some((bc, readid)), _) => (bc, readid),
+            (BarcodeLocation::Both, None, Some(bc)) => (bc, name.clone()),
+            _ => {
+                //Passed on as it is
+                count_no_barcode += 1;
+                writer.write_alignment_record(&header, &record).expect("Could not write BAM record");
+                continue;
+            }
+        };
+        let bc = normalize_barcode(&bc, strip_suffix, plain, add_suffix);
+
+        ////// Write it where asked
+        let mut record_buf = RecordBuf::try_from_alignment_record(&header, &record).expect("Could not convert BAM record");
+        *record_buf.name_mut() = Some(match to {
+            BarcodeLocation::Tag => readid,
+            _ => format!("{}_{}", bc, readid)
+        }.into());
+        match to {
+            BarcodeLocation::Name => {
+                record_buf.data_mut().remove(&Tag::CELL_BARCODE_ID);
+            },
+            _ => {
+                record_buf.data_mut().insert(Tag::CELL_BARCODE_ID, Value::from(bc.as_str()));
+            }
+        }
+        writer.write_alignment_record(&header, &record_buf).expect("Could not write BAM record");
+    }
+    writer.try_finish().expect("Could not finish BAM file");
+
+    println!("Records: {}   without a barcode, passed on unchanged: {}", count_records, count_no_barcode);
+    if count_records > 0 && count_no_barcode == count_records {
+        warn!("No record had a barcode in the expected place; check --from");
+    }
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Learn whitelist ///////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+fn learn_barcode_whitelist(
+    path_in_r2:&PathBuf,
+    path_out:&PathBuf,
+    max_reads:usize,
+    max_per_round:usize,
+    min_distance:usize,
+    min_count:u64
+) {
+
+    //Wells are named on the smallest plate that fits all barcodes of a round
+    let format = match PlateFormat::for_num_wells(max_per_round) {
+        Some(format) => format,
+        None => {
+            error!("At most {} barcodes per round are supported", PlateFormat::Wells384.num_wells());
+            process::exit(1)
+        }
+    };
+
+    ////// Count the observed sequence at each round position
+    let mut f_r2 = open_fastq(path_in_r2);
+    let mut counts: Vec<HashMap<Vec<u8>, u64>> = vec![HashMap::new(); 4];
+    let mut read_count = 0;
+    while let Some(record) = f_r2.next() {
+        let record = record.expect("Error reading record");
+        if let Some(barcode_tuple) = extract_bc_optimistic_atrandi(record.seq()) {
+            for i in 0..4 {
+                *counts[i].entry(barcode_tuple[i].to_vec()).or_insert(0) += 1;
+            }
+        }
+        read_count += 1;
+        if read_count == max_reads {
+            break;
+        }
+    }
+
+    if read_count == 0 {
+        error!("No reads in {}", path_in_r2.display());
+        process::exit(1)
+    }
+
+    ////// Cluster each round and write in bc.csv format
+    let output = File::create(path_out).expect("creation of whitelist failed");
+    let mut writer = BufWriter::new(output);
+    writer.write_all("pos\twell\tseq\n".as_bytes()).expect("Unable to write data");
+    for (i, counts) in counts.iter().enumerate() {
+        let learned = learn_whitelist(counts, max_per_round, min_distance, min_count);
+        let covered: u64 = learned.iter().map(|(_,cnt)| cnt).sum();
+        println!("Round {}: {} barcodes, covering {:.2}% of reads", i+1, learned.len(), 100.0*covered as f64/read_count as f64);
+        if learned.is_empty() {
+            warn!("No barcodes learned for round {}", i+1);
+        }
+        for (j, (bc, _)) in learned.iter().enumerate() {
+            let line = format!("{}\t{}\t{}\n", i+1, format.well_name(j), String::from_utf8_lossy(bc));
+            writer.write_all(line.as_bytes()).expect("Unable to write data");
+        }
+    }
+
+    println!("done");
+}
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Correct barcodes from stdin ///////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+/// Correct raw barcode blocks read line by line from stdin, and write each line to stdout with the corrected barcode
+/// (- if none), the outcome, and the mismatches per round appended as tab-separated columns. The block is the whole
+/// line, or a column of a TSV; qualities can come from another column. Blocks of exactly 44 bases are accepted
+fn correct_bc_stream(column:Option<usize>, qual_column:Option<usize>, header:bool, barcode_spec:&BarcodeSpec) {
+    use std::io::BufRead;
+
+    let atrandi_barcodes = match barcode_spec.load() {
+        Ok(atrandi_barcodes) => atrandi_barcodes,
+        Err(e) => {
+            error!("Could not load barcodes: {}", e);
+            process::exit(1)
+        }
+    };
+
+    let stdin = std::io::stdin();
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let mut bc_read: Vec<u8> = Vec::new();
+    let mut bc_qual: Vec<u8> = Vec::new();
+    let mut concat_bc: Vec<u8> = Vec::new();
+    for (i, line) in stdin.lock().lines().enumerate() {
+        let line = line.expect("Could not read from stdin");
+        if header && i == 0 {
+            writeln!(out, "{}\tcorrected\toutcome\tmismatches", line).expect("Unable to write data");
+            continue;
+        }
+        let fields = line.split('\t').collect_vec();
+        let field = |col:usize| match fields.get(col - 1) {
+            Some(field) => field.trim().as_bytes(),
+            None => {
+                error!("Line {} has no column {}", i + 1, col);
+                process::exit(1)
+            }
+        };
+
+        //The extractors want at least one base after the block
+        bc_read.clear();
+        bc_read.extend_from_slice(column.map_or(line.trim().as_bytes(), field));
+        bc_read.push(b'N');
+        let qual = qual_column.map(|col| {
+            bc_qual.clear();
+            bc_qual.extend_from_slice(field(col));
+            bc_qual.push(b'!');
+            &bc_qual[..]
+        });
+
+        let (bc, outcome) = atrandi_barcodes.correct_with_outcome(&bc_read, qual, false);
+        out.write_all(line.as_bytes()).expect("Unable to write data");
+        match bc {
+            Some(bc) => {
+                atrandi_barcodes.write_bc_name(&bc, &mut concat_bc);
+                let mismatches = atrandi_barcodes.round_mismatches(&bc_read, &bc).map_or("-".to_string(), |m| m.iter().join(","));
+                writeln!(out, "\t{}\t{}\t{}", String::from_utf8_lossy(&concat_bc), outcome.label(), mismatches).expect("Unable to write data");
+            },
+            None => writeln!(out, "\t-\t{}\t-", outcome.label()).expect("Unable to write data")
+        }
+    }
+    out.flush().expect("Unable to write data");
+}
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// End-to-end pipeline ///////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Run barcode correction, alignment or k-mer pseudoalignment, and counting, with all outputs in one directory
+#[allow(clippy::too_many_arguments)]
+fn count_pipeline(
+    path_in_r1:&PathBuf,
+    path_in_r2:&PathBuf,
+    outdir:&Path,
+    align_cmd:&Option<String>,
+    path_transcripts:&Option<PathBuf>,
+    k:usize,
+    min_votes:usize,
+    path_gtf:&Option<PathBuf>,
+    strandedness:Strandedness,
+    progress_json:Option<u64>,
+    input:&InputOptions,
+    compress:&CompressOptions,
+    barcode_spec:&BarcodeSpec
+) {
+    let outputs = RunOutputs::in_dir(outdir);
+    outputs.create_dir();
+    let path_counts = outputs.path(OUT_COUNTS);
+    let mut report: Vec<(String, String)> = Vec::new();
+
+    match path_transcripts {
+        Some(path_transcripts) => {
+            println!("== Counting by k-mer pseudoalignment");
+            count_kmers(path_in_r1, path_in_r2, path_transcripts, &path_counts, k, min_votes, input, barcode_spec);
+        },
+        None => {
+            let path_hist = outputs.path(OUT_HISTOGRAM);
+            let path_bam = outputs.path(OUT_ALIGNED_BAM);
+
+            println!("== Correcting barcodes and aligning");
+            parse_to_fastq(
+                path_in_r1, path_in_r2,
+                &path_hist,
+                &ToFastqOptions {
+                    align_cmd: align_cmd.clone(),
+                    align_out: Some(path_bam.clone()),
+                    progress_json,
+                    ..Default::default()
+                },
+                &RunMetadata::default(),
+                input,
+                compress,
+                barcode_spec
+            );
+
+            let hist = read_histogram(&path_hist).expect("Failed to read histogram");
+            report.push(("reads_with_barcode".to_string(), hist.iter().map(|(_,cnt)| cnt).sum::<i64>().to_string()));
+            report.push(("barcodes_seen".to_string(), hist.len().to_string()));
+
+            println!("== Counting");
+            count_seq_per_bc(
+                &path_bam, &path_counts,
+                &CountSeqOptions {
+                    path_gtf: path_gtf.clone(),
+                    strandedness,
+                    ..Default::default()
+                }
+            );
+        }
+    }
+
+    ////// Unified report
+    let matrix = CountMatrix::read(&path_counts).expect("Failed to read count table");
+    report.push(("cells_in_matrix".to_string(), matrix.num_cells().to_string()));
+    report.push(("features".to_string(), matrix.features.len().to_string()));
+    report.push(("total_counts".to_string(), matrix.total().to_string()));
+
+    let output = File::create(outputs.path(OUT_SUMMARY)).expect("creation of summary failed");
+    let mut writer = BufWriter::new(output);
+    writer.write_all("metric\tvalue\n".as_bytes()).expect("Unable to write data");
+    for (key, value) in &report {
+        println!("{}: {}", key, value);
+        let line = format!("{}\t{}\n", key, value);
+        writer.write_all(line.as_bytes()).expect("Unable to write data");
+    }
+
+    println!("done");
+}
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Long reads ////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+/// Find the barcode block anywhere in long reads, on either strand. Reads are renamed BC_readid, oriented
+/// like R2, and trimmed to the sequence after the barcode block
+fn long_read_barcodes(
+    path_in:&PathBuf,
+    path_out:&PathBuf,
+    histogram_file:&PathBuf,
+    max_dist:u8,
+    compress:&CompressOptions,
+    barcode_spec:&BarcodeSpec
+) {
+
+    println!("reading whitelist ");
+    let atrandi_barcodes = barcode_spec.load().expect("Failed to read barcode file");
+    let mut finder = BarcodeBlockFinder::new(&atrandi_barcodes.chemistry);
+
+    let mut reader = open_fastq(path_in);
+    let mut parz = compress.output(path_out, 1);
+    let mut batch: Vec<u8> = Vec::with_capacity(OUTPUT_BATCH_SIZE + 1024);
+
+    let mut barcode_per_cell_count: HashMap<PackedBarcode, i32> = HashMap::new();
+    let mut concat_bc: Vec<u8> = Vec::new();
+    let mut new_name: Vec<u8> = Vec::new();
+
+    let mut read_count = 0;
+    let mut count_block_found = 0;
+    let mut count_ok_reads = 0;
+    let mut count_reverse = 0;
+    while let Some(record) = reader.next() {
+        read_count += 1;
+        if read_count%100000 == 0 {
+            println!("Processed reads: {}   Block found: {}   Ok reads: {}", read_count, count_block_found, count_ok_reads);
+        }
+        let record = record.expect("Error reading record");
+
+        //Search both strands; keep the best placement
+        let seq_rc = revcomp(record.seq());
+        let hit_fwd = finder.find(record.seq(), max_dist);
+        let hit_rev = finder.find(&seq_rc, max_dist);
+        let (is_reverse, (start, end, _)) = match (hit_fwd, hit_rev) {
+            (Some(f), Some(r)) => if r.2 < f.2 {(true, r)} else {(false, f)},
+            (Some(f), None) => (false, f),
+            (None, Some(r)) => (true, r),
+            (None, None) => continue
+        };
+        let (seq, qual) = if is_reverse {
+            count_reverse += 1;
+            (seq_rc, record.qual().iter().rev().cloned().collect_vec())
+        } else {
+            (record.seq().to_vec(), record.qual().to_vec())
+        };
+        count_block_found += 1;
+
+        let bc = match atrandi_barcodes.get_correct_bc_from_read(&seq[start..], Some(&qual[start..]), false) {
+            Some(bc) => bc,
+            None => continue
+        };
+        count_ok_reads += 1;
 
+        atrandi_barcodes.write_bc_name(&bc, &mut concat_bc);
+        *barcode_per_cell_count.entry(bc.pack()).or_insert(0) += 1;
 
-        //Get the barcode
-        let name = record.name().unwrap().to_str_lossy();
-        let (bc,_) = name.split_once('_').expect("BAM record name does not follow convention");
+        make_read_name(&mut new_name, &concat_bc, record.head());
+        write_fastq(&mut batch, &new_name, &seq[end..], &qual[end..]);
+        flush_fastq_batch(&mut parz, &mut batch, false);
+    }
+
+    flush_fastq_batch(&mut parz, &mut batch, true);
+    parz.finish().expect("Unable to write data");
+
+    ////// Write barcode histogram
+    let mut writer_h = compress.table(histogram_file);
+    writer_h.write_all("barcode\tcount\n".as_bytes()).expect("Unable to write data");
+    for (bc, cnt) in &barcode_per_cell_count {
+        atrandi_barcodes.write_packed_name(*bc, &mut concat_bc);
+        let toprint = format!("{}\t{}\n", String::from_utf8_lossy(&concat_bc), cnt);
+        writer_h.write_all(toprint.as_bytes()).expect("Unable to write data");
+    }
+    writer_h.finish().expect("Unable to write data");
+
+    println!("Processed reads: {}   Block found: {} ({} on reverse strand)   Ok reads: {}", 
+        read_count, count_block_found, count_reverse, count_ok_reads);
+    println!("done");
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Self test /////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+/// Barcode file and guides of the embedded self-test dataset
+const SELFTEST_BARCODES: &str = include_str!("../bc.csv");
+const SELFTEST_GUIDES: [(&str, &str); 2] = [("g1", "ACGTTGCAACGTTGCAACGT"), ("g2", "TTGGCCAATTGGCCAATTGG")];
+
+/// Cells of the self-test dataset: wells of each round on the plate, and the number of read pairs
+const SELFTEST_CELLS: [([usize;4], usize); 3] = [([0, 1, 2, 3], 30), ([4, 5, 6, 7], 20), ([8, 9, 10, 11], 10)];
+const SELFTEST_JUNK_READS: usize = 5;
+const SELFTEST_INSERT_LEN: usize = 36;
+
+
+/// Run ToFastq and guide counting on a small generated dataset, and check the outputs against what was put in
+fn self_test(keep: bool) {
+    let dir = std::env::temp_dir().join(format!("quick_bc_selftest_{}", process::id()));
+    std::fs::create_dir_all(&dir).expect("Could not create self-test directory");
+    println!("Self test in {}", dir.display());
+
+    ////// Write the inputs
+    let path_bc = dir.join("bc.csv");
+    std::fs::write(&path_bc, SELFTEST_BARCODES).expect("Could not write barcode file");
+    let path_guides = dir.join("guides.fa");
+    let guides_fasta: String = SELFTEST_GUIDES.iter().map(|(name, seq)| format!(">{}\n{}\n", name, seq)).collect();
+    std::fs::write(&path_guides, guides_fasta).expect("Could not write guide file");
+
+    let barcode_spec = BarcodeSpec {plates: vec![path_bc.to_string_lossy().to_string()], ..Default::default()};
+    let atrandi_barcodes = barcode_spec.load().expect("Failed to read embedded barcodes");
+
+    let mut r1: Vec<u8> = Vec::new();
+    let mut r2: Vec<u8> = Vec::new();
+    let mut block: Vec<u8> = Vec::new();
+    let mut expected_hist: Vec<(String,i64)> = Vec::new();
+    let mut expected_counts = CountMatrix::new(SELFTEST_GUIDES.iter().map(|(name,_)| FeatureInfo::new(name, FEATURE_TYPE_GUIDE)).collect());
+    let mut concat_bc: Vec<u8> = Vec::new();
+    let mut read_id = 0;
+    for (wells, num_reads) in SELFTEST_CELLS {
+        let bc = CellBarcode {plate: 0, wells};
+        atrandi_barcodes.write_bc_name(&bc, &mut concat_bc);
+        let name = String::from_utf8_lossy(&concat_bc).to_string();
+        expected_hist.push((name.clone(), num_reads as i64));
+        for i in 0..num_reads {
+            read_id += 1;
+            atrandi_barcodes.write_expected_block(&bc, &mut block);
+            //A sequencing error in every third read, which should be corrected
+            if i%3 == 0 {
+                block[40] = if block[40]==b'A' {b'C'} else {b'A'};
+            }
+            block.extend(std::iter::repeat_n(b'T', SELFTEST_INSERT_LEN));
+            let guide = i%2;
+            let seq_r1 = format!("CCC{}GGGGGGGGGGGGG", SELFTEST_GUIDES[guide].1);
+            write_fastq(&mut r1, format!("read{}", read_id).as_bytes(), seq_r1.as_bytes(), &vec![b'I'; seq_r1.len()]);
+            write_fastq(&mut r2, format!("read{}", read_id).as_bytes(), &block, &vec![b'I'; block.len()]);
+            expected_counts.add(&name, guide, 1);
+        }
+    }
+    for _ in 0..SELFTEST_JUNK_READS {
+        read_id += 1;
+        let junk = vec![b'A'; BC_BLOCK_LEN + SELFTEST_INSERT_LEN];
+        write_fastq(&mut r1, format!("read{}", read_id).as_bytes(), &junk, &vec![b'I'; junk.len()]);
+        write_fastq(&mut r2, format!("read{}", read_id).as_bytes(), &junk, &vec![b'I'; junk.len()]);
+    }
+    let path_r1 = dir.join("r1.fastq");
+    let path_r2 = dir.join("r2.fastq");
+    std::fs::write(&path_r1, r1).expect("Could not write R1");
+    std::fs::write(&path_r2, r2).expect("Could not write R2");
+
+    ////// Run the pipeline
+    let path_o1 = dir.join("out_r1.fastq.gz");
+    let path_o2 = dir.join("out_r2.fastq.gz");
+    let path_hist = dir.join("hist.tsv");
+    let path_counts = dir.join("counts");
+    parse_to_fastq(
+        &path_r1, &path_r2,
+        &path_hist,
+        &ToFastqOptions {
+            path_out_r1: Some(path_o1.clone()),
+            path_out_r2: Some(path_o2.clone()),
+            ..Default::default()
+        },
+        &RunMetadata::default(),
+        &InputOptions::default(),
+        &CompressOptions {threads: Some(1), buffer: None, max_memory: None, checksums: None},
+        &barcode_spec
+    );
+    count_guides(&path_r1, &path_r2, &vec![path_guides.clone()], &path_counts, 3, &InputOptions::default(), &barcode_spec);
+
+    ////// Check the outputs
+    let mut failed = 0;
+    let mut check = |what: &str, ok: bool| {
+        println!("{}: {}", what, if ok {"ok"} else {"FAILED"});
+        if !ok {
+            failed += 1;
+        }
+    };
+
+    let mut hist = read_histogram(&path_hist).expect("Could not read histogram");
+    hist.sort();
+    expected_hist.sort();
+    check("barcode histogram", hist == expected_hist);
+
+    let num_assigned: usize = SELFTEST_CELLS.iter().map(|(_, n)| n).sum();
+    let mut f_o2 = open_fastq(&path_o2);
+    let mut num_out = 0;
+    let mut all_trimmed = true;
+    while let Some(record) = f_o2.next() {
+        let record = record.expect("Error reading record");
+        num_out += 1;
+        all_trimmed &= record.seq().len() == SELFTEST_INSERT_LEN && record.head().contains(&b'_');
+    }
+    check("reads written", num_out == num_assigned);
+    check("barcode trimmed from R2", all_trimmed);
+
+    let counts = CountMatrix::read(&path_counts).expect("Could not read count table");
+    check("guide counts", counts.counts == expected_counts.counts && counts.features == expected_counts.features);
+
+    if keep {
+        println!("Keeping self-test files in {}", dir.display());
+    } else {
+        std::fs::remove_dir_all(&dir).expect("Could not remove self-test directory");
+    }
+
+    if failed > 0 {
+        error!("Self test failed: {} checks did not pass", failed);
+        process::exit(1)
+    }
+    println!("Self test passed");
+}
+
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// Pre-flight check //////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+/// Read the first records of a FASTQ file, checking that it decompresses and parses. Returns the records and
+/// the compression format
+fn sample_fastq(path:&PathBuf, num_reads:usize) -> Result<(Vec<OwnedRecord>, niffler::Format), String> {
+    let file = File::open(path).map_err(|e| format!("could not open: {}", e))?;
+    let (reader, compression) = get_reader(Box::new(file)).map_err(|e| format!("could not detect compression: {}", e))?;
+    let mut reader = FastqReader::new(reader);
+    let mut records = Vec::new();
+    while records.len() < num_reads {
+        match reader.next() {
+            Some(Ok(record)) => {
+                check_fastq_record(&record).map_err(|problem| 
+                    format!("malformed record {}: {}", String::from_utf8_lossy(record.head()), problem))?;
+                records.push(record.to_owned_record());
+            },
+            Some(Err(e)) => return Err(format!("error after {} reads: {}", records.len(), e)),
+            None => break
+        }
+    }
+    if records.is_empty() {
+        return Err("no reads".to_string());
+    }
+    Ok((records, compression))
+}
+
+/// Name of a read as used for pairing: the header up to the first space, without a /1 or /2 suffix
+fn pairing_name(head:&[u8]) -> &[u8] {
+    let name = head.split(|c| *c == b' ').next().unwrap_or(head);
+    match name {
+        [rest @ .., b'/', b'1' | b'2'] => rest,
+        _ => name
+    }
+}
+
+/// Validate the inputs of a ToFastq run before starting it: that the FASTQ files decompress and parse, R1 and R2
+/// names pair up, the whitelists load and match reads, and the output directory is writable with enough space
+fn check_inputs(path_r1:&PathBuf, path_r2:&PathBuf, outdir:&Option<PathBuf>, num_reads:usize, barcode_spec:&BarcodeSpec) {
+    let mut failed = 0;
+    let mut check = |what: &str, result: Result<String, String>| {
+        match result {
+            Ok(detail) => println!("{}: ok ({})", what, detail),
+            Err(detail) => {
+                println!("{}: FAILED ({})", what, detail);
+                failed += 1;
+            }
+        }
+    };
+
+    let sample_r1 = sample_fastq(path_r1, num_reads);
+    let sample_r2 = sample_fastq(path_r2, num_reads);
+    for (name, sample) in [("R1", &sample_r1), ("R2", &sample_r2)] {
+        check(&format!("{} readable", name), match sample {
+            Ok((records, compression)) => Ok(format!("{} reads, {:?} compression", records.len(), compression)),
+            Err(e) => Err(e.clone())
+        });
+        if let Ok((records, _)) = sample {
+            if let Some(profile) = QualityProfile::new(records.iter().map(|r| r.qual())) {
+                let detail = format!("{:?}, {} distinct qualities{}", profile.offset(), profile.distinct, 
+                    if profile.is_binned() {", binned"} else {""});
+                check(&format!("{} quality encoding", name), 
+                    if profile.offset() == PhredOffset::Phred64 {Err(detail + "; use --fix-phred64")} else {Ok(detail)});
+            }
+        }
+    }
+
+    if let (Ok((records_r1, _)), Ok((records_r2, _))) = (&sample_r1, &sample_r2) {
+        let mismatch = records_r1.iter().zip(records_r2.iter())
+            .position(|(r1, r2)| pairing_name(r1.head()) != pairing_name(r2.head()));
+        check("R1/R2 pairing", match mismatch {
+            Some(i) => Err(format!("read {} is {} in R1 but {} in R2", i + 1, 
+                String::from_utf8_lossy(pairing_name(records_r1[i].head())), String::from_utf8_lossy(pairing_name(records_r2[i].head())))),
+            None if records_r1.len() != records_r2.len() => Err(format!("R1 has {} reads but R2 has {}", records_r1.len(), records_r2.len())),
+            None => Ok(format!("{} pairs", records_r1.len()))
+        });
+    }
+
+    match barcode_spec.load() {
+        Ok(atrandi_barcodes) => {
+            check("whitelist", Ok(format!("{} plates", atrandi_barcodes.plates.len())));
+            if let Ok((records_r2, _)) = &sample_r2 {
+                let num_assigned = records_r2.iter()
+                    .filter(|r| atrandi_barcodes.get_correct_bc_from_read(r.seq(), Some(r.qual()), false).is_some())
+                    .count();
+                println!("reads assigned a barcode: {} of {} ({:.1}%)", 
+                    num_assigned, records_r2.len(), 100.0 * num_assigned as f64 / records_r2.len() as f64);
+            }
+        },
+        Err(e) => check("whitelist", Err(e.to_string()))
+    }
+
+    if let Some(outdir) = outdir {
+        let probe = outdir.join(".quick_bc_check");
+        check("output directory writable", std::fs::create_dir_all(outdir)
+            .and_then(|_| File::create(&probe))
+            .and_then(|_| std::fs::remove_file(&probe))
+            .map(|_| outdir.display().to_string())
+            .map_err(|e| format!("{}: {}", outdir.display(), e)));
+
+        //Outputs are compressed FASTQ much like the inputs, the barcode block aside
+        let input_size: u64 = [path_r1, path_r2].iter().filter_map(|p| std::fs::metadata(p).ok()).map(|m| m.len()).sum();
+        check("disk space", match fs2::available_space(outdir) {
+            Ok(available) if available >= input_size => Ok(format!("{} MB free, about {} MB needed", available / 1_000_000, input_size / 1_000_000)),
+            Ok(available) => Err(format!("{} MB free, about {} MB needed", available / 1_000_000, input_size / 1_000_000)),
+            Err(e) => Err(format!("could not get free space: {}", e))
+        });
+    }
+
+    if failed > 0 {
+        error!("Check failed: {} checks did not pass", failed);
+        process::exit(1)
+    }
+    println!("All checks passed");
+}
+
+
+use quick_bc::countfile::{CountMatrix, FeatureInfo, group_lengths, read_feature_map, read_spike_in_molecules};
+use quick_bc::trim::{quality_trim_len, find_read_through, phred64_to_phred33, PhredOffset, QualityProfile};
+use bio::alphabets::dna::{revcomp, complement};
+use quick_bc::io::{Barcode, read_barcodes, open_fasta};
+use quick_bc::kmer::KmerIndex;
+use quick_bc::umi::{UmiCounts, UmiStats};
+use quick_bc::cellindex::{CellIndex, LogIndex};
+use quick_bc::bgzf::{ParBgzfReader, is_bgzf, open_bgzf_at};
+use quick_bc::checksum::ChecksumManifest;
+use quick_bc::annotation::{Gene, RegionIndex, Strandedness, read_gtf};
+use quick_bc::histogram::{BloomFilter, CountMinSketch, SeenKeys, TableWriter, read_histogram, merge_histograms, store_histogram, top_barcodes, estimate_cells, DEFAULT_EXPECTED_CELLS};
+use quick_bc::barcode::{AtrandiBarcodes, BarcodeSpec, CellBarcode, PackedBarcode, Chemistry, Scoring, BarcodeBlockFinder, CycleStats, BlockProfile, BC_BLOCK_LEN, CorrectionOutcome, OutcomeCounts, QualityStats, QualitySummary, mean_quality, repeat_fraction, num_similar_elements, extract_bc_optimistic_atrandi, learn_whitelist, PlateFormat};
+use quick_bc::collision::{well_frequencies, pairwise_collision_probability, expected_collision_rate, uniform_well_frequencies, min_wells_per_round, simulate_collisions, min_pairwise_distance};
+use seq_io::fasta::Record as FastaRecord;
+
+
+/////////////////////////////////////////////////////////////////////////////////////////
+///////////////////////////////// CLI parser ////////////////////////////////////////////
+/////////////////////////////////////////////////////////////////////////////////////////
+
+
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]  // reads from Cargo.toml
+struct Cli {
+    /// print debug info
+    #[arg(short, long, default_value_t = false, global = true)]
+    debug: bool,
+    /// Barcode whitelist file(s), possibly compressed (.gz, .zst), or an https:// URL if built with the remote feature.
+    /// Several plates can be given as PREFIX=FILE; the prefix then becomes part of the cell barcode
+    #[arg(long, global = true, num_args = 1.., default_value = "bc.csv")]
+    barcodes: Vec<String>,
+    /// Linkers between the barcode rounds, in the order they appear in the read
+    #[arg(long, global = true, default_value = "AGGA,ACTC,AAGG")]
+    linkers: String,
+    /// How to score observed barcodes against the whitelist
+    #[arg(long, global = true, value_enum, default_value_t = Scoring::Hamming)]
+    scoring: Scoring,
+    /// Base quality below which mismatches count half, for --scoring quality
+    #[arg(long, global = true, default_value_t = 20)]
+    scoring_min_qual: u8,
+    /// Custom layout of the barcode block, instead of the Atrandi one: B for barcode bases, U for UMI, other
+    /// characters skipped; or a regex with named groups bc1..bc4. Barcodes are given in read order, last round first
+    #[arg(long, global = true)]
+    pattern: Option<String>,
+    /// Sidecar file for the whitelist correction index. Loaded if it exists and matches the barcodes, otherwise written
+    #[arg(long, global = true)]
+    whitelist_index: Option<PathBuf>,
+    /// Barcode histogram from a first pass. If given, all rounds are corrected together, to the best scoring
+    /// combination among the barcodes seen in it
+    #[arg(long, global = true)]
+    joint_barcodes: Option<PathBuf>,
+    /// Minimum reads of a barcode in --joint-barcodes for its combination to be used
+    #[arg(long, global = true, default_value_t = 100)]
+    joint_min_count: i64,
+    /// Expected SHA-256 checksums of the barcode files, one per file in the order given. The run stops if any differs
+    #[arg(long, global = true, num_args = 1.., value_delimiter = ',')]
+    whitelist_sha256: Vec<String>,
+    /// TSV of the wells used in each round, with columns round, well and optionally plate. Reads whose corrected
+    /// barcode has a well not listed are rejected; see --restrict-wells to correct among them instead
+    #[arg(long, global = true)]
+    used_wells: Option<PathBuf>,
+    /// Mask barcode bases with a base quality below this to N before correction. A mismatch at N is no evidence
+    /// for another barcode, so bad cycles at fixed positions cannot lead reads to the wrong cell
+    #[arg(long, global = true)]
+    min_bc_base_qual: Option<u8>,
+    /// Only correct to the barcodes of these wells, for partially used plates, e.g. "1:A1-H6,2:A1-H12": a round,
+    /// then a well or a block of wells. Rounds not listed keep all wells. Unlike --used-wells, reads are corrected
+    /// among these wells rather than rejected afterwards
+    #[arg(long, global = true)]
+    restrict_wells: Option<String>,
+    /// TSV with run metadata for read groups: SAM tags (ID, SM, PL, LB, PU) and their values
+    #[arg(long, global = true)]
+    metadata: Option<PathBuf>,
+    /// Read group ID; default is the sample name
+    #[arg(long, global = true)]
+    rg_id: Option<String>,
+    /// Sample name for read groups (SM)
+    #[arg(long, global = true)]
+    rg_sample: Option<String>,
+    /// Sequencing platform for read groups (PL), e.g. ILLUMINA
+    #[arg(long, global = true)]
+    rg_platform: Option<String>,
+    /// Library for read groups (LB)
+    #[arg(long, global = true)]
+    rg_library: Option<String>,
+    /// Platform unit for read groups (PU), e.g. flowcell.lane
+    #[arg(long, global = true)]
+    rg_platform_unit: Option<String>,
+    /// Compression threads per output file. Default is to share the available CPUs among the output files
+    #[arg(long, global = true)]
+    compress_threads: Option<usize>,
+    /// Compression buffer size per output file, in bytes. Default is 128 KiB
+    #[arg(long, global = true)]
+    compress_buffer: Option<usize>,
+    /// Memory cap per compressed output file, in MiB. A writer holds up to about buffer * (4 * threads + 1) bytes
+    /// of blocks waiting to be compressed or written, plus a 4 MiB batch; the buffer and then the thread count are
+    /// reduced to fit. Writes block, rather than buffer more, when the output is slower than the compression
+    #[arg(long, global = true)]
+    compress_max_memory: Option<usize>,
+    /// Memory limit for the barcode counting structures of ToFastq, in MiB (compression is limited separately).
+    /// Near the limit, new barcodes are counted approximately and duplicates found with a Bloom filter; if that is
+    /// not enough, the run stops early and its outputs are marked partial
+    #[arg(long, global = true)]
+    max_memory: Option<usize>,
+    /// Only warn, rather than fail, if R1 and R2 have different numbers of reads
+    #[arg(long, global = true, default_value_t = false)]
+    lenient: bool,
+    /// Skip read pairs where either read is malformed (quality and sequence of different length, invalid characters),
+    /// rather than fail
+    #[arg(long, global = true, default_value_t = false)]
+    skip_malformed: bool,
+    /// Convert input qualities from Phred+64 to Phred+33 if they look like Phred+64, rather than fail
+    #[arg(long, global = true, default_value_t = false)]
+    fix_phred64: bool,
+    /// Threads to decompress each bgzf compressed input on, as this becomes the bottleneck once correction is
+    /// parallel. Plain gzip can only be decompressed as one stream. 0 reads bgzf as a single stream too
+    #[arg(long, global = true, default_value_t = 4)]
+    decompress_threads: usize,
+    /// Emit single-line JSON progress events on stderr (reads, assignment rate, ETA) while correcting barcodes
+    #[arg(long, global = true, default_value_t = false)]
+    progress_json: bool,
+    /// Seconds between progress events
+    #[arg(long, global = true, default_value_t = 10)]
+    progress_interval: u64,
+    /// Write SHA-256 checksums of the outputs to outputs.sha256, in the output directory. Large outputs are
+    /// checksummed as they are written
+    #[arg(long, global = true, default_value_t = false)]
+    checksums: bool,
+    /// Overwrite existing output files. By default the run stops if any of them exist
+    #[arg(long, global = true, default_value_t = false)]
+    force: bool,
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Identify BC, make fastq
+    ToFastq {
+        /// forward reads
+        #[arg(long)]
+        i1: PathBuf,
+        /// reverse reads
+        #[arg(long)]
+        i2: PathBuf,
+
+        /// forward reads output; gzip compressed, or bzip2/xz/zstd if the name ends in .bz2/.xz/.zst
+        #[arg(long, required_unless_present_any = ["align_cmd", "out_bam", "outdir"])]
+        o1: Option<PathBuf>,
+        /// reverse reads output
+        #[arg(long, required_unless_present_any = ["align_cmd", "out_bam", "outdir"])]
+        o2: Option<PathBuf>,
+
+        /// write outputs to this directory with conventional names: SAMPLE_R1.fastq.gz, SAMPLE_R2.fastq.gz,
+        /// barcode_histogram.tsv and report.json. Outputs given explicitly take precedence
+        #[arg(long)]
+        outdir: Option<PathBuf>,
+        /// sample name for the files in --outdir and the sample sheet. Default is the read group sample, or "sample"
+        #[arg(long)]
+        sample: Option<String>,
+
+        /// instead of writing FASTQ files, run this aligner command in a shell and stream interleaved reads
+        /// into its stdin, e.g. "bwa mem -p -C -R {rg} ref.fa - | samtools view -b -o {out}". {rg} is replaced by the read
+        /// group line, quoted for the shell
+        #[arg(long, conflicts_with_all = ["o1", "o2"])]
+        align_cmd: Option<String>,
+        /// aligner output file, substituted for {out} in the aligner command
+        #[arg(long, requires = "align_cmd")]
+        align_out: Option<PathBuf>,
+
+        /// instead of FASTQ files, write an unaligned BAM with read pairs as unmapped mates. The corrected barcode
+        /// goes in CB, the raw barcode block in CR/CY, and the UMI (see --umi-len) in UR/UB/QX
+        #[arg(long, conflicts_with_all = ["o1", "o2", "align_cmd", "split_by_cell"])]
+        out_bam: Option<PathBuf>,
+
+        /// histogram output (gzip compressed if the name ends in .gz)
+        #[arg(long, required_unless_present = "outdir")]
+        h: Option<PathBuf>,
+
+        /// do not trim the barcode region from reverse reads
+        #[arg(long, default_value_t = false)]
+        no_trim: bool,
+
+        /// additional bases (UMI/linker) to trim from reverse reads after the barcode region
+        #[arg(long, default_value_t = 0, conflicts_with = "no_trim")]
+        trim_extra: usize,
+
+        /// trim 3' ends of output reads where the mean quality in a window falls below this
+        #[arg(long)]
+        min_qual: Option<u8>,
+
+        /// window size for quality trimming
+        #[arg(long, default_value_t = 4)]
+        window: usize,
+
+        /// fail if the fraction of reads assigned a barcode is below this. The check is made before the outputs are
+        /// finished, so a failed run writes no histogram, report or sample sheet
+        #[arg(long, default_value_t = 0.0)]
+        min_assign_rate: f64,
+
+        /// do not fail if no or too few reads were assigned a barcode
+        #[arg(long, default_value_t = false)]
+        allow_empty: bool,
+
+        /// assign reads too short for the full barcode block to a partial barcode, with missing rounds written as -.
+        /// These R2 reads end within the barcode block, so they are written with no bases unless --no-trim is given
+        #[arg(long, default_value_t = false)]
+        allow_partial: bool,
+
+        /// write the mismatch rate per sequencing cycle of the barcode block, for assigned reads
+        #[arg(long)]
+        cycle_stats: Option<PathBuf>,
+
+        /// write the consensus of the raw barcode block of each cell as FASTA. The header lists the positions
+        /// where it differs from the expected barcode, as position:expected>consensus
+        #[arg(long)]
+        bc_consensus: Option<PathBuf>,
+
+        /// write, per cell, the fraction of reads differing from the expected barcode at each position of the block
+        #[arg(long)]
+        bc_consensus_errors: Option<PathBuf>,
+
+        /// minimum reads for a cell to be in --bc-consensus and --bc-consensus-errors
+        #[arg(long, default_value_t = 10)]
+        bc_consensus_min_reads: u32,
+
+        /// write an index of the output reads by cell, for FetchCell. The outputs are then bgzf compressed
+        #[arg(long, conflicts_with_all = ["align_cmd", "out_bam"])]
+        cell_index: Option<PathBuf>,
+
+        /// log the assignment of each read pair: read name, cell (* if none), correction outcome (partial for
+        /// partial barcodes, low_complexity or low_bc_quality for reads left out before correction) and barcode
+        /// block as read. Every read pair is logged. Written bgzf compressed (give a name ending in .gz), in read
+        /// order, with an index by cell (.cidx) for FetchLog. This index is not a tabix index
+        #[arg(long)]
+        assignment_log: Option<PathBuf>,
+
+        /// trim forward reads that run through a short insert into the barcode block
+        #[arg(long, default_value_t = false)]
+        trim_read_through: bool,
+
+        /// look for the barcode construct (linker scaffold) in forward reads, a library artifact; count such reads,
+        /// or also trim them where the construct starts
+        #[arg(long, value_enum, default_value_t = R1BlockPolicy::Ignore)]
+        r1_block: R1BlockPolicy,
+
+        /// length of the UMI following the barcode region in reverse reads
+        #[arg(long, default_value_t = 0)]
+        umi_len: usize,
+
+        /// drop duplicate reads, with the same barcode, UMI and this many first bases of the forward read
+        #[arg(long)]
+        dedup_prefix: Option<usize>,
+
+        /// write the duplication rate per cell
+        #[arg(long, requires = "dedup_prefix")]
+        dedup_report: Option<PathBuf>,
+
+        /// write at most this many read pairs per barcode; further reads of the cell are dropped
+        #[arg(long)]
+        max_reads_per_cell: Option<u64>,
+
+        /// count at most this many distinct barcodes exactly; further barcodes are counted approximately and only
+        /// added to the histogram once they are seen often, to bound memory on noisy libraries. Until then, their
+        /// reads are not capped by --max-reads-per-cell nor included in the duplication report
+        #[arg(long)]
+        max_distinct_barcodes: Option<usize>,
+
+        /// write a TSV translating each barcode to a short numeric ID. IDs are given in order of first appearance,
+        /// so they differ between runs; do not compare them across runs
+        #[arg(long)]
+        translation_table: Option<PathBuf>,
+
+        /// name reads by the numeric ID of the barcode instead of the full barcode, to make BAM files smaller.
+        /// Use --translation-table of the same run to get the barcodes back
+        #[arg(long, default_value_t = false, requires = "translation_table")]
+        short_names: bool,
+
+        /// add the barcode to the read comment as SAM tags: CB:Z: corrected, CR:Z: and CY:Z: the block as read
+        /// and its qualities, and UR:Z: the UMI if --umi-len is given. Aligners can copy them into the BAM (e.g. bwa mem -C,
+        /// with -R {rg} in --align-cmd so the read group of the comment is in the header). uBAM output always has them
+        #[arg(long, default_value_t = false)]
+        raw_barcode_tag: bool,
+
+        /// count read pairs whose name was seen before, using a Bloom filter; a small fraction of unique names may
+        /// be counted too. The count goes in the JSON report
+        #[arg(long, default_value_t = false)]
+        check_duplicate_names: bool,
+
+        /// append .dupN to the names of read pairs whose name was seen before, N counting the duplicates.
+        /// Implies --check-duplicate-names
+        #[arg(long, default_value_t = false)]
+        uniquify_names: bool,
+
+        /// size of the Bloom filter for duplicate names, in MiB. About 10 bits per read pair keep false positives near 1%
+        #[arg(long, default_value_t = 256)]
+        name_filter_mb: usize,
+
+        /// also write the reads of each called cell to a pair of FASTQ files in this directory. Requires --o1/--o2
+        #[arg(long, conflicts_with_all = ["align_cmd", "short_names"])]
+        split_by_cell: Option<PathBuf>,
+
+        /// minimum reads for a barcode to count as a cell with --split-by-cell
+        #[arg(long, default_value_t = 1000)]
+        split_min_reads: i64,
+
+        /// maximum number of files open at once with --split-by-cell; keep below the open file limit (ulimit -n)
+        #[arg(long, default_value_t = 256)]
+        split_max_open: usize,
+
+        /// do not assign reads whose barcode block has a mean base quality below this
+        #[arg(long)]
+        min_bc_mean_qual: Option<u8>,
+
+        /// do not try to correct barcode blocks that are more repetitive than this, i.e. a larger fraction of one base,
+        /// or of a dinucleotide repeat (e.g. 0.8). These are typically from empty clusters
+        #[arg(long)]
+        max_repeat_fraction: Option<f64>,
+
+        /// write a JSON report with read counts and base quality of the barcode block compared to the rest of the reads
+        #[arg(long)]
+        report_json: Option<PathBuf>,
+
+        /// write a sample sheet for downstream pipelines (nf-core style CSV, or JSON if the name ends in .json) with
+        /// the output paths, read counts, estimated cells and chemistry. Written to --outdir by default
+        #[arg(long, conflicts_with_all = ["align_cmd", "out_bam"])]
+        sample_sheet: Option<PathBuf>,
+
+        /// expected number of cells, to estimate the number of cells for the sample sheet
+        #[arg(long, default_value_t = DEFAULT_EXPECTED_CELLS)]
+        expected_cells: usize,
+
+        /// only handle every N-th read pair, for array jobs: this job takes pairs I, I+N, I+2N, ... counting from 0.
+        /// The read outputs of the jobs can be concatenated, and their histograms merged with MergeHist. Short names,
+        /// translation tables and indices are numbered per job, so they cannot be merged and are not allowed here
+        #[arg(long, requires = "split_count")]
+        split_index: Option<u64>,
+
+        /// number of jobs the read pairs are split over; see --split-index
+        #[arg(long, requires = "split_index", value_parser = clap::value_parser!(u64).range(1..),
+            conflicts_with_all = ["short_names", "translation_table", "cell_index", "assignment_log"])]
+        split_count: Option<u64>,
+
+        /// correct barcodes on this many worker threads. Default is to correct them on the main thread, as the
+        /// reads are written
+        #[arg(long, default_value_t = 0)]
+        threads: usize,
+
+        /// order of the output reads when correcting on worker threads. Some downstream tools, e.g. for
+        /// deduplication, expect the input order
+        #[arg(long, value_enum, default_value_t = OutputOrder::Input)]
+        output_order: OutputOrder,
+
+    },
+    CountSeq {
+        /// Bam input file
+        #[arg(short,long)]
+        ibam: PathBuf,
+
+        /// Count file
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Prefix of mitochondrial reference sequences, e.g. chrM; adds percentage to cell_qc.tsv
+        #[arg(long)]
+        mito_prefix: Option<String>,
+
+        /// File listing ribosomal reference sequences, one per line; adds percentage to cell_qc.tsv
+        #[arg(long)]
+        ribo_list: Option<PathBuf>,
+
+        /// BED file with regions to count reads in, instead of whole reference sequences
+        #[arg(long, conflicts_with = "gtf")]
+        regions: Option<PathBuf>,
+
+        /// GTF file with genes to count reads in, instead of whole reference sequences
+        #[arg(long)]
+        gtf: Option<PathBuf>,
+
+        /// Library strandedness, used when counting in regions that have a strand
+        #[arg(long, value_enum, default_value_t = Strandedness::Unstranded)]
+        strandedness: Strandedness,
+
+        /// Also write spliced and unspliced count tables for RNA velocity; requires --gtf
+        #[arg(long, default_value_t = false, requires = "gtf")]
+        velocity: bool,
+
+        /// TSV mapping feature IDs to groups (e.g. amplicons to genes, alternate contigs to chromosomes); counts are summed per group
+        #[arg(long)]
+        feature_map: Option<PathBuf>,
+
+        /// Do not count unmapped reads. By default they are counted under the feature *
+        #[arg(long, default_value_t = false)]
+        exclude_unmapped: bool,
+
+        /// What to do with records whose name does not hold a barcode, BC_readid
+        #[arg(long, value_enum, default_value_t = BadNamePolicy::Error)]
+        on_bad_name: BadNamePolicy,
+
+        /// Only count reads overlapping this region, chr:start-end, using the BAM index (.bai)
+        #[arg(long)]
+        region: Option<String>,
+
+        /// Write background.tsv, the ambient profile summed over barcodes with at most this many counts (empty droplets)
+        #[arg(long)]
+        background_max_count: Option<i64>,
+
+        /// Also write feature_lengths.tsv, and normalized.tsv with CPM and TPM per cell and feature. Lengths are
+        /// exonic for genes, and summed over the members of groups from --feature-map. Meant for small panels
+        #[arg(long, default_value_t = false)]
+        normalized: bool,
+
+        /// Count molecules rather than reads: collapse the UMIs (UR tag) of each cell and feature with the directional
+        /// adjacency method of UMI-tools. Writes the UMI error rate to umi_stats.tsv. Records without a UMI are skipped
+        #[arg(long, default_value_t = false)]
+        umi: bool,
+
+        /// Only keep genes of these biotypes (gene_type or gene_biotype in the GTF), e.g. protein_coding,lncRNA. Reads
+        /// are still assigned using all genes. Counts left out are summarized per cell in cell_qc.tsv
+        #[arg(long, num_args = 1.., value_delimiter = ',', requires = "gtf")]
+        include_biotypes: Vec<String>,
+
+        /// Leave out genes of these biotypes, e.g. rRNA,Mt_rRNA
+        #[arg(long, num_args = 1.., value_delimiter = ',', requires = "gtf")]
+        exclude_biotypes: Vec<String>,
+
+        /// Features with IDs starting with this are spike-ins, e.g. ERCC-. They are counted in a table of their own
+        /// (spike_in), and summarized per cell in cell_qc.tsv
+        #[arg(long)]
+        spike_in_prefix: Option<String>,
+
+        /// Molecules of each spike-in added per cell: ID and number, tab separated. Used to estimate the capture
+        /// efficiency of each cell in cell_qc.tsv
+        #[arg(long, requires = "spike_in_prefix")]
+        spike_in_molecules: Option<PathBuf>,
+
+        /// Only count the N barcodes with the most reads, leaving out background barcodes. Found with a first pass
+        /// over the BAM, unless --top-cells-histogram is given
+        #[arg(long, conflicts_with = "background_max_count")]
+        top_cells: Option<usize>,
+
+        /// Barcode histogram from ToFastq, to pick the top cells from instead of counting reads in the BAM
+        #[arg(long, requires = "top_cells")]
+        top_cells_histogram: Option<PathBuf>,
+
+        /// Expected number of cells, to call cells for the insert size report (insert_sizes.tsv) unless
+        /// --top-cells is given
+        #[arg(long, default_value_t = DEFAULT_EXPECTED_CELLS)]
+        expected_cells: usize,
+
+        /// Count reference sequences in parallel using this many threads; requires a BAM index (.bai)
+        #[arg(long, default_value_t = 1, conflicts_with = "region")]
+        threads: usize
+    },
+    /// Convert a coordinate-sorted barcoded BAM into a fragment file for ATAC
+    BamToFragments {
+        /// Bam input file, coordinate sorted
+        #[arg(short,long)]
+        ibam: PathBuf,
+
+        /// Fragment file, bgzf compressed (fragments.tsv.gz)
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Minimum mapping quality
+        #[arg(long, default_value_t = 30)]
+        min_mapq: u8,
+
+        /// What to do with records whose name does not hold a barcode, BC_readid
+        #[arg(long, value_enum, default_value_t = BadNamePolicy::Error)]
+        on_bad_name: BadNamePolicy
+    },
+    /// Compute coverage tracks (bedGraph) per group of cells
+    Coverage {
+        /// Bam input file
+        #[arg(short,long)]
+        ibam: PathBuf,
+
+        /// List of cells, or TSV with cell and group (e.g. cluster)
+        #[arg(long)]
+        cells: PathBuf,
+
+        /// Output directory, one bedGraph per group
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Size of coverage bins
+        #[arg(long, default_value_t = 50, value_parser = clap::value_parser!(u64).range(1..))]
+        bin_size: u64,
+
+        /// What to do with records whose name does not hold a barcode, BC_readid
+        #[arg(long, value_enum, default_value_t = BadNamePolicy::Error)]
+        on_bad_name: BadNamePolicy
+    },
+    /// Species-mixing (barnyard) statistics from a count table over reference sequences
+    Barnyard {
+        /// Count directory, from CountSeq
+        #[arg(short,long)]
+        input: PathBuf,
+
+        /// Prefixes of reference sequence names for each genome, e.g. hg38_ mm10_
+        #[arg(long, required = true, num_args = 2..)]
+        genomes: Vec<String>,
+
+        /// Per-cell species assignment
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Minimum reads for a cell to be considered
+        #[arg(long, default_value_t = 100)]
+        min_reads: i32,
+
+        /// Minimum fraction of reads from one genome to assign a cell to it
+        #[arg(long, default_value_t = 0.8)]
+        min_fraction: f64
+    },
+    /// Reads per well of each plate and round, as a grid for plotting as a heatmap
+    PlateHeatmap {
+        /// Barcode histogram, from ToFastq
+        #[arg(long)]
+        h: PathBuf,
+
+        /// Heatmap output (TSV)
+        #[arg(short,long)]
+        out: PathBuf
+    },
+    /// Write a standalone HTML report with the knee plot, barcode correction, plate heatmaps, and read qualities and lengths
+    Report {
+        /// Barcode histogram, from ToFastq
+        #[arg(long)]
+        h: PathBuf,
+
+        /// JSON report, from ToFastq --report-json. Without it, only the histogram is shown
+        #[arg(long)]
+        report_json: Option<PathBuf>,
+
+        /// Insert sizes per quartile of cells, from CountSeq (insert_sizes.tsv)
+        #[arg(long)]
+        insert_sizes: Option<PathBuf>,
+
+        /// HTML output
+        #[arg(short,long)]
+        out: PathBuf
+    },
+    /// Estimate barcode collision rate, and flag likely multiplets
+    Collisions {
+        /// Barcode histogram, from ToFastq
+        #[arg(long)]
+        h: PathBuf,
+
+        /// Cell status output
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Number of cells to call; top barcodes by read count
+        #[arg(long)]
+        num_cells: Option<usize>,
+
+        /// Minimum reads to call a cell, if the number of cells is not given
+        #[arg(long, default_value_t = 1000)]
+        min_reads: i64,
+
+        /// Flag cells with more than this many times the median reads
+        #[arg(long, default_value_t = 2.5)]
+        max_fold: f64
+    },
+    /// Plan an experiment: simulate barcode collisions for numbers of cells and wells used per round, and show
+    /// how many mismatches the barcodes of the wells used can tolerate
+    Design {
+        /// Numbers of cells to load, e.g. 1000,10000,100000
+        #[arg(long, required = true, num_args = 1.., value_delimiter = ',')]
+        cells: Vec<usize>,
+
+        /// Number of barcoding rounds
+        #[arg(long, default_value_t = 4)]
+        rounds: usize,
+
+        /// Wells used per round; one number for all rounds, or one per round
+        #[arg(long, num_args = 1.., value_delimiter = ',', default_value = "96")]
+        wells: Vec<usize>,
+
+        /// Random experiments to simulate per number of cells
+        #[arg(long, default_value_t = 100)]
+        simulations: usize,
+
+        /// Seed for the simulations
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+
+        /// Collision rate (fraction of cells) to find the fewest wells per round for
+        #[arg(long, default_value_t = 0.05)]
+        max_collision_rate: f64,
+
+        /// Table output, in addition to printing it
+        #[arg(short,long)]
+        out: Option<PathBuf>
+    },
+    /// Rewrite a BAM with the cell barcodes moved between the read name (BC_readid) and the CB tag, optionally
+    /// changing their style, e.g. to compare with CellRanger or STARsolo cell by cell
+    BamNormalize {
+        /// Bam input file
+        #[arg(short,long)]
+        ibam: PathBuf,
+
+        /// Bam output file
+        #[arg(short,long)]
+        obam: PathBuf,
+
+        /// where the input has the barcodes; with both, the read name is tried first
+        #[arg(long, value_enum, default_value_t = BarcodeLocation::Name)]
+        from: BarcodeLocation,
+
+        /// where to put the barcodes in the output
+        #[arg(long, value_enum, default_value_t = BarcodeLocation::Tag)]
+        to: BarcodeLocation,
+
+        /// drop a numeric suffix such as -1 from input barcodes
+        #[arg(long, default_value_t = false)]
+        strip_suffix: bool,
+
+        /// drop the . between the rounds of a barcode, giving a plain sequence
+        #[arg(long, default_value_t = false)]
+        plain: bool,
+
+        /// add this suffix to the barcodes, e.g. -1 as CellRanger does
+        #[arg(long)]
+        add_suffix: Option<String>
+    },
+    /// Rewrite a BAM, assigning each cell (or pool of cells) a read group
+    AssignReadGroups {
+        /// Bam input file
+        #[arg(short,long)]
+        ibam: PathBuf,
+
+        /// Bam output file
+        #[arg(short,long)]
+        obam: PathBuf,
+
+        /// Optional TSV with the read group of each cell
+        #[arg(long)]
+        map: Option<PathBuf>,
+
+        /// Maximum number of read groups; cells are pooled if there are more
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        max_groups: u64,
+
+        /// What to do with records whose name does not hold a barcode, BC_readid. With skip, they are
+        /// written without a read group
+        #[arg(long, value_enum, default_value_t = BadNamePolicy::Error)]
+        on_bad_name: BadNamePolicy
+    },
+    /// Correct raw barcode blocks read from stdin, one per line or in a TSV column, and write the lines to stdout
+    /// with the corrected barcode, outcome and mismatches per round appended
+    CorrectBc {
+        /// column of the TSV holding the barcode block, counting from 1. Default is the whole line
+        #[arg(long)]
+        column: Option<usize>,
+
+        /// column holding the base qualities of the block, for quality-aware scoring
+        #[arg(long)]
+        qual_column: Option<usize>,
+
+        /// the first line is a header; it is passed on with the new column names
+        #[arg(long, default_value_t = false)]
+        header: bool
+    },
+    /// Infer the barcode whitelist of each round from the reads, and write it in bc.csv format
+    LearnWhitelist {
+        /// Reverse read input file, holding the barcodes
+        #[arg(long)]
+        i2: PathBuf,
+
+        /// Whitelist output file
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Number of reads to sample
+        #[arg(long, default_value_t = 1000000)]
+        max_reads: usize,
+
+        /// Maximum number of barcodes per round
+        #[arg(long, default_value_t = 96)]
+        max_per_round: usize,
+
+        /// Barcodes within this many mismatches of a more frequent one are treated as errors
+        #[arg(long, default_value_t = 1)]
+        min_distance: usize,
+
+        /// Minimum number of reads for a barcode to be kept
+        #[arg(long, default_value_t = 100)]
+        min_count: u64
+    },
+    /// Run the whole pipeline, FASTQ to count matrix: barcode correction, then alignment with an external
+    /// aligner or k-mer pseudoalignment, then counting
+    Count {
+        /// forward reads, containing the cDNA
+        #[arg(long)]
+        i1: PathBuf,
+        /// reverse reads, containing the cell barcode
+        #[arg(long)]
+        i2: PathBuf,
 
-        //Figure out which feature. Need to map <no chromosome>
-        let seqid = record.reference_sequence_id();
-        let feature_name = match seqid {
-            Some(seqid) => {
-                seqid.expect("huh")
-            },
-            None => {
-                id_noname
-            }
-        };
+        /// Output directory
+        #[arg(short,long)]
+        outdir: PathBuf,
 
-        //Update count in table
-        barcode_per_cell_count.entry(bc.to_string())
-        .and_modify(|cellmap| { 
+        /// Aligner command, reading interleaved FASTQ on stdin and writing a BAM to {out},
+        /// e.g. "bwa mem -p ref.fa - | samtools view -b -o {out}"
+        #[arg(long, required_unless_present = "transcripts", conflicts_with = "transcripts")]
+        align_cmd: Option<String>,
 
-            (*cellmap).entry(feature_name)
-            .and_modify(|x| *x += 1)
-            .or_insert(1);
+        /// FASTA file with transcripts, to count by k-mer pseudoalignment instead of aligning
+        #[arg(long)]
+        transcripts: Option<PathBuf>,
 
+        /// k-mer size for pseudoalignment, at most 31
+        #[arg(short, long, default_value_t = 31, value_parser = clap::value_parser!(u8).range(1..=31))]
+        k: u8,
 
-            // (*cellmap).insert(feature_name, 1);          //Fail. not updating. TODO
+        /// Minimum number of k-mers supporting a transcript
+        #[arg(long, default_value_t = 2)]
+        min_votes: usize,
 
-        })
-        .or_insert({
-            let mut cellmap = HashMap::new();
-            cellmap.insert(feature_name, 1);
-            cellmap
-        });
-        
-    }
+        /// GTF file, to count aligned reads per gene
+        #[arg(long, conflicts_with = "transcripts")]
+        gtf: Option<PathBuf>,
 
+        /// Library strandedness, used when counting in genes
+        #[arg(long, value_enum, default_value_t = Strandedness::Unstranded)]
+        strandedness: Strandedness
+    },
+    /// Find and correct barcodes in long reads (Nanopore/PacBio), searching anywhere on both strands
+    LongReads {
+        /// Long read input file
+        #[arg(short,long)]
+        input: PathBuf,
 
-    //println!("{:?}", barcode_per_cell_count);
+        /// Renamed and trimmed reads
+        #[arg(short,long)]
+        out: PathBuf,
 
+        /// histogram output (gzip compressed if the name ends in .gz)
+        #[arg(long)]
+        h: PathBuf,
 
+        /// Maximum edit distance when searching for the linker scaffold
+        #[arg(long, default_value_t = 6)]
+        max_dist: u8
+    },
+    /// Run ToFastq and guide counting on a small embedded dataset and check the results, to validate the installation
+    SelfTest {
+        /// keep the generated files
+        #[arg(long, default_value_t = false)]
+        keep: bool
+    },
+    /// Validate inputs before a long ToFastq run: FASTQ integrity and pairing on the first reads, whitelists,
+    /// and output directory writability and free space
+    Check {
+        /// forward reads
+        #[arg(long)]
+        i1: PathBuf,
+        /// reverse reads
+        #[arg(long)]
+        i2: PathBuf,
 
-    store_counttable(
-        path_csv, 
-        barcode_per_cell_count, 
-        name_of_features
-    ).expect("Failed to store count table");
+        /// output directory to check for writability and free space
+        #[arg(long)]
+        outdir: Option<PathBuf>,
 
-}
+        /// number of reads to check from the start of each file
+        #[arg(long, default_value_t = 10000)]
+        num_reads: usize
+    },
+    /// Apply new cell filters to corrected FASTQ files from ToFastq, without extracting and correcting barcodes
+    /// again. Cells are chosen from the ToFastq histogram; --used-wells is also applied if given, also to partial
+    /// barcodes
+    Refilter {
+        /// forward reads, from ToFastq
+        #[arg(long)]
+        i1: PathBuf,
+        /// reverse reads, from ToFastq
+        #[arg(long)]
+        i2: PathBuf,
 
+        /// forward reads output
+        #[arg(long)]
+        o1: PathBuf,
+        /// reverse reads output
+        #[arg(long)]
+        o2: PathBuf,
 
-use quick_bc::countfile::store_counttable;
+        /// barcode histogram, from ToFastq
+        #[arg(long)]
+        h: PathBuf,
 
+        /// histogram of the reads written per cell
+        #[arg(long)]
+        out_h: Option<PathBuf>,
 
-/////////////////////////////////////////////////////////////////////////////////////////
-///////////////////////////////// CLI parser ////////////////////////////////////////////
-/////////////////////////////////////////////////////////////////////////////////////////
+        /// assignment log of the ToFastq run, to take the cell of each read from instead of its name (needed for
+        /// --short-names). The run must have written its reads in input order
+        #[arg(long)]
+        assignment_log: Option<PathBuf>,
 
+        /// only keep reads with these correction outcomes in the assignment log, e.g. exact,corrected_1_mismatch
+        #[arg(long, num_args = 1.., value_delimiter = ',', requires = "assignment_log")]
+        outcomes: Vec<String>,
 
+        /// only keep cells with at least this many reads in the histogram
+        #[arg(long, default_value_t = 0)]
+        min_reads: i64,
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]  // reads from Cargo.toml
-struct Cli {
-    /// print debug info
-    #[arg(short, long, default_value_t = false, global = true)]
-    debug: bool,
-    #[command(subcommand)]
-    command: Option<Commands>,
-}
+        /// only keep the N cells with the most reads in the histogram
+        #[arg(long)]
+        top_cells: Option<usize>,
 
+        /// only keep cells in this file, one barcode per line
+        #[arg(long)]
+        cells: Option<PathBuf>,
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Identify BC, make fastq
-    ToFastq {
-        /// forward reads
+        /// keep at most this many read pairs per cell
+        #[arg(long)]
+        max_reads_per_cell: Option<u64>
+    },
+    /// Extract the reads of one cell from corrected FASTQ files written by ToFastq with --cell-index
+    FetchCell {
+        /// forward reads, from ToFastq
         #[arg(long)]
         i1: PathBuf,
-        /// reverse reads
+        /// reverse reads, from ToFastq
         #[arg(long)]
         i2: PathBuf,
 
-        /// forward reads
+        /// cell index, from ToFastq --cell-index
+        #[arg(long)]
+        index: PathBuf,
+
+        /// cell barcode, as in the read names
+        #[arg(long)]
+        cell: String,
+
+        /// forward reads output
         #[arg(long)]
         o1: PathBuf,
-        /// reverse reads
+        /// reverse reads output
         #[arg(long)]
-        o2: PathBuf,
+        o2: PathBuf
+    },
+    /// Print the read assignments of one cell from a log written by ToFastq with --assignment-log
+    FetchLog {
+        /// assignment log, from ToFastq; its index is read from next to it
+        #[arg(long)]
+        log: PathBuf,
 
-        /// histogram output
+        /// cell barcode, or * for the reads not assigned to a cell
         #[arg(long)]
-        h: PathBuf
+        cell: String,
 
+        /// output file, or stdout if not given
+        #[arg(short, long)]
+        out: Option<PathBuf>
     },
-    CountSeq {
-        /// Bam input file
+    /// Merge several count tables, e.g. from different lanes or samples
+    MergeCounts {
+        /// Count directories to merge
+        #[arg(short, long, required = true, num_args = 1..)]
+        input: Vec<PathBuf>,
+
+        /// Prefix to add to cell barcodes, one per input in the same order
+        #[arg(long, num_args = 1..)]
+        prefix: Vec<String>,
+
+        /// Merged count directory
         #[arg(short,long)]
-        ibam: PathBuf,
+        out: PathBuf
+    },
+    /// Rarefy a count table to a fixed number of counts per cell, for comparing samples of different depth
+    DownsampleCounts {
+        /// Count directory
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Counts to keep per cell. Cells with fewer are left out
+        #[arg(long)]
+        counts_per_cell: usize,
+
+        /// Seed for the sampling
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+
+        /// Downsampled count directory
+        #[arg(short,long)]
+        out: PathBuf
+    },
+    /// Sum several barcode histograms, e.g. from different lanes
+    MergeHist {
+        /// Histograms to merge
+        #[arg(short, long, required = true, num_args = 1..)]
+        input: Vec<PathBuf>,
+
+        /// Merged histogram
+        #[arg(short,long)]
+        out: PathBuf
+    },
+    /// Convert a count table to long format (cell, feature, count)
+    ConvertCounts {
+        /// Count directory
+        #[arg(short,long)]
+        input: PathBuf,
+
+        /// Output file
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = LongFormat::Tsv)]
+        format: LongFormat,
+
+        /// Only keep entries with at least this count
+        #[arg(long, default_value_t = 1)]
+        min_count: i32
+    },
+    /// List the top features of each called cell, one line per cell, for quick checks of targeted panels
+    TopFeatures {
+        /// Count directory
+        #[arg(short,long)]
+        input: PathBuf,
+
+        /// Output TSV file
+        #[arg(short,long)]
+        out: PathBuf,
+
+        /// Features to list per cell
+        #[arg(short = 'k', long, default_value_t = 5)]
+        top: usize,
+
+        /// Number of cells to list, those with the most counts. Default is to call cells from the counts
+        #[arg(long)]
+        cells: Option<usize>,
+
+        /// Expected number of cells, for calling cells
+        #[arg(long, default_value_t = DEFAULT_EXPECTED_CELLS)]
+        expected_cells: usize
+    },
+    /// Count feature barcodes (e.g. antibody tags) per cell
+    CountFeatures {
+        /// forward reads, containing the feature barcode
+        #[arg(long)]
+        i1: PathBuf,
+        /// reverse reads, containing the cell barcode
+        #[arg(long)]
+        i2: PathBuf,
+
+        /// FASTA file(s) with feature barcodes
+        #[arg(long, required = true, num_args = 1..)]
+        features: Vec<PathBuf>,
+
+        /// Position in forward read where the feature barcode starts
+        #[arg(long, default_value_t = 0)]
+        feature_start: usize,
+
+        /// Maximum edit distance to a feature barcode
+        #[arg(long, default_value_t = 1)]
+        max_dist: u8,
+
+        /// Count file
+        #[arg(short,long)]
+        out: PathBuf
+    },
+    /// Count CRISPR guides per cell
+    CountGuides {
+        /// forward reads, containing the guide
+        #[arg(long)]
+        i1: PathBuf,
+        /// reverse reads, containing the cell barcode
+        #[arg(long)]
+        i2: PathBuf,
+
+        /// FASTA file(s) with the guide library
+        #[arg(long, required = true, num_args = 1..)]
+        guides: Vec<PathBuf>,
+
+        /// Position in forward read where the guide starts
+        #[arg(long, default_value_t = 0)]
+        guide_start: usize,
+
+        /// Count file
+        #[arg(short,long)]
+        out: PathBuf
+    },
+    /// Experimental: count transcripts per cell by k-mer voting, without an aligner
+    CountKmers {
+        /// forward reads, containing the cDNA
+        #[arg(long)]
+        i1: PathBuf,
+        /// reverse reads, containing the cell barcode
+        #[arg(long)]
+        i2: PathBuf,
+
+        /// FASTA file with transcripts
+        #[arg(long)]
+        transcripts: PathBuf,
+
+        /// k-mer size, at most 31
+        #[arg(short, long, default_value_t = 31, value_parser = clap::value_parser!(u8).range(1..=31))]
+        k: u8,
+
+        /// Minimum number of k-mers supporting a transcript
+        #[arg(long, default_value_t = 2)]
+        min_votes: usize,
 
         /// Count file
         #[arg(short,long)]
         out: PathBuf
-    }    
+    }
+}
+
+
+/// On the first SIGINT/SIGTERM, let the command finish what it has written. On the second, stop at once. Only
+/// for commands that check INTERRUPTED; others keep the default of stopping at once
+fn install_interrupt_handler() {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!("Interrupted; finishing outputs. Interrupt again to stop immediately");
+    }).expect("Could not install signal handler");
 }
 
 
@@ -514,26 +6145,628 @@ fn main() {
     let level = if cli.debug { "debug" } else { "info" };
     Builder::from_env(Env::default().default_filter_or(level)).init();
 
+    let compress = CompressOptions {
+        threads: cli.compress_threads,
+        buffer: cli.compress_buffer,
+        max_memory: cli.compress_max_memory.map(|mib| mib*1024*1024),
+        checksums: if cli.checksums {Some(ChecksumManifest::default())} else {None}
+    };
+    let input_options = InputOptions {lenient: cli.lenient, skip_malformed: cli.skip_malformed, fix_phred64: cli.fix_phred64, decompress_threads: cli.decompress_threads};
+    let chemistry = match Chemistry::from_linkers(&cli.linkers) {
+        Ok(chemistry) => chemistry,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1)
+        }
+    };
+    let barcode_spec = BarcodeSpec {
+        plates: cli.barcodes.clone(),
+        chemistry,
+        scoring: cli.scoring,
+        scoring_min_qual: cli.scoring_min_qual,
+        pattern: cli.pattern.clone(),
+        index: cli.whitelist_index.clone(),
+        joint: cli.joint_barcodes.clone(),
+        joint_min_count: cli.joint_min_count,
+        whitelist_sha256: cli.whitelist_sha256.clone(),
+        used_wells: cli.used_wells.clone(),
+        min_base_qual: cli.min_bc_base_qual,
+        restrict_wells: cli.restrict_wells.clone()
+    };
+    let mut metadata = match &cli.metadata {
+        Some(path) => RunMetadata::read(path).expect("Could not read metadata file"),
+        None => RunMetadata::default()
+    };
+    metadata.id = cli.rg_id.clone().or(metadata.id);
+    metadata.sample = cli.rg_sample.clone().or(metadata.sample);
+    metadata.platform = cli.rg_platform.clone().or(metadata.platform);
+    metadata.library = cli.rg_library.clone().or(metadata.library);
+    metadata.platform_unit = cli.rg_platform_unit.clone().or(metadata.platform_unit);
+    if !metadata.is_empty() {
+        debug!("Read group: {}", metadata.read_group_line());
+    }
+
+    //Outputs written by the command, checked before it runs
+    let mut outputs = RunOutputs::default();
+
     match &cli.command {
-        Some(Commands::ToFastq { i1, i2, o1, o2, h}) => {
-            parse_to_fastq(
-                &i1, &i2, 
-                &o1, &o2,
-                &h
-            );
+        Some(Commands::ToFastq { i1, i2, o1, o2, outdir, sample, align_cmd, align_out, out_bam, h, no_trim, trim_extra, min_qual, window, min_assign_rate, allow_empty, allow_partial, cycle_stats, bc_consensus, bc_consensus_errors, bc_consensus_min_reads, cell_index, assignment_log, trim_read_through, r1_block, umi_len, dedup_prefix, dedup_report, max_reads_per_cell, max_distinct_barcodes, translation_table, short_names, raw_barcode_tag, check_duplicate_names, uniquify_names, name_filter_mb, split_by_cell, split_min_reads, split_max_open, min_bc_mean_qual, max_repeat_fraction, report_json, sample_sheet, expected_cells, split_index, split_count, threads, output_order}) => {
+            install_interrupt_handler();
+            let (mut o1, mut o2, mut h, mut report_json, mut sample_sheet) = (o1.clone(), o2.clone(), h.clone(), report_json.clone(), sample_sheet.clone());
+            let sample = sample.clone().or(metadata.sample.clone()).unwrap_or("sample".to_string());
+            if let Some(outdir) = outdir {
+                outputs = RunOutputs::new(outdir, &sample);
+                outputs.create_dir();
+                if align_cmd.is_none() && out_bam.is_none() {
+                    o1 = o1.or(Some(outputs.path(OUT_R1)));
+                    o2 = o2.or(Some(outputs.path(OUT_R2)));
+                    sample_sheet = sample_sheet.or(Some(outputs.path(OUT_SAMPLE_SHEET)));
+                }
+                h = h.or(Some(outputs.path(OUT_HISTOGRAM)));
+                report_json = report_json.or(Some(outputs.path(OUT_REPORT_JSON)));
+            }
+            let h = h.expect("No histogram output");
+            let shard = split_index.zip(*split_count);
+            if let Some((index, count)) = shard {
+                if index >= count {
+                    error!("--split-index must be below --split-count ({})", count);
+                    process::exit(1);
+                }
+            }
+            outputs.add([&o1, &o2, align_out, out_bam, &Some(h.clone()), cycle_stats, bc_consensus, bc_consensus_errors, cell_index, assignment_log,
+                &assignment_log.as_ref().map(|p| assignment_log_index(p)), dedup_report, translation_table, split_by_cell, &report_json, &sample_sheet]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            if cell_index.is_some() && [&o1, &o2].iter().any(|p| p.as_ref().is_some_and(|p| !p.to_string_lossy().ends_with(".gz"))) {
+                error!("With --cell-index, the reads are written bgzf compressed; give output names ending in .gz");
+                process::exit(1);
+            }
+            if assignment_log.as_ref().is_some_and(|p| !p.to_string_lossy().ends_with(".gz")) {
+                error!("The assignment log is written bgzf compressed; give a name ending in .gz");
+                process::exit(1);
+            }
+            let options = ToFastqOptions {
+                path_out_r1: o1.clone(),
+                path_out_r2: o2.clone(),
+                align_cmd: align_cmd.clone(),
+                align_out: align_out.clone(),
+                path_out_bam: out_bam.clone(),
+                no_trim: *no_trim,
+                trim_extra: *trim_extra,
+                min_qual: *min_qual,
+                qual_window: *window,
+                min_assign_rate: *min_assign_rate,
+                allow_empty: *allow_empty,
+                allow_partial: *allow_partial,
+                cycle_stats_file: cycle_stats.clone(),
+                bc_consensus: bc_consensus.clone(),
+                bc_consensus_errors: bc_consensus_errors.clone(),
+                bc_consensus_min_reads: *bc_consensus_min_reads,
+                cell_index_file: cell_index.clone(),
+                assignment_log_file: assignment_log.clone(),
+                trim_read_through: *trim_read_through,
+                r1_block: *r1_block,
+                umi_len: *umi_len,
+                dedup_prefix: *dedup_prefix,
+                dedup_report: dedup_report.clone(),
+                max_reads_per_cell: *max_reads_per_cell,
+                max_distinct_barcodes: *max_distinct_barcodes,
+                max_memory: cli.max_memory.map(|mib| mib*1024*1024),
+                translation_table: translation_table.clone(),
+                short_names: *short_names,
+                raw_barcode_tag: *raw_barcode_tag,
+                name_filter_mb: if *check_duplicate_names || *uniquify_names {Some(*name_filter_mb)} else {None},
+                uniquify_names: *uniquify_names,
+                min_bc_mean_qual: *min_bc_mean_qual,
+                max_repeat_fraction: *max_repeat_fraction,
+                report_json: report_json.clone(),
+                sample_sheet: sample_sheet.clone(),
+                sample: sample.clone(),
+                expected_cells: *expected_cells,
+                shard,
+                correct_threads: *threads,
+                output_order: *output_order,
+                progress_json: cli.progress_json.then_some(cli.progress_interval)
+            };
+            parse_to_fastq(i1, i2, &h, &options, &metadata, &input_options, &compress, &barcode_spec);
+            if let (Some(split_dir), Some(o1), Some(o2)) = (split_by_cell, &o1, &o2) {
+                //Short names do not tell the cell; the log does
+                split_fastq_by_cell(
+                    o1, o2, &h, assignment_log.as_ref().filter(|_| *short_names), split_dir, *split_min_reads, *split_max_open, &input_options
+                );
+            }
         }
-        Some(Commands::CountSeq { ibam, out}) => {
+        Some(Commands::CountSeq { ibam, out, mito_prefix, ribo_list, regions, gtf, strandedness, velocity, feature_map, exclude_unmapped, on_bad_name, region, background_max_count, normalized, umi, top_cells, top_cells_histogram, include_biotypes, exclude_biotypes, spike_in_prefix, spike_in_molecules, expected_cells, threads}) => {
+            //The other tables are written next to the count table
+            outputs.add([&Some(out.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
             count_seq_per_bc(
-                &ibam, &out
+                ibam, out,
+                &CountSeqOptions {
+                    mito_prefix: mito_prefix.clone(),
+                    ribo_list: ribo_list.clone(),
+                    path_regions: regions.clone(),
+                    path_gtf: gtf.clone(),
+                    strandedness: *strandedness,
+                    velocity: *velocity,
+                    feature_map: feature_map.clone(),
+                    exclude_unmapped: *exclude_unmapped,
+                    on_bad_name: *on_bad_name,
+                    region: region.clone(),
+                    background_max_count: *background_max_count,
+                    normalized: *normalized,
+                    umi: *umi,
+                    top_cells: *top_cells,
+                    top_cells_histogram: top_cells_histogram.clone(),
+                    include_biotypes: include_biotypes.clone(),
+                    exclude_biotypes: exclude_biotypes.clone(),
+                    spike_in_prefix: spike_in_prefix.clone(),
+                    spike_in_molecules: spike_in_molecules.clone(),
+                    expected_cells: *expected_cells,
+                    threads: *threads
+                }
+            );
+        }
+        Some(Commands::BamToFragments { ibam, out, min_mapq, on_bad_name}) => {
+            bam_to_fragments(
+                ibam, out, *min_mapq, *on_bad_name, &compress
+            );
+        }
+        Some(Commands::Coverage { ibam, cells, out, bin_size, on_bad_name}) => {
+            coverage_per_group(
+                ibam, cells, out, *bin_size as usize, *on_bad_name
+            );
+        }
+        Some(Commands::Barnyard { input, genomes, out, min_reads, min_fraction}) => {
+            barnyard(
+                input, genomes, out, *min_reads, *min_fraction
+            );
+        }
+        Some(Commands::PlateHeatmap { h, out}) => {
+            plate_heatmap(h, out, &barcode_spec);
+        }
+        Some(Commands::Report { h, report_json, insert_sizes, out}) => {
+            html_report(h, report_json, insert_sizes, out, &barcode_spec);
+        }
+        Some(Commands::Collisions { h, out, num_cells, min_reads, max_fold}) => {
+            estimate_collisions(
+                h, out, *num_cells, *min_reads, *max_fold
+            );
+        }
+        Some(Commands::Design { cells, rounds, wells, simulations, seed, max_collision_rate, out}) => {
+            design_experiment(
+                cells, *rounds, wells, *simulations, *seed, *max_collision_rate, out, &barcode_spec
+            );
+        }
+        Some(Commands::BamNormalize { ibam, obam, from, to, strip_suffix, plain, add_suffix }) => {
+            outputs.add([&Some(obam.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            normalize_bam_barcodes(
+                ibam, obam, *from, *to, *strip_suffix, *plain, add_suffix
+            );
+        }
+        Some(Commands::AssignReadGroups { ibam, obam, map, max_groups, on_bad_name}) => {
+            assign_read_groups(
+                ibam, obam, map, *max_groups as usize, *on_bad_name, &metadata
+            );
+        }
+        Some(Commands::CorrectBc { column, qual_column, header }) => {
+            correct_bc_stream(*column, *qual_column, *header, &barcode_spec);
+        }
+        Some(Commands::LearnWhitelist { i2, out, max_reads, max_per_round, min_distance, min_count}) => {
+            learn_barcode_whitelist(
+                i2, out, *max_reads, *max_per_round, *min_distance, *min_count
+            );
+        }
+        Some(Commands::Count { i1, i2, outdir, align_cmd, transcripts, k, min_votes, gtf, strandedness}) => {
+            outputs = RunOutputs::in_dir(outdir);
+            let mut written = vec![OUT_COUNTS, OUT_SUMMARY];
+            if transcripts.is_none() {
+                written.extend([OUT_HISTOGRAM, OUT_ALIGNED_BAM]);
+            }
+            let paths = written.iter().map(|template| Some(outputs.path(template))).collect_vec();
+            outputs.add(&paths);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            count_pipeline(
+                i1, i2, outdir,
+                align_cmd, transcripts, *k as usize, *min_votes,
+                gtf, *strandedness, cli.progress_json.then_some(cli.progress_interval), &input_options, &compress, &barcode_spec
+            );
+        }
+        Some(Commands::LongReads { input, out, h, max_dist}) => {
+            long_read_barcodes(
+                input, out, h, *max_dist, &compress, &barcode_spec
+            );
+        }
+        Some(Commands::SelfTest { keep }) => {
+            self_test(*keep);
+        }
+        Some(Commands::Check { i1, i2, outdir, num_reads }) => {
+            check_inputs(i1, i2, outdir, *num_reads, &barcode_spec);
+        }
+        Some(Commands::Refilter { i1, i2, o1, o2, h, out_h, assignment_log, outcomes, min_reads, top_cells, cells, max_reads_per_cell }) => {
+            outputs.add([&Some(o1.clone()), &Some(o2.clone()), out_h]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            refilter_fastq(
+                i1, i2, o1, o2, h, assignment_log, outcomes, out_h,
+                *min_reads, *top_cells, cells, *max_reads_per_cell,
+                &input_options, &compress, &barcode_spec
+            );
+        }
+        Some(Commands::FetchCell { i1, i2, index, cell, o1, o2 }) => {
+            outputs.add([&Some(o1.clone()), &Some(o2.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            fetch_cell(
+                i1, i2, index, cell, o1, o2, &compress
+            );
+        }
+        Some(Commands::FetchLog { log, cell, out }) => {
+            fetch_log(
+                log, cell, out
+            );
+        }
+        Some(Commands::MergeCounts { input, prefix, out}) => {
+            outputs.add([&Some(out.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            merge_counts(
+                input, prefix, out
+            );
+        }
+        Some(Commands::DownsampleCounts { input, counts_per_cell, seed, out}) => {
+            outputs.add([&Some(out.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            downsample_counts(
+                input, *counts_per_cell, *seed, out
+            );
+        }
+        Some(Commands::MergeHist { input, out}) => {
+            outputs.add([&Some(out.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            merge_histograms_cmd(
+                input, out
+            );
+        }
+        Some(Commands::ConvertCounts { input, out, format, min_count}) => {
+            outputs.add([&Some(out.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            convert_counts(
+                input, out, *format, *min_count
+            );
+        }
+        Some(Commands::TopFeatures { input, out, top, cells, expected_cells}) => {
+            outputs.add([&Some(out.clone())]);
+            outputs.check_overwrite(cli.force, cli.checksums);
+            top_features_cmd(
+                input, out, *top, *cells, *expected_cells
+            );
+        }
+        Some(Commands::CountFeatures { i1, i2, features, feature_start, max_dist, out}) => {
+            count_features(
+                i1, i2, features, out, 
+                *feature_start, *max_dist, &input_options, &barcode_spec
+            );
+        }
+        Some(Commands::CountGuides { i1, i2, guides, guide_start, out}) => {
+            count_guides(
+                i1, i2, guides, out, 
+                *guide_start, &input_options, &barcode_spec
+            );
+        }
+        Some(Commands::CountKmers { i1, i2, transcripts, k, min_votes, out}) => {
+            count_kmers(
+                i1, i2, transcripts, out, 
+                *k as usize, *min_votes, &input_options, &barcode_spec
             );
         }
         
         None => {}
     }
 
-
+    ////// Manifest of the checksums of the outputs. The small outputs are checksummed once complete
+    if let Some(checksums) = &compress.checksums {
+        outputs.checksum(checksums);
+        if !checksums.is_empty() {
+            let path_manifest = outputs.manifest_path()
+                .unwrap_or_else(|| checksums.first_dir().unwrap_or_default().join(OUT_CHECKSUMS));
+            checksums.store(&path_manifest).expect("Failed to store checksums");
+            println!("Checksums: {}", path_manifest.display());
+        }
+    }
 
 }
 
 
 
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write R1 and R2 FASTQ files of the self-test cells, each with reads_per_cell read pairs. Returns the
+    /// paths and the cell names
+    fn write_test_reads(dir: &Path, barcode_spec: &BarcodeSpec, reads_per_cell: usize) -> (PathBuf, PathBuf, Vec<String>) {
+        let atrandi_barcodes = barcode_spec.load().unwrap();
+        let mut r1: Vec<u8> = Vec::new();
+        let mut r2: Vec<u8> = Vec::new();
+        let mut block: Vec<u8> = Vec::new();
+        let mut concat_bc: Vec<u8> = Vec::new();
+        let mut names = Vec::new();
+        let mut read_id = 0;
+        for (wells, _) in SELFTEST_CELLS {
+            let bc = CellBarcode {plate: 0, wells};
+            atrandi_barcodes.write_bc_name(&bc, &mut concat_bc);
+            names.push(String::from_utf8_lossy(&concat_bc).to_string());
+            for _ in 0..reads_per_cell {
+                read_id += 1;
+                atrandi_barcodes.write_expected_block(&bc, &mut block);
+                block.extend(std::iter::repeat_n(b'T', SELFTEST_INSERT_LEN));
+                let seq_r1 = format!("CCC{}GGGGGGGGGGGGG", SELFTEST_GUIDES[read_id%2].1);
+                write_fastq(&mut r1, format!("read{}", read_id).as_bytes(), seq_r1.as_bytes(), &vec![b'I'; seq_r1.len()]);
+                write_fastq(&mut r2, format!("read{}", read_id).as_bytes(), &block, &vec![b'I'; block.len()]);
+            }
+        }
+        let path_r1 = dir.join("r1.fastq");
+        let path_r2 = dir.join("r2.fastq");
+        std::fs::write(&path_r1, r1).unwrap();
+        std::fs::write(&path_r2, r2).unwrap();
+        (path_r1, path_r2, names)
+    }
+
+    /// Names of the reads in a FASTQ file, in file order
+    fn read_names(path: &PathBuf) -> Vec<Vec<u8>> {
+        let mut reader = open_fastq(path);
+        let mut names = Vec::new();
+        while let Some(record) = reader.next() {
+            names.push(record.unwrap().head().to_vec());
+        }
+        names
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("@RG\\tID:a b"), "'@RG\\tID:a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_cell_index_fetch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let path_bc = dir.join("bc.csv");
+        std::fs::write(&path_bc, SELFTEST_BARCODES).unwrap();
+        let barcode_spec = BarcodeSpec {plates: vec![path_bc.to_string_lossy().to_string()], ..Default::default()};
+
+        //Enough reads for many bgzf blocks
+        let (path_r1, path_r2, cells) = write_test_reads(&dir, &barcode_spec, 2000);
+        let path_o1 = dir.join("out_r1.fastq.gz");
+        let path_o2 = dir.join("out_r2.fastq.gz");
+        let path_index = dir.join("out.cidx");
+        let compress = CompressOptions {threads: Some(2), buffer: Some(DEFAULT_COMPRESS_BUFFER), max_memory: None, checksums: None};
+        parse_to_fastq(
+            &path_r1, &path_r2,
+            &dir.join("hist.tsv"),
+            &ToFastqOptions {
+                path_out_r1: Some(path_o1.clone()),
+                path_out_r2: Some(path_o2.clone()),
+                cell_index_file: Some(path_index.clone()),
+                ..Default::default()
+            },
+            &RunMetadata::default(),
+            &InputOptions::default(),
+            &compress,
+            &barcode_spec
+        );
+
+        let all_r1 = read_names(&path_o1);
+        for cell in &cells {
+            let path_f1 = dir.join("fetched_r1.fastq.gz");
+            let path_f2 = dir.join("fetched_r2.fastq.gz");
+            fetch_cell(&path_o1, &path_o2, &path_index, cell, &path_f1, &path_f2, &compress);
+
+            let prefix = format!("{}_", cell);
+            let expected: Vec<Vec<u8>> = all_r1.iter().filter(|n| n.starts_with(prefix.as_bytes())).cloned().collect();
+            assert_eq!(expected.len(), 2000);
+            assert_eq!(read_names(&path_f1), expected);
+            assert_eq!(read_names(&path_f2), expected);
+        }
+    }
+
+    #[test]
+    fn test_count_seq_threads() {
+        use noodles::bam;
+        use noodles::core::Position;
+        use noodles::sam::alignment::RecordBuf;
+        use noodles::sam::alignment::io::Write as AlignmentWrite;
+        use noodles::sam::alignment::record::Flags;
+        use noodles::sam::alignment::record::cigar::{Op, op::Kind};
+        use noodles::sam::alignment::record_buf::{Cigar, Sequence};
+        use noodles::sam::header::record::value::{Map, map::ReferenceSequence};
+        use std::num::NonZeroUsize;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+
+        //Coordinate sorted BAM over two references, one with a ':' in its name. Every fourth read is unmapped but
+        //placed at its mate, and some reads at the end are not placed at all
+        let header = noodles::sam::Header::builder()
+            .add_reference_sequence("HLA-A*01:01", Map::<ReferenceSequence>::new(NonZeroUsize::new(100000).unwrap()))
+            .add_reference_sequence("chr2", Map::<ReferenceSequence>::new(NonZeroUsize::new(100000).unwrap()))
+            .build();
+        let path_bam = dir.join("in.bam");
+        let mut writer = bam::io::Writer::new(File::create(&path_bam).unwrap());
+        writer.write_header(&header).unwrap();
+        let mut num_unmapped = 0;
+        for seqid in [Some(0), Some(1), None] {
+            for i in 0..500 {
+                let mut builder = RecordBuf::builder()
+                    .set_name(format!("CELL{}_read{}_{}", i%7, seqid.unwrap_or(2), i))
+                    .set_sequence(Sequence::from(vec![b'A'; 20]));
+                if let Some(seqid) = seqid {
+                    builder = builder.set_reference_sequence_id(seqid).set_alignment_start(Position::new(1 + 100*i).unwrap());
+                }
+                if seqid.is_none() || i%4 == 0 {
+                    builder = builder.set_flags(Flags::UNMAPPED);
+                    num_unmapped += 1;
+                } else {
+                    builder = builder.set_flags(Flags::empty()).set_cigar(Cigar::from(vec![Op::new(Kind::Match, 20)]));
+                }
+                writer.write_alignment_record(&header, &builder.build()).unwrap();
+            }
+        }
+        writer.try_finish().unwrap();
+        drop(writer);
+        let index = bam::fs::index(&path_bam).unwrap();
+        bam::bai::fs::write(dir.join("in.bam.bai"), &index).unwrap();
+
+        let count = |threads: usize| {
+            let out = dir.join(format!("counts_{}", threads));
+            count_seq_per_bc(&path_bam, &out, &CountSeqOptions {threads, ..Default::default()});
+            out
+        };
+        let out_single = count(1);
+        let out_parallel = count(3);
+
+        let matrix_single = CountMatrix::read(&out_single).unwrap();
+        let matrix_parallel = CountMatrix::read(&out_parallel).unwrap();
+        assert_eq!(matrix_single.counts, matrix_parallel.counts);
+        assert_eq!(std::fs::read(out_single.join(OUT_MAPPING_STATS)).unwrap(), std::fs::read(out_parallel.join(OUT_MAPPING_STATS)).unwrap());
+
+        //All unmapped reads are counted under *, placed or not
+        let id_unmapped = matrix_parallel.features.iter().position(|f| f.id == "*").unwrap();
+        let counted_unmapped: i32 = matrix_parallel.counts.values().filter_map(|c| c.get(&id_unmapped)).sum();
+        assert_eq!(counted_unmapped, num_unmapped);
+    }
+
+    #[test]
+    fn test_correction_threads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let path_bc = dir.join("bc.csv");
+        std::fs::write(&path_bc, SELFTEST_BARCODES).unwrap();
+        let barcode_spec = BarcodeSpec {plates: vec![path_bc.to_string_lossy().to_string()], ..Default::default()};
+        let (path_r1, path_r2, _) = write_test_reads(&dir, &barcode_spec, 3000);
+
+        //Correct with a number of threads in some order. Returns the R1 and R2 written
+        let run = |correct_threads: usize, output_order: OutputOrder| {
+            let path_o1 = dir.join(format!("out_{}_r1.fastq", correct_threads));
+            let path_o2 = dir.join(format!("out_{}_r2.fastq", correct_threads));
+            parse_to_fastq(
+                &path_r1, &path_r2,
+                &dir.join("hist.tsv"),
+                &ToFastqOptions {
+                    path_out_r1: Some(path_o1.clone()),
+                    path_out_r2: Some(path_o2.clone()),
+                    correct_threads,
+                    output_order,
+                    ..Default::default()
+                },
+                &RunMetadata::default(),
+                &InputOptions::default(),
+                &CompressOptions {threads: None, buffer: None, max_memory: None, checksums: None},
+                &barcode_spec
+            );
+            (std::fs::read(path_o1).unwrap(), std::fs::read(path_o2).unwrap())
+        };
+
+        //Records of a FASTQ file, sorted
+        let sorted_records = |fastq: &Vec<u8>| {
+            let lines = fastq.split(|c| *c == b'\n').collect_vec();
+            lines.chunks(4).map(|record| record.join(&b"\n"[..])).sorted().collect_vec()
+        };
+
+        let (serial_r1, serial_r2) = run(0, OutputOrder::Input);
+        let (ordered_r1, ordered_r2) = run(3, OutputOrder::Input);
+        assert!(!serial_r1.is_empty());
+        assert_eq!(serial_r1, ordered_r1);
+        assert_eq!(serial_r2, ordered_r2);
+
+        let (unordered_r1, unordered_r2) = run(3, OutputOrder::Unordered);
+        assert_eq!(sorted_records(&serial_r1), sorted_records(&unordered_r1));
+        assert_eq!(sorted_records(&serial_r2), sorted_records(&unordered_r2));
+    }
+
+    #[test]
+    fn test_long_reads_block_at_end() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let path_bc = dir.join("bc.csv");
+        std::fs::write(&path_bc, SELFTEST_BARCODES).unwrap();
+        let barcode_spec = BarcodeSpec {plates: vec![path_bc.to_string_lossy().to_string()], ..Default::default()};
+        let atrandi_barcodes = barcode_spec.load().unwrap();
+
+        //The block is the last bases of the read, on either strand
+        let mut block = Vec::new();
+        atrandi_barcodes.write_expected_block(&CellBarcode {plate: 0, wells: [1, 2, 3, 4]}, &mut block);
+        let mut forward = vec![b'T'; 30];
+        forward.extend_from_slice(&block);
+        let mut reverse = revcomp(&block);
+        reverse.extend(std::iter::repeat_n(b'T', 30));
+        let mut fastq = Vec::new();
+        write_fastq(&mut fastq, b"forward", &forward, &vec![b'I'; forward.len()]);
+        write_fastq(&mut fastq, b"reverse", &reverse, &vec![b'I'; reverse.len()]);
+        let path_in = dir.join("long.fastq");
+        std::fs::write(&path_in, fastq).unwrap();
+
+        let path_hist = dir.join("hist.tsv");
+        long_read_barcodes(&path_in, &dir.join("out.fastq"), &path_hist, 2,
+            &CompressOptions {threads: None, buffer: None, max_memory: None, checksums: None}, &barcode_spec);
+        let hist = read_histogram(&path_hist).unwrap();
+        assert_eq!(hist.iter().map(|(_, n)| *n).sum::<i64>(), 2);
+    }
+
+    #[test]
+    fn test_pattern_longer_than_block() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let path_bc = dir.join("bc.csv");
+        std::fs::write(&path_bc, SELFTEST_BARCODES).unwrap();
+        let pattern = "xxxxxxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBB";
+        let barcode_spec = BarcodeSpec {plates: vec![path_bc.to_string_lossy().to_string()], pattern: Some(pattern.to_string()), ..Default::default()};
+        let atrandi_barcodes = barcode_spec.load().unwrap();
+
+        //Six bases before the block, and a UMI after it
+        let mut block = Vec::new();
+        atrandi_barcodes.write_expected_block(&CellBarcode {plate: 0, wells: [1, 2, 3, 4]}, &mut block);
+        let mut seq_r2 = b"GGGGGG".to_vec();
+        seq_r2.extend_from_slice(&block);
+        seq_r2.extend_from_slice(b"ACGTAC");
+        seq_r2.extend(std::iter::repeat_n(b'T', SELFTEST_INSERT_LEN));
+        let mut r1 = Vec::new();
+        let mut r2 = Vec::new();
+        write_fastq(&mut r1, b"read1", b"CCCCCCCCCCCCCCCCCCCC", &[b'I'; 20]);
+        write_fastq(&mut r2, b"read1", &seq_r2, &vec![b'I'; seq_r2.len()]);
+        let (path_r1, path_r2) = (dir.join("r1.fastq"), dir.join("r2.fastq"));
+        std::fs::write(&path_r1, r1).unwrap();
+        std::fs::write(&path_r2, r2).unwrap();
+
+        let path_o2 = dir.join("out_r2.fastq");
+        let path_cycles = dir.join("cycles.tsv");
+        parse_to_fastq(
+            &path_r1, &path_r2,
+            &dir.join("hist.tsv"),
+            &ToFastqOptions {
+                path_out_r1: Some(dir.join("out_r1.fastq")),
+                path_out_r2: Some(path_o2.clone()),
+                cycle_stats_file: Some(path_cycles.clone()),
+                umi_len: 6,
+                raw_barcode_tag: true,
+                no_trim: true,
+                ..Default::default()
+            },
+            &RunMetadata::default(),
+            &InputOptions::default(),
+            &CompressOptions {threads: None, buffer: None, max_memory: None, checksums: None},
+            &barcode_spec
+        );
+
+        //The raw barcode and UMI are taken where the pattern ends, and every cycle of the block is counted
+        let out_r2 = String::from_utf8(std::fs::read(path_o2).unwrap()).unwrap();
+        assert!(out_r2.contains(&format!("CR:Z:{}\t", String::from_utf8_lossy(&seq_r2[..50]))));
+        assert!(out_r2.contains("UR:Z:ACGTAC"));
+        let cycles = std::fs::read_to_string(path_cycles).unwrap();
+        assert_eq!(cycles.lines().count(), 1 + 50);
+        assert!(cycles.lines().nth(1).unwrap().starts_with("0\tlinker\t1\t0"));
+        assert!(cycles.lines().nth(7).unwrap().starts_with("6\tround4\t1\t0"));
+    }
+}
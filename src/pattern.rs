@@ -0,0 +1,165 @@
+use std::error::Error;
+
+use regex::bytes::Regex;
+
+
+/// Extracts named segments, such as barcodes and UMIs, from a read according to a pattern. Two kinds of
+/// patterns are supported:
+///
+/// * Fixed layouts, one character per base: runs of B are barcodes (bc1, bc2, ... in read order),
+///   runs of U are UMIs (umi1, umi2, ...), and any other character is a skipped base.
+///   The Atrandi block is BBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBB
+/// * Regular expressions with named groups, e.g. (?P<bc1>.{8})AGGA(?P<bc2>.{8}), matched at the start of the read.
+///   Barcodes are taken in the order of their names, bc1, bc2, ..., whatever the order of the groups
+pub enum PatternExtractor {
+    Fixed {
+        names: Vec<String>,
+        ranges: Vec<(usize, usize)>,
+        len: usize
+    },
+    Regex {
+        names: Vec<String>,
+        regex: Regex
+    }
+}
+
+impl PatternExtractor {
+
+    /// Parse a pattern. It is taken to be a regular expression if it contains a named group
+    pub fn new(pattern:&str) -> Result<PatternExtractor, Box<dyn Error>> {
+        if pattern.contains("(?P<") || pattern.contains("(?<") {
+            let regex = Regex::new(&format!("^(?:{})", pattern))?;
+            let names: Vec<String> = regex.capture_names().flatten().map(|n| n.to_string()).collect();
+            if names.is_empty() {
+                return Err(format!("Pattern has no named groups: {}", pattern).into());
+            }
+            Ok(PatternExtractor::Regex {names, regex})
+        } else {
+            let mut names = Vec::new();
+            let mut ranges = Vec::new();
+            let (mut num_bc, mut num_umi) = (0, 0);
+            let chars = pattern.as_bytes();
+            let mut i = 0;
+            while i < chars.len() {
+                let c = chars[i];
+                let start = i;
+                while i < chars.len() && chars[i]==c {
+                    i += 1;
+                }
+                match c {
+                    b'B' => {
+                        num_bc += 1;
+                        names.push(format!("bc{}", num_bc));
+                        ranges.push((start, i));
+                    },
+                    b'U' => {
+                        num_umi += 1;
+                        names.push(format!("umi{}", num_umi));
+                        ranges.push((start, i));
+                    },
+                    _ => {}
+                }
+            }
+            if names.is_empty() {
+                return Err(format!("Pattern has no barcode (B) or UMI (U) bases: {}", pattern).into());
+            }
+            Ok(PatternExtractor::Fixed {names, ranges, len: chars.len()})
+        }
+    }
+
+
+    /// Names of the segments, in the order they are returned by extract
+    pub fn names(&self) -> &[String] {
+        match self {
+            PatternExtractor::Fixed {names, ..} => names,
+            PatternExtractor::Regex {names, ..} => names
+        }
+    }
+
+
+    /// Indices of the barcode segments, i.e. those named bc*, ordered by name: bc1, bc2, ... For fixed
+    /// layouts this is read order
+    pub fn barcode_segments(&self) -> Vec<usize> {
+        let mut segments: Vec<(usize, &String)> = self.names().iter().enumerate().filter(|(_, n)| n.starts_with("bc")).collect();
+        segments.sort_by_key(|(i, n)| (n[2..].parse::<usize>().unwrap_or(usize::MAX), *i));
+        segments.into_iter().map(|(i, _)| i).collect()
+    }
+
+
+    /// Extract all segments from a read. Returns None if the read does not match the pattern
+    pub fn extract<'a>(&self, read:&'a [u8]) -> Option<Vec<&'a [u8]>> {
+        Some(self.extract_ranges(read)?.iter().map(|(from, to)| &read[*from..*to]).collect())
+    }
+
+
+    /// Where each segment is in a read, as start and end (exclusive). Returns None if the read does not match
+    /// the pattern. The same ranges apply to the base qualities of the read
+    pub fn extract_ranges(&self, read:&[u8]) -> Option<Vec<(usize, usize)>> {
+        match self {
+            PatternExtractor::Fixed {ranges, len, ..} => {
+                if read.len() < *len {
+                    return None;
+                }
+                Some(ranges.clone())
+            },
+            PatternExtractor::Regex {names, regex} => {
+                let caps = regex.captures(read)?;
+                names.iter().map(|n| caps.name(n).map(|m| (m.start(), m.end()))).collect()
+            }
+        }
+    }
+
+
+    /// Where each segment is in a read that may be too short for the whole pattern; segments that do not fit
+    /// are None. A regular expression either matches in full or not at all
+    pub fn partial_ranges(&self, read:&[u8]) -> Vec<Option<(usize, usize)>> {
+        match self {
+            PatternExtractor::Fixed {ranges, ..} => ranges.iter().map(|(from, to)| if *to <= read.len() {Some((*from, *to))} else {None}).collect(),
+            PatternExtractor::Regex {names, ..} => match self.extract_ranges(read) {
+                Some(ranges) => ranges.into_iter().map(Some).collect(),
+                None => vec![None; names.len()]
+            }
+        }
+    }
+
+
+    /// Position in the read after the matched pattern, e.g. where to trim
+    pub fn end(&self, read:&[u8]) -> Option<usize> {
+        match self {
+            PatternExtractor::Fixed {len, ..} => if read.len() >= *len {Some(*len)} else {None},
+            PatternExtractor::Regex {regex, ..} => regex.find(read).map(|m| m.end())
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_extractor() {
+        let read = b"AAAAAAAAxxxxCCCCCCCCxxxxGGGGGGGGTTTTTT";
+
+        let fixed = PatternExtractor::new("BBBBBBBBxxxxBBBBBBBBxxxxBBBBBBBBUUUU").unwrap();
+        assert_eq!(fixed.names(), &["bc1", "bc2", "bc3", "umi1"]);
+        assert_eq!(fixed.extract(read).unwrap(), vec![&b"AAAAAAAA"[..], b"CCCCCCCC", b"GGGGGGGG", b"TTTT"]);
+        assert_eq!(fixed.barcode_segments(), vec![0, 1, 2]);
+        assert_eq!(fixed.end(read), Some(36));
+        assert_eq!(fixed.extract(&read[..20]), None);
+        assert_eq!(fixed.partial_ranges(&read[..20]), vec![Some((0, 8)), Some((12, 20)), None, None]);
+
+        let regex = PatternExtractor::new("(?P<bc1>.{8})xxxx(?P<bc2>C{8})").unwrap();
+        assert_eq!(regex.extract(read).unwrap(), vec![&b"AAAAAAAA"[..], b"CCCCCCCC"]);
+        assert_eq!(regex.end(read), Some(20));
+        assert_eq!(regex.extract(b"GGGG"), None);
+        assert_eq!(regex.extract_ranges(read), Some(vec![(0, 8), (12, 20)]));
+
+        //Barcodes are ordered by name, not by where their groups are
+        let swapped = PatternExtractor::new("(?P<bc2>.{8})xxxx(?P<umi1>.{4})(?P<bc1>.{8})").unwrap();
+        assert_eq!(swapped.barcode_segments(), vec![2, 0]);
+
+        assert!(PatternExtractor::new("xxxx").is_err());
+    }
+}
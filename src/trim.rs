@@ -0,0 +1,189 @@
+use bio::pattern_matching::myers::Myers;
+
+/// Sliding window 3' quality trimming, in the style of Trimmomatic SLIDINGWINDOW.
+/// Scans from the 5' end and cuts the read at the first window where the mean quality
+/// drops below min_qual. Qualities are Phred+33. Returns the length of the read to keep
+pub fn quality_trim_len(qual: &[u8], min_qual: u8, window: usize) -> usize {
+    let window = window.max(1);
+    if qual.len() < window {
+        //Too short for a full window; judge the whole read
+        let sum: usize = qual.iter().map(|&q| q.saturating_sub(33) as usize).sum();
+        if qual.is_empty() || sum >= min_qual as usize * qual.len() {
+            return qual.len();
+        } else {
+            return 0;
+        }
+    }
+
+    let threshold = min_qual as usize * window;
+    let mut sum: usize = qual[0..window].iter().map(|&q| q.saturating_sub(33) as usize).sum();
+    let mut start = 0;
+    loop {
+        if sum < threshold {
+            //Keep the bases of the window that are still good enough, from the left
+            let mut keep = start;
+            while keep < start + window && qual[keep].saturating_sub(33) >= min_qual {
+                keep += 1;
+            }
+            return keep;
+        }
+        if start + window >= qual.len() {
+            return qual.len();
+        }
+        sum = sum + qual[start + window].saturating_sub(33) as usize - qual[start].saturating_sub(33) as usize;
+        start += 1;
+    }
+}
+
+
+
+/// Find where a forward read runs through the insert into the reverse complement of the barcode block,
+/// i.e. the length of the insert. The block is first searched anywhere in the read allowing max_dist edits;
+/// if not found, a prefix of it of at least min_overlap bases at the 3' end of the read, allowing one mismatch
+pub fn find_read_through(read: &[u8], rc_block: &[u8], min_overlap: usize, max_dist: u8) -> Option<usize> {
+    if rc_block.is_empty() || rc_block.len() > 64 {
+        return None;
+    }
+    if could_contain(read, rc_block, max_dist) {
+        let mut myers = Myers::<u64>::new(rc_block);
+        if let Some((start, _, _)) = myers.find_all(read, max_dist).next() {
+            return Some(start);
+        }
+    }
+
+    let max_overlap = rc_block.len().min(read.len());
+    for overlap in (min_overlap.max(1)..max_overlap).rev() {
+        let tail = &read[(read.len()-overlap)..];
+        let mismatches = tail.iter().zip(rc_block.iter()).filter(|(a,b)| a!=b).count();
+        if mismatches <= 1 {
+            return Some(read.len()-overlap);
+        }
+    }
+    None
+}
+
+
+/// Whether a read may hold the pattern with at most max_dist edits. Split into max_dist+1 pieces, at least one
+/// piece of the pattern must then be in the read unchanged. Most reads have none, and need no alignment
+fn could_contain(read: &[u8], pattern: &[u8], max_dist: u8) -> bool {
+    let num_pieces = max_dist as usize + 1;
+    let piece_len = pattern.len() / num_pieces;
+    if piece_len == 0 {
+        return true;
+    }
+    (0..num_pieces).any(|i| {
+        let to = if i+1 == num_pieces {pattern.len()} else {(i+1)*piece_len};
+        let piece = &pattern[(i*piece_len)..to];
+        read.windows(piece.len()).any(|w| w == piece)
+    })
+}
+
+
+/// Quality encodings a FASTQ file may use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhredOffset {
+    Phred33,
+    Phred64,
+    Ambiguous   //All qualities fit both encodings
+}
+
+
+/// Binned qualities (e.g. NovaSeq, 4 levels; HiSeq X, 8 levels) have at most this many distinct values
+const MAX_BINNED_LEVELS: usize = 8;
+
+/// Range and number of distinct quality characters in a sample of reads, to tell how qualities are encoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QualityProfile {
+    pub min: u8,
+    pub max: u8,
+    pub distinct: usize
+}
+
+impl QualityProfile {
+
+    /// Profile of the quality strings of some reads; None if there are no qualities
+    pub fn new<'a>(quals: impl Iterator<Item = &'a [u8]>) -> Option<QualityProfile> {
+        let mut seen = [false; 256];
+        for qual in quals {
+            for &q in qual {
+                seen[q as usize] = true;
+            }
+        }
+        let min = seen.iter().position(|s| *s)?;
+        let max = seen.iter().rposition(|s| *s)?;
+        Some(QualityProfile {
+            min: min as u8,
+            max: max as u8,
+            distinct: seen.iter().filter(|s| **s).count()
+        })
+    }
+
+    /// Characters below ';' only occur in Phred+33. Phred+64 starts at '@' (Q0) and goes above 'J', the highest
+    /// Phred+33 quality of current instruments
+    pub fn offset(&self) -> PhredOffset {
+        if self.min < b';' {
+            PhredOffset::Phred33
+        } else if self.min >= b'@' && self.max > b'J' {
+            PhredOffset::Phred64
+        } else {
+            PhredOffset::Ambiguous
+        }
+    }
+
+    /// Whether qualities are binned into a few levels, which gives quality-aware scoring little to go on
+    pub fn is_binned(&self) -> bool {
+        self.distinct <= MAX_BINNED_LEVELS
+    }
+}
+
+
+/// Convert Phred+64 qualities to Phred+33 in place
+pub fn phred64_to_phred33(qual: &mut [u8]) {
+    for q in qual.iter_mut() {
+        *q = q.saturating_sub(31).max(b'!');
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality_trim_len() {
+        // I = Q40, # = Q2
+        assert_eq!(quality_trim_len(b"IIIIIIIIII", 20, 4), 10);
+        assert_eq!(quality_trim_len(b"IIIIII####", 20, 4), 6);
+        assert_eq!(quality_trim_len(b"##########", 20, 4), 0);
+        assert_eq!(quality_trim_len(b"II", 20, 4), 2);
+        assert_eq!(quality_trim_len(b"", 20, 4), 0);
+    }
+
+    #[test]
+    fn test_find_read_through() {
+        let block = b"ACGTTGCAAGGCTTAC";
+        assert_eq!(find_read_through(b"GGGGGGGGGGACGTTGCAAGGCTTACTTTT", block, 8, 2), Some(10));
+        // runs into the first 10 bases of the block at the end of the read
+        assert_eq!(find_read_through(b"GGGGGGGGGGGGGGGGGGGGACGTTGCAAG", block, 8, 2), Some(20));
+        assert_eq!(find_read_through(b"GGGGGGGGGGGGGGGGGGGGGGGGGGGGGG", block, 8, 2), None);
+        // two edits in the block are still found
+        assert_eq!(find_read_through(b"GGGGGGGGGGACGTAGCAAGGCTAACTTTTGGGGG", block, 8, 2), Some(10));
+    }
+
+    #[test]
+    fn test_quality_profile() {
+        let phred33 = QualityProfile::new([&b"IIII#5AF"[..], b"FFF:"].into_iter()).unwrap();
+        assert_eq!(phred33.offset(), PhredOffset::Phred33);
+        assert_eq!((phred33.min, phred33.max, phred33.distinct), (b'#', b'I', 6));
+
+        let phred64 = QualityProfile::new([&b"hhhhBBf`"[..]].into_iter()).unwrap();
+        assert_eq!(phred64.offset(), PhredOffset::Phred64);
+        assert!(phred64.is_binned());
+        assert_eq!(QualityProfile::new([&b"FFFF"[..]].into_iter()).unwrap().offset(), PhredOffset::Ambiguous);
+        assert_eq!(QualityProfile::new(std::iter::empty()), None);
+
+        let mut qual = b"h@B".to_vec();
+        phred64_to_phred33(&mut qual);
+        assert_eq!(qual, b"I!#");
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use crate::countfile::CountMatrix;
+
+
+/// Number of positions where two UMIs of the same length differ
+fn hamming(a:&[u8], b:&[u8]) -> usize {
+    a.iter().zip(b.iter()).filter(|(x, y)| x != y).count() + a.len().abs_diff(b.len())
+}
+
+
+/// Result of collapsing the UMIs of one or more cell/feature pairs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UmiStats {
+    pub reads: u64,
+    pub umis: u64,          //Distinct UMIs as read
+    pub molecules: u64,     //UMIs left after collapsing
+    pub corrected: u64      //Reads whose UMI was merged into a more abundant one
+}
+
+impl UmiStats {
+
+    pub fn add(&mut self, other: &UmiStats) {
+        self.reads += other.reads;
+        self.umis += other.umis;
+        self.molecules += other.molecules;
+        self.corrected += other.corrected;
+    }
+
+    /// Fraction of reads whose UMI was taken to be a sequencing or PCR error
+    pub fn error_rate(&self) -> f64 {
+        if self.reads == 0 {0.0} else {self.corrected as f64 / self.reads as f64}
+    }
+}
+
+
+/// Collapse UMIs with the directional adjacency method of UMI-tools: UMI a absorbs b if they differ at one
+/// position and count(a) >= 2*count(b)-1. Starting from the most abundant UMI, everything reachable this
+/// way is one molecule
+pub fn directional_collapse(umis:&HashMap<Vec<u8>, u32>) -> UmiStats {
+    let mut sorted: Vec<(&Vec<u8>, u32)> = umis.iter().map(|(umi, n)| (umi, *n)).collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    let mut stats = UmiStats {
+        reads: sorted.iter().map(|(_, n)| *n as u64).sum(),
+        umis: sorted.len() as u64,
+        ..Default::default()
+    };
+    let mut visited = vec![false; sorted.len()];
+    let mut queue: Vec<usize> = Vec::new();
+    for seed in 0..sorted.len() {
+        if visited[seed] {
+            continue;
+        }
+        stats.molecules += 1;
+        visited[seed] = true;
+        queue.push(seed);
+        while let Some(from) = queue.pop() {
+            let (umi_from, n_from) = sorted[from];
+            for to in 0..sorted.len() {
+                let (umi_to, n_to) = sorted[to];
+                if !visited[to] && n_from >= 2*n_to - 1 && hamming(umi_from, umi_to) == 1 {
+                    visited[to] = true;
+                    stats.corrected += n_to as u64;
+                    queue.push(to);
+                }
+            }
+        }
+    }
+    stats
+}
+
+
+/// UMIs seen per cell and feature, to be collapsed into molecule counts once all reads are in
+#[derive(Default)]
+pub struct UmiCounts {
+    groups: HashMap<(String, usize), HashMap<Vec<u8>, u32>>
+}
+
+impl UmiCounts {
+
+    pub fn add(&mut self, cell: &str, feature: usize, umi: &[u8]) {
+        let group = self.groups.entry((cell.to_string(), feature)).or_default();
+        match group.get_mut(umi) {
+            Some(n) => *n += 1,
+            None => {
+                group.insert(umi.to_vec(), 1);
+            }
+        }
+    }
+
+    /// Add the UMIs of another set, e.g. counted by another thread
+    pub fn merge(&mut self, other: UmiCounts) {
+        for (key, umis) in other.groups {
+            let group = self.groups.entry(key).or_default();
+            for (umi, n) in umis {
+                *group.entry(umi).or_insert(0) += n;
+            }
+        }
+    }
+
+    /// Collapse the UMIs of each cell and feature, and add the molecules to a count table
+    pub fn collapse_into(self, matrix: &mut CountMatrix) -> UmiStats {
+        let mut total = UmiStats::default();
+        for ((cell, feature), umis) in self.groups {
+            let stats = directional_collapse(&umis);
+            matrix.add(&cell, feature, stats.molecules as i32);
+            total.add(&stats);
+        }
+        total
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn umis(list:&[(&str, u32)]) -> HashMap<Vec<u8>, u32> {
+        list.iter().map(|(umi, n)| (umi.as_bytes().to_vec(), *n)).collect()
+    }
+
+    #[test]
+    fn test_directional_collapse() {
+        //The 1-mismatch neighbour with few reads is an error of the abundant UMI
+        let stats = directional_collapse(&umis(&[("AAAA", 10), ("AAAT", 2), ("CCCC", 5)]));
+        assert_eq!(stats, UmiStats {reads: 17, umis: 3, molecules: 2, corrected: 2});
+
+        //Similar counts: two molecules, not one
+        let stats = directional_collapse(&umis(&[("AAAA", 10), ("AAAT", 8)]));
+        assert_eq!(stats.molecules, 2);
+
+        //Chains are followed: AAAA -> AAAT -> AATT
+        let stats = directional_collapse(&umis(&[("AAAA", 20), ("AAAT", 5), ("AATT", 1)]));
+        assert_eq!(stats.molecules, 1);
+        assert!((stats.error_rate() - 6.0/26.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_umi_counts() {
+        let mut counts = UmiCounts::default();
+        for umi in ["AAAA", "AAAA", "AAAA", "AAAT", "GGGG"] {
+            counts.add("cell1", 0, umi.as_bytes());
+        }
+        let mut other = UmiCounts::default();
+        other.add("cell2", 1, b"CCCC");
+        counts.merge(other);
+
+        let mut matrix = CountMatrix::new(vec![]);
+        let stats = counts.collapse_into(&mut matrix);
+        assert_eq!(matrix.counts["cell1"][&0], 2);
+        assert_eq!(matrix.counts["cell2"][&1], 1);
+        assert_eq!(stats.reads, 6);
+        assert_eq!(stats.corrected, 1);
+    }
+}